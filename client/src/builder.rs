@@ -0,0 +1,220 @@
+use std::{net::{IpAddr, SocketAddr}, path::PathBuf, time::Duration};
+use common::{CustomError, MAX_INLINE_DATA, access::AccessOptions, alloc::AllocOptions, timeout::{CancellationToken, PollMode}, verify::VerifyPattern, warmup::Warmup};
+use rdma_sys::ibv_qp_init_attr;
+use crate::{grpc_client::GrpcClient, rdma_client::RdmaClient};
+
+/// Port of the gRPC control plane the server listens on by default.
+pub const DEFAULT_PORT: u16 = 7471;
+
+/// Capabilities of the client QP.
+#[derive(Clone, Copy, Debug)]
+pub struct QpConfig{
+    pub max_send_wr: u32,
+    pub max_recv_wr: u32,
+    pub max_send_sge: u32,
+    pub max_recv_sge: u32,
+    /// At least `MAX_INLINE_DATA`, metadata messages are sent inline.
+    pub max_inline_data: u32,
+}
+
+impl Default for QpConfig{
+    fn default() -> Self{
+        QpConfig{
+            max_send_wr: 4096,
+            max_recv_wr: 4096,
+            max_send_sge: 1,
+            max_recv_sge: 1,
+            max_inline_data: MAX_INLINE_DATA as u32,
+        }
+    }
+}
+
+impl QpConfig{
+    pub(crate) fn init_attr(&self) -> ibv_qp_init_attr{
+        let mut attr = unsafe { std::mem::zeroed::<ibv_qp_init_attr>() };
+        attr.cap.max_send_wr = self.max_send_wr;
+        attr.cap.max_recv_wr = self.max_recv_wr;
+        attr.cap.max_send_sge = self.max_send_sge;
+        attr.cap.max_recv_sge = self.max_recv_sge;
+        attr.cap.max_inline_data = self.max_inline_data;
+        attr
+    }
+}
+
+/// Builds a connected `RdmaClient`, see `RdmaClient::builder`.
+#[derive(Clone)]
+pub struct RdmaClientBuilder{
+    server: Option<IpAddr>,
+    port: u16,
+    grpc_rdma_port: Option<u16>,
+    pub(crate) client_id: u32,
+    message_size: u32,
+    qp: QpConfig,
+    device: Option<String>,
+    source_address: Option<IpAddr>,
+    alloc: AllocOptions,
+    timeout: Option<Duration>,
+    poll_mode: PollMode,
+    token: CancellationToken,
+    verify: Option<(VerifyPattern, u64)>,
+    access: AccessOptions,
+    warmup: Warmup,
+    counters: Option<PathBuf>,
+}
+
+impl Default for RdmaClientBuilder{
+    fn default() -> Self{
+        RdmaClientBuilder{
+            server: None,
+            port: DEFAULT_PORT,
+            grpc_rdma_port: None,
+            client_id: 0,
+            message_size: 0,
+            qp: QpConfig::default(),
+            device: None,
+            source_address: None,
+            alloc: AllocOptions::default(),
+            timeout: Some(Duration::from_secs(30)),
+            poll_mode: PollMode::default(),
+            token: CancellationToken::new(),
+            verify: None,
+            access: AccessOptions::default(),
+            warmup: Warmup::default(),
+            counters: None,
+        }
+    }
+}
+
+impl RdmaClientBuilder{
+    pub fn new() -> RdmaClientBuilder{
+        RdmaClientBuilder::default()
+    }
+    /// Address of the server, required.
+    pub fn server(mut self, server: IpAddr) -> Self{
+        self.server = Some(server);
+        self
+    }
+    /// Port of the gRPC control plane of the server.
+    pub fn port(mut self, port: u16) -> Self{
+        self.port = port;
+        self
+    }
+    /// Reaches the gRPC control plane over RDMA CM on this port of the
+    /// server, falling back to TCP on `port` if that fails.
+    pub fn grpc_rdma_port(mut self, grpc_rdma_port: Option<u16>) -> Self{
+        self.grpc_rdma_port = grpc_rdma_port;
+        self
+    }
+    pub fn client_id(mut self, client_id: u32) -> Self{
+        self.client_id = client_id;
+        self
+    }
+    /// Largest message size the client is going to use, checked by the
+    /// server when it admits the session. 0 if unknown.
+    pub fn message_size(mut self, message_size: u32) -> Self{
+        self.message_size = message_size;
+        self
+    }
+    pub fn qp_config(mut self, qp: QpConfig) -> Self{
+        self.qp = qp;
+        self
+    }
+    /// Fails the connect unless the server is reached through the RDMA
+    /// device of this name.
+    pub fn device(mut self, device: impl Into<String>) -> Self{
+        self.device = Some(device.into());
+        self
+    }
+    /// Local address to connect from, which selects the device and port.
+    pub fn source_address(mut self, source_address: IpAddr) -> Self{
+        self.source_address = Some(source_address);
+        self
+    }
+    pub fn alloc(mut self, alloc: AllocOptions) -> Self{
+        self.alloc = alloc;
+        self
+    }
+    /// Bounds every blocking wait, `None` waits forever.
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self{
+        self.timeout = timeout;
+        self
+    }
+    /// How the client waits for completions.
+    pub fn poll_mode(mut self, poll_mode: PollMode) -> Self{
+        self.poll_mode = poll_mode;
+        self
+    }
+    /// Token which aborts the connect and the blocking waits of the clients
+    /// built, shared by all of them.
+    pub fn cancellation_token(mut self, token: CancellationToken) -> Self{
+        self.token = token;
+        self
+    }
+    /// Verifies the payloads of all transfers with `pattern`.
+    pub fn verify(mut self, pattern: VerifyPattern, seed: u64) -> Self{
+        self.verify = Some((pattern, seed));
+        self
+    }
+    /// Buffer size and access pattern of write and read tests.
+    pub fn access(mut self, access: AccessOptions) -> Self{
+        self.access = access;
+        self
+    }
+    /// Operations run before every test and left out of its results.
+    pub fn warmup(mut self, warmup: Warmup) -> Self{
+        self.warmup = warmup;
+        self
+    }
+    /// Snapshots the port counters below the sysfs root `root` around every
+    /// test, `None` skips them.
+    pub fn counters(mut self, root: Option<PathBuf>) -> Self{
+        self.counters = root;
+        self
+    }
+    /// Asks the server for a session over gRPC, connects the QP to the port
+    /// it hands out and waits until the server listens on it.
+    pub async fn connect(self) -> anyhow::Result<RdmaClient, CustomError>{
+        let server = match self.server{
+            Some(server) => server,
+            None => return Err(CustomError::new("server address not set".to_string(), -libc::EINVAL)),
+        };
+        if (self.qp.max_inline_data as usize) < MAX_INLINE_DATA{
+            return Err(CustomError::new(format!("max_inline_data must be at least {}", MAX_INLINE_DATA), -libc::EINVAL));
+        }
+        let grpc_address = format!("http://{}", SocketAddr::new(server, self.port));
+        let grpc_rdma_address = self.grpc_rdma_port.map(|port| format!("http://{}", SocketAddr::new(server, port)));
+        let mut grpc_client = GrpcClient::new(grpc_address, self.client_id).with_rdma_address(grpc_rdma_address);
+        // admission is checked against the largest buffer of the session
+        let buffer_size = self.access.buffer_size(self.message_size as usize);
+        let buffer_size = u32::try_from(buffer_size)
+            .map_err(|_| CustomError::new(format!("buffer size {} exceeds the {} bytes a connection request can announce", buffer_size, u32::MAX), -libc::EINVAL))?;
+        let rdma_port = grpc_client.request_connection(buffer_size).await
+            .map_err(|status| CustomError::new(format!("connection request rejected: {:?} {}", status.code(), status.message()), -1))?;
+        if self.token.is_cancelled(){
+            return Err(CustomError::new("connect cancelled".to_string(), -libc::ECANCELED));
+        }
+        let mut rdma_client = RdmaClient::new(self.alloc);
+        rdma_client.set_timeout(self.timeout);
+        rdma_client.set_poll_mode(self.poll_mode);
+        rdma_client.set_cancellation_token(self.token.clone());
+        if let Some((pattern, seed)) = self.verify{
+            rdma_client.set_verify(pattern, seed);
+        }
+        rdma_client.set_access(self.access);
+        rdma_client.set_warmup(self.warmup);
+        rdma_client.set_counters(self.counters);
+        // the connect blocks on the CM, so it runs off the runtime workers
+        let source_address = self.source_address;
+        let device = self.device.clone();
+        let qp = self.qp;
+        let mut rdma_client = tokio::task::spawn_blocking(move ||{
+            rdma_client.connect(server, rdma_port as u16, source_address, device.as_deref(), &qp).map(|_| rdma_client)
+        }).await.unwrap_or_else(|_| Err(CustomError::new("connect thread panicked".to_string(), -1)))?;
+        if let Err(status) = grpc_client.listen().await{
+            let _ = tokio::task::spawn_blocking(move || rdma_client.shutdown()).await;
+            return Err(CustomError::new(format!("listen request failed: {:?} {}", status.code(), status.message()), -1));
+        }
+        rdma_client.set_grpc_client(grpc_client);
+        Ok(rdma_client)
+    }
+}
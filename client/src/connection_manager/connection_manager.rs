@@ -4,12 +4,75 @@
 pub struct ConnectRequest {
     #[prost(uint32, tag = "1")]
     pub client_id: u32,
+    #[prost(uint32, tag = "2")]
+    pub message_size: u32,
+    /// session handed out by RequestConnection, set on Listen
+    #[prost(uint32, tag = "3")]
+    pub session_id: u32,
 }
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct ConnectResponse {
     #[prost(uint32, tag = "1")]
     pub server_port: u32,
+    /// server assigned id of the session
+    #[prost(uint32, tag = "2")]
+    pub session_id: u32,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct UsageRequest {
+    #[prost(uint32, tag = "1")]
+    pub session_id: u32,
+    #[prost(uint32, tag = "2")]
+    pub runs: u32,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RunUsage {
+    #[prost(uint64, tag = "1")]
+    pub wall_ns: u64,
+    #[prost(uint64, tag = "2")]
+    pub user_ns: u64,
+    #[prost(uint64, tag = "3")]
+    pub system_ns: u64,
+    #[prost(uint64, tag = "4")]
+    pub voluntary_switches: u64,
+    #[prost(uint64, tag = "5")]
+    pub involuntary_switches: u64,
+    /// changes of the port counters, empty if the server does not read them
+    #[prost(map = "string, uint64", tag = "6")]
+    pub counters: ::std::collections::HashMap<::prost::alloc::string::String, u64>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct UsageResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub runs: ::prost::alloc::vec::Vec<RunUsage>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct GroupRequest {
+    #[prost(uint32, tag = "1")]
+    pub client_id: u32,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct GroupResponse {
+    /// multicast address the server sends to
+    #[prost(string, tag = "1")]
+    pub group: ::prost::alloc::string::String,
+    #[prost(uint32, tag = "2")]
+    pub message_size: u32,
+    /// sequence number of the next datagram the server sends
+    #[prost(uint64, tag = "3")]
+    pub next_seq: u64,
+    /// subscribers after the request
+    #[prost(uint32, tag = "4")]
+    pub members: u32,
+    /// time the subscription lasts unless the client joins again
+    #[prost(uint64, tag = "5")]
+    pub lease_ms: u64,
 }
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -183,6 +246,81 @@ pub mod connection_manager_client {
                 );
             self.inner.unary(req, path, codec).await
         }
+        pub async fn session_usage(
+            &mut self,
+            request: impl tonic::IntoRequest<super::UsageRequest>,
+        ) -> std::result::Result<tonic::Response<super::UsageResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/connection_manager.ConnectionManager/SessionUsage",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(
+                    GrpcMethod::new(
+                        "connection_manager.ConnectionManager",
+                        "SessionUsage",
+                    ),
+                );
+            self.inner.unary(req, path, codec).await
+        }
+        pub async fn join_group(
+            &mut self,
+            request: impl tonic::IntoRequest<super::GroupRequest>,
+        ) -> std::result::Result<tonic::Response<super::GroupResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/connection_manager.ConnectionManager/JoinGroup",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(
+                    GrpcMethod::new("connection_manager.ConnectionManager", "JoinGroup"),
+                );
+            self.inner.unary(req, path, codec).await
+        }
+        pub async fn leave_group(
+            &mut self,
+            request: impl tonic::IntoRequest<super::GroupRequest>,
+        ) -> std::result::Result<tonic::Response<super::GroupResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/connection_manager.ConnectionManager/LeaveGroup",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(
+                    GrpcMethod::new("connection_manager.ConnectionManager", "LeaveGroup"),
+                );
+            self.inner.unary(req, path, codec).await
+        }
     }
 }
 /// Generated server implementations.
@@ -200,6 +338,18 @@ pub mod connection_manager_server {
             &self,
             request: tonic::Request<super::ConnectRequest>,
         ) -> std::result::Result<tonic::Response<super::ConnectResponse>, tonic::Status>;
+        async fn session_usage(
+            &self,
+            request: tonic::Request<super::UsageRequest>,
+        ) -> std::result::Result<tonic::Response<super::UsageResponse>, tonic::Status>;
+        async fn join_group(
+            &self,
+            request: tonic::Request<super::GroupRequest>,
+        ) -> std::result::Result<tonic::Response<super::GroupResponse>, tonic::Status>;
+        async fn leave_group(
+            &self,
+            request: tonic::Request<super::GroupRequest>,
+        ) -> std::result::Result<tonic::Response<super::GroupResponse>, tonic::Status>;
     }
     #[derive(Debug)]
     pub struct ConnectionManagerServer<T: ConnectionManager> {
@@ -376,6 +526,145 @@ pub mod connection_manager_server {
                     };
                     Box::pin(fut)
                 }
+                "/connection_manager.ConnectionManager/SessionUsage" => {
+                    #[allow(non_camel_case_types)]
+                    struct SessionUsageSvc<T: ConnectionManager>(pub Arc<T>);
+                    impl<
+                        T: ConnectionManager,
+                    > tonic::server::UnaryService<super::UsageRequest>
+                    for SessionUsageSvc<T> {
+                        type Response = super::UsageResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::UsageRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as ConnectionManager>::session_usage(&inner, request)
+                                    .await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = SessionUsageSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/connection_manager.ConnectionManager/JoinGroup" => {
+                    #[allow(non_camel_case_types)]
+                    struct JoinGroupSvc<T: ConnectionManager>(pub Arc<T>);
+                    impl<
+                        T: ConnectionManager,
+                    > tonic::server::UnaryService<super::GroupRequest>
+                    for JoinGroupSvc<T> {
+                        type Response = super::GroupResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::GroupRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as ConnectionManager>::join_group(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = JoinGroupSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/connection_manager.ConnectionManager/LeaveGroup" => {
+                    #[allow(non_camel_case_types)]
+                    struct LeaveGroupSvc<T: ConnectionManager>(pub Arc<T>);
+                    impl<
+                        T: ConnectionManager,
+                    > tonic::server::UnaryService<super::GroupRequest>
+                    for LeaveGroupSvc<T> {
+                        type Response = super::GroupResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::GroupRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as ConnectionManager>::leave_group(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = LeaveGroupSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
                 _ => {
                     Box::pin(async move {
                         Ok(
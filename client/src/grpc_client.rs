@@ -1,34 +1,148 @@
-use crate::connection_manager::connection_manager::{
-    connection_manager_client::ConnectionManagerClient, ConnectRequest
-};
-use tonic::Request;
+use std::time::Duration;
+use common::{counters::CounterDelta, doctor::Check, grpc_transport::RdmaConnector, usage::{CpuUsage, RunUsage}};
+use crate::{connection_manager::connection_manager::{
+    connection_manager_client::ConnectionManagerClient, ConnectRequest, GroupRequest, GroupResponse, UsageRequest
+}, multicast::GroupMembership};
+use tonic::{transport::{Channel, Endpoint}, Request, Status};
 
+#[derive(Clone)]
 pub struct GrpcClient{
     address: String,
     client_id: u32,
+    /// Session handed out by the server on the connection request.
+    session_id: u32,
+    rdma_address: Option<String>,
 }
 
 impl GrpcClient{
-    pub async fn request_connection(&self) -> anyhow::Result<u32>{
-        let address = self.address.clone();
+    /// Connects over RDMA if the server serves the control plane over it,
+    /// falling back to TCP.
+    async fn client(&self) -> anyhow::Result<ConnectionManagerClient<Channel>, Status>{
+        if let Some(rdma_address) = self.rdma_address.as_ref(){
+            let endpoint = Endpoint::from_shared(rdma_address.clone())
+                .map_err(|e| Status::invalid_argument(format!("{}: {}", rdma_address, e)))?;
+            match endpoint.connect_with_connector(RdmaConnector::default()).await{
+                Ok(channel) => return Ok(ConnectionManagerClient::new(channel)),
+                Err(e) => println!("grpc over rdma to {} failed, falling back to tcp: {}", rdma_address, e),
+            }
+        }
+        ConnectionManagerClient::connect(self.address.clone()).await
+            .map_err(|e| Status::unavailable(format!("{}: {}", self.address, e)))
+    }
+    /// Connects to the control plane over TCP and, if set, over RDMA, each
+    /// on its own and giving up after `timeout`.
+    pub async fn check_reachable(&self, timeout: Duration) -> Vec<Check>{
+        let hint = "check that the server runs and listens on this port, and that no firewall drops the connection";
+        let mut checks = Vec::new();
+        let name = format!("grpc {}", self.address);
+        let connect = async{
+            Endpoint::from_shared(self.address.clone()).map_err(|e| e.to_string())?
+                .connect_timeout(timeout)
+                .connect().await.map_err(|e| e.to_string())
+        };
+        checks.push(match connect.await{
+            Ok(_) => Check::pass(name, "reachable over tcp"),
+            Err(e) => Check::fail(name, e, hint),
+        });
+        if let Some(rdma_address) = self.rdma_address.as_ref(){
+            let name = format!("grpc over rdma {}", rdma_address);
+            let connect = async{
+                let endpoint = Endpoint::from_shared(rdma_address.clone()).map_err(|e| e.to_string())?;
+                match tokio::time::timeout(timeout, endpoint.connect_with_connector(RdmaConnector::default())).await{
+                    Ok(result) => result.map_err(|e| e.to_string()),
+                    Err(_) => Err(format!("no connection within {:?}", timeout)),
+                }
+            };
+            checks.push(match connect.await{
+                Ok(_) => Check::pass(name, "reachable over rdma cm"),
+                Err(e) => Check::warn(name, e, "start the server with --grpc-rdma-port, the client falls back to tcp meanwhile"),
+            });
+        }
+        checks
+    }
+    /// Asks for an RDMA port for messages of `message_size` bytes and keeps
+    /// the id of the session. Fails with the gRPC status if the server does
+    /// not admit the session.
+    pub async fn request_connection(&mut self, message_size: u32) -> anyhow::Result<u32, Status>{
         let client_id = self.client_id;
-        let mut client = ConnectionManagerClient::connect(address).await.unwrap();
-        let request = Request::new(ConnectRequest{client_id});
-        let response = client.request_connection(request).await.unwrap().into_inner();
+        let mut client = self.client().await?;
+        let request = Request::new(ConnectRequest{client_id, message_size, session_id: 0});
+        let response = client.request_connection(request).await?.into_inner();
+        self.session_id = response.session_id;
         Ok(response.server_port)
     }
-    pub async fn listen(&self) -> anyhow::Result<()>{
-        let address = self.address.clone();
+    pub async fn listen(&self) -> anyhow::Result<(), Status>{
         let client_id = self.client_id;
-        let mut client = ConnectionManagerClient::connect(address).await.unwrap();
-        let request = Request::new(ConnectRequest{client_id});
-        let _response = client.listen(request).await.unwrap().into_inner();
+        let session_id = self.session_id;
+        let mut client = self.client().await?;
+        let request = Request::new(ConnectRequest{client_id, message_size: 0, session_id});
+        let _response = client.listen(request).await?.into_inner();
         Ok(())
     }
+    /// Usage of the server in the `runs` runs of the session since the last
+    /// query, once it has finished them.
+    pub async fn session_usage(&self, runs: u32) -> anyhow::Result<Vec<RunUsage>, Status>{
+        let session_id = self.session_id;
+        let mut client = self.client().await?;
+        let request = Request::new(UsageRequest{session_id, runs});
+        let response = client.session_usage(request).await?.into_inner();
+        Ok(response.runs.into_iter().map(|run| RunUsage{
+            cpu: CpuUsage{
+                wall: Duration::from_nanos(run.wall_ns),
+                user: Duration::from_nanos(run.user_ns),
+                system: Duration::from_nanos(run.system_ns),
+                voluntary_switches: run.voluntary_switches,
+                involuntary_switches: run.involuntary_switches,
+                threads: 1,
+            },
+            counters: match run.counters.is_empty(){
+                true => None,
+                false => Some(CounterDelta(run.counters.into_iter().collect())),
+            },
+        }).collect())
+    }
+    /// Subscribes to the multicast group of the server, which sends to it
+    /// while it has subscribers, or renews the lease of the subscription.
+    /// Joining the group through the CM is up to the caller.
+    pub async fn join_group(&self) -> anyhow::Result<GroupMembership, Status>{
+        let client_id = self.client_id;
+        let mut client = self.client().await?;
+        let response = client.join_group(Request::new(GroupRequest{client_id})).await?.into_inner();
+        group_membership(response)
+    }
+    pub async fn leave_group(&self) -> anyhow::Result<GroupMembership, Status>{
+        let client_id = self.client_id;
+        let mut client = self.client().await?;
+        let response = client.leave_group(Request::new(GroupRequest{client_id})).await?.into_inner();
+        group_membership(response)
+    }
+    pub fn client_id(&self) -> u32{
+        self.client_id
+    }
     pub fn new(address: String, client_id: u32) -> Self{
         GrpcClient{
             address,
             client_id,
+            session_id: 0,
+            rdma_address: None,
         }
     }
-}
\ No newline at end of file
+    /// Reaches the control plane over RDMA CM at `rdma_address` first.
+    pub fn with_rdma_address(mut self, rdma_address: Option<String>) -> Self{
+        self.rdma_address = rdma_address;
+        self
+    }
+}
+
+fn group_membership(response: GroupResponse) -> anyhow::Result<GroupMembership, Status>{
+    if response.lease_ms == 0{
+        return Err(Status::internal("multicast group without a lease".to_string()));
+    }
+    Ok(GroupMembership{
+        group: response.group.parse().map_err(|_| Status::internal(format!("invalid multicast group {}", response.group)))?,
+        message_size: response.message_size as usize,
+        next_seq: response.next_seq,
+        members: response.members,
+        lease: Duration::from_millis(response.lease_ms),
+    })
+}
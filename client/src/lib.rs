@@ -0,0 +1,13 @@
+pub mod builder;
+pub mod connection_manager;
+pub mod grpc_client;
+pub mod multicast;
+pub mod rdma_client;
+pub mod ud_client;
+pub mod workload;
+
+pub use builder::{QpConfig, RdmaClientBuilder};
+pub use multicast::{MulticastSubscriber, SubscriberResult};
+pub use rdma_client::{RdmaClient, TransferResult};
+pub use ud_client::{UdClient, UdMode, UdResult};
+pub use workload::WorkloadRunner;
@@ -1,39 +1,303 @@
-use clap::Parser;
-use common::CustomError;
-use rdma_client::RdmaClient;
-pub mod grpc_client;
-pub mod connection_manager;
-pub mod rdma_client;
+use std::{net::{IpAddr, SocketAddr}, path::PathBuf, time::Duration};
+use clap::{Parser, Subcommand};
+use client::{MulticastSubscriber, RdmaClient, UdClient, UdMode, WorkloadRunner, grpc_client::GrpcClient};
+use common::{CustomError, Operation, access::{AccessOptions, AccessPattern, KeyDistribution}, affinity::{CpuList, ThreadPlacement}, alloc::{AllocOptions, AllocStrategy}, counters::DEFAULT_SYSFS_ROOT, doctor::{self, Doctor}, rate::{Arrival, Rate}, signal::shutdown_signal, timeout::{CancellationToken, PollMode, WaitControl}, verify::VerifyPattern, warmup::Warmup, workload::{OpenLoop, WorkloadSpec, WorkloadTarget}};
 
 #[derive(Parser)]
 struct Args{
     #[clap(short, long)]
-    server: String,
+    server: IpAddr,
     #[clap(short, long, default_value = "7471")]
     port: u16,
+    /// Reach the gRPC control plane over RDMA CM on this port, falling back to TCP
+    #[clap(long)]
+    grpc_rdma_port: Option<u16>,
     #[clap(short, long, default_value = "128")]
     msg_size: usize,
     #[clap(short, long, default_value = "5")]
     iterations: usize,
+    /// Operation of the test: write, send or read
+    #[clap(long, default_value = "write")]
+    op: Operation,
+    /// Buffer allocation: heap, aligned, huge-2m or huge-1g
+    #[clap(long, default_value = "heap")]
+    alloc: AllocStrategy,
+    /// Bind buffers to the NUMA node of the RDMA device
+    #[clap(long)]
+    numa_local: bool,
+    /// Verify payloads with the pattern: incrementing, prng or sequence.
+    /// Verified send tests run with a single credit, whatever the server
+    /// advertises, so each message is checked before its slot is reused
+    #[clap(long)]
+    verify: Option<VerifyPattern>,
+    /// Seed of the prng verify pattern and of random access
+    #[clap(long, default_value = "0")]
+    seed: u64,
+    /// Size of the buffer the messages are spread across, 0 is the message size
+    #[clap(long, default_value = "0")]
+    buffer_size: usize,
+    /// Access pattern within the buffer: sequential, strided or random
+    #[clap(long, default_value = "sequential")]
+    access: AccessPattern,
+    /// Bytes between strided accesses, 0 is the message size
+    #[clap(long, default_value = "0")]
+    stride: usize,
+    /// Distribution of random accesses: uniform or zipfian
+    #[clap(long, default_value = "uniform")]
+    distribution: KeyDistribution,
+    /// Skew of the zipfian distribution, between 0 and 1
+    #[clap(long, default_value = "0.99")]
+    zipf_theta: f64,
+    /// Run a mixed workload op:weight[:size],... instead of the test,
+    /// e.g. read:70:4096,write:25:64-65536,cas:5
+    #[clap(long)]
+    workload: Option<String>,
+    /// QPs the workload runs over, each its own session
+    #[clap(long, default_value = "1")]
+    qps: usize,
+    /// Operations of the workload over all QPs
+    #[clap(long, default_value = "100000")]
+    ops: u64,
+    /// Run the workload for this many seconds instead of a number of operations
+    #[clap(long)]
+    duration_secs: Option<u64>,
+    /// Post the workload open loop at this rate over all QPs, in ops/s like 250k or bits/s like 10gbps,
+    /// only with --workload
+    #[clap(long, requires = "workload")]
+    rate: Option<Rate>,
+    /// Gaps between open-loop operations: constant or poisson, only with --rate
+    #[clap(long, default_value = "constant", requires = "rate")]
+    arrival: Arrival,
+    /// Write the latency histograms of the workload as CSV to this file
+    #[clap(long)]
+    histogram: Option<PathBuf>,
+    /// Operations run before each test and left out of its results
+    #[clap(long, default_value = "0")]
+    warmup_iterations: usize,
+    /// Seconds the warmup runs at least
+    #[clap(long, default_value = "0")]
+    warmup_secs: u64,
+    /// Pin the posting and polling thread of each QP to these CPUs in turn, e.g. 2,4-7
+    #[clap(long)]
+    cpu: Option<CpuList>,
+    /// Timeout of every blocking RDMA wait in milliseconds, 0 waits forever
+    #[clap(long, default_value = "30000")]
+    timeout_ms: u64,
+    /// Completion waits: busy, event, adaptive or adaptive:<spin budget in µs>
+    #[clap(long, default_value = "event")]
+    poll_mode: PollMode,
+    /// Snapshot the port counters around each test and report the changes of client and server
+    #[clap(long)]
+    counters: bool,
+    /// Directory the RDMA devices and their counters are read from
+    #[clap(long, default_value = DEFAULT_SYSFS_ROOT)]
+    sysfs_root: PathBuf,
+    /// Run a UD benchmark against the UD port of the server instead of the test
+    #[clap(long)]
+    ud_port: Option<u16>,
+    /// UD benchmark: send or ping-pong
+    #[clap(long, default_value = "send")]
+    ud_mode: UdMode,
+    /// Milliseconds a UD datagram gets to arrive before it counts as lost
+    #[clap(long, default_value = "100")]
+    ud_loss_timeout_ms: u64,
+    /// Subscribe to the multicast group of the server for this many seconds instead of the test
+    #[clap(long)]
+    mcast_secs: Option<u64>,
+    /// Local address the multicast group is joined from
+    #[clap(long)]
+    mcast_bind: Option<IpAddr>,
+    /// Multicast subscribers, each with an endpoint of its own
+    #[clap(long, default_value = "1")]
+    mcast_subscribers: u32,
+    /// Client id in the requests to the server, the first of the multicast subscribers
+    #[clap(long, default_value = "0")]
+    client_id: u32,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command{
+    /// Check the RDMA prerequisites of this host and the reachability of
+    /// the server instead of running a test
+    Doctor,
+}
+
+/// Prints a pass/fail report of the prerequisites with hints for the
+/// failures, and fails if any check failed.
+async fn doctor(args: &Args) -> anyhow::Result<(), CustomError>{
+    // registered memory is the buffer the messages are spread across
+    let mut report = Doctor::new(&args.sysfs_root).check_host(args.buffer_size.max(args.msg_size));
+    report.push(doctor::check_route(args.server));
+    let grpc_address = format!("http://{}", SocketAddr::new(args.server, args.port));
+    let grpc_rdma_address = args.grpc_rdma_port.map(|port| format!("http://{}", SocketAddr::new(args.server, port)));
+    let grpc_client = GrpcClient::new(grpc_address, 0).with_rdma_address(grpc_rdma_address);
+    for check in grpc_client.check_reachable(Duration::from_secs(5)).await{
+        report.push(check);
+    }
+    println!("{}", report);
+    if !report.passed(){
+        return Err(CustomError::new("doctor found failed checks".to_string(), -1));
+    }
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<(), CustomError> {
     let args = Args::parse();
-    let grpc_address = format!("http://{}:{}",args.server,args.port);
-    let grpc_client = grpc_client::GrpcClient::new(grpc_address,0 );
-    let rdma_port = grpc_client.request_connection().await.unwrap();
-    let port = format!("{}\0",rdma_port);
-    let server = format!("{}\0",args.server);
-    let port = port.as_str();
-    let server = server.as_str();
-    let mut rdma_client = RdmaClient::new();
-    rdma_client.connect(server,port)?;
-    grpc_client.listen().await.unwrap();
-    //rdma_client.read(args.msg_size, args.iterations)?;
-    //rdma_client.send(args.msg_size, args.iterations)?;
-    rdma_client.write(args.msg_size, args.iterations)?;
-    rdma_client.disconnect()?;
+    if let Some(Command::Doctor) = args.command{
+        return doctor(&args).await;
+    }
+    let timeout = if args.timeout_ms > 0{
+        Some(Duration::from_millis(args.timeout_ms))
+    } else {
+        None
+    };
+    if let Some(ud_port) = args.ud_port{
+        let wait_control = WaitControl{
+            timeout,
+            token: CancellationToken::new(),
+            poll_mode: args.poll_mode,
+        };
+        let token = wait_control.token.clone();
+        tokio::spawn(async move{
+            let signal = shutdown_signal().await;
+            println!("received {}, shutting down", signal);
+            token.cancel();
+        });
+        // the benchmark blocks, so it runs on a thread of its own like the
+        // other tests
+        let result = std::thread::scope(|scope|{
+            scope.spawn(||{
+                let mut ud_client = UdClient::connect(args.server, ud_port, &wait_control)?;
+                ud_client.set_loss_timeout(Duration::from_millis(args.ud_loss_timeout_ms));
+                ud_client.run(args.ud_mode, args.msg_size, args.iterations)
+            }).join().unwrap_or_else(|_| Err(CustomError::new("test thread panicked".to_string(), -1)))
+        })?;
+        println!("{}", result);
+        println!("Client done");
+        return Ok(());
+    }
+    if let Some(mcast_secs) = args.mcast_secs{
+        let wait_control = WaitControl{
+            timeout,
+            token: CancellationToken::new(),
+            poll_mode: args.poll_mode,
+        };
+        let token = wait_control.token.clone();
+        tokio::spawn(async move{
+            let signal = shutdown_signal().await;
+            println!("received {}, shutting down", signal);
+            token.cancel();
+        });
+        let grpc_address = format!("http://{}", SocketAddr::new(args.server, args.port));
+        let grpc_rdma_address = args.grpc_rdma_port.map(|port| format!("http://{}", SocketAddr::new(args.server, port)));
+        let subscribers = (0..args.mcast_subscribers).map(|i|{
+            let grpc_client = GrpcClient::new(grpc_address.clone(), args.client_id + i).with_rdma_address(grpc_rdma_address.clone());
+            let mut subscriber = MulticastSubscriber::new(grpc_client);
+            subscriber.set_bind_address(args.mcast_bind);
+            subscriber.set_loss_timeout(Duration::from_millis(args.ud_loss_timeout_ms));
+            subscriber
+        }).collect::<Vec<_>>();
+        let results = futures::future::join_all(subscribers.iter().map(|subscriber| subscriber.run(Duration::from_secs(mcast_secs), &wait_control))).await;
+        for result in results{
+            println!("{}", result?);
+        }
+        println!("Client done");
+        return Ok(());
+    }
+    let mut builder = RdmaClient::builder()
+        .server(args.server)
+        .client_id(args.client_id)
+        .port(args.port)
+        .grpc_rdma_port(args.grpc_rdma_port)
+        .message_size(args.msg_size as u32)
+        .alloc(AllocOptions{
+            strategy: args.alloc,
+            numa_local: args.numa_local,
+        })
+        .timeout(timeout)
+        .poll_mode(args.poll_mode)
+        .access(AccessOptions{
+            buffer_size: args.buffer_size,
+            pattern: args.access,
+            stride: args.stride,
+            distribution: args.distribution,
+            zipf_theta: args.zipf_theta,
+            seed: args.seed,
+        })
+        .warmup(Warmup{
+            iterations: args.warmup_iterations,
+            duration: Duration::from_secs(args.warmup_secs),
+        })
+        .counters(args.counters.then(|| args.sysfs_root.clone()));
+    // an interrupt aborts the connect as well as the test, the session is
+    // still torn down so the server does not wait for it
+    let token = CancellationToken::new();
+    let signal_token = token.clone();
+    tokio::spawn(async move{
+        let signal = shutdown_signal().await;
+        println!("received {}, shutting down", signal);
+        signal_token.cancel();
+    });
+    builder = builder.cancellation_token(token);
+    let cpus = args.cpu.clone().unwrap_or_default();
+    if let Some(pattern) = args.verify{
+        builder = builder.verify(pattern, args.seed);
+    }
+    if let Some(mix) = args.workload.as_ref(){
+        let target = match args.duration_secs{
+            Some(secs) => WorkloadTarget::Duration(Duration::from_secs(secs)),
+            None => WorkloadTarget::Ops(args.ops),
+        };
+        let mut spec = WorkloadSpec::parse(mix, target).map_err(|e| CustomError::new(e, -libc::EINVAL))?;
+        spec.open_loop = args.rate.map(|rate| OpenLoop{rate, arrival: args.arrival});
+        let builder = builder.message_size(spec.max_message_size() as u32);
+        let mut runner = WorkloadRunner::connect(builder, args.qps).await?;
+        runner.set_cpus(cpus);
+        let report = runner.run(&spec, args.seed).await?;
+        print!("{}", report);
+        if let Some(path) = args.histogram.as_ref(){
+            let mut file = std::fs::File::create(path).map_err(|e| CustomError::new(format!("{}: {}", path.display(), e), -1))?;
+            report.write_csv(&mut file).map_err(|e| CustomError::new(format!("{}: {}", path.display(), e), -1))?;
+        }
+        println!("Client done");
+        return Ok(());
+    }
+    if args.op == Operation::Atomic{
+        return Err(CustomError::new("atomics run as part of a --workload".to_string(), -libc::EINVAL));
+    }
+    let mut rdma_client = builder.connect().await?;
+    // the test runs on a thread of its own, which is pinned instead of a
+    // runtime worker
+    let result = std::thread::scope(|scope|{
+        scope.spawn(||{
+            let mut placement = ThreadPlacement::start("qp 0", cpus.get(0))?;
+            let result = match args.op{
+                Operation::SendRecv => rdma_client.send(args.msg_size, args.iterations)?,
+                Operation::Read => rdma_client.read(args.msg_size, args.iterations)?,
+                _ => rdma_client.write(args.msg_size, args.iterations)?,
+            };
+            placement.sample();
+            Ok::<_, CustomError>((result, placement))
+        }).join().unwrap_or_else(|_| Err(CustomError::new("test thread panicked".to_string(), -1)))
+    });
+    let server_usage = match result{
+        Ok(_) => rdma_client.server_usage().await,
+        Err(_) => Ok(Vec::new()),
+    };
+    let _ = tokio::task::spawn_blocking(move || rdma_client.shutdown()).await;
+    let (mut result, placement) = result?;
+    match server_usage{
+        Ok(usage) => if let Some(usage) = usage.last(){
+            result.server_usage = Some(usage.cpu);
+            result.server_counters = usage.counters.clone();
+        },
+        Err(e) => println!("no server usage: {}", e),
+    }
+    println!("{}", result);
+    println!("{}", placement);
     println!("Client done");
     Ok(())
 }
@@ -0,0 +1,280 @@
+use std::{fmt::Display, net::IpAddr, time::{Duration, Instant}};
+use common::{CustomError, timeout::WaitControl, ud::{DatagramKind, UdEndpoint}};
+use tonic::Status;
+use crate::{grpc_client::GrpcClient, ud_client::DEFAULT_LOSS_TIMEOUT};
+
+/// The multicast group of the server as the control plane reports it on
+/// joins and leaves.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GroupMembership{
+    pub group: IpAddr,
+    pub message_size: usize,
+    /// Sequence number of the next datagram the server sends.
+    pub next_seq: u64,
+    /// Subscribers after the request.
+    pub members: u32,
+    /// Time the subscription lasts unless it is renewed.
+    pub lease: Duration,
+}
+
+/// Sequence numbers of the datagrams that arrived.
+#[derive(Clone, Copy, Debug, Default)]
+struct Stream{
+    first: Option<u64>,
+    highest: Option<u64>,
+    received: u64,
+    out_of_order: u64,
+}
+
+impl Stream{
+    fn record(&mut self, seq: u64){
+        self.first.get_or_insert(seq);
+        self.received += 1;
+        match self.highest{
+            Some(highest) if seq < highest => self.out_of_order += 1,
+            _ => self.highest = Some(seq),
+        }
+    }
+}
+
+/// What one subscriber got of the stream of the server. Datagrams sent
+/// before its first one arrived were sent while the CM join was still under
+/// way and are not counted as lost.
+#[derive(Clone, Debug)]
+pub struct SubscriberResult{
+    pub client_id: u32,
+    pub group: IpAddr,
+    pub message_size: usize,
+    /// Sequence number of the first datagram that arrived.
+    pub first_seq: Option<u64>,
+    /// Sequence number the server was at when the subscriber left.
+    pub end_seq: u64,
+    pub received: u64,
+    /// Datagrams that arrived after one with a higher sequence number.
+    pub out_of_order: u64,
+    pub elapsed: Duration,
+}
+
+impl SubscriberResult{
+    /// Datagrams the server sent from the first one that arrived until the
+    /// subscriber left.
+    pub fn expected(&self) -> u64{
+        self.first_seq.map(|first_seq| self.end_seq.saturating_sub(first_seq)).unwrap_or(0)
+    }
+    pub fn lost(&self) -> u64{
+        self.expected().saturating_sub(self.received)
+    }
+    /// Percentage of the expected datagrams that did not arrive.
+    pub fn loss(&self) -> f64{
+        if self.expected() == 0{
+            return 0.0;
+        }
+        self.lost() as f64 * 100.0 / self.expected() as f64
+    }
+    pub fn gbps(&self) -> f64{
+        let secs = self.elapsed.as_secs_f64();
+        if secs == 0.0{
+            return 0.0;
+        }
+        (self.received * self.message_size as u64) as f64 * 8.0 / secs / 1e9
+    }
+}
+
+impl Display for SubscriberResult{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+        writeln!(f, "multicast subscriber {} of {}: {} x {} bytes in {:?}, {:.3} Gbit/s received",
+            self.client_id, self.group, self.received, self.message_size, self.elapsed, self.gbps())?;
+        match self.first_seq{
+            Some(first_seq) => write!(f, "  seq {} to {}: {} of {} arrived ({:.2}% lost, {} out of order)",
+                first_seq, self.end_seq, self.received, self.expected(), self.loss(), self.out_of_order),
+            None => write!(f, "  nothing arrived before seq {}", self.end_seq),
+        }
+    }
+}
+
+/// Joins that renew the subscription within one lease, so a late one does
+/// not end it.
+const RENEWALS_PER_LEASE: u32 = 3;
+
+fn status_error(what: &str, status: Status) -> CustomError{
+    CustomError::new(format!("{}: {:?} {}", what, status.code(), status.message()), -1)
+}
+
+/// Subscribes to the multicast group of the server: membership goes through
+/// the control plane, which starts and stops the stream, and the group
+/// itself is joined through the CM. The subscription is renewed a few times
+/// per lease while receiving.
+pub struct MulticastSubscriber{
+    grpc_client: GrpcClient,
+    bind_address: Option<IpAddr>,
+    loss_timeout: Duration,
+}
+
+impl MulticastSubscriber{
+    pub fn new(grpc_client: GrpcClient) -> MulticastSubscriber{
+        MulticastSubscriber{
+            grpc_client,
+            bind_address: None,
+            loss_timeout: DEFAULT_LOSS_TIMEOUT,
+        }
+    }
+    /// Local address the group is joined from, the one routing to it by
+    /// default.
+    pub fn set_bind_address(&mut self, bind_address: Option<IpAddr>){
+        self.bind_address = bind_address;
+    }
+    /// Time the datagrams sent until the subscriber left get to arrive.
+    pub fn set_loss_timeout(&mut self, loss_timeout: Duration){
+        self.loss_timeout = loss_timeout;
+    }
+    /// Receives the stream for `duration`, or until the token of
+    /// `wait_control` is cancelled, and leaves the group.
+    pub async fn run(&self, duration: Duration, wait_control: &WaitControl) -> anyhow::Result<SubscriberResult, CustomError>{
+        let membership = self.grpc_client.join_group().await
+            .map_err(|status| status_error("join group request failed", status))?;
+        let bind_address = self.bind_address;
+        let group = membership.group;
+        let join_control = wait_control.clone();
+        let endpoint = tokio::task::spawn_blocking(move ||{
+            UdEndpoint::join_multicast(bind_address, group, &join_control)
+        }).await.unwrap_or_else(|_| Err(CustomError::new("join thread panicked".to_string(), -1)));
+        let endpoint = match endpoint{
+            Ok(endpoint) => endpoint,
+            Err(e) => {
+                let _ = self.grpc_client.leave_group().await;
+                return Err(e);
+            },
+        };
+        let start = Instant::now();
+        let mut receive = tokio::task::spawn_blocking(move ||{
+            let mut endpoint = endpoint;
+            let mut stream = Stream::default();
+            loop {
+                let remaining = duration.saturating_sub(start.elapsed());
+                if remaining.is_zero(){
+                    break;
+                }
+                match endpoint.recv(remaining){
+                    Ok(Some(datagram)) if datagram.kind == DatagramKind::Data => stream.record(datagram.seq),
+                    Ok(_) => {},
+                    Err(e) if e.code() == -libc::ECANCELED => break,
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok((endpoint, stream))
+        });
+        let renew_period = membership.lease / RENEWALS_PER_LEASE;
+        let mut renew = tokio::time::interval_at(tokio::time::Instant::now() + renew_period, renew_period);
+        let received = loop {
+            tokio::select!{
+                received = &mut receive => break received.unwrap_or_else(|_| Err(CustomError::new("receive thread panicked".to_string(), -1))),
+                _ = renew.tick() => {
+                    if let Err(status) = self.grpc_client.join_group().await{
+                        println!("{}", status_error("renewing the group membership failed", status));
+                    }
+                },
+            }
+        };
+        // the server keeps the subscriber until it leaves, even if the
+        // receives failed
+        let left = self.grpc_client.leave_group().await
+            .map_err(|status| status_error("leave group request failed", status));
+        let (endpoint, stream) = received?;
+        let end_seq = left?.next_seq;
+        let loss_timeout = self.loss_timeout;
+        let stream = tokio::task::spawn_blocking(move ||{
+            let mut endpoint = endpoint;
+            let mut stream = stream;
+            // datagrams sent before the leave may still be on their way,
+            // later ones go to the other subscribers
+            while stream.highest.is_none_or(|highest| highest + 1 < end_seq){
+                match endpoint.recv(loss_timeout){
+                    Ok(Some(datagram)) if datagram.kind == DatagramKind::Data && datagram.seq < end_seq => stream.record(datagram.seq),
+                    Ok(Some(datagram)) if datagram.kind == DatagramKind::Data => break,
+                    Ok(Some(_)) => {},
+                    Ok(None) => break,
+                    Err(e) if e.code() == -libc::ECANCELED => break,
+                    Err(e) => return Err(e),
+                }
+            }
+            endpoint.leave_multicast()?;
+            Ok(stream)
+        }).await.unwrap_or_else(|_| Err(CustomError::new("receive thread panicked".to_string(), -1)))?;
+        Ok(SubscriberResult{
+            client_id: self.grpc_client.client_id(),
+            group,
+            message_size: membership.message_size,
+            first_seq: stream.first,
+            end_seq,
+            received: stream.received,
+            out_of_order: stream.out_of_order,
+            elapsed: start.elapsed(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn result(seqs: &[u64], end_seq: u64) -> SubscriberResult{
+        let mut stream = Stream::default();
+        for seq in seqs{
+            stream.record(*seq);
+        }
+        SubscriberResult{
+            client_id: 1,
+            group: "239.1.1.1".parse().unwrap(),
+            message_size: 1024,
+            first_seq: stream.first,
+            end_seq,
+            received: stream.received,
+            out_of_order: stream.out_of_order,
+            elapsed: Duration::from_secs(1),
+        }
+    }
+
+    #[test]
+    fn in_order_delivery_loses_nothing(){
+        let result = result(&[10, 11, 12, 13], 14);
+        assert_eq!(result.first_seq, Some(10));
+        assert_eq!(result.expected(), 4);
+        assert_eq!(result.lost(), 0);
+        assert_eq!(result.loss(), 0.0);
+        assert_eq!(result.out_of_order, 0);
+    }
+
+    #[test]
+    fn gaps_count_as_lost(){
+        let result = result(&[0, 1, 4, 5], 8);
+        assert_eq!(result.expected(), 8);
+        assert_eq!(result.received, 4);
+        assert_eq!(result.lost(), 4);
+        assert_eq!(result.loss(), 50.0);
+    }
+
+    #[test]
+    fn reordering_is_counted_but_not_lost(){
+        let result = result(&[5, 7, 6, 8, 9], 10);
+        assert_eq!(result.out_of_order, 1);
+        assert_eq!(result.lost(), 0);
+    }
+
+    #[test]
+    fn stream_starts_at_the_first_arrival(){
+        // datagrams before the first one were sent while the join was under way
+        let result = result(&[100, 101], 102);
+        assert_eq!(result.expected(), 2);
+        assert_eq!(result.lost(), 0);
+    }
+
+    #[test]
+    fn nothing_arrived(){
+        let result = result(&[], 50);
+        assert_eq!(result.first_seq, None);
+        assert_eq!(result.expected(), 0);
+        assert_eq!(result.lost(), 0);
+        assert_eq!(result.loss(), 0.0);
+        assert!(result.to_string().contains("nothing arrived before seq 50"));
+    }
+}
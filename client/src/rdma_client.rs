@@ -1,154 +1,584 @@
-use std::ptr::null_mut;
-use common::*;
+use std::{net::IpAddr, path::PathBuf, ptr::null_mut, sync::{atomic::{AtomicU32, Ordering}, Mutex, MutexGuard}, time::{Duration, Instant}};
+use common::{*, access::{AccessOptions, OffsetGenerator, Rng}, alloc::AllocOptions, counters::{CounterDelta, CounterMeter, CounterSource}, mr_pool::{BufferPool, MrCache}, rate::{wait_until, Schedule}, remote::RemoteRegion, timeout::{connect_ep, CancellationToken, PollMode}, usage::{CpuUsage, RunUsage, UsageMeter}, verify::{Verifier, VerifyPattern}, warmup::Warmup, workload::{OpKind, WorkloadReport, WorkloadSpec, WorkloadTarget}};
 use rdma_sys::*;
+use crate::{builder::{QpConfig, RdmaClientBuilder}, grpc_client::GrpcClient};
 
+const POOL_MAX_CACHED_BYTES: usize = 256 * 1024 * 1024;
+const MR_CACHE_MAX_BYTES: usize = 64 * 1024 * 1024;
+const MR_CACHE_MAX_ENTRIES: usize = 64;
+/// Bound of the waits during `shutdown` after the client was cancelled.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+/// Messages of one warmup request of a send test.
+const WARMUP_SEND_BATCH: usize = 1024;
+
+/// Outcome of a test transfer.
+#[derive(Debug, Clone)]
+pub struct TransferResult{
+    pub operation: Operation,
+    pub message_size: usize,
+    pub iterations: usize,
+    /// Time from the first post to the last completion.
+    pub elapsed: Duration,
+    /// CPU usage of the posting and polling thread in that time.
+    pub client_usage: CpuUsage,
+    /// CPU usage of the server session, as reported over the control plane.
+    pub server_usage: Option<CpuUsage>,
+    /// Change of the port counters of the client in that time.
+    pub counters: Option<CounterDelta>,
+    /// Change of the port counters of the server, as reported over the
+    /// control plane.
+    pub server_counters: Option<CounterDelta>,
+}
+
+impl TransferResult{
+    pub fn bytes(&self) -> u64{
+        (self.message_size * self.iterations) as u64
+    }
+    pub fn gbps(&self) -> f64{
+        let secs = self.elapsed.as_secs_f64();
+        if secs == 0.0{
+            return 0.0;
+        }
+        self.bytes() as f64 * 8.0 / secs / 1e9
+    }
+}
+
+impl std::fmt::Display for TransferResult{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+        writeln!(f, "{:?}: {} x {} bytes in {:?}, {:.3} Gbit/s", self.operation, self.iterations, self.message_size, self.elapsed, self.gbps())?;
+        write!(f, "  client cpu: {}, {:.3} ns/byte", self.client_usage, self.client_usage.ns_per_byte(self.bytes()))?;
+        if let Some(server_usage) = self.server_usage.as_ref(){
+            write!(f, "\n  server cpu: {}, {:.3} ns/byte", server_usage, server_usage.ns_per_byte(self.bytes()))?;
+        }
+        if let Some(counters) = self.counters.as_ref(){
+            write!(f, "\n  client counters: {}", counters)?;
+        }
+        if let Some(server_counters) = self.server_counters.as_ref(){
+            write!(f, "\n  server counters: {}", server_counters)?;
+        }
+        Ok(())
+    }
+}
 
 pub struct RdmaClient{
     id: Id,
+    pool: Mutex<BufferPool>,
+    mr_cache: Mutex<MrCache>,
+    /// Control message of the session, registered once on connect so it
+    /// stays at one address for as long as the registration.
+    control: Mutex<Box<MetaData>>,
+    verify: Option<(VerifyPattern, u64)>,
+    access: AccessOptions,
+    warmup: Warmup,
+    timeout: Option<Duration>,
+    poll_mode: PollMode,
+    token: CancellationToken,
+    grpc_client: Option<GrpcClient>,
+    /// Sysfs root the port counters are read below, `None` skips them.
+    counter_root: Option<PathBuf>,
+    /// Runs finished since the last usage query, the server keeps the CPU
+    /// usage of each.
+    runs: AtomicU32,
 }
 
 impl RdmaClient{
-    pub fn new() -> RdmaClient{
+    pub fn new(alloc: AllocOptions) -> RdmaClient{
         RdmaClient{
-            id: Id(null_mut()),
+            id: Id::new(null_mut()),
+            pool: Mutex::new(BufferPool::new(POOL_MAX_CACHED_BYTES, alloc)),
+            mr_cache: Mutex::new(MrCache::new(MR_CACHE_MAX_BYTES, MR_CACHE_MAX_ENTRIES)),
+            control: Mutex::new(Box::default()),
+            verify: None,
+            access: AccessOptions::default(),
+            warmup: Warmup::default(),
+            timeout: None,
+            poll_mode: PollMode::default(),
+            token: CancellationToken::new(),
+            grpc_client: None,
+            counter_root: None,
+            runs: AtomicU32::new(0),
         }
     }
-
-    pub fn connect(&mut self, ip: &str, port: &str) -> anyhow::Result<(), CustomError>{
-        let mut hints = unsafe { std::mem::zeroed::<rdma_addrinfo>() };
-        let mut res: *mut rdma_addrinfo = null_mut();
-    
-        hints.ai_port_space = rdma_port_space::RDMA_PS_TCP as i32;
-        let ret =
-            unsafe { rdma_getaddrinfo(ip.as_ptr().cast(), port.as_ptr().cast(), &hints, &mut res) };
-    
-        if ret != 0 {
-            return Err(CustomError::new("rdma_getaddrinfo".to_string(), ret).into());
+    /// Bounds every blocking wait of the following tests, `None` waits forever.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>){
+        self.timeout = timeout;
+        self.id.set_timeout(timeout);
+    }
+    /// How the following tests wait for completions.
+    pub fn set_poll_mode(&mut self, poll_mode: PollMode){
+        self.poll_mode = poll_mode;
+        self.id.set_poll_mode(poll_mode);
+    }
+    /// Snapshots the counters of the client port below `root` around each
+    /// following test, `None` skips them.
+    pub fn set_counters(&mut self, root: Option<PathBuf>){
+        self.counter_root = root;
+    }
+    fn counter_meter(&self) -> Option<CounterMeter>{
+        let source = CounterSource::for_id(self.counter_root.as_ref()?, &self.id)?;
+        CounterMeter::start(&source)
+    }
+    /// Control plane of the session, which the server usage is queried on.
+    pub(crate) fn set_grpc_client(&mut self, grpc_client: GrpcClient){
+        self.grpc_client = Some(grpc_client);
+    }
+    /// Usage of the server in each run of this client since the last query,
+    /// waiting for the server to finish the last one.
+    pub async fn server_usage(&self) -> anyhow::Result<Vec<RunUsage>, CustomError>{
+        let grpc_client = match self.grpc_client.as_ref(){
+            Some(grpc_client) => grpc_client,
+            None => return Err(CustomError::new("client has no control plane".to_string(), -libc::ENOTCONN)),
+        };
+        grpc_client.session_usage(self.runs.swap(0, Ordering::SeqCst)).await
+            .map_err(|status| CustomError::new(format!("usage request failed: {:?} {}", status.code(), status.message()), -1))
+    }
+    /// Token which aborts the blocking waits of the client when cancelled.
+    pub fn cancellation_token(&self) -> CancellationToken{
+        self.token.clone()
+    }
+    /// Aborts the blocking waits of the client, the connect included, when
+    /// `token` is cancelled.
+    pub fn set_cancellation_token(&mut self, token: CancellationToken){
+        self.token = token.clone();
+        self.id.set_cancellation_token(token);
+    }
+    /// Enables payload verification for all following tests.
+    pub fn set_verify(&mut self, pattern: VerifyPattern, seed: u64){
+        self.verify = Some((pattern, seed));
+    }
+    /// Buffer size and access pattern of the following write and read tests.
+    pub fn set_access(&mut self, access: AccessOptions){
+        self.access = access;
+    }
+    /// Operations run before every following test and left out of its
+    /// results.
+    pub fn set_warmup(&mut self, warmup: Warmup){
+        self.warmup = warmup;
+    }
+    /// Runs `op` until the warmup is done or the client is cancelled.
+    fn warm_up<F: FnMut() -> anyhow::Result<(), CustomError>>(&self, mut op: F) -> anyhow::Result<(), CustomError>{
+        if !self.warmup.is_enabled(){
+            return Ok(());
         }
-    
-        let mut attr = unsafe { std::mem::zeroed::<ibv_qp_init_attr>() };
-        let mut id: *mut rdma_cm_id = null_mut();
-        attr.cap.max_send_wr = 4096;
-        attr.cap.max_recv_wr = 4096;
-        attr.cap.max_send_sge = 1;
-        attr.cap.max_recv_sge = 1;
-        attr.cap.max_inline_data = 64;
-        attr.qp_context = id.cast();
-        attr.sq_sig_all = 0;
-        let ret = unsafe { rdma_create_ep(&mut id, res, null_mut(), &mut attr) };
-        if ret != 0 {
-            unsafe { rdma_freeaddrinfo(res); }
-            return Err(CustomError::new("rdma_create_ep".to_string(), ret).into());
+        let start = Instant::now();
+        let mut done = 0;
+        while !self.warmup.is_done(done, start) && !self.token.is_cancelled(){
+            op()?;
+            done += 1;
         }
-
-        /*
-        let send_cq = unsafe { (*id).send_cq };
-        if !send_cq.is_null(){
-            println!("send cq: {}", unsafe { (*send_cq).cqe });
-            let ret = unsafe { ibv_resize_cq(send_cq, 65535)};
-            if ret != 0 {
-                return Err(CustomError::new("ibv_resize_cq".to_string(), ret).into());
-            }
+        println!("warmup: {} ops in {:?}", done, start.elapsed());
+        Ok(())
+    }
+    /// Offsets of a write or read test. Verified tests always move the whole
+    /// buffer, so they cannot use a larger one.
+    fn offsets(&self, message_size: usize) -> anyhow::Result<OffsetGenerator, CustomError>{
+        if self.verify.is_some() && self.access.buffer_size(message_size) > message_size{
+            return Err(CustomError::new("verification needs the buffer size to equal the message size".to_string(), -libc::EINVAL));
+        }
+        OffsetGenerator::new(&self.access, message_size)
+    }
+    /// The control message, cleared for the next request.
+    fn control(&self) -> anyhow::Result<MutexGuard<'_, Box<MetaData>>, CustomError>{
+        let mut control = self.control.lock().unwrap();
+        if control.mr().is_null(){
+            return Err(CustomError::new("client is not connected".to_string(), -libc::ENOTCONN));
+        }
+        control.reset();
+        Ok(control)
+    }
+    fn verifier(&self) -> Option<Verifier>{
+        self.verify.map(|(pattern, seed)| Verifier::new(pattern, seed))
+    }
+    /// Receives the answer of the server to the finished message of a
+    /// verified write or send test, which fails if the server found
+    /// mismatched payloads.
+    fn recv_verify_report(&self, metadata_request: &mut MetaData, mr_addr: &MrAddr) -> anyhow::Result<(), CustomError>{
+        if self.verify.is_none(){
+            return Ok(());
+        }
+        metadata_request.rdma_recv(&self.id, mr_addr)?;
+        match metadata_request.get_request_type(){
+            MetaDataRequestTypes::ErrorResponse => Err(CustomError::server(metadata_request.server_error())),
+            _ => Ok(()),
+        }
+    }
+    fn set_verify_request(&self, metadata_request: &mut MetaData){
+        if let Some((pattern, seed)) = self.verify{
+            metadata_request.set_verify(pattern.code(), seed);
         }
-        println!("send cq: {}", unsafe { (*send_cq).cqe });
+    }
+
+    /// Starts building a client, see `RdmaClientBuilder`.
+    pub fn builder() -> RdmaClientBuilder{
+        RdmaClientBuilder::new()
+    }
 
-        let recv_cq = unsafe { (*id).recv_cq };
-        if !recv_cq.is_null(){
-            println!("recv cq: {}", unsafe { (*recv_cq).cqe });
-            let ret = unsafe { ibv_resize_cq(recv_cq, 65535)};
-            if ret != 0 {
-                return Err(CustomError::new("ibv_resize_cq".to_string(), ret).into());
+    /// Connects the QP to `server:port`, from `source_address` if set. Fails
+    /// before connecting if `device` is set and the route to the server
+    /// goes through another device.
+    pub fn connect(&mut self, server: IpAddr, port: u16, source_address: Option<IpAddr>, device: Option<&str>, qp: &QpConfig) -> anyhow::Result<(), CustomError>{
+        let mut attr = qp.init_attr();
+        attr.sq_sig_all = 0;
+        let mut new_id = create_ep(&server.to_string(), port, source_address, false, Some(&mut attr))?;
+        let id = new_id.id();
+        if let Some(device) = device{
+            let device_name = new_id.device_name();
+            if device_name.as_deref() != Some(device){
+                unsafe { rdma_destroy_ep(id) };
+                return Err(CustomError::new(format!("server is reached through device {:?}, not {}", device_name, device), -libc::ENODEV));
             }
         }
-        println!("recv cq: {}", unsafe { (*recv_cq).cqe });
-        */
 
-        let ret = unsafe { rdma_connect(id, null_mut()) };
-        if ret != 0 {
-            unsafe { rdma_disconnect(id); }
-            return Err(CustomError::new("rdma_connect".to_string(), ret).into());
+        new_id.set_timeout(self.timeout);
+        new_id.set_cancellation_token(self.token.clone());
+        new_id.set_poll_mode(self.poll_mode);
+        if let Err(e) = connect_ep(&new_id){
+            unsafe { rdma_destroy_ep(id); }
+            return Err(e);
         }
-        self.id = Id(id);
+        self.id = new_id;
+        let mut mr_cache = self.mr_cache.lock().unwrap();
+        self.control.lock().unwrap().create_and_register_mr_cached(&self.id, Operation::SendRecv, &mut mr_cache)?;
         Ok(())
     }
 
     pub fn disconnect(&self) -> anyhow::Result<(), CustomError>{
         println!("Disconnecting");
-        let mut metadata_request = MetaData::default();
+        let mut metadata_request = self.control()?;
         metadata_request.set_request_type(MetaDataRequestTypes::Disconnect);
-        let mr_addr = metadata_request.create_and_register_mr(&self.id, Operation::SendRecv)?;
+        let mr_addr = metadata_request.registered_mr_addr();
         metadata_request.rdma_send(&self.id, &mr_addr)?;
         Ok(())
     }
+    /// Tears the connection down after a finished or interrupted test: sends
+    /// `Disconnect` while the QP still works, disconnects the CM id,
+    /// deregisters all buffers and destroys the endpoint.
+    pub fn shutdown(&mut self){
+        if self.id.id().is_null(){
+            return;
+        }
+        // the cancelled token of an interrupted client would abort the
+        // Disconnect before it is sent
+        if self.token.is_cancelled(){
+            self.id.set_cancellation_token(CancellationToken::new());
+            self.id.set_timeout(Some(SHUTDOWN_TIMEOUT));
+        }
+        if let Err(e) = self.disconnect(){
+            println!("disconnect request failed: {}", e);
+        }
+        unsafe { rdma_disconnect(self.id.id()) };
+        self.control.lock().unwrap().set_mr(null_mut());
+        self.mr_cache.lock().unwrap().clear();
+        self.pool.lock().unwrap().clear();
+        unsafe { rdma_destroy_ep(self.id.id()) };
+        self.id = Id::new(null_mut());
+    }
 
-    pub fn write(&self, message_size: usize, iterations: usize) -> anyhow::Result<(), CustomError> {
-        let mut metadata_request = MetaData::default();
+    pub fn write(&self, message_size: usize, iterations: usize) -> anyhow::Result<TransferResult, CustomError> {
+        let mut offsets = self.offsets(message_size)?;
+        let buffer_size = self.access.buffer_size(message_size);
+        let mut metadata_request = self.control()?;
         metadata_request.set_request_type(MetaDataRequestTypes::WriteRequest);
         metadata_request.set_message_size(message_size as u32);
-        let metadata_mr_addr = metadata_request.create_and_register_mr(&self.id, Operation::SendRecv)?;
+        metadata_request.set_buffer_size(buffer_size as u64);
+        metadata_request.set_iterations(iterations as u32);
+        self.set_verify_request(&mut metadata_request);
+        let elapsed;
+        let client_usage;
+        let counters;
+        let metadata_mr_addr = metadata_request.registered_mr_addr();
         metadata_request.rdma_send(&self.id, &metadata_mr_addr)?;
         metadata_request.rdma_recv(&self.id, &metadata_mr_addr)?;
         match metadata_request.get_request_type(){
             MetaDataRequestTypes::WriteResponse => {
-                let mut data = Data::new(message_size);
-                data.create_and_register_mr(&self.id, Operation::Write)?;
-                data.rdma_write(&self.id, metadata_request.rkey(), metadata_request.remote_address(), iterations)?;
-                println!("RDMA Write finished");
+                let region = metadata_request.remote_region();
+                let mut data = BufferPool::checkout_shared(&self.pool, &self.id, buffer_size, Operation::Write)?;
+                let mut warmup_offsets = self.offsets(message_size)?;
+                self.warm_up(|| data.rdma_write_offsets(&self.id, &region, message_size, &mut warmup_offsets, 1))?;
+                // the counters are read from sysfs, which stays out of the
+                // measurement
+                let counter_meter = self.counter_meter();
+                let start = Instant::now();
+                let meter = UsageMeter::start();
+                match self.verifier(){
+                    Some(mut verifier) => {
+                        for i in 0..iterations{
+                            verifier.fill(data.as_mut_slice(), i as u64);
+                            data.rdma_write_region(&self.id, &region, 0, 1)?;
+                        }
+                        println!("{}", verifier);
+                    },
+                    None => {
+                        data.rdma_write_offsets(&self.id, &region, message_size, &mut offsets, iterations)?;
+                    }
+                }
+                elapsed = start.elapsed();
+                client_usage = meter.stop();
+                counters = counter_meter.and_then(|counter_meter| counter_meter.stop());
+                drop(data);
                 metadata_request.set_request_type(MetaDataRequestTypes::WriteFinished);
                 metadata_request.rdma_send(&self.id, &metadata_mr_addr)?;
+                self.runs.fetch_add(1, Ordering::SeqCst);
+                self.recv_verify_report(&mut metadata_request, &metadata_mr_addr)?;
+            },
+            MetaDataRequestTypes::ErrorResponse => {
+                return Err(CustomError::server(metadata_request.server_error()));
             },
             _ => {
                 return Err(CustomError::new("unexpected request type".to_string(), 0).into());
             }  
         }
-        Ok(())
+        Ok(TransferResult{
+            operation: Operation::Write,
+            message_size,
+            iterations,
+            elapsed,
+            client_usage,
+            server_usage: None,
+            counters,
+            server_counters: None,
+        })
     }
     
-    pub fn send(&self, message_size: usize, iterations: usize) -> anyhow::Result<(), CustomError> {
-        let mut metadata_request = MetaData::default();
+    pub fn send(&self, message_size: usize, iterations: usize) -> anyhow::Result<TransferResult, CustomError> {
+        self.warm_up_send(message_size)?;
+        self.send_messages(message_size, iterations, true)
+    }
+    /// The server receives the number of messages a request announces, so
+    /// the warmup of a send test runs as requests of its own, of at most
+    /// `WARMUP_SEND_BATCH` unverified messages each.
+    fn warm_up_send(&self, message_size: usize) -> anyhow::Result<(), CustomError>{
+        if !self.warmup.is_enabled(){
+            return Ok(());
+        }
+        let start = Instant::now();
+        let mut done = 0;
+        while !self.warmup.is_done(done, start) && !self.token.is_cancelled(){
+            let batch = self.warmup.iterations.saturating_sub(done).clamp(1, WARMUP_SEND_BATCH);
+            self.send_messages(message_size, batch, false)?;
+            done += batch;
+        }
+        println!("warmup: {} ops in {:?}", done, start.elapsed());
+        Ok(())
+    }
+    fn send_messages(&self, message_size: usize, iterations: usize, verify: bool) -> anyhow::Result<TransferResult, CustomError> {
+        let mut metadata_request = self.control()?;
         metadata_request.set_request_type(MetaDataRequestTypes::SendRequest);
         metadata_request.set_message_size(message_size as u32);
         metadata_request.set_iterations(iterations as u32);
-        let mr_ar = metadata_request.create_and_register_mr(&self.id, Operation::SendRecv)?;
+        if verify{
+            self.set_verify_request(&mut metadata_request);
+        }
+        let elapsed;
+        let client_usage;
+        let counters;
+        let mr_ar = metadata_request.registered_mr_addr();
         metadata_request.rdma_send(&self.id, &mr_ar)?;
         metadata_request.rdma_recv(&self.id, &mr_ar)?;
         match metadata_request.get_request_type(){
             MetaDataRequestTypes::SendResponse => {
-                let mut data = Data::new(message_size);
-                let data_mr_addr = data.create_and_register_mr(&self.id, Operation::SendRecv)?;
-                data.rdma_send_data(&self.id, &data_mr_addr, iterations)?;
-                println!("Send finished");
+                let mut data = BufferPool::checkout_shared(&self.pool, &self.id, message_size, Operation::SendRecv)?;
+                let data_mr_addr = data.registered_mr_addr();
+                let mut verifier = self.verifier().filter(|_| verify);
+                let counter_meter = self.counter_meter();
+                let start = Instant::now();
+                let meter = UsageMeter::start();
+                data.rdma_send_data_with_credits(&self.id, &data_mr_addr, iterations, metadata_request.credits() as usize, verifier.as_mut())?;
+                elapsed = start.elapsed();
+                client_usage = meter.stop();
+                counters = counter_meter.and_then(|counter_meter| counter_meter.stop());
+                drop(data);
+                if let Some(verifier) = verifier{
+                    println!("{}", verifier);
+                }
                 metadata_request.set_request_type(MetaDataRequestTypes::SendFinished);
                 metadata_request.rdma_send(&self.id, &mr_ar)?;
+                self.runs.fetch_add(1, Ordering::SeqCst);
+                if verify{
+                    self.recv_verify_report(&mut metadata_request, &mr_ar)?;
+                }
+            },
+            MetaDataRequestTypes::ErrorResponse => {
+                return Err(CustomError::server(metadata_request.server_error()));
             },
             _ => {
                 return Err(CustomError::new("unexpected request type".to_string(), 0).into());
             }
         }
-        Ok(())
+        Ok(TransferResult{
+            operation: Operation::SendRecv,
+            message_size,
+            iterations,
+            elapsed,
+            client_usage,
+            server_usage: None,
+            counters,
+            server_counters: None,
+        })
     }
 
-    pub fn read(&self, message_size: usize, iterations: usize) -> anyhow::Result<(), CustomError> {
-        let mut metadata_request = MetaData::default();
+    pub fn read(&self, message_size: usize, iterations: usize) -> anyhow::Result<TransferResult, CustomError> {
+        let mut offsets = self.offsets(message_size)?;
+        let buffer_size = self.access.buffer_size(message_size);
+        let mut metadata_request = self.control()?;
         metadata_request.set_request_type(MetaDataRequestTypes::ReadRequest);
         metadata_request.set_message_size(message_size as u32);
-        let metadata_mr_addr = metadata_request.create_and_register_mr(&self.id, Operation::SendRecv)?;
+        metadata_request.set_buffer_size(buffer_size as u64);
+        metadata_request.set_iterations(iterations as u32);
+        self.set_verify_request(&mut metadata_request);
+        let elapsed;
+        let client_usage;
+        let counters;
+        let metadata_mr_addr = metadata_request.registered_mr_addr();
         metadata_request.rdma_send(&self.id, &metadata_mr_addr)?;
         metadata_request.rdma_recv(&self.id, &metadata_mr_addr)?;
         match metadata_request.get_request_type(){
             MetaDataRequestTypes::ReadResponse => {
-                let mut data = Data::new(message_size);
-                data.create_and_register_mr(&self.id, Operation::Read)?;
-                data.rdma_read(&self.id, metadata_request.rkey(), metadata_request.remote_address(), iterations)?;
-                println!("RDMA Read finished");
+                let region = metadata_request.remote_region();
+                let mut data = BufferPool::checkout_shared(&self.pool, &self.id, buffer_size, Operation::Read)?;
+                let mut warmup_offsets = self.offsets(message_size)?;
+                self.warm_up(|| data.rdma_read_offsets(&self.id, &region, message_size, &mut warmup_offsets, 1))?;
+                let mut mismatches = 0;
+                let counter_meter = self.counter_meter();
+                let start = Instant::now();
+                let meter = UsageMeter::start();
+                match self.verifier(){
+                    Some(mut verifier) => {
+                        for i in 0..iterations{
+                            data.as_mut_slice().fill(0);
+                            data.rdma_read_region(&self.id, &region, 0, 1)?;
+                            verifier.check(data.as_slice(), 0, i as u64);
+                        }
+                        println!("{}", verifier);
+                        mismatches = verifier.mismatch_count();
+                    },
+                    None => {
+                        data.rdma_read_offsets(&self.id, &region, message_size, &mut offsets, iterations)?;
+                    }
+                }
+                elapsed = start.elapsed();
+                client_usage = meter.stop();
+                counters = counter_meter.and_then(|counter_meter| counter_meter.stop());
+                drop(data);
                 metadata_request.set_request_type(MetaDataRequestTypes::ReadFinished);
                 metadata_request.rdma_send(&self.id, &metadata_mr_addr)?;
+                self.runs.fetch_add(1, Ordering::SeqCst);
+                if mismatches > 0{
+                    return Err(CustomError::new(format!("read verification failed, {} mismatched bytes", mismatches), -1));
+                }
+            },
+            MetaDataRequestTypes::ErrorResponse => {
+                return Err(CustomError::server(metadata_request.server_error()));
             },
             _ => {
                 return Err(CustomError::new("unexpected request type".to_string(), 0).into());
             }  
         }
-        Ok(())
+        Ok(TransferResult{
+            operation: Operation::Read,
+            message_size,
+            iterations,
+            elapsed,
+            client_usage,
+            server_usage: None,
+            counters,
+            server_counters: None,
+        })
     }
-    
-}
\ No newline at end of file
+
+
+    /// Runs the operations of `spec` on this QP until `target` is reached,
+    /// each one posted on its own and timed until its completion, from the
+    /// post or, in an open loop, from its scheduled start. The messages land
+    /// at the offsets of the access pattern within a region sized for the
+    /// largest message.
+    pub fn run_workload(&self, spec: &WorkloadSpec, target: WorkloadTarget, seed: u64) -> anyhow::Result<WorkloadReport, CustomError> {
+        let message_size = spec.max_message_size().max(std::mem::size_of::<u64>());
+        let mut offsets = OffsetGenerator::new(&self.access, message_size)?;
+        let buffer_size = self.access.buffer_size(message_size);
+        let mut metadata_request = self.control()?;
+        metadata_request.set_request_type(MetaDataRequestTypes::WorkloadRequest);
+        metadata_request.set_message_size(message_size as u32);
+        metadata_request.set_buffer_size(buffer_size as u64);
+        let metadata_mr_addr = metadata_request.registered_mr_addr();
+        metadata_request.rdma_send(&self.id, &metadata_mr_addr)?;
+        metadata_request.rdma_recv(&self.id, &metadata_mr_addr)?;
+        let mut report = WorkloadReport::new(1);
+        match metadata_request.get_request_type(){
+            MetaDataRequestTypes::WorkloadResponse => {
+                let region = metadata_request.remote_region();
+                let mut data = BufferPool::checkout_shared(&self.pool, &self.id, buffer_size, Operation::Read)?;
+                let mut rng = Rng::new(seed);
+                let mut value = 0u64;
+                self.warm_up(||{
+                    let (kind, size) = spec.sample(&mut rng);
+                    self.workload_op(&mut data, &region, kind, size, offsets.next_offset(), &mut value)
+                })?;
+                report.offered_ops_per_sec = spec.offered_ops_per_sec();
+                let mut schedule = spec.open_loop.zip(report.offered_ops_per_sec)
+                    .map(|(open_loop, ops_per_sec)| Schedule::new(ops_per_sec, open_loop.arrival, seed));
+                let counter_meter = self.counter_meter();
+                let start = Instant::now();
+                let meter = UsageMeter::start();
+                let mut done = 0u64;
+                while !self.token.is_cancelled(){
+                    match target{
+                        WorkloadTarget::Ops(ops) if done >= ops => break,
+                        WorkloadTarget::Duration(duration) if start.elapsed() >= duration => break,
+                        _ => {},
+                    }
+                    let (kind, size) = spec.sample(&mut rng);
+                    let offset = offsets.next_offset();
+                    // an open-loop op counts from its intended start, even
+                    // if the previous one made it late
+                    let op_start = match schedule.as_mut(){
+                        Some(schedule) => {
+                            let intended = schedule.next_start();
+                            wait_until(intended);
+                            intended
+                        },
+                        None => Instant::now(),
+                    };
+                    self.workload_op(&mut data, &region, kind, size, offset, &mut value)?;
+                    report.stats_mut(kind).record(size, op_start.elapsed());
+                    done += 1;
+                }
+                report.elapsed = start.elapsed();
+                report.client_usage = meter.stop();
+                report.counters = counter_meter.and_then(|counter_meter| counter_meter.stop());
+                drop(data);
+                metadata_request.set_request_type(MetaDataRequestTypes::WorkloadFinished);
+                metadata_request.rdma_send(&self.id, &metadata_mr_addr)?;
+                self.runs.fetch_add(1, Ordering::SeqCst);
+            },
+            MetaDataRequestTypes::ErrorResponse => {
+                return Err(CustomError::server(metadata_request.server_error()));
+            },
+            _ => {
+                return Err(CustomError::new("unexpected request type".to_string(), 0));
+            }
+        }
+        Ok(report)
+    }
+    /// Posts one workload operation at `offset` and waits for it. `value` is
+    /// the last value a CAS saw.
+    fn workload_op(&self, data: &mut Data, region: &RemoteRegion, kind: OpKind, size: usize, offset: u64, value: &mut u64) -> anyhow::Result<(), CustomError>{
+        match kind{
+            OpKind::Read => data.rdma_one_sided_at(&self.id, Operation::Read, offset as usize, region, offset, size),
+            OpKind::Write => data.rdma_one_sided_at(&self.id, Operation::Write, offset as usize, region, offset, size),
+            OpKind::CompareAndSwap => {
+                // swaps in the successor of the value seen last, so an
+                // uncontended CAS succeeds
+                let previous = data.rdma_compare_and_swap_at(&self.id, 0, region, cas_offset(region, offset), *value, value.wrapping_add(1))?;
+                *value = if previous == *value { value.wrapping_add(1) } else { previous };
+                Ok(())
+            },
+        }
+    }
+}
+
+/// 8 byte aligned offset for a CAS close to `offset` in `region`.
+fn cas_offset(region: &RemoteRegion, offset: u64) -> u64{
+    let misalignment = (region.addr + offset) % 8;
+    if offset >= misalignment { offset - misalignment } else { offset + 8 - misalignment }
+}
@@ -0,0 +1,181 @@
+use std::{fmt::Display, net::IpAddr, str::FromStr, time::{Duration, Instant}};
+use common::{CustomError, histogram::Histogram, timeout::WaitControl, ud::{Datagram, DatagramKind, UdEndpoint, UD_HEADER_LEN}};
+
+/// Time a datagram gets to arrive before it counts as lost.
+pub const DEFAULT_LOSS_TIMEOUT: Duration = Duration::from_millis(100);
+/// `Done` datagrams sent before giving up on the report of the server,
+/// which may be lost like any other datagram.
+const DONE_ATTEMPTS: usize = 10;
+
+/// Benchmark run over UD.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UdMode{
+    /// Streams datagrams and asks the server how many arrived.
+    #[default]
+    Send,
+    /// Sends one ping at a time and waits for its pong.
+    PingPong,
+}
+
+impl FromStr for UdMode{
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err>{
+        match s{
+            "send" => Ok(UdMode::Send),
+            "ping-pong" | "pingpong" => Ok(UdMode::PingPong),
+            _ => Err(format!("invalid ud mode {}, expected send or ping-pong", s)),
+        }
+    }
+}
+
+/// Outcome of a UD benchmark. Datagrams may be lost, so what was sent and
+/// what arrived are counted on both sides.
+#[derive(Clone, Debug)]
+pub struct UdResult{
+    pub mode: UdMode,
+    pub message_size: usize,
+    pub mtu: usize,
+    /// Datagrams the client sent, data or pings.
+    pub sent: u64,
+    /// Datagrams of those the server received.
+    pub server_received: u64,
+    /// Datagrams the server received out of order.
+    pub out_of_order: u64,
+    /// Pongs the client received in time.
+    pub pongs: u64,
+    pub elapsed: Duration,
+    /// Round trip times of the pings answered in time.
+    pub rtt: Option<Histogram>,
+}
+
+impl UdResult{
+    pub fn gbps(&self) -> f64{
+        let secs = self.elapsed.as_secs_f64();
+        if secs == 0.0{
+            return 0.0;
+        }
+        (self.sent * self.message_size as u64) as f64 * 8.0 / secs / 1e9
+    }
+    /// Percentage of the datagrams sent that did not arrive at the server.
+    pub fn loss(&self) -> f64{
+        if self.sent == 0{
+            return 0.0;
+        }
+        self.sent.saturating_sub(self.server_received) as f64 * 100.0 / self.sent as f64
+    }
+}
+
+impl Display for UdResult{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+        match self.mode{
+            UdMode::Send => {
+                writeln!(f, "UD send: {} x {} bytes in {:?}, {:.3} Gbit/s sent, path MTU {}", self.sent, self.message_size, self.elapsed, self.gbps(), self.mtu)?;
+                write!(f, "  server received {} ({:.2}% lost, {} out of order)", self.server_received, self.loss(), self.out_of_order)
+            },
+            UdMode::PingPong => {
+                writeln!(f, "UD ping-pong: {} x {} bytes in {:?}, path MTU {}", self.sent, self.message_size, self.elapsed, self.mtu)?;
+                write!(f, "  {} pongs, {} pings and {} pongs lost, {} out of order",
+                    self.pongs, self.sent.saturating_sub(self.server_received), self.server_received.saturating_sub(self.pongs), self.out_of_order)?;
+                if let Some(rtt) = self.rtt.as_ref().filter(|rtt| rtt.count() > 0){
+                    write!(f, "\n  rtt min {:?} mean {:?} p50 {:?} p99 {:?} p99.9 {:?} max {:?}",
+                        rtt.min(), rtt.mean(), rtt.percentile(0.5), rtt.percentile(0.99), rtt.percentile(0.999), rtt.max())?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+/// Client of the UD benchmarks of the server, connected to it through the
+/// CM without a gRPC session.
+pub struct UdClient{
+    endpoint: UdEndpoint,
+    loss_timeout: Duration,
+}
+
+impl UdClient{
+    pub fn connect(server: IpAddr, port: u16, wait_control: &WaitControl) -> anyhow::Result<UdClient, CustomError>{
+        Ok(UdClient{
+            endpoint: UdEndpoint::connect(server, port, wait_control)?,
+            loss_timeout: DEFAULT_LOSS_TIMEOUT,
+        })
+    }
+    pub fn set_loss_timeout(&mut self, loss_timeout: Duration){
+        self.loss_timeout = loss_timeout;
+    }
+    /// Largest message, header included.
+    pub fn mtu(&self) -> usize{
+        self.endpoint.mtu()
+    }
+    pub fn run(&mut self, mode: UdMode, message_size: usize, iterations: usize) -> anyhow::Result<UdResult, CustomError>{
+        if message_size < UD_HEADER_LEN || message_size > self.mtu(){
+            return Err(CustomError::new(format!("ud messages are {} to {} bytes on this path, not {}", UD_HEADER_LEN, self.mtu(), message_size), -libc::EMSGSIZE));
+        }
+        match mode{
+            UdMode::Send => self.send(message_size, iterations as u64),
+            UdMode::PingPong => self.ping_pong(message_size, iterations as u64),
+        }
+    }
+    fn send(&mut self, message_size: usize, iterations: u64) -> anyhow::Result<UdResult, CustomError>{
+        let start = Instant::now();
+        for seq in 0..iterations{
+            self.endpoint.send(DatagramKind::Data, seq, 0, message_size)?;
+        }
+        // datagrams still in flight count towards the run
+        self.endpoint.wait_sends()?;
+        let elapsed = start.elapsed();
+        let report = self.finish(iterations)?;
+        Ok(UdResult{
+            mode: UdMode::Send,
+            message_size,
+            mtu: self.mtu(),
+            sent: iterations,
+            server_received: report.seq,
+            out_of_order: report.arg,
+            pongs: 0,
+            elapsed,
+            rtt: None,
+        })
+    }
+    fn ping_pong(&mut self, message_size: usize, iterations: u64) -> anyhow::Result<UdResult, CustomError>{
+        let mut rtt = Histogram::new();
+        let start = Instant::now();
+        for seq in 0..iterations{
+            let sent = Instant::now();
+            self.endpoint.send(DatagramKind::Ping, seq, 0, message_size)?;
+            // pongs of earlier pings which arrived late are skipped
+            while let Some(datagram) = self.endpoint.recv(self.loss_timeout.saturating_sub(sent.elapsed()))?{
+                if datagram.kind == DatagramKind::Pong && datagram.seq == seq{
+                    rtt.record(sent.elapsed());
+                    break;
+                }
+            }
+        }
+        let elapsed = start.elapsed();
+        let report = self.finish(iterations)?;
+        Ok(UdResult{
+            mode: UdMode::PingPong,
+            message_size,
+            mtu: self.mtu(),
+            sent: iterations,
+            server_received: report.seq,
+            out_of_order: report.arg,
+            pongs: rtt.count(),
+            elapsed,
+            rtt: Some(rtt),
+        })
+    }
+    /// Tells the server that `sent` datagrams were sent and waits for its
+    /// report, repeating the `Done` until one arrives.
+    fn finish(&mut self, sent: u64) -> anyhow::Result<Datagram, CustomError>{
+        for _ in 0..DONE_ATTEMPTS{
+            self.endpoint.send(DatagramKind::Done, sent, 0, UD_HEADER_LEN)?;
+            while let Some(datagram) = self.endpoint.recv(self.loss_timeout)?{
+                if datagram.kind == DatagramKind::Report{
+                    return Ok(datagram);
+                }
+            }
+        }
+        Err(CustomError::new(format!("no report from the server after {} attempts", DONE_ATTEMPTS), -libc::ETIMEDOUT))
+    }
+}
@@ -0,0 +1,107 @@
+use common::{CustomError, affinity::{CpuList, ThreadPlacement}, rate::Rate, timeout::CancellationToken, workload::{WorkloadReport, WorkloadSpec, WorkloadTarget}};
+use crate::{builder::RdmaClientBuilder, rdma_client::RdmaClient};
+
+/// Runs a mixed workload over several QPs at once, each its own session of
+/// the server.
+pub struct WorkloadRunner{
+    clients: Vec<RdmaClient>,
+    cpus: CpuList,
+}
+
+impl WorkloadRunner{
+    /// Connects `qps` sessions with `builder`, numbering their client ids up
+    /// from the one of `builder`.
+    pub async fn connect(builder: RdmaClientBuilder, qps: usize) -> anyhow::Result<WorkloadRunner, CustomError>{
+        let mut runner = WorkloadRunner{
+            clients: Vec::with_capacity(qps),
+            cpus: CpuList::default(),
+        };
+        for i in 0..qps.max(1){
+            let client_id = builder.client_id + i as u32;
+            match builder.clone().client_id(client_id).connect().await{
+                Ok(client) => runner.clients.push(client),
+                Err(e) => {
+                    let _ = tokio::task::spawn_blocking(move || runner.shutdown()).await;
+                    return Err(e);
+                }
+            }
+        }
+        Ok(runner)
+    }
+    /// Pins the thread of each QP to the next of `cpus`.
+    pub fn set_cpus(&mut self, cpus: CpuList){
+        self.cpus = cpus;
+    }
+    /// Tokens which stop the run of their QP.
+    pub fn cancellation_tokens(&self) -> Vec<CancellationToken>{
+        self.clients.iter().map(|client| client.cancellation_token()).collect()
+    }
+    /// Runs `spec` on all QPs, each on a thread of its own, an op count
+    /// target and an open-loop rate are split between them, and merges their
+    /// reports. The sessions are shut down afterwards.
+    pub async fn run(mut self, spec: &WorkloadSpec, seed: u64) -> anyhow::Result<WorkloadReport, CustomError>{
+        let qps = self.clients.len();
+        let mut jh_list = Vec::with_capacity(qps);
+        for (i, client) in self.clients.drain(..).enumerate(){
+            let target = match spec.target{
+                WorkloadTarget::Ops(ops) => WorkloadTarget::Ops(ops / qps as u64 + u64::from((i as u64) < ops % qps as u64)),
+                target => target,
+            };
+            let mut spec = spec.clone();
+            // every QP offers its share of the rate
+            let offered = spec.offered_ops_per_sec();
+            if let (Some(open_loop), Some(ops_per_sec)) = (spec.open_loop.as_mut(), offered){
+                open_loop.rate = Rate::OpsPerSec(ops_per_sec / qps as f64);
+            }
+            let cpu = self.cpus.get(i);
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            std::thread::spawn(move ||{
+                let result = ThreadPlacement::start(format!("qp {}", i), cpu).and_then(|mut placement|{
+                    let mut report = client.run_workload(&spec, target, seed.wrapping_add(i as u64))?;
+                    placement.sample();
+                    report.threads.push(placement);
+                    Ok(report)
+                });
+                let _ = tx.send((client, result));
+            });
+            jh_list.push(rx);
+        }
+        let mut report = WorkloadReport::new(qps);
+        let mut error = None;
+        for result in futures::future::join_all(jh_list).await{
+            // the server keeps the usage of a session until it closes, so
+            // it is queried before the shutdown
+            let mut client = match result{
+                Ok((client, Ok(mut qp_report))) => {
+                    match client.server_usage().await{
+                        Ok(usage) => if let Some(usage) = usage.last(){
+                            qp_report.server_usage = Some(usage.cpu);
+                            qp_report.server_counters = usage.counters.clone();
+                        },
+                        Err(e) => println!("no server usage: {}", e),
+                    }
+                    report.merge(&qp_report);
+                    client
+                },
+                Ok((client, Err(e))) => {
+                    error = Some(e);
+                    client
+                },
+                Err(e) => {
+                    error = Some(CustomError::new(format!("workload thread failed: {}", e), -1));
+                    continue;
+                }
+            };
+            let _ = tokio::task::spawn_blocking(move || client.shutdown()).await;
+        }
+        match error{
+            Some(e) => Err(e),
+            None => Ok(report),
+        }
+    }
+    fn shutdown(&mut self){
+        for client in self.clients.iter_mut(){
+            client.shutdown();
+        }
+    }
+}
@@ -4,9 +4,13 @@ use crate::{
         ConnectionManager,
         ConnectionManagerServer
     },
-    ConnectRequest, ConnectResponse
+    ConnectRequest, ConnectResponse, GroupRequest, GroupResponse, RunUsage, UsageRequest, UsageResponse
 },
+multicast::GroupStatus,
 server_manager::ServerManagerClient};
+use std::net::SocketAddr;
+use common::{grpc_transport::incoming, stream::RdmaListener};
+use futures::FutureExt;
 use tonic::{transport::Server, Request, Response, Status};
 
 
@@ -14,6 +18,7 @@ use tonic::{transport::Server, Request, Response, Status};
 pub struct GrpcServer{
     address: String,
     server_manager_client: ServerManagerClient,
+    rdma_port: Option<u16>,
 }
 
 impl GrpcServer {
@@ -21,16 +26,39 @@ impl GrpcServer {
         GrpcServer {
             address,
             server_manager_client,
+            rdma_port: None,
         }
     }
 
-    pub async fn run(&self) -> anyhow::Result<()>{
+    /// Serves the control plane over RDMA CM on `rdma_port` as well, next to
+    /// TCP for clients without RDMA transport.
+    pub fn with_rdma_port(mut self, rdma_port: Option<u16>) -> GrpcServer {
+        self.rdma_port = rdma_port;
+        self
+    }
+
+    /// Serves until `shutdown` resolves, then stops accepting requests and
+    /// waits for the ones in flight.
+    pub async fn run<F: std::future::Future<Output = ()>>(&self, shutdown: F) -> anyhow::Result<()>{
         println!("Server listening on {}", self.address);
-        let address = self.address.clone();
-        Server::builder()
+        let address: SocketAddr = self.address.parse()?;
+        let shutdown = shutdown.shared();
+        let tcp = Server::builder()
             .add_service(ConnectionManagerServer::new(self.clone()))
-            .serve(address.parse().unwrap())
-            .await.unwrap();
+            .serve_with_shutdown(address, shutdown.clone());
+        match self.rdma_port{
+            Some(rdma_port) => {
+                let rdma_address = SocketAddr::new(address.ip(), rdma_port);
+                let listener = RdmaListener::bind(rdma_address).await?;
+                println!("Server listening on {} over rdma", rdma_address);
+                let rdma = Server::builder()
+                    .add_service(ConnectionManagerServer::new(self.clone()))
+                    .serve_with_incoming_shutdown(incoming(listener, |e| println!("rdma grpc connection failed: {}", e)), shutdown);
+                futures::try_join!(tcp, rdma)?;
+            },
+            None => tcp.await?,
+        }
+        println!("grpc server stopped");
         Ok(())
     }
 
@@ -45,9 +73,11 @@ impl ConnectionManager for GrpcServer {
     ) -> Result<Response<ConnectResponse>, Status> {
         let connection_request = request.into_inner();
         let mut client = self.server_manager_client.clone();
-        let rdma_port = client.request_connection(connection_request.client_id).await;
+        let grant = client.request_connection(connection_request.client_id, connection_request.message_size).await
+            .map_err(Status::resource_exhausted)?;
         let connection_response = ConnectResponse{
-            server_port: rdma_port
+            server_port: grant.port,
+            session_id: grant.session_id,
         };
         Ok(Response::new(connection_response))
     }
@@ -57,9 +87,58 @@ impl ConnectionManager for GrpcServer {
     ) -> Result<Response<ConnectResponse>, Status> {
         let connection_request = request.into_inner();
         let mut client = self.server_manager_client.clone();
-        let _rdma_port = client.listen(connection_request.client_id).await;
+        client.listen(connection_request.session_id).await
+            .map_err(|e| Status::not_found(e.to_string()))?;
         let connection_response = ConnectResponse::default();
         Ok(Response::new(connection_response))
     }
+    async fn session_usage(
+        &self,
+        request: Request<UsageRequest>,
+    ) -> Result<Response<UsageResponse>, Status> {
+        let usage_request = request.into_inner();
+        let mut client = self.server_manager_client.clone();
+        let usage = client.session_usage(usage_request.session_id, usage_request.runs as usize).await
+            .map_err(Status::deadline_exceeded)?;
+        let runs = usage.into_iter().map(|usage| RunUsage{
+            wall_ns: usage.cpu.wall.as_nanos() as u64,
+            user_ns: usage.cpu.user.as_nanos() as u64,
+            system_ns: usage.cpu.system.as_nanos() as u64,
+            voluntary_switches: usage.cpu.voluntary_switches,
+            involuntary_switches: usage.cpu.involuntary_switches,
+            counters: usage.counters.map(|counters| counters.0.into_iter().collect()).unwrap_or_default(),
+        }).collect();
+        Ok(Response::new(UsageResponse{runs}))
+    }
+    async fn join_group(
+        &self,
+        request: Request<GroupRequest>,
+    ) -> Result<Response<GroupResponse>, Status> {
+        let group_request = request.into_inner();
+        let mut client = self.server_manager_client.clone();
+        let status = client.join_group(group_request.client_id).await
+            .map_err(Status::failed_precondition)?;
+        Ok(Response::new(group_response(status)))
+    }
+    async fn leave_group(
+        &self,
+        request: Request<GroupRequest>,
+    ) -> Result<Response<GroupResponse>, Status> {
+        let group_request = request.into_inner();
+        let mut client = self.server_manager_client.clone();
+        let status = client.leave_group(group_request.client_id).await
+            .map_err(Status::failed_precondition)?;
+        Ok(Response::new(group_response(status)))
+    }
 
-}
\ No newline at end of file
+}
+
+fn group_response(status: GroupStatus) -> GroupResponse{
+    GroupResponse{
+        group: status.group.to_string(),
+        message_size: status.message_size as u32,
+        next_seq: status.next_seq,
+        members: status.members as u32,
+        lease_ms: status.lease.as_millis() as u64,
+    }
+}
@@ -0,0 +1,189 @@
+use std::{collections::HashMap, ops::{Deref, DerefMut}, sync::{Arc, Mutex}};
+use common::{*, srq::{srq_recv, SharedReceiveQueue}};
+use crate::{operations::{DisconnectHandler, ReadHandler, SendHandler, WorkloadHandler, WriteHandler}, rdma_server::RdmaServer};
+
+/// What the server does after a request was handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionAction{
+    /// Wait for the next request of the session.
+    Continue,
+    /// Tear the session down.
+    Close,
+}
+
+/// Serves one request type. Handlers are shared by all sessions, each of
+/// which calls them on its own worker thread, one request at a time, so a
+/// handler may block that thread but runs concurrently with the handlers of
+/// other sessions.
+pub trait RequestHandler: Send + Sync{
+    /// Serves `request`, which was received on `session`. Errors abort the
+    /// session, requests which cannot be served should be answered with
+    /// `Session::reject` instead.
+    fn handle(&self, session: &Session, request: &mut MetaData) -> anyhow::Result<SessionAction, CustomError>;
+}
+
+impl<F> RequestHandler for F
+where
+    F: Fn(&Session, &mut MetaData) -> anyhow::Result<SessionAction, CustomError> + Send + Sync,
+{
+    fn handle(&self, session: &Session, request: &mut MetaData) -> anyhow::Result<SessionAction, CustomError>{
+        self(session, request)
+    }
+}
+
+/// Handlers keyed by the raw `MetaData::request_type`, so applications can
+/// add request types beyond `MetaDataRequestTypes`.
+#[derive(Clone)]
+pub struct HandlerRegistry{
+    handlers: HashMap<u8, Arc<dyn RequestHandler>>,
+}
+
+impl HandlerRegistry{
+    /// A registry without any handlers.
+    pub fn new() -> HandlerRegistry{
+        HandlerRegistry{
+            handlers: HashMap::new(),
+        }
+    }
+    /// A registry with the write, send, read, workload and disconnect
+    /// handlers.
+    pub fn with_defaults() -> HandlerRegistry{
+        let mut registry = HandlerRegistry::new();
+        registry.register(MetaDataRequestTypes::WriteRequest as u8, WriteHandler);
+        registry.register(MetaDataRequestTypes::SendRequest as u8, SendHandler);
+        registry.register(MetaDataRequestTypes::ReadRequest as u8, ReadHandler);
+        registry.register(MetaDataRequestTypes::WorkloadRequest as u8, WorkloadHandler);
+        registry.register(MetaDataRequestTypes::Disconnect as u8, DisconnectHandler);
+        registry
+    }
+    /// Registers `handler` for `request_type`, replacing the previous one.
+    pub fn register<H: RequestHandler + 'static>(&mut self, request_type: u8, handler: H){
+        self.handlers.insert(request_type, Arc::new(handler));
+    }
+    pub fn remove(&mut self, request_type: u8){
+        self.handlers.remove(&request_type);
+    }
+    pub fn get(&self, request_type: u8) -> Option<Arc<dyn RequestHandler>>{
+        self.handlers.get(&request_type).cloned()
+    }
+}
+
+impl Default for HandlerRegistry{
+    fn default() -> Self{
+        HandlerRegistry::new()
+    }
+}
+
+/// A connected client as seen by a `RequestHandler`. It exchanges requests
+/// through the registered metadata buffer of the session and hands out
+/// registered data buffers within the server resource limits.
+pub struct Session<'a>{
+    server: &'a RdmaServer,
+    session_id: u32,
+    id: &'a Id,
+    metadata_mr_addr: &'a MrAddr,
+}
+
+impl<'a> Session<'a>{
+    pub(crate) fn new(server: &'a RdmaServer, session_id: u32, id: &'a Id, metadata_mr_addr: &'a MrAddr) -> Session<'a>{
+        Session{
+            server,
+            session_id,
+            id,
+            metadata_mr_addr,
+        }
+    }
+    /// Server assigned id of the session.
+    pub fn session_id(&self) -> u32{
+        self.session_id
+    }
+    pub fn id(&self) -> &Id{
+        self.id
+    }
+    /// Registration of the metadata buffer the request was received in.
+    pub fn metadata_mr_addr(&self) -> &MrAddr{
+        self.metadata_mr_addr
+    }
+    /// Sends `request` back to the client as the response.
+    pub fn respond(&self, request: &mut MetaData) -> anyhow::Result<(), CustomError>{
+        request.rdma_send(self.id, self.metadata_mr_addr)
+    }
+    /// Receives the next request of the client into `request`, e.g. the
+    /// finished message of a transfer.
+    pub fn recv_request(&self, request: &mut MetaData) -> anyhow::Result<(), CustomError>{
+        self.server.recv_metadata(self.id, request, self.metadata_mr_addr)
+    }
+    /// Like `recv_request` but without the session timeout, for a request
+    /// which follows a client side run of any length, e.g. the finished
+    /// message of a workload. The wait still ends with the session token.
+    pub fn recv_request_after_run(&self, request: &mut MetaData) -> anyhow::Result<(), CustomError>{
+        let mut run_id = self.id.clone();
+        run_id.set_timeout(None);
+        self.server.recv_metadata(&run_id, request, self.metadata_mr_addr)
+    }
+    /// Answers `request` with an `ErrorResponse`. The session stays up.
+    pub fn reject(&self, request: &mut MetaData, error: ServerError) -> anyhow::Result<SessionAction, CustomError>{
+        self.server.send_error(self.id, request, self.metadata_mr_addr, error.code, &error.reason)?;
+        Ok(SessionAction::Continue)
+    }
+    /// Checks a registered buffer of `size` bytes usable for `operation` out
+    /// of the server pool. Fails if it would exceed the resource limits. The
+    /// buffer goes back to the pool when it is dropped.
+    pub fn checkout(&self, size: usize, operation: Operation) -> Result<SessionBuffer<'a>, ServerError>{
+        let data = self.server.checkout(self.session_id, self.id, size, operation)?;
+        Ok(SessionBuffer{
+            server: self.server,
+            session_id: self.session_id,
+            data: Some(data),
+        })
+    }
+    /// The server SRQ, if the session QP receives through it.
+    pub fn shared_receive_queue(&self) -> Option<Arc<Mutex<SharedReceiveQueue>>>{
+        self.server.shared_receive_queue()
+    }
+    /// Receives `iterations` messages into `data`, through the SRQ if the
+    /// server has one.
+    pub fn recv<T: MrObject>(&self, data: &mut T, iterations: usize) -> anyhow::Result<(), CustomError>{
+        match self.shared_receive_queue(){
+            Some(srq) => srq_recv(&srq, self.id, data, iterations),
+            None => {
+                let mr_addr = data.registered_mr_addr();
+                data.rdma_recv_data(self.id, &mr_addr, iterations)
+            }
+        }
+    }
+    /// Receive slots advertised to senders, 0 disables flow control.
+    pub fn credits(&self) -> u32{
+        self.server.credits()
+    }
+}
+
+/// A buffer checked out by a session. Dropping it returns it to the server
+/// pool and its bytes to the resource limits, also when the handler fails
+/// half way.
+pub struct SessionBuffer<'a>{
+    server: &'a RdmaServer,
+    session_id: u32,
+    data: Option<Data>,
+}
+
+impl Deref for SessionBuffer<'_>{
+    type Target = Data;
+    fn deref(&self) -> &Data{
+        self.data.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for SessionBuffer<'_>{
+    fn deref_mut(&mut self) -> &mut Data{
+        self.data.as_mut().unwrap()
+    }
+}
+
+impl Drop for SessionBuffer<'_>{
+    fn drop(&mut self){
+        if let Some(data) = self.data.take(){
+            self.server.checkin(self.session_id, data);
+        }
+    }
+}
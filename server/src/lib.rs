@@ -0,0 +1,13 @@
+pub mod connection_manager;
+pub mod grpc_server;
+pub mod handler;
+pub mod limits;
+pub mod multicast;
+pub mod operations;
+pub mod rdma_server;
+pub mod server_manager;
+pub mod service;
+pub mod ud_server;
+
+pub use handler::{HandlerRegistry, RequestHandler, Session, SessionAction, SessionBuffer};
+pub use service::{Server, ServerConfig};
@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+use common::alloc::memlock_limit;
+
+/// Server resource limits, 0 means unlimited.
+#[derive(Clone, Default)]
+pub struct ResourceLimits{
+    pub max_message_size: usize,
+    pub max_session_bytes: usize,
+    pub max_total_bytes: usize,
+    pub max_sessions: usize,
+    pub max_qps: usize,
+}
+
+impl ResourceLimits{
+    /// Bytes the session buffers can have registered at once under the
+    /// limits, at least the `pool_cached_bytes` the buffer pool keeps
+    /// registered between runs. `None` if the limits do not bound them.
+    pub fn registered_bytes_bound(&self, pool_cached_bytes: usize) -> Option<usize>{
+        if self.max_total_bytes > 0{
+            return Some(self.max_total_bytes);
+        }
+        if self.max_session_bytes > 0 && self.max_sessions > 0{
+            return Some(self.max_session_bytes.saturating_mul(self.max_sessions).max(pool_cached_bytes));
+        }
+        None
+    }
+}
+
+fn exceeds(limit: usize, value: usize) -> bool{
+    limit != 0 && value > limit
+}
+
+/// Usage accounting against `ResourceLimits`, shared by the server manager,
+/// which admits sessions, and the RDMA server, which creates QPs and
+/// registers buffers. Registered bytes are kept per session id.
+pub struct ResourceTracker{
+    limits: ResourceLimits,
+    sessions: usize,
+    qps: usize,
+    session_bytes: HashMap<u32, usize>,
+    /// RLIMIT_MEMLOCK of the process, `None` if unlimited.
+    memlock: Option<usize>,
+}
+
+impl ResourceTracker{
+    pub fn new(limits: ResourceLimits) -> ResourceTracker{
+        ResourceTracker{
+            limits,
+            sessions: 0,
+            qps: 0,
+            session_bytes: HashMap::new(),
+            memlock: memlock_limit(),
+        }
+    }
+    /// Admits a new session asking for messages of `message_size` bytes,
+    /// 0 if unknown.
+    pub fn admit_session(&mut self, message_size: usize) -> Result<(), String>{
+        if exceeds(self.limits.max_message_size, message_size){
+            return Err(format!("message size {} exceeds limit {}", message_size, self.limits.max_message_size));
+        }
+        if exceeds(self.limits.max_sessions, self.sessions + 1){
+            return Err(format!("session limit {} reached", self.limits.max_sessions));
+        }
+        if exceeds(self.limits.max_qps, self.qps + 1){
+            return Err(format!("qp limit {} reached", self.limits.max_qps));
+        }
+        self.sessions += 1;
+        Ok(())
+    }
+    pub fn release_session(&mut self){
+        self.sessions = self.sessions.saturating_sub(1);
+    }
+    pub fn sessions(&self) -> usize{
+        self.sessions
+    }
+    pub fn acquire_qp(&mut self) -> Result<(), String>{
+        if exceeds(self.limits.max_qps, self.qps + 1){
+            return Err(format!("qp limit {} reached", self.limits.max_qps));
+        }
+        self.qps += 1;
+        Ok(())
+    }
+    pub fn release_qp(&mut self){
+        self.qps = self.qps.saturating_sub(1);
+    }
+    /// Checks a buffer of `message_size` bytes for `session_id`, which holds
+    /// `registered_size` registered bytes. Only `new_registration` of them
+    /// are registered on top of the `total_registered` bytes the server
+    /// already has registered, the rest comes from the pool. The buffer is
+    /// accounted to the session.
+    pub fn acquire_bytes(&mut self, session_id: u32, message_size: usize, registered_size: usize, new_registration: usize, total_registered: usize) -> Result<(), String>{
+        if exceeds(self.limits.max_message_size, message_size){
+            return Err(format!("message size {} exceeds limit {}", message_size, self.limits.max_message_size));
+        }
+        let session_bytes = self.session_bytes.get(&session_id).copied().unwrap_or(0);
+        if exceeds(self.limits.max_session_bytes, session_bytes + registered_size){
+            return Err(format!("session registered bytes limit {} reached", self.limits.max_session_bytes));
+        }
+        if exceeds(self.limits.max_total_bytes, total_registered + new_registration){
+            return Err(format!("total registered bytes limit {} reached", self.limits.max_total_bytes));
+        }
+        if let Some(memlock) = self.memlock{
+            if total_registered + new_registration > memlock{
+                return Err(format!("RLIMIT_MEMLOCK of {} bytes too low", memlock));
+            }
+        }
+        *self.session_bytes.entry(session_id).or_default() += registered_size;
+        Ok(())
+    }
+    pub fn release_bytes(&mut self, session_id: u32, registered_size: usize){
+        if let Some(session_bytes) = self.session_bytes.get_mut(&session_id){
+            *session_bytes = session_bytes.saturating_sub(registered_size);
+            if *session_bytes == 0{
+                self.session_bytes.remove(&session_id);
+            }
+        }
+    }
+    /// Forgets the bytes of a closed session.
+    pub fn release_session_bytes(&mut self, session_id: u32){
+        self.session_bytes.remove(&session_id);
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn tracker(limits: ResourceLimits) -> ResourceTracker{
+        let mut tracker = ResourceTracker::new(limits);
+        tracker.memlock = None;
+        tracker
+    }
+
+    #[test]
+    fn sessions_are_admitted_up_to_the_limit(){
+        let mut tracker = tracker(ResourceLimits{max_sessions: 2, ..Default::default()});
+        assert!(tracker.admit_session(0).is_ok());
+        assert!(tracker.admit_session(0).is_ok());
+        assert!(tracker.admit_session(0).is_err());
+        tracker.release_session();
+        assert_eq!(tracker.sessions(), 1);
+        assert!(tracker.admit_session(0).is_ok());
+    }
+
+    #[test]
+    fn oversized_messages_are_refused(){
+        let mut tracker = tracker(ResourceLimits{max_message_size: 4096, ..Default::default()});
+        assert!(tracker.admit_session(4096).is_ok());
+        assert!(tracker.admit_session(4097).is_err());
+        assert!(tracker.acquire_bytes(1, 4097, 8192, 8192, 0).is_err());
+        assert!(tracker.acquire_bytes(1, 4096, 4096, 4096, 0).is_ok());
+    }
+
+    #[test]
+    fn qps_are_limited_at_admission_and_creation(){
+        let mut tracker = tracker(ResourceLimits{max_qps: 1, ..Default::default()});
+        assert!(tracker.admit_session(0).is_ok());
+        assert!(tracker.acquire_qp().is_ok());
+        assert!(tracker.acquire_qp().is_err());
+        assert!(tracker.admit_session(0).is_err());
+        tracker.release_qp();
+        assert!(tracker.acquire_qp().is_ok());
+    }
+
+    #[test]
+    fn session_bytes_are_limited_per_session(){
+        let mut tracker = tracker(ResourceLimits{max_session_bytes: 8192, ..Default::default()});
+        assert!(tracker.acquire_bytes(1, 4096, 4096, 4096, 0).is_ok());
+        assert!(tracker.acquire_bytes(1, 4096, 4096, 4096, 4096).is_ok());
+        assert!(tracker.acquire_bytes(1, 1, 4096, 0, 8192).is_err());
+        // other sessions have budgets of their own
+        assert!(tracker.acquire_bytes(2, 4096, 4096, 4096, 8192).is_ok());
+        tracker.release_bytes(1, 4096);
+        assert!(tracker.acquire_bytes(1, 4096, 4096, 0, 12288).is_ok());
+    }
+
+    #[test]
+    fn total_bytes_count_new_registrations_only(){
+        let mut tracker = tracker(ResourceLimits{max_total_bytes: 8192, ..Default::default()});
+        assert!(tracker.acquire_bytes(1, 4096, 4096, 4096, 4096).is_ok());
+        assert!(tracker.acquire_bytes(2, 4096, 4096, 4096, 8192).is_err());
+        // a cached buffer is registered already
+        assert!(tracker.acquire_bytes(2, 4096, 4096, 0, 8192).is_ok());
+    }
+
+    #[test]
+    fn registrations_need_memlock_headroom(){
+        let mut tracker = tracker(ResourceLimits::default());
+        tracker.memlock = Some(8192);
+        assert!(tracker.acquire_bytes(1, 4096, 4096, 4096, 4096).is_ok());
+        let error = tracker.acquire_bytes(1, 4096, 4096, 4096, 8192).unwrap_err();
+        assert!(error.contains("RLIMIT_MEMLOCK"), "{}", error);
+        assert!(tracker.acquire_bytes(1, 4096, 4096, 0, 8192).is_ok());
+    }
+
+    #[test]
+    fn closed_sessions_give_their_bytes_back(){
+        let mut tracker = tracker(ResourceLimits{max_session_bytes: 4096, ..Default::default()});
+        assert!(tracker.acquire_bytes(1, 4096, 4096, 4096, 0).is_ok());
+        assert!(tracker.acquire_bytes(1, 4096, 4096, 4096, 4096).is_err());
+        tracker.release_session_bytes(1);
+        assert!(tracker.session_bytes.is_empty());
+        assert!(tracker.acquire_bytes(1, 4096, 4096, 0, 4096).is_ok());
+    }
+
+    #[test]
+    fn registered_bytes_bound_follows_the_limits(){
+        assert_eq!(ResourceLimits::default().registered_bytes_bound(1024), None);
+        let limits = ResourceLimits{max_total_bytes: 4096, max_session_bytes: 1024, max_sessions: 2, ..Default::default()};
+        assert_eq!(limits.registered_bytes_bound(1 << 20), Some(4096));
+        let limits = ResourceLimits{max_session_bytes: 1024, max_sessions: 2, ..Default::default()};
+        assert_eq!(limits.registered_bytes_bound(1024), Some(2048));
+        assert_eq!(limits.registered_bytes_bound(1 << 20), Some(1 << 20));
+    }
+}
@@ -1,13 +1,7 @@
-use clap::Parser;
-use common::CustomError;
-use grpc_server::GrpcServer;
-use server_manager::ServerManager;
-use rdma_server::RdmaServer;
-
-pub mod grpc_server;
-pub mod connection_manager;
-pub mod rdma_server;
-pub mod server_manager;
+use std::{net::IpAddr, path::PathBuf, time::Duration};
+use clap::{Parser, Subcommand};
+use common::{CustomError, affinity::CpuList, alloc::{AllocOptions, AllocStrategy}, counters::DEFAULT_SYSFS_ROOT, doctor::{self, Doctor}, rate::Rate, signal::shutdown_signal, timeout::PollMode};
+use server::{Server, ServerConfig, limits::ResourceLimits, multicast::MulticastConfig, rdma_server::{SrqConfig, POOL_MAX_CACHED_BYTES}};
 
 #[derive(Parser)]
 struct Args{
@@ -15,47 +9,159 @@ struct Args{
     address: String,
     #[clap(short, long)]
     port: u16,
+    /// Also serve the gRPC control plane over RDMA CM on this port
+    #[clap(long)]
+    grpc_rdma_port: Option<u16>,
+    /// Receive on all sessions through one shared receive queue
+    #[clap(long)]
+    srq: bool,
+    #[clap(long, default_value = "1024")]
+    srq_size: u32,
+    #[clap(long, default_value = "4096")]
+    srq_buffer_size: usize,
+    #[clap(long, default_value = "64")]
+    srq_limit: u32,
+    /// Receive slots advertised to senders in send/recv tests, 0 disables flow control
+    #[clap(long, default_value = "128")]
+    credits: u32,
+    /// Buffer allocation: heap, aligned, huge-2m or huge-1g
+    #[clap(long, default_value = "heap")]
+    alloc: AllocStrategy,
+    /// Bind buffers to the NUMA node of the RDMA device
+    #[clap(long)]
+    numa_local: bool,
+    /// Timeout of every blocking RDMA and CM wait in milliseconds, 0 waits forever.
+    /// The wait of a session for the next request of its client, and for the end of a workload, is not bounded
+    #[clap(long, default_value = "30000")]
+    timeout_ms: u64,
+    /// Completion waits: busy, event, adaptive or adaptive:<spin budget in µs>
+    #[clap(long, default_value = "event")]
+    poll_mode: PollMode,
+    /// Largest message size a client may ask for, 0 is unlimited
+    #[clap(long, default_value = "0")]
+    max_message_size: usize,
+    /// Bytes a session may have registered at once, 0 is unlimited
+    #[clap(long, default_value = "0")]
+    max_session_bytes: usize,
+    /// Bytes the server may have registered in total, 0 is unlimited
+    #[clap(long, default_value = "0")]
+    max_total_bytes: usize,
+    /// Concurrent sessions, 0 is unlimited
+    #[clap(long, default_value = "0")]
+    max_sessions: usize,
+    /// Concurrent QPs, 0 is unlimited
+    #[clap(long, default_value = "0")]
+    max_qps: usize,
+    /// Time active sessions get to finish on SIGINT/SIGTERM before they are cancelled
+    #[clap(long, default_value = "5000")]
+    shutdown_grace_ms: u64,
+    /// Pin the session workers to these CPUs in turn, e.g. 2,4-7
+    #[clap(long)]
+    cpu: Option<CpuList>,
+    /// Snapshot the port counters around each run and report the changes to the client
+    #[clap(long)]
+    counters: bool,
+    /// Directory the RDMA devices and their counters are read from
+    #[clap(long, default_value = DEFAULT_SYSFS_ROOT)]
+    sysfs_root: PathBuf,
+    /// Serve the UD send and ping-pong benchmarks on this port
+    #[clap(long)]
+    ud_port: Option<u16>,
+    /// Send to this multicast group while clients subscribe to it
+    #[clap(long)]
+    mcast_group: Option<IpAddr>,
+    /// Bytes of every multicast datagram, at most the path MTU
+    #[clap(long, default_value = "1024")]
+    mcast_size: usize,
+    /// Multicast send rate in datagrams/s like 100k or bits/s like 1gbps
+    #[clap(long, default_value = "100k")]
+    mcast_rate: Rate,
+    #[clap(subcommand)]
+    command: Option<Command>,
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<(), CustomError> {
-
-    let args = Args::parse();
+#[derive(Subcommand)]
+enum Command{
+    /// Check the RDMA prerequisites of this host and the listen address
+    /// instead of serving
+    Doctor,
+}
 
-    let mut jh_list = Vec::new();
+fn limits(args: &Args) -> ResourceLimits{
+    ResourceLimits{
+        max_message_size: args.max_message_size,
+        max_session_bytes: args.max_session_bytes,
+        max_total_bytes: args.max_total_bytes,
+        max_sessions: args.max_sessions,
+        max_qps: args.max_qps,
+    }
+}
 
-    let rdma_server = RdmaServer::new();
-    let rdma_server_client = rdma_server.client.clone();
-    let jh = tokio::spawn(async move{
-        rdma_server.run().await.unwrap();
-    });
-    jh_list.push(jh);
+/// Prints a pass/fail report of the prerequisites with hints for the
+/// failures, and fails if any check failed. The memlock limit is checked
+/// against the session buffers the limits allow, or at least the buffer
+/// pool cache without limits, plus the SRQ buffers.
+fn doctor(args: &Args) -> anyhow::Result<(), CustomError>{
+    let session_bytes = limits(args).registered_bytes_bound(POOL_MAX_CACHED_BYTES).unwrap_or(POOL_MAX_CACHED_BYTES);
+    let srq_bytes = if args.srq { args.srq_size as usize * args.srq_buffer_size } else { 0 };
+    let mut report = Doctor::new(&args.sysfs_root).check_host(session_bytes.saturating_add(srq_bytes));
+    report.push(doctor::check_local_address(&args.address));
+    report.push(doctor::check_listen_port(&args.address, args.port));
+    println!("{}", report);
+    if !report.passed(){
+        return Err(CustomError::new("doctor found failed checks".to_string(), -1));
+    }
+    Ok(())
+}
 
-    let sm = ServerManager::new(args.address.clone(), rdma_server_client);
-    let sm_client = sm.client.clone();
-    let jh = tokio::spawn(async move{
-        sm.run().await;
-    });
-    jh_list.push(jh);
+#[tokio::main]
+async fn main() -> anyhow::Result<(), CustomError> {
 
-    let grpc_address = format!("{}:{}",args.address,args.port);
-    let jh = tokio::spawn(async move{
-        let grpc_server = GrpcServer::new(grpc_address, sm_client);
-        grpc_server.run().await.unwrap();
+    let args = Args::parse();
+    if let Some(Command::Doctor) = args.command {
+        return doctor(&args);
+    }
+
+    let srq_config = if args.srq {
+        Some(SrqConfig{
+            max_wr: args.srq_size,
+            buffer_size: args.srq_buffer_size,
+            srq_limit: args.srq_limit,
+        })
+    } else {
+        None
+    };
+    let timeout = if args.timeout_ms > 0 {
+        Some(Duration::from_millis(args.timeout_ms))
+    } else {
+        None
+    };
+    let limits = limits(&args);
+    let mut config = ServerConfig::new(args.address, args.port);
+    config.grpc_rdma_port = args.grpc_rdma_port;
+    config.srq = srq_config;
+    config.credits = args.credits;
+    config.alloc = AllocOptions{
+        strategy: args.alloc,
+        numa_local: args.numa_local,
+    };
+    config.timeout = timeout;
+    config.poll_mode = args.poll_mode;
+    config.limits = limits;
+    config.shutdown_grace = Duration::from_millis(args.shutdown_grace_ms);
+    config.cpus = args.cpu.unwrap_or_default();
+    if args.counters{
+        config.counters = Some(args.sysfs_root);
+    }
+    config.ud_port = args.ud_port;
+    config.multicast = args.mcast_group.map(|group| MulticastConfig{
+        group,
+        message_size: args.mcast_size,
+        rate: args.mcast_rate,
     });
-    jh_list.push(jh);
-
-
-    futures::future::join_all(jh_list).await;
-
-
 
-
-
-    //let mut rdma_server = RdmaServer::new();
-    //rdma_server.listen(address, port)?;
-    //rdma_server.write()?;
-
-
-    Ok(())
-}
\ No newline at end of file
+    Server::new(config).run(async {
+        let signal = shutdown_signal().await;
+        println!("received {}, shutting down", signal);
+    }).await
+}
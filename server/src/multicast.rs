@@ -0,0 +1,158 @@
+use std::{collections::HashMap, net::IpAddr, sync::Mutex, time::{Duration, Instant}};
+use common::{CustomError, rate::{wait_until, Arrival, Rate, Schedule}, timeout::{CancellationToken, PollMode, WaitControl}, ud::{DatagramKind, UdEndpoint, UD_HEADER_LEN}};
+
+/// Time the sender sleeps between checks for subscribers while the group
+/// has none.
+const IDLE_POLL: Duration = Duration::from_millis(10);
+
+/// Time a subscriber stays in the group after its last join, so the stream
+/// stops for one that went away without leaving.
+pub const MEMBER_LEASE: Duration = Duration::from_secs(10);
+
+/// What the server sends to a multicast group.
+#[derive(Clone, Debug)]
+pub struct MulticastConfig{
+    pub group: IpAddr,
+    /// Bytes of every datagram, header included, at most the path MTU.
+    pub message_size: usize,
+    pub rate: Rate,
+}
+
+/// The group as a subscriber sees it when it joins or leaves.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GroupStatus{
+    pub group: IpAddr,
+    pub message_size: usize,
+    /// Sequence number of the next datagram sent, which tells a joining
+    /// subscriber where its stream starts and a leaving one where it ends.
+    pub next_seq: u64,
+    pub members: usize,
+    /// Time the subscription lasts without another join.
+    pub lease: Duration,
+}
+
+/// Subscribers and stream position, under one lock so a leave sees every
+/// datagram numbered for the subscriber in `next_seq`.
+#[derive(Default)]
+struct Group{
+    /// Subscribers by client id with the end of their leases.
+    members: HashMap<u32, Instant>,
+    next_seq: u64,
+    /// Whether the sender is joined to the group and sending.
+    running: bool,
+}
+
+/// Sends data datagrams with increasing sequence numbers to a multicast
+/// group while it has subscribers. Subscribers are tracked by the client
+/// ids of their gRPC requests, the CM joins of the subscribers are between
+/// them and the fabric.
+pub struct MulticastSender{
+    config: MulticastConfig,
+    group: Mutex<Group>,
+    timeout: Option<Duration>,
+    poll_mode: PollMode,
+}
+
+impl MulticastSender{
+    pub fn new(config: MulticastConfig) -> MulticastSender{
+        MulticastSender{
+            config,
+            group: Mutex::new(Group::default()),
+            timeout: None,
+            poll_mode: PollMode::default(),
+        }
+    }
+    /// Timeout of the CM join and of the sends.
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self{
+        self.timeout = timeout;
+        self
+    }
+    pub fn with_poll_mode(mut self, poll_mode: PollMode) -> Self{
+        self.poll_mode = poll_mode;
+        self
+    }
+    fn status(&self, group: &Group) -> GroupStatus{
+        GroupStatus{
+            group: self.config.group,
+            message_size: self.config.message_size,
+            next_seq: group.next_seq,
+            members: group.members.len(),
+            lease: MEMBER_LEASE,
+        }
+    }
+    /// Adds `client_id` to the subscribers, sending starts with the first.
+    /// Joining again renews the lease of a subscriber.
+    pub fn join(&self, client_id: u32) -> Result<GroupStatus, String>{
+        let mut group = self.group.lock().unwrap();
+        if !group.running{
+            return Err(format!("multicast sender of group {} is not running", self.config.group));
+        }
+        group.members.insert(client_id, Instant::now() + MEMBER_LEASE);
+        Ok(self.status(&group))
+    }
+    /// Removes `client_id` from the subscribers, sending pauses after the
+    /// last.
+    pub fn leave(&self, client_id: u32) -> Result<GroupStatus, String>{
+        let mut group = self.group.lock().unwrap();
+        if group.members.remove(&client_id).is_none(){
+            return Err(format!("client {} is not in group {}", client_id, self.config.group));
+        }
+        Ok(self.status(&group))
+    }
+    /// Joins the group from `bind_address` and sends to it until the token
+    /// is cancelled. Joins fail from the time it returns.
+    pub fn run(&self, bind_address: IpAddr, token: CancellationToken) -> anyhow::Result<(), CustomError>{
+        let result = self.send(bind_address, token);
+        let mut group = self.group.lock().unwrap();
+        group.running = false;
+        group.members.clear();
+        result
+    }
+    fn send(&self, bind_address: IpAddr, token: CancellationToken) -> anyhow::Result<(), CustomError>{
+        let wait_control = WaitControl{
+            timeout: self.timeout,
+            token: token.clone(),
+            poll_mode: self.poll_mode,
+        };
+        let mut endpoint = UdEndpoint::join_multicast(Some(bind_address), self.config.group, &wait_control)?;
+        let message_size = self.config.message_size;
+        if message_size < UD_HEADER_LEN || message_size > endpoint.mtu(){
+            return Err(CustomError::new(format!("multicast messages are {} to {} bytes on this path, not {}", UD_HEADER_LEN, endpoint.mtu(), message_size), -libc::EMSGSIZE));
+        }
+        self.group.lock().unwrap().running = true;
+        println!("sending {} byte datagrams to multicast group {}", message_size, self.config.group);
+        let ops_per_sec = self.config.rate.ops_per_sec(message_size as f64);
+        let mut schedule = None;
+        while !token.is_cancelled(){
+            if self.live_members() == 0{
+                // a new schedule on the next subscriber, so the idle time
+                // is not made up in a burst
+                schedule = None;
+                std::thread::sleep(IDLE_POLL);
+                continue;
+            }
+            wait_until(schedule.get_or_insert_with(|| Schedule::new(ops_per_sec, Arrival::Constant, 0)).next_start());
+            let (seq, members) = {
+                let mut group = self.group.lock().unwrap();
+                let seq = group.next_seq;
+                group.next_seq += 1;
+                (seq, group.members.len())
+            };
+            endpoint.send(DatagramKind::Data, seq, members as u64, message_size)?;
+        }
+        endpoint.leave_multicast()
+    }
+    /// Drops the subscribers whose leases ran out and counts the rest.
+    fn live_members(&self) -> usize{
+        let now = Instant::now();
+        let mut group = self.group.lock().unwrap();
+        group.members.retain(|client_id, expiry|{
+            if *expiry > now{
+                return true;
+            }
+            println!("lease of client {} on multicast group {} ran out", client_id, self.config.group);
+            false
+        });
+        group.members.len()
+    }
+}
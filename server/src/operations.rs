@@ -0,0 +1,177 @@
+use common::{*, remote::RemoteRegion, verify::{Verifier, VerifyPattern}};
+use crate::handler::{RequestHandler, Session, SessionAction};
+
+fn verifier(request: &MetaData) -> Option<Verifier>{
+    VerifyPattern::from_code(request.verify()).map(|pattern| Verifier::new(pattern, request.seed()))
+}
+
+/// Size of the buffer handed out for one-sided access, at least the message
+/// size.
+fn region_size(request: &MetaData) -> usize{
+    (request.buffer_size() as usize).max(request.message_size() as usize)
+}
+
+/// A client may send `Disconnect` instead of the finished message.
+fn next_action(request: &MetaData) -> SessionAction{
+    match request.get_request_type(){
+        MetaDataRequestTypes::Disconnect => SessionAction::Close,
+        _ => SessionAction::Continue,
+    }
+}
+
+/// Answers the finished message of a verified transfer with the outcome of
+/// the checks: the finished message echoed back, or an `ErrorResponse` with
+/// `VerifyFailed` if payloads mismatched.
+fn report(session: &Session, request: &mut MetaData, verifier: Option<&Verifier>) -> anyhow::Result<SessionAction, CustomError>{
+    let action = next_action(request);
+    let Some(verifier) = verifier else {
+        return Ok(action);
+    };
+    println!("{}", verifier);
+    if action == SessionAction::Close{
+        return Ok(action);
+    }
+    if verifier.mismatch_count() > 0{
+        let reason = format!("{} mismatched bytes", verifier.mismatch_count());
+        return session.reject(request, ServerError{code: ErrorCode::VerifyFailed, reason});
+    }
+    session.respond(request)?;
+    Ok(action)
+}
+
+/// Hands out a buffer the client writes into.
+pub struct WriteHandler;
+
+impl RequestHandler for WriteHandler{
+    fn handle(&self, session: &Session, request: &mut MetaData) -> anyhow::Result<SessionAction, CustomError>{
+        let mut verifier = verifier(request);
+        let data = match session.checkout(region_size(request), Operation::Write){
+            Ok(data) => data,
+            Err(error) => return session.reject(request, error),
+        };
+        let last_iteration = request.iterations().max(1) as u64 - 1;
+        request.set_request_type(MetaDataRequestTypes::WriteResponse);
+        request.set_remote_region(&RemoteRegion::new(data.mr_addr(), data.len() as u64, data.mr_rkey(), Operation::Write));
+        session.respond(request)?;
+        session.recv_request(request)?;
+        if let Some(verifier) = verifier.as_mut(){
+            verifier.check(data.as_slice(), last_iteration, last_iteration);
+        }
+        report(session, request, verifier.as_ref())
+    }
+}
+
+/// Receives the messages the client sends, through the SRQ or with credit
+/// based flow control.
+pub struct SendHandler;
+
+impl RequestHandler for SendHandler{
+    fn handle(&self, session: &Session, request: &mut MetaData) -> anyhow::Result<SessionAction, CustomError>{
+        let mut verifier = verifier(request);
+        if let Some(srq) = session.shared_receive_queue(){
+            let message_size = request.message_size() as usize;
+            let buffer_size = srq.lock().unwrap().buffer_size();
+            if message_size > buffer_size{
+                let reason = format!("message size {} exceeds srq buffer size {}", message_size, buffer_size);
+                return session.reject(request, ServerError{code: ErrorCode::MessageTooLarge, reason});
+            }
+            let mut data = match session.checkout(message_size, Operation::SendRecv){
+                Ok(data) => data,
+                Err(error) => return session.reject(request, error),
+            };
+            request.set_request_type(MetaDataRequestTypes::SendResponse);
+            request.set_credits(0);
+            session.respond(request)?;
+            match verifier.as_mut(){
+                Some(verifier) => {
+                    for i in 0..request.iterations() as u64{
+                        session.recv(&mut *data, 1)?;
+                        verifier.check(data.as_slice(), i, i);
+                    }
+                },
+                None => session.recv(&mut *data, request.iterations() as usize)?,
+            }
+            session.recv_request(request)?;
+            return report(session, request, verifier.as_ref());
+        }
+        let mut data = match session.checkout(request.message_size() as usize, Operation::SendRecv){
+            Ok(data) => data,
+            Err(error) => return session.reject(request, error),
+        };
+        let data_mr_addr = data.registered_mr_addr();
+        let iterations = request.iterations() as usize;
+        // a verified transfer checks each message before its slot is
+        // reposted, so only one may be outstanding
+        let credits = if verifier.is_some() { 1 } else { session.credits() };
+        if credits == 0{
+            request.set_request_type(MetaDataRequestTypes::SendResponse);
+            request.set_credits(0);
+            session.respond(request)?;
+            data.rdma_recv_data(session.id(), &data_mr_addr, iterations)?;
+            session.recv_request(request)?;
+            return Ok(next_action(request));
+        }
+        let credit_receiver = data.rdma_post_credits(session.id(), &data_mr_addr, iterations, credits as usize)?;
+        request.set_request_type(MetaDataRequestTypes::SendResponse);
+        request.set_credits(credit_receiver.credits() as u32);
+        session.respond(request)?;
+        data.rdma_recv_data_with_credits(session.id(), &data_mr_addr, credit_receiver, iterations, verifier.as_mut())?;
+        session.recv_request(request)?;
+        report(session, request, verifier.as_ref())
+    }
+}
+
+/// Hands out a buffer the client reads from.
+pub struct ReadHandler;
+
+impl RequestHandler for ReadHandler{
+    fn handle(&self, session: &Session, request: &mut MetaData) -> anyhow::Result<SessionAction, CustomError>{
+        let mut verifier = verifier(request);
+        let mut data = match session.checkout(region_size(request), Operation::Read){
+            Ok(data) => data,
+            Err(error) => return session.reject(request, error),
+        };
+        if let Some(verifier) = verifier.as_mut(){
+            verifier.fill(data.as_mut_slice(), 0);
+        }
+        request.set_request_type(MetaDataRequestTypes::ReadResponse);
+        request.set_remote_region(&RemoteRegion::new(data.mr_addr(), data.len() as u64, data.mr_rkey(), Operation::Read));
+        session.respond(request)?;
+        session.recv_request(request)?;
+        if let Some(verifier) = verifier{
+            println!("{}", verifier);
+        }
+        Ok(next_action(request))
+    }
+}
+
+/// Hands out a region the client reads, writes and runs atomics on until it
+/// finishes its workload.
+pub struct WorkloadHandler;
+
+impl RequestHandler for WorkloadHandler{
+    fn handle(&self, session: &Session, request: &mut MetaData) -> anyhow::Result<SessionAction, CustomError>{
+        let data = match session.checkout(region_size(request), Operation::Atomic){
+            Ok(data) => data,
+            Err(error) => return session.reject(request, error),
+        };
+        request.set_request_type(MetaDataRequestTypes::WorkloadResponse);
+        request.set_remote_region(&RemoteRegion::new(data.mr_addr(), data.len() as u64, data.mr_rkey(), Operation::Atomic));
+        session.respond(request)?;
+        // the workload runs for as long as the client was asked to
+        session.recv_request_after_run(request)?;
+        Ok(next_action(request))
+    }
+}
+
+/// Ends the session.
+pub struct DisconnectHandler;
+
+impl RequestHandler for DisconnectHandler{
+    fn handle(&self, session: &Session, _request: &mut MetaData) -> anyhow::Result<SessionAction, CustomError>{
+        if let Some(srq) = session.shared_receive_queue(){
+            srq.lock().unwrap().remove_qp(session.id().qp_num());
+        }
+        Ok(SessionAction::Close)
+    }
+}
@@ -1,70 +1,311 @@
 
-use std::{ptr::null_mut, sync::{Arc, Mutex}};
+use std::{collections::HashMap, path::PathBuf, ptr::null_mut, sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex}, time::{Duration, Instant}};
 use rdma_sys::*;
-use common::*;
+use common::{*, affinity::{CpuList, ThreadPlacement}, alloc::AllocOptions, counters::{CounterMeter, CounterSource}, mr_pool::BufferPool, srq::{srq_async_event_loop, srq_recv, SharedReceiveQueue}, timeout::{wait_cm_event, CancellationToken, PollMode, WaitControl}, usage::{RunUsage, UsageMeter}};
 use tokio::sync::RwLock;
+use crate::{handler::{HandlerRegistry, Session, SessionAction}, limits::ResourceTracker};
+
+/// Bytes of idle buffers the pool keeps registered.
+pub const POOL_MAX_CACHED_BYTES: usize = 1024 * 1024 * 1024;
+/// How often a usage query checks whether the runs were recorded.
+const USAGE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Usage of the finished runs of each session, until it is queried or the
+/// session closes.
+type UsageMap = Arc<Mutex<HashMap<u32, Vec<RunUsage>>>>;
+
+/// Sizing of the shared receive queue. When it is set on the server, all
+/// session QPs receive through one SRQ instead of posting their own receives.
+#[derive(Clone)]
+pub struct SrqConfig{
+    pub max_wr: u32,
+    pub buffer_size: usize,
+    pub srq_limit: u32,
+}
+
+/// The SRQ of the server and the thread refilling it on limit events.
+struct SrqWorker{
+    srq: Arc<Mutex<SharedReceiveQueue>>,
+    /// Stops the event thread.
+    token: CancellationToken,
+    events: std::thread::JoinHandle<()>,
+}
+
+/// Hands the reply of a listen to its session, which answers once it is
+/// closed.
+type ListenSender = tokio::sync::oneshot::Sender<tokio::sync::oneshot::Sender<()>>;
 
 #[derive(Clone)]
 pub struct RdmaServer{
     pub client: RdmaServerClient,
-    rx: Arc<RwLock<tokio::sync::mpsc::Receiver<RdmaServerCommand>>>
+    rx: Arc<RwLock<tokio::sync::mpsc::Receiver<RdmaServerCommand>>>,
+    srq_config: Option<SrqConfig>,
+    srq: Arc<Mutex<Option<SrqWorker>>>,
+    credits: u32,
+    pool: Arc<Mutex<BufferPool>>,
+    timeout: Option<Duration>,
+    poll_mode: PollMode,
+    /// Cancels the blocking waits of all sessions.
+    shutdown_token: CancellationToken,
+    resources: Arc<Mutex<ResourceTracker>>,
+    handlers: Arc<HandlerRegistry>,
+    cpus: CpuList,
+    /// Sessions served so far, which picks the CPU of the next one.
+    sessions_started: Arc<AtomicUsize>,
+    usage: UsageMap,
+    /// Sysfs root the port counters are read below, `None` skips them.
+    counter_root: Option<PathBuf>,
 }
 
 impl RdmaServer{
-    pub fn new() -> RdmaServer{
+    pub fn new(srq_config: Option<SrqConfig>, credits: u32, alloc: AllocOptions, timeout: Option<Duration>, resources: Arc<Mutex<ResourceTracker>>, handlers: HandlerRegistry) -> RdmaServer{
         let (tx, rx) = tokio::sync::mpsc::channel(1);
-        let client = RdmaServerClient::new(tx);
+        let shutdown_token = CancellationToken::new();
+        let usage = UsageMap::default();
+        let client = RdmaServerClient::new(tx, shutdown_token.clone(), usage.clone());
         RdmaServer{
             client,
-            rx: Arc::new(RwLock::new(rx))
+            rx: Arc::new(RwLock::new(rx)),
+            srq_config,
+            srq: Arc::new(Mutex::new(None)),
+            credits,
+            pool: Arc::new(Mutex::new(BufferPool::new(POOL_MAX_CACHED_BYTES, alloc))),
+            timeout,
+            poll_mode: PollMode::default(),
+            shutdown_token,
+            resources,
+            handlers: Arc::new(handlers),
+            cpus: CpuList::default(),
+            sessions_started: Arc::new(AtomicUsize::new(0)),
+            usage,
+            counter_root: None,
+        }
+    }
+    /// Snapshots the counters of the session port below `root` around each
+    /// run.
+    pub fn with_counters(mut self, root: Option<PathBuf>) -> RdmaServer{
+        self.counter_root = root;
+        self
+    }
+    /// How the session workers wait for completions.
+    pub fn with_poll_mode(mut self, poll_mode: PollMode) -> RdmaServer{
+        self.poll_mode = poll_mode;
+        self
+    }
+    /// Pins the worker of each session to the next of `cpus`.
+    pub fn with_cpus(mut self, cpus: CpuList) -> RdmaServer{
+        self.cpus = cpus;
+        self
+    }
+    pub(crate) fn credits(&self) -> u32{
+        self.credits
+    }
+    pub(crate) fn shared_receive_queue(&self) -> Option<Arc<Mutex<SharedReceiveQueue>>>{
+        self.srq.lock().unwrap().as_ref().map(|worker| worker.srq.clone())
+    }
+    /// Returns the server SRQ, creating it on the device of `id` on first use.
+    /// The first creation also starts the thread which refills the SRQ on
+    /// limit events.
+    fn get_or_create_srq(&self, id: &Id, srq_config: &SrqConfig) -> anyhow::Result<Arc<Mutex<SharedReceiveQueue>>, CustomError>{
+        let mut srq = self.srq.lock().unwrap();
+        if let Some(worker) = srq.as_ref(){
+            return Ok(worker.srq.clone());
+        }
+        let shared_receive_queue = SharedReceiveQueue::new(id, srq_config.max_wr, srq_config.buffer_size, srq_config.srq_limit)?;
+        let shared_receive_queue = Arc::new(Mutex::new(shared_receive_queue));
+        let event_srq = shared_receive_queue.clone();
+        let token = CancellationToken::new();
+        let event_token = token.clone();
+        let events = std::thread::spawn(move ||{
+            if let Err(e) = srq_async_event_loop(event_srq, event_token){
+                println!("srq async event loop stopped: {}", e);
+            }
+        });
+        *srq = Some(SrqWorker{
+            srq: shared_receive_queue.clone(),
+            token,
+            events,
+        });
+        Ok(shared_receive_queue)
+    }
+    /// Stops the SRQ event thread and destroys the SRQ with its buffers,
+    /// once no session uses it anymore.
+    fn close_srq(&self){
+        let Some(worker) = self.srq.lock().unwrap().take() else {
+            return;
+        };
+        worker.token.cancel();
+        if worker.events.join().is_err(){
+            println!("srq async event thread panicked");
+        }
+        if Arc::strong_count(&worker.srq) > 1{
+            println!("srq is still in use, it is destroyed by its last user");
+        }
+    }
+    /// Checks a buffer out of the pool for `session_id` within the resource
+    /// limits.
+    pub(crate) fn checkout(&self, session_id: u32, id: &Id, size: usize, operation: Operation) -> Result<Data, ServerError>{
+        let mut pool = self.pool.lock().unwrap();
+        let registered_size = pool.registered_size(size);
+        let new_registration = pool.new_registration(id, size, operation);
+        self.resources.lock().unwrap().acquire_bytes(session_id, size, registered_size, new_registration, pool.registered_bytes())
+            .map_err(|reason| ServerError{code: ErrorCode::ResourceExhausted, reason})?;
+        pool.checkout(id, size, operation).map_err(|e| {
+            self.resources.lock().unwrap().release_bytes(session_id, registered_size);
+            ServerError{code: ErrorCode::AllocationFailed, reason: e.message().to_string()}
+        })
+    }
+    pub(crate) fn checkin(&self, session_id: u32, data: Data){
+        let mut pool = self.pool.lock().unwrap();
+        self.resources.lock().unwrap().release_bytes(session_id, pool.registered_size(data.len()));
+        pool.checkin(data);
+    }
+    pub(crate) fn recv_metadata(&self, id: &Id, metadata: &mut MetaData, mr_addr: &MrAddr) -> anyhow::Result<(), CustomError>{
+        match self.shared_receive_queue(){
+            Some(srq) => srq_recv(&srq, id, metadata, 1),
+            None => metadata.rdma_recv(id, mr_addr),
+        }
+    }
+    /// Answers the pending request with an `ErrorResponse` so that the client
+    /// does not wait for a response which never comes. The session stays up.
+    pub(crate) fn send_error(&self, id: &Id, metadata: &mut MetaData, mr_addr: &MrAddr, code: ErrorCode, reason: &str) -> anyhow::Result<(), CustomError>{
+        println!("rejecting {:?}: {:?} {}", metadata.get_request_type(), code, reason);
+        metadata.set_request_type(MetaDataRequestTypes::ErrorResponse);
+        metadata.set_error(code, reason);
+        metadata.rdma_send(id, mr_addr)
+    }
+    /// Tears a session down in order: disconnects the CM id, which also
+    /// tells the peer, and destroys the session and listen endpoints. The
+    /// pooled buffers stay registered for the next session.
+    fn close_session(&self, id: Id, listen_id: Id, session_id: u32){
+        self.resources.lock().unwrap().release_session_bytes(session_id);
+        unsafe { rdma_disconnect(id.id()) };
+        if let Some(srq) = self.shared_receive_queue(){
+            srq.lock().unwrap().remove_qp(id.qp_num());
         }
+        unsafe { rdma_destroy_ep(id.id()) };
+        self.resources.lock().unwrap().release_qp();
+        unsafe { rdma_destroy_ep(listen_id.id()) };
     }
     pub async fn run(&self) -> anyhow::Result<()>{
         let mut rx = self.rx.write().await;
-        let my_id = Arc::new(Mutex::new(Id(null_mut())));
+        // connected sessions waiting for their listen, by session id
+        let mut pending: HashMap<u32, ListenSender> = HashMap::new();
+        let mut sessions = tokio::task::JoinSet::new();
         while let Some(rdma_server_command) = rx.recv().await{
-            let rdma_server = self.clone();
             match rdma_server_command{
-                RdmaServerCommand::Listen{tx} => {
-                    loop {
-                        let ret = rdma_server.listen(my_id.clone()).await.unwrap();
-                        if ret == 0 {
-                            break;
+                RdmaServerCommand::Listen{session_id, tx} => {
+                    // a session which is unknown or already gone answers
+                    // right away
+                    match pending.remove(&session_id){
+                        Some(listen) => {
+                            if let Err(tx) = listen.send(tx){
+                                let _ = tx.send(());
+                            }
+                        },
+                        None => {
+                            let _ = tx.send(());
                         }
                     }
-                    tx.send(()).unwrap();
                 },
-                RdmaServerCommand::Connect{address, port} => {
-                    let id = rdma_server.connect(address, port).await.unwrap();
-                    let mut my_id = my_id.lock().unwrap();
-                    *my_id = id;
+                RdmaServerCommand::Connect{address, port, session_id, closed} => {
+                    pending.retain(|_, listen| !listen.is_closed());
+                    while sessions.try_join_next().is_some(){}
+                    let (listen_tx, listen_rx) = tokio::sync::oneshot::channel();
+                    pending.insert(session_id, listen_tx);
+                    sessions.spawn(self.clone().run_session(address, port, session_id, closed, listen_rx));
+                },
+                RdmaServerCommand::Shutdown{tx} => {
+                    // sessions waiting for their listen close right away,
+                    // the others end with the cancelled shutdown token
+                    pending.clear();
+                    while sessions.join_next().await.is_some(){}
+                    self.close_srq();
+                    self.pool.lock().unwrap().clear();
+                    rx.close();
+                    tx.send(()).unwrap();
+                    break;
                 }
             }
         }
         println!("rdma server stopped");
         Ok(())
     }
-    pub async fn connect(self, address: String, port: u16) -> anyhow::Result<Id, CustomError>{
-        let port = format!("{}\0",port);
-        let address = format!("{}\0",address);
-        let port = port.as_str();
-        let address = address.as_str();
-        let mut hints = unsafe { std::mem::zeroed::<rdma_addrinfo>() };
-        let mut res: *mut rdma_addrinfo = null_mut();
-        hints.ai_flags = RAI_PASSIVE.try_into().unwrap();
-        hints.ai_port_space = rdma_port_space::RDMA_PS_TCP.try_into().unwrap();
-        let ret = unsafe {
-            rdma_getaddrinfo(
-                address.as_ptr().cast(),
-                port.as_ptr().cast(),
-                &hints,
-                &mut res,
-            )
+    /// Runs session `session_id` in a task of its own, so that sessions
+    /// neither wait for nor close each other: accepts its client on
+    /// `address:port`, serves it on a worker thread once it is listened on
+    /// and tears it down. `closed` is told once the session is closed, also
+    /// if it never connected.
+    async fn run_session(self, address: String, port: u16, session_id: u32, closed: tokio::sync::oneshot::Sender<()>, listen_rx: tokio::sync::oneshot::Receiver<tokio::sync::oneshot::Sender<()>>){
+        // waiting for the client blocks on the CM, so it runs off the
+        // runtime workers
+        let rdma_server = self.clone();
+        let connected = tokio::task::spawn_blocking(move || rdma_server.connect(address, port)).await
+            .unwrap_or_else(|_| Err(CustomError::new("connect thread panicked".to_string(), -1)));
+        let (listen_id, id) = match connected{
+            Ok(ids) => ids,
+            Err(e) => {
+                println!("connect of session {} failed: {}", session_id, e);
+                let _ = closed.send(());
+                return;
+            }
         };
-        if ret != 0 {
-            return Err(CustomError::new("rdma_getaddrinfo".to_string(), ret).into());
+        // a connected client has the timeout to ask for the listen, its
+        // session is closed otherwise
+        let listen = match self.timeout{
+            Some(timeout) => match tokio::time::timeout(timeout, listen_rx).await{
+                Ok(listen) => listen.ok(),
+                Err(_) => {
+                    println!("session {} was not listened on within {:?}, closing it", session_id, timeout);
+                    None
+                }
+            },
+            None => listen_rx.await.ok(),
+        };
+        if listen.is_some(){
+            let (done_tx, done_rx) = tokio::sync::oneshot::channel();
+            let rdma_server = self.clone();
+            let session_cm_id = id.clone();
+            let handle = tokio::runtime::Handle::current();
+            std::thread::spawn(move ||{
+                rdma_server.serve_session(&session_cm_id, session_id, &handle);
+                let _ = done_tx.send(());
+            });
+            let _ = done_rx.await;
+        }
+        self.close_session(id, listen_id, session_id);
+        let _ = closed.send(());
+        if let Some(listen) = listen{
+            let _ = listen.send(());
+        }
+    }
+    /// Serves the requests of session `session_id` on `id` until it closes,
+    /// on the calling thread which is pinned to the next CPU first.
+    fn serve_session(&self, id: &Id, session_id: u32, handle: &tokio::runtime::Handle){
+        let cpu = self.cpus.get(self.sessions_started.fetch_add(1, Ordering::Relaxed));
+        let mut placement = match ThreadPlacement::start("session worker", cpu){
+            Ok(placement) => placement,
+            Err(e) => {
+                println!("session worker stays unpinned: {}", e);
+                ThreadPlacement::start("session worker", None).unwrap()
+            }
+        };
+        loop {
+            match handle.block_on(self.listen(id, session_id)){
+                Ok(SessionAction::Close) => break,
+                Ok(SessionAction::Continue) => {},
+                Err(e) => {
+                    println!("session {} aborted: {}", session_id, e);
+                    break;
+                }
+            }
+            placement.sample();
         }
-        let mut listen_id = null_mut();
+        println!("{}", placement);
+    }
+    /// Waits for the client of a session on `address:port` and accepts it.
+    /// Returns the listen id and the connected id.
+    pub fn connect(&self, address: String, port: u16) -> anyhow::Result<(Id, Id), CustomError>{
         let mut id = null_mut();
     
         let mut init_attr = unsafe { std::mem::zeroed::<ibv_qp_init_attr>() };
@@ -72,13 +313,15 @@ impl RdmaServer{
         init_attr.cap.max_recv_wr = 4096;
         init_attr.cap.max_send_sge = 1;
         init_attr.cap.max_recv_sge = 1;
-        init_attr.cap.max_inline_data = 64;
+        init_attr.cap.max_inline_data = MAX_INLINE_DATA as u32;
         init_attr.sq_sig_all = 1;
-        let ret = unsafe { rdma_create_ep(&mut listen_id, res, null_mut(), &mut init_attr) };
-        if ret != 0 {
-            unsafe { rdma_freeaddrinfo(res); }
-            return Err(CustomError::new("rdma_create_ep".to_string(), ret).into());
-        }
+        // With an SRQ the QP is created after the request arrived, once the
+        // device is known, so the listen id is created without QP attributes.
+        let listen_init_attr = match self.srq_config{
+            Some(_) => None,
+            None => Some(&mut init_attr),
+        };
+        let listen_id = create_ep(&address, port, None, true, listen_init_attr)?.id();
         println!("Waiting for connection");
         let ret = unsafe { rdma_listen(listen_id, 0) };
         if ret != 0 {
@@ -86,15 +329,53 @@ impl RdmaServer{
             return Err(CustomError::new("rdma_listen".to_string(), ret).into());
         }
         
+        let wait_control = WaitControl{
+            timeout: self.timeout,
+            token: self.shutdown_token.clone(),
+            poll_mode: self.poll_mode,
+        };
+        if let Err(e) = wait_cm_event(&Id::new(listen_id), &wait_control){
+            unsafe { rdma_destroy_ep(listen_id); }
+            return Err(e);
+        }
         let ret = unsafe { rdma_get_request(listen_id, &mut id) };
         if ret != 0 {
             unsafe { rdma_destroy_ep(listen_id); }
             return Err(CustomError::new("rdma_get_request".to_string(), ret).into());
         }
+        if let Err(reason) = self.resources.lock().unwrap().acquire_qp(){
+            unsafe { rdma_reject(id, null_mut(), 0) };
+            unsafe { rdma_destroy_ep(id); }
+            unsafe { rdma_destroy_ep(listen_id); }
+            return Err(CustomError::new(reason, -libc::ENOSPC));
+        }
+        if let Err(e) = self.accept(id, &mut init_attr){
+            if let Some(srq) = self.shared_receive_queue(){
+                srq.lock().unwrap().remove_qp(Id::new(id).qp_num());
+            }
+            unsafe { rdma_destroy_ep(id); }
+            unsafe { rdma_destroy_ep(listen_id); }
+            self.resources.lock().unwrap().release_qp();
+            return Err(e);
+        }
+        let mut id = Id::new(id);
+        id.set_timeout(wait_control.timeout);
+        id.set_cancellation_token(wait_control.token);
+        id.set_poll_mode(wait_control.poll_mode);
+        Ok((Id::new(listen_id), id))
+    }
+    /// Creates the QP of the requested `id` on the SRQ, if the server has
+    /// one, and accepts the connection.
+    fn accept(&self, id: *mut rdma_cm_id, init_attr: &mut ibv_qp_init_attr) -> anyhow::Result<(), CustomError>{
+        if let Some(srq_config) = self.srq_config.as_ref(){
+            let srq = self.get_or_create_srq(&Id::new(id), srq_config)?;
+            let mut srq = srq.lock().unwrap();
+            srq.create_qp(&Id::new(id), init_attr)?;
+        }
         println!("Connection received, accepting it");
         let ret = unsafe { rdma_accept(id, null_mut()) };
         if ret != 0 {
-            return Err(CustomError::new("rdma_accept".to_string(), ret).into());
+            return Err(CustomError::new("rdma_accept".to_string(), ret));
         }
 
         let mut qp_attr = unsafe { std::mem::zeroed::<ibv_qp_attr>() };
@@ -103,24 +384,17 @@ impl RdmaServer{
                 (*id).qp,
                 &mut qp_attr,
                 ibv_qp_attr_mask::IBV_QP_CAP.0.try_into().unwrap(),
-                &mut init_attr,
+                init_attr,
             )
         };
         if ret != 0 {
-            unsafe { rdma_destroy_ep(id); }
-            return Err(CustomError::new("ibv_query_qp".to_string(), ret).into());
+            return Err(CustomError::new("ibv_query_qp".to_string(), ret));
         }
         qp_attr.timeout = 14;
         unsafe { ibv_modify_qp((*id).qp, &mut qp_attr, ibv_qp_attr_mask::IBV_QP_TIMEOUT.0 as i32) };
-        if id.is_null() {
-            return Err(CustomError::new("rdma_get_request".to_string(), ret).into());
-        }
-        Ok(Id(id))
+        Ok(())
     }
-    pub async fn listen(&self, my_id: Arc<Mutex<Id>>) -> anyhow::Result<u8, CustomError> {
-        let my_id_clone = my_id.clone();
-        let my_id_lock = my_id_clone.lock().unwrap();
-        let id = Id(my_id_lock.0);
+    pub async fn listen(&self, id: &Id, session_id: u32) -> anyhow::Result<SessionAction, CustomError> {
         /* 
         let recv_cq = unsafe { (*id).recv_cq };
         if !recv_cq.is_null(){
@@ -147,74 +421,106 @@ impl RdmaServer{
         */
         
         let mut metadata_request = MetaData::default();
-        let metadata_mr_addr = metadata_request.create_and_register_mr(&id, Operation::SendRecv)?;
-        metadata_request.rdma_recv(&id, &metadata_mr_addr)?;
+        let metadata_mr_addr = metadata_request.create_and_register_mr(id, Operation::SendRecv)?;
+        let ret = self.handle_request(id, &mut metadata_request, &metadata_mr_addr, session_id);
+        unsafe { rdma_dereg_mr(metadata_mr_addr.mr) };
+        ret
+    }
+    /// Serves one request. The CPU usage and counter changes of a run which
+    /// was not rejected are recorded for `session_id`, from the request until
+    /// the handler is done.
+    fn handle_request(&self, id: &Id, metadata_request: &mut MetaData, metadata_mr_addr: &MrAddr, session_id: u32) -> anyhow::Result<SessionAction, CustomError>{
+        // a client may stay idle between tests for as long as it likes, the
+        // wait ends with the session token
+        let mut idle_id = id.clone();
+        idle_id.set_timeout(None);
+        self.recv_metadata(&idle_id, metadata_request, metadata_mr_addr)?;
         println!("{:?}", metadata_request.get_request_type());
-        match metadata_request.get_request_type(){
-            MetaDataRequestTypes::WriteRequest => {
-                let mut data = Data::new(metadata_request.message_size() as usize);
-                data.create_and_register_mr(&id, Operation::Write)?;
-                metadata_request.set_request_type(MetaDataRequestTypes::WriteResponse);
-                metadata_request.set_remote_address(data.mr_addr());
-                metadata_request.set_rkey(data.mr_rkey());
-                metadata_request.rdma_send(&id, &metadata_mr_addr)?;
-                metadata_request.rdma_recv(&id, &metadata_mr_addr)?;
-                return Ok(metadata_request.get_request_type() as u8);
-            },
-            MetaDataRequestTypes::SendRequest => {
-                let mut data = Data::new(metadata_request.message_size() as usize);
-                let data_mr_addr = data.create_and_register_mr(&id, Operation::SendRecv)?;
-                metadata_request.set_request_type(MetaDataRequestTypes::SendResponse);
-                metadata_request.rdma_send(&id, &metadata_mr_addr)?;
-                data.rdma_recv_data(&id, &data_mr_addr, metadata_request.iterations() as usize)?;
-                metadata_request.rdma_recv(&id, &metadata_mr_addr)?;
-                return Ok(metadata_request.get_request_type() as u8);
-            },
-            MetaDataRequestTypes::ReadRequest => {
-                let mut data = Data::new(metadata_request.message_size() as usize);
-                data.create_and_register_mr(&id, Operation::Read)?;
-                metadata_request.set_request_type(MetaDataRequestTypes::ReadResponse);
-                metadata_request.set_remote_address(data.mr_addr());
-                metadata_request.set_rkey(data.mr_rkey());
-                metadata_request.rdma_send(&id, &metadata_mr_addr)?;
-                metadata_request.rdma_recv(&id, &metadata_mr_addr)?;
-                return Ok(metadata_request.get_request_type() as u8);
-            },
-            MetaDataRequestTypes::Disconnect => {
-                return Ok(0);
-            },
-            _ => {
-                return Ok(0);
+        let counter_meter = self.counter_root.as_ref()
+            .and_then(|root| CounterSource::for_id(root, id))
+            .and_then(|source| CounterMeter::start(&source));
+        let meter = UsageMeter::start();
+        let session = Session::new(self, session_id, id, metadata_mr_addr);
+        let action = match self.handlers.get(metadata_request.request_type){
+            Some(handler) => handler.handle(&session, metadata_request)?,
+            None => {
+                let reason = format!("unsupported request type {}", metadata_request.request_type);
+                session.reject(metadata_request, ServerError{code: ErrorCode::UnsupportedRequest, reason})?
             }
+        };
+        let rejected = matches!(metadata_request.get_request_type(), MetaDataRequestTypes::ErrorResponse);
+        if action == SessionAction::Continue && !rejected{
+            let run = RunUsage{
+                cpu: meter.stop(),
+                counters: counter_meter.and_then(|counter_meter| counter_meter.stop()),
+            };
+            self.usage.lock().unwrap().entry(session_id).or_default().push(run);
         }
+        Ok(action)
     }
 }
 
 #[derive(Clone)]
 pub struct RdmaServerClient{
-    tx: tokio::sync::mpsc::Sender<RdmaServerCommand>
+    tx: tokio::sync::mpsc::Sender<RdmaServerCommand>,
+    shutdown_token: CancellationToken,
+    usage: UsageMap,
 }
 
 impl RdmaServerClient{
-    pub fn new(tx: tokio::sync::mpsc::Sender<RdmaServerCommand>) -> Self{
+    pub fn new(tx: tokio::sync::mpsc::Sender<RdmaServerCommand>, shutdown_token: CancellationToken, usage: UsageMap) -> Self{
         RdmaServerClient{
-            tx
+            tx,
+            shutdown_token,
+            usage,
+        }
+    }
+    /// Takes the usage of the runs of `session_id` once `runs` of them are
+    /// recorded, waiting at most `wait` for the session to finish them.
+    /// Does not go through the server actor.
+    pub async fn usage(&self, session_id: u32, runs: usize, wait: Duration) -> Result<Vec<RunUsage>, String>{
+        let deadline = Instant::now() + wait;
+        loop {
+            {
+                let mut usage = self.usage.lock().unwrap();
+                if usage.get(&session_id).map_or(0, |recorded| recorded.len()) >= runs{
+                    return Ok(usage.remove(&session_id).unwrap_or_default());
+                }
+            }
+            if Instant::now() >= deadline{
+                return Err(format!("session {} has not finished {} runs", session_id, runs));
+            }
+            tokio::time::sleep(USAGE_POLL_INTERVAL).await;
         }
     }
-    pub async fn listen(&mut self) -> anyhow::Result<()>{
+    /// Drops the usage recorded for `session_id`, which closed.
+    pub fn forget_usage(&self, session_id: u32){
+        self.usage.lock().unwrap().remove(&session_id);
+    }
+    /// Serves session `session_id`, which resolves once it is closed.
+    pub async fn listen(&mut self, session_id: u32) -> anyhow::Result<()>{
         let (tx, rx) = tokio::sync::oneshot::channel();
-        self.tx.send(RdmaServerCommand::Listen{tx}).await.unwrap();
+        self.tx.send(RdmaServerCommand::Listen{session_id, tx}).await.unwrap();
         rx.await.unwrap();
         Ok(())
     }
-    pub async fn connect(&mut self, address: String, port: u16) -> anyhow::Result<()>{
-        match self.tx.send(RdmaServerCommand::Connect{address, port}).await{
-            Ok(_) => {},
-            Err(e) => {
-                println!("error: {}",e);
-            }
-        
+    /// Accepts the client of session `session_id` on `address:port`. The
+    /// returned receiver resolves once the session is closed, whether it
+    /// failed to connect, was never listened on or was served.
+    pub async fn connect(&mut self, address: String, port: u16, session_id: u32) -> tokio::sync::oneshot::Receiver<()>{
+        let (closed, closed_rx) = tokio::sync::oneshot::channel();
+        if let Err(e) = self.tx.send(RdmaServerCommand::Connect{address, port, session_id, closed}).await{
+            println!("error: {}",e);
         }
+        closed_rx
+    }
+    /// Stops the server actor. All sessions are cancelled and torn down,
+    /// then the SRQ is destroyed and the pooled buffers are deregistered.
+    pub async fn shutdown(&mut self) -> anyhow::Result<()>{
+        self.shutdown_token.cancel();
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.tx.send(RdmaServerCommand::Shutdown{tx}).await.unwrap();
+        rx.await.unwrap();
         Ok(())
     }
 
@@ -222,10 +528,16 @@ impl RdmaServerClient{
 
 pub enum RdmaServerCommand{
     Listen{
+        session_id: u32,
         tx: tokio::sync::oneshot::Sender<()>
     },
     Connect{
         address: String,
         port: u16,
+        session_id: u32,
+        closed: tokio::sync::oneshot::Sender<()>,
+    },
+    Shutdown{
+        tx: tokio::sync::oneshot::Sender<()>
     }
 }
\ No newline at end of file
@@ -1,54 +1,140 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::Duration};
 
-use crate::rdma_server::RdmaServerClient;
+use common::usage::RunUsage;
+use crate::{limits::ResourceTracker, multicast::{GroupStatus, MulticastSender}, rdma_server::RdmaServerClient};
 use tokio::sync::RwLock;
 
+/// Time a usage query waits for the session to finish the runs.
+const USAGE_WAIT: Duration = Duration::from_secs(5);
+
+/// An admitted session: the RDMA port its client connects to and the id it
+/// is known by.
+#[derive(Clone, Copy, Debug)]
+pub struct SessionGrant{
+    pub port: u32,
+    pub session_id: u32,
+}
+
 pub struct ServerManager{
     pub client: ServerManagerClient,
     rx: Arc<RwLock<tokio::sync::mpsc::Receiver<ServerManagerCommand>>>,
     address: String,
     rdma_server_client: RdmaServerClient,
+    resources: Arc<Mutex<ResourceTracker>>,
+    multicast: Option<Arc<MulticastSender>>,
 }
 
 impl ServerManager{
-    pub fn new(address: String, rdma_server_client: RdmaServerClient) -> Self{
+    pub fn new(address: String, rdma_server_client: RdmaServerClient, resources: Arc<Mutex<ResourceTracker>>) -> Self{
         let (tx, rx) = tokio::sync::mpsc::channel(1);
         let client = ServerManagerClient::new(tx);
         ServerManager{
             client,
             rx: Arc::new(RwLock::new(rx)),
             address,
-            rdma_server_client
+            rdma_server_client,
+            resources,
+            multicast: None,
         }
     }
+    /// Coordinates the subscribers of the multicast group `multicast` sends
+    /// to.
+    pub fn with_multicast(mut self, multicast: Option<Arc<MulticastSender>>) -> Self{
+        self.multicast = multicast;
+        self
+    }
 
+    /// Serves the commands. Admitted sessions are keyed by a server assigned
+    /// id, as client ids need not be unique, and hold their slot until the
+    /// RDMA server reports them closed.
     pub async fn run(self){
         let mut rx = self.rx.write().await;
         let mut client_map = HashMap::new();
+        let mut next_session_id: u32 = 1;
         while let Some(server_manager_command) = rx.recv().await{
             let address = self.address.clone();
             match server_manager_command{
-                ServerManagerCommand::ConnectionRequest{client_id, tx} => {
+                ServerManagerCommand::ConnectionRequest{client_id, message_size, tx} => {
+                    if let Err(reason) = self.resources.lock().unwrap().admit_session(message_size as usize){
+                        tx.send(Err(reason)).unwrap();
+                        continue;
+                    }
+                    let session_id = next_session_id;
+                    next_session_id = next_session_id.checked_add(1).unwrap_or(1);
                     let port = portpicker::pick_unused_port().unwrap();
                     let rdma_server_client_clone_1 = self.rdma_server_client.clone();
                     let rdma_server_client_clone_2 = self.rdma_server_client.clone();
+                    let mut server_manager_client = self.client.clone();
                     tokio::spawn(async move{
-                        //rdma_server.clone().listen(address.clone(), port).await.unwrap();
-                        rdma_server_client_clone_1.clone().connect(address.clone(), port).await.unwrap();
+                        let closed = rdma_server_client_clone_1.clone().connect(address.clone(), port, session_id).await;
+                        let _ = closed.await;
+                        server_manager_client.session_closed(session_id).await;
                     });
-                    client_map.insert(client_id, rdma_server_client_clone_2);
-                    tx.send(port as u32).unwrap();
+                    client_map.insert(session_id, rdma_server_client_clone_2);
+                    println!("client {} granted session {} on port {}", client_id, session_id, port);
+                    tx.send(Ok(SessionGrant{port: port as u32, session_id})).unwrap();
                 },
-                ServerManagerCommand::Listen{client_id, tx} => {
-                    let rdma_server_client = client_map.get(&client_id).unwrap();
-                    let mut rdma_server_client = rdma_server_client.clone();
+                ServerManagerCommand::Listen{session_id, tx} => {
+                    let mut rdma_server_client = match client_map.get(&session_id){
+                        Some(rdma_server_client) => rdma_server_client.clone(),
+                        None => {
+                            tx.send(Err(anyhow::anyhow!("unknown session {}", session_id))).unwrap();
+                            continue;
+                        }
+                    };
                     tokio::spawn(async move{
-                        rdma_server_client.listen().await.unwrap();
+                        rdma_server_client.listen(session_id).await.unwrap();
                     });
                     tx.send(Ok(())).unwrap();
+                },
+                ServerManagerCommand::SessionUsage{session_id, runs, tx} => {
+                    let rdma_server_client = self.rdma_server_client.clone();
+                    tokio::spawn(async move{
+                        let _ = tx.send(rdma_server_client.usage(session_id, runs, USAGE_WAIT).await);
+                    });
+                },
+                ServerManagerCommand::JoinGroup{client_id, tx} => {
+                    let status = match &self.multicast{
+                        Some(multicast) => multicast.join(client_id),
+                        None => Err("server does not send to a multicast group".to_string()),
+                    };
+                    if let Ok(status) = &status{
+                        println!("client {} joined multicast group {}, {} members", client_id, status.group, status.members);
+                    }
+                    let _ = tx.send(status);
+                },
+                ServerManagerCommand::LeaveGroup{client_id, tx} => {
+                    let status = match &self.multicast{
+                        Some(multicast) => multicast.leave(client_id),
+                        None => Err("server does not send to a multicast group".to_string()),
+                    };
+                    if let Ok(status) = &status{
+                        println!("client {} left multicast group {}, {} members", client_id, status.group, status.members);
+                    }
+                    let _ = tx.send(status);
+                },
+                ServerManagerCommand::SessionClosed{session_id} => {
+                    self.rdma_server_client.forget_usage(session_id);
+                    if client_map.remove(&session_id).is_some(){
+                        self.resources.lock().unwrap().release_session();
+                    }
+                },
+                ServerManagerCommand::Shutdown{tx} => {
+                    if !client_map.is_empty(){
+                        println!("cancelling {} active sessions", client_map.len());
+                    }
+                    let mut rdma_server_client = self.rdma_server_client.clone();
+                    rdma_server_client.shutdown().await.unwrap();
+                    for _ in client_map.drain(){
+                        self.resources.lock().unwrap().release_session();
+                    }
+                    rx.close();
+                    tx.send(()).unwrap();
+                    break;
                 }
             }
         }
+        println!("server manager stopped");
     }
 }
 #[derive(Clone)]
@@ -63,26 +149,77 @@ impl ServerManagerClient{
             tx
         }
     }
-    pub async fn request_connection(&mut self, client_id: u32) -> u32{
+    /// Returns the RDMA port and id of the session, or why it was not
+    /// admitted.
+    pub async fn request_connection(&mut self, client_id: u32, message_size: u32) -> Result<SessionGrant, String>{
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.tx.send(ServerManagerCommand::ConnectionRequest{client_id, message_size, tx}).await.unwrap();
+        rx.await.unwrap()
+    }
+    pub async fn listen(&mut self, session_id: u32) -> anyhow::Result<()>{
         let (tx, rx) = tokio::sync::oneshot::channel();
-        self.tx.send(ServerManagerCommand::ConnectionRequest{client_id, tx}).await.unwrap();
+        self.tx.send(ServerManagerCommand::Listen{session_id, tx}).await.unwrap();
         rx.await.unwrap()
     }
-    pub async fn listen(&mut self, client_id: u32) -> anyhow::Result<()>{
+    /// Usage of the server in the last `runs` runs of session `session_id`,
+    /// or why it is not available. It is kept until the session closes.
+    pub async fn session_usage(&mut self, session_id: u32, runs: usize) -> Result<Vec<RunUsage>, String>{
         let (tx, rx) = tokio::sync::oneshot::channel();
-        self.tx.send(ServerManagerCommand::Listen{client_id, tx}).await.unwrap();
+        self.tx.send(ServerManagerCommand::SessionUsage{session_id, runs, tx}).await.unwrap();
         rx.await.unwrap()
     }
+    /// Subscribes `client_id` to the multicast group of the server.
+    pub async fn join_group(&mut self, client_id: u32) -> Result<GroupStatus, String>{
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.tx.send(ServerManagerCommand::JoinGroup{client_id, tx}).await.unwrap();
+        rx.await.unwrap()
+    }
+    pub async fn leave_group(&mut self, client_id: u32) -> Result<GroupStatus, String>{
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.tx.send(ServerManagerCommand::LeaveGroup{client_id, tx}).await.unwrap();
+        rx.await.unwrap()
+    }
+    pub async fn session_closed(&mut self, session_id: u32){
+        // sessions cancelled by a shutdown close after the manager stopped
+        let _ = self.tx.send(ServerManagerCommand::SessionClosed{session_id}).await;
+    }
+    /// Stops the manager after cancelling all sessions and stopping the
+    /// RDMA server.
+    pub async fn shutdown(&mut self){
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.tx.send(ServerManagerCommand::Shutdown{tx}).await.unwrap();
+        rx.await.unwrap();
+    }
 
 }
 
 pub enum ServerManagerCommand{
     ConnectionRequest{
         client_id: u32,
-        tx: tokio::sync::oneshot::Sender<u32>
+        message_size: u32,
+        tx: tokio::sync::oneshot::Sender<Result<SessionGrant, String>>
     },
     Listen{
-        client_id: u32,
+        session_id: u32,
         tx: tokio::sync::oneshot::Sender<anyhow::Result<()>>
+    },
+    SessionUsage{
+        session_id: u32,
+        runs: usize,
+        tx: tokio::sync::oneshot::Sender<Result<Vec<RunUsage>, String>>
+    },
+    JoinGroup{
+        client_id: u32,
+        tx: tokio::sync::oneshot::Sender<Result<GroupStatus, String>>
+    },
+    LeaveGroup{
+        client_id: u32,
+        tx: tokio::sync::oneshot::Sender<Result<GroupStatus, String>>
+    },
+    SessionClosed{
+        session_id: u32,
+    },
+    Shutdown{
+        tx: tokio::sync::oneshot::Sender<()>
     }
 }
\ No newline at end of file
@@ -0,0 +1,164 @@
+use std::{future::Future, net::IpAddr, path::PathBuf, sync::{Arc, Mutex}, time::{Duration, Instant}};
+use common::{CustomError, affinity::CpuList, alloc::AllocOptions, timeout::{CancellationToken, PollMode}};
+use crate::{grpc_server::GrpcServer, handler::{HandlerRegistry, RequestHandler}, limits::{ResourceLimits, ResourceTracker}, multicast::{MulticastConfig, MulticastSender}, rdma_server::{RdmaServer, SrqConfig}, server_manager::ServerManager, ud_server::UdServer};
+
+/// Everything needed to start a `Server`.
+#[derive(Clone)]
+pub struct ServerConfig{
+    /// Address the gRPC control plane and the RDMA sessions listen on.
+    pub address: String,
+    /// Port of the gRPC control plane.
+    pub port: u16,
+    /// Also serve the gRPC control plane over RDMA CM on this port.
+    pub grpc_rdma_port: Option<u16>,
+    /// Receive on all sessions through one shared receive queue.
+    pub srq: Option<SrqConfig>,
+    /// Receive slots advertised to senders, 0 disables flow control.
+    pub credits: u32,
+    pub alloc: AllocOptions,
+    /// Timeout of every blocking RDMA and CM wait, `None` waits forever.
+    pub timeout: Option<Duration>,
+    /// How the sessions wait for completions.
+    pub poll_mode: PollMode,
+    pub limits: ResourceLimits,
+    /// Time active sessions get to finish on shutdown before they are
+    /// cancelled.
+    pub shutdown_grace: Duration,
+    /// CPUs the session workers are pinned to in turn, empty leaves them
+    /// unpinned.
+    pub cpus: CpuList,
+    /// Sysfs root of the port counters snapshotted around each run, `None`
+    /// skips them.
+    pub counters: Option<PathBuf>,
+    /// Serve the UD benchmarks on this port.
+    pub ud_port: Option<u16>,
+    /// Send to this multicast group while clients subscribe to it.
+    pub multicast: Option<MulticastConfig>,
+}
+
+impl ServerConfig{
+    pub fn new(address: String, port: u16) -> ServerConfig{
+        ServerConfig{
+            address,
+            port,
+            grpc_rdma_port: None,
+            srq: None,
+            credits: 128,
+            alloc: AllocOptions::default(),
+            timeout: Some(Duration::from_secs(30)),
+            poll_mode: PollMode::default(),
+            limits: ResourceLimits::default(),
+            shutdown_grace: Duration::from_secs(5),
+            cpus: CpuList::default(),
+            counters: None,
+            ud_port: None,
+            multicast: None,
+        }
+    }
+}
+
+/// The server for embedding into other applications: the gRPC control
+/// plane, the session manager and the RDMA server, serving the requests of
+/// each session with the registered handlers.
+pub struct Server{
+    config: ServerConfig,
+    handlers: HandlerRegistry,
+}
+
+impl Server{
+    /// A server with the write, send, read, workload and disconnect handlers.
+    pub fn new(config: ServerConfig) -> Server{
+        Server{
+            config,
+            handlers: HandlerRegistry::with_defaults(),
+        }
+    }
+    pub fn with_handlers(config: ServerConfig, handlers: HandlerRegistry) -> Server{
+        Server{
+            config,
+            handlers,
+        }
+    }
+    /// Registers `handler` for `request_type`, replacing the previous one.
+    pub fn register_handler<H: RequestHandler + 'static>(&mut self, request_type: u8, handler: H) -> &mut Server{
+        self.handlers.register(request_type, handler);
+        self
+    }
+    /// Serves until `shutdown` resolves. Then it stops accepting sessions,
+    /// gives the active ones the grace period to finish, cancels the rest
+    /// and stops.
+    pub async fn run<F: Future<Output = ()>>(self, shutdown: F) -> anyhow::Result<(), CustomError>{
+        let config = self.config;
+        let mut jh_list = Vec::new();
+        let resources = Arc::new(Mutex::new(ResourceTracker::new(config.limits.clone())));
+        let rdma_server = RdmaServer::new(config.srq.clone(), config.credits, config.alloc, config.timeout, resources.clone(), self.handlers)
+            .with_poll_mode(config.poll_mode)
+            .with_cpus(config.cpus.clone())
+            .with_counters(config.counters.clone());
+        let rdma_server_client = rdma_server.client.clone();
+        let jh = tokio::spawn(async move{
+            rdma_server.run().await.unwrap();
+        });
+        jh_list.push(jh);
+
+        let multicast = config.multicast.clone().map(|multicast| Arc::new(MulticastSender::new(multicast)
+            .with_timeout(config.timeout)
+            .with_poll_mode(config.poll_mode)));
+        let sm = ServerManager::new(config.address.clone(), rdma_server_client, resources.clone())
+            .with_multicast(multicast.clone());
+        let sm_client = sm.client.clone();
+        let mut shutdown_sm_client = sm.client.clone();
+        let jh = tokio::spawn(async move{
+            sm.run().await;
+        });
+        jh_list.push(jh);
+
+        let grpc_address = format!("{}:{}", config.address, config.port);
+        let grpc_rdma_port = config.grpc_rdma_port;
+        let (grpc_shutdown_tx, grpc_shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let jh = tokio::spawn(async move{
+            let grpc_server = GrpcServer::new(grpc_address, sm_client).with_rdma_port(grpc_rdma_port);
+            grpc_server.run(async { grpc_shutdown_rx.await.ok(); }).await.unwrap();
+        });
+        jh_list.push(jh);
+
+        let ud_token = CancellationToken::new();
+        let ud_address = || config.address.parse::<IpAddr>()
+            .map_err(|_| CustomError::new(format!("UD needs an IP address to listen on, not {}", config.address), -libc::EINVAL));
+        if let Some(ud_port) = config.ud_port{
+            let address = ud_address()?;
+            let ud_server = UdServer::new(address, ud_port, ud_token.clone())
+                .with_timeout(config.timeout)
+                .with_poll_mode(config.poll_mode);
+            let jh = tokio::task::spawn_blocking(move ||{
+                if let Err(e) = ud_server.run(){
+                    println!("UD server failed: {}", e);
+                }
+            });
+            jh_list.push(jh);
+        }
+        if let Some(multicast) = multicast{
+            let address = ud_address()?;
+            let token = ud_token.clone();
+            let jh = tokio::task::spawn_blocking(move ||{
+                if let Err(e) = multicast.run(address, token){
+                    println!("multicast sender failed: {}", e);
+                }
+            });
+            jh_list.push(jh);
+        }
+
+        shutdown.await;
+        // stop accepting new sessions, give the active ones the grace period
+        // to finish and cancel the rest
+        grpc_shutdown_tx.send(()).unwrap();
+        let grace_deadline = Instant::now() + config.shutdown_grace;
+        while resources.lock().unwrap().sessions() > 0 && Instant::now() < grace_deadline{
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        ud_token.cancel();
+        shutdown_sm_client.shutdown().await;
+        futures::future::join_all(jh_list).await;
+        Ok(())
+    }
+}
@@ -0,0 +1,101 @@
+use std::{net::IpAddr, time::Duration};
+use common::{CustomError, timeout::{CancellationToken, PollMode, WaitControl}, ud::{DatagramKind, UdEndpoint, UdListener, UD_HEADER_LEN}};
+
+/// Time a UD session may stay silent before the server closes it.
+pub const UD_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serves the UD benchmarks. Every client connects its own endpoint, whose
+/// data datagrams are counted and whose pings are answered with pongs of the
+/// same size. `Done` is answered with a report of what arrived.
+pub struct UdServer{
+    address: IpAddr,
+    port: u16,
+    timeout: Option<Duration>,
+    poll_mode: PollMode,
+    token: CancellationToken,
+}
+
+impl UdServer{
+    pub fn new(address: IpAddr, port: u16, token: CancellationToken) -> UdServer{
+        UdServer{
+            address,
+            port,
+            timeout: Some(Duration::from_secs(30)),
+            poll_mode: PollMode::default(),
+            token,
+        }
+    }
+    /// Timeout of the sends of each session.
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self{
+        self.timeout = timeout;
+        self
+    }
+    pub fn with_poll_mode(mut self, poll_mode: PollMode) -> Self{
+        self.poll_mode = poll_mode;
+        self
+    }
+    /// Accepts endpoints until the token is cancelled, serving each on a
+    /// thread of its own.
+    pub fn run(&self) -> anyhow::Result<(), CustomError>{
+        let listen_control = WaitControl{
+            timeout: None,
+            token: self.token.clone(),
+            poll_mode: self.poll_mode,
+        };
+        let listener = UdListener::bind(self.address, self.port, &listen_control)?;
+        println!("UD server listening on {}:{}", self.address, self.port);
+        let session_control = WaitControl{
+            timeout: self.timeout,
+            token: self.token.clone(),
+            poll_mode: self.poll_mode,
+        };
+        loop {
+            match listener.accept(&session_control){
+                Ok(endpoint) => {
+                    std::thread::spawn(move ||{
+                        if let Err(e) = serve(endpoint){
+                            println!("UD session failed: {}", e);
+                        }
+                    });
+                },
+                Err(e) if e.code() == -libc::ECANCELED => return Ok(()),
+                Err(e) => println!("UD accept failed: {}", e),
+            }
+        }
+    }
+}
+
+/// Serves one endpoint until it stays idle or the server shuts down.
+fn serve(mut endpoint: UdEndpoint) -> anyhow::Result<(), CustomError>{
+    let mut received = 0u64;
+    let mut out_of_order = 0u64;
+    let mut next_seq = 0u64;
+    loop {
+        let datagram = match endpoint.recv(UD_IDLE_TIMEOUT){
+            Ok(Some(datagram)) => datagram,
+            Ok(None) => return Ok(()),
+            Err(e) if e.code() == -libc::ECANCELED => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        match datagram.kind{
+            DatagramKind::Data | DatagramKind::Ping => {
+                received += 1;
+                if datagram.seq < next_seq{
+                    out_of_order += 1;
+                } else {
+                    next_seq = datagram.seq + 1;
+                }
+                if datagram.kind == DatagramKind::Ping{
+                    endpoint.send(DatagramKind::Pong, datagram.seq, 0, datagram.len)?;
+                }
+            },
+            // a lost report makes the client repeat its done, which is
+            // answered again
+            DatagramKind::Done => {
+                println!("UD session of qp {}: {} of {} datagrams arrived, {} out of order", datagram.src_qp, received, datagram.seq, out_of_order);
+                endpoint.send(DatagramKind::Report, received, out_of_order, UD_HEADER_LEN)?;
+            },
+            DatagramKind::Pong | DatagramKind::Report => {},
+        }
+    }
+}
@@ -0,0 +1,280 @@
+use crate::CustomError;
+
+/// Order in which messages visit the offsets of the remote buffer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessPattern{
+    /// Message sized slots one after the other, wrapping at the end.
+    Sequential,
+    /// Offsets `stride` bytes apart, wrapping at the end.
+    Strided,
+    /// Message sized slots drawn from `KeyDistribution`.
+    Random,
+}
+
+impl std::str::FromStr for AccessPattern{
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err>{
+        match s{
+            "sequential" => Ok(AccessPattern::Sequential),
+            "strided" => Ok(AccessPattern::Strided),
+            "random" => Ok(AccessPattern::Random),
+            _ => Err(format!("unknown access pattern {}, expected sequential, strided or random", s)),
+        }
+    }
+}
+
+/// Distribution of the slots of `AccessPattern::Random`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyDistribution{
+    Uniform,
+    /// Slot `k` is drawn with a probability proportional to `1 / (k + 1)^theta`,
+    /// so the hot slots are at the start of the buffer.
+    Zipfian,
+}
+
+impl std::str::FromStr for KeyDistribution{
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err>{
+        match s{
+            "uniform" => Ok(KeyDistribution::Uniform),
+            "zipfian" => Ok(KeyDistribution::Zipfian),
+            _ => Err(format!("unknown distribution {}, expected uniform or zipfian", s)),
+        }
+    }
+}
+
+/// Where in a buffer larger than the message one-sided operations land.
+#[derive(Clone, Copy, Debug)]
+pub struct AccessOptions{
+    /// Size of the local and the remote buffer, 0 is the message size.
+    pub buffer_size: usize,
+    pub pattern: AccessPattern,
+    /// Bytes between the offsets of `AccessPattern::Strided`, 0 is the
+    /// message size.
+    pub stride: usize,
+    pub distribution: KeyDistribution,
+    /// Skew of `KeyDistribution::Zipfian`, between 0 and 1 exclusive.
+    pub zipf_theta: f64,
+    pub seed: u64,
+}
+
+impl Default for AccessOptions{
+    fn default() -> Self{
+        AccessOptions{
+            buffer_size: 0,
+            pattern: AccessPattern::Sequential,
+            stride: 0,
+            distribution: KeyDistribution::Uniform,
+            zipf_theta: 0.99,
+            seed: 0,
+        }
+    }
+}
+
+impl AccessOptions{
+    /// Size of the buffers for messages of `message_size` bytes.
+    pub fn buffer_size(&self, message_size: usize) -> usize{
+        self.buffer_size.max(message_size)
+    }
+}
+
+/// Multiplier spreading seeds over the state space.
+pub(crate) const GOLDEN_GAMMA: u64 = 0x9E37_79B9_7F4A_7C15;
+
+/// xorshift64* generator. The prng verify pattern draws its payload from it
+/// as well, with a state derived from the seed and the iteration.
+#[derive(Clone, Debug)]
+pub struct Rng{
+    state: u64,
+}
+
+impl Rng{
+    pub fn new(seed: u64) -> Rng{
+        Rng::from_state(seed.wrapping_mul(GOLDEN_GAMMA))
+    }
+    /// Starts from `state` as is, bar the low bit which keeps it non-zero.
+    pub fn from_state(state: u64) -> Rng{
+        Rng{
+            state: state | 1,
+        }
+    }
+    pub fn next_u64(&mut self) -> u64{
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+    /// Uniform in `[0, 1)`.
+    pub fn next_f64(&mut self) -> f64{
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+    /// Uniform in `[0, n)`, `n` must not be 0.
+    pub fn below(&mut self, n: u64) -> u64{
+        self.next_u64() % n
+    }
+}
+
+/// Zipfian ranks in `[0, n)` after Gray et al., "Quickly Generating
+/// Billion-Record Synthetic Databases", as YCSB draws its keys.
+#[derive(Clone, Debug)]
+pub struct Zipfian{
+    n: u64,
+    theta: f64,
+    alpha: f64,
+    zetan: f64,
+    eta: f64,
+}
+
+impl Zipfian{
+    pub fn new(n: u64, theta: f64) -> anyhow::Result<Zipfian, CustomError>{
+        if n == 0 || !(theta > 0.0 && theta < 1.0){
+            return Err(CustomError::new(format!("zipfian needs at least one item and a theta between 0 and 1, got {} and {}", n, theta), -libc::EINVAL));
+        }
+        let zeta = |count: u64| (1..=count).map(|i| 1.0 / (i as f64).powf(theta)).sum::<f64>();
+        let zetan = zeta(n);
+        let zeta2 = zeta(2.min(n));
+        let eta = if n > 1 { (1.0 - (2.0 / n as f64).powf(1.0 - theta)) / (1.0 - zeta2 / zetan) } else { 0.0 };
+        Ok(Zipfian{
+            n,
+            theta,
+            alpha: 1.0 / (1.0 - theta),
+            zetan,
+            eta,
+        })
+    }
+    pub fn sample(&self, rng: &mut Rng) -> u64{
+        let u = rng.next_f64();
+        let uz = u * self.zetan;
+        if uz < 1.0{
+            return 0;
+        }
+        if uz < 1.0 + 0.5f64.powf(self.theta){
+            return 1.min(self.n - 1);
+        }
+        let rank = (self.n as f64 * (self.eta * u - self.eta + 1.0).powf(self.alpha)) as u64;
+        rank.min(self.n - 1)
+    }
+}
+
+/// Offsets of successive messages of `message_size` bytes within a buffer
+/// of `buffer_size` bytes, following `AccessOptions`.
+pub struct OffsetGenerator{
+    pattern: AccessPattern,
+    message_size: u64,
+    stride: u64,
+    /// Offsets a full pass visits, slots for sequential and random access.
+    positions: u64,
+    zipfian: Option<Zipfian>,
+    rng: Rng,
+    next: u64,
+}
+
+impl OffsetGenerator{
+    pub fn new(options: &AccessOptions, message_size: usize) -> anyhow::Result<OffsetGenerator, CustomError>{
+        let buffer_size = options.buffer_size(message_size) as u64;
+        let message_size = message_size as u64;
+        if message_size == 0{
+            return Err(CustomError::new("message size must not be 0".to_string(), -libc::EINVAL));
+        }
+        let stride = if options.stride == 0 { message_size } else { options.stride as u64 };
+        let positions = match options.pattern{
+            AccessPattern::Strided => (buffer_size - message_size) / stride + 1,
+            _ => buffer_size / message_size,
+        };
+        let zipfian = match (options.pattern, options.distribution){
+            (AccessPattern::Random, KeyDistribution::Zipfian) => Some(Zipfian::new(positions, options.zipf_theta)?),
+            _ => None,
+        };
+        Ok(OffsetGenerator{
+            pattern: options.pattern,
+            message_size,
+            stride,
+            positions,
+            zipfian,
+            rng: Rng::new(options.seed),
+            next: 0,
+        })
+    }
+    /// Byte offset of the next message.
+    pub fn next_offset(&mut self) -> u64{
+        let position = match self.pattern{
+            AccessPattern::Sequential | AccessPattern::Strided => {
+                let position = self.next % self.positions;
+                self.next += 1;
+                position
+            },
+            AccessPattern::Random => match self.zipfian.as_ref(){
+                Some(zipfian) => zipfian.sample(&mut self.rng),
+                None => self.rng.below(self.positions),
+            },
+        };
+        match self.pattern{
+            AccessPattern::Strided => position * self.stride,
+            _ => position * self.message_size,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn options(buffer_size: usize, pattern: AccessPattern, distribution: KeyDistribution) -> AccessOptions{
+        AccessOptions{
+            buffer_size,
+            pattern,
+            distribution,
+            seed: 7,
+            ..AccessOptions::default()
+        }
+    }
+
+    #[test]
+    fn sequential_wraps_at_the_end_of_the_buffer(){
+        let mut offsets = OffsetGenerator::new(&options(4096, AccessPattern::Sequential, KeyDistribution::Uniform), 1024).unwrap();
+        let visited: Vec<u64> = (0..6).map(|_| offsets.next_offset()).collect();
+        assert_eq!(visited, vec![0, 1024, 2048, 3072, 0, 1024]);
+    }
+
+    #[test]
+    fn sequential_skips_a_partial_last_slot(){
+        let mut offsets = OffsetGenerator::new(&options(2500, AccessPattern::Sequential, KeyDistribution::Uniform), 1000).unwrap();
+        let visited: Vec<u64> = (0..3).map(|_| offsets.next_offset()).collect();
+        assert_eq!(visited, vec![0, 1000, 0]);
+    }
+
+    #[test]
+    fn uniform_stays_in_bounds_and_reaches_every_slot(){
+        let mut offsets = OffsetGenerator::new(&options(16 * 64, AccessPattern::Random, KeyDistribution::Uniform), 64).unwrap();
+        let mut hits = [0usize; 16];
+        for _ in 0..10_000{
+            let offset = offsets.next_offset();
+            assert_eq!(offset % 64, 0);
+            assert!(offset + 64 <= 16 * 64);
+            hits[(offset / 64) as usize] += 1;
+        }
+        assert!(hits.iter().all(|&count| count > 0));
+    }
+
+    #[test]
+    fn zipfian_stays_in_bounds_and_favours_the_first_slot(){
+        let mut offsets = OffsetGenerator::new(&options(100 * 8, AccessPattern::Random, KeyDistribution::Zipfian), 8).unwrap();
+        let mut hits = [0usize; 100];
+        for _ in 0..10_000{
+            let offset = offsets.next_offset();
+            assert_eq!(offset % 8, 0);
+            assert!(offset + 8 <= 100 * 8);
+            hits[(offset / 8) as usize] += 1;
+        }
+        let hottest = hits.iter().enumerate().max_by_key(|(_, &count)| count).map(|(slot, _)| slot);
+        assert_eq!(hottest, Some(0));
+        assert!(hits[0] > hits[99]);
+    }
+
+    #[test]
+    fn zipfian_rejects_a_theta_outside_zero_and_one(){
+        let mut options = options(1024, AccessPattern::Random, KeyDistribution::Zipfian);
+        options.zipf_theta = 1.0;
+        assert!(OffsetGenerator::new(&options, 64).is_err());
+    }
+}
@@ -0,0 +1,113 @@
+use std::{collections::BTreeSet, fmt::Display, str::FromStr};
+use crate::CustomError;
+
+/// CPUs threads are pinned to, given as a list like `0,2,4-7`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CpuList(Vec<usize>);
+
+impl CpuList{
+    pub fn new(cpus: Vec<usize>) -> CpuList{
+        CpuList(cpus)
+    }
+    pub fn is_empty(&self) -> bool{
+        self.0.is_empty()
+    }
+    /// CPU of the `index`th thread, wrapping around the list. `None` if the
+    /// list is empty and threads stay unpinned.
+    pub fn get(&self, index: usize) -> Option<usize>{
+        if self.0.is_empty(){
+            return None;
+        }
+        Some(self.0[index % self.0.len()])
+    }
+}
+
+impl FromStr for CpuList{
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err>{
+        let parse = |cpu: &str| cpu.trim().parse::<usize>().map_err(|_| format!("invalid cpu {} in {}", cpu, s));
+        let mut cpus = Vec::new();
+        for entry in s.split(',').filter(|entry| !entry.trim().is_empty()){
+            match entry.split_once('-'){
+                Some((first, last)) => {
+                    let (first, last) = (parse(first)?, parse(last)?);
+                    if first > last{
+                        return Err(format!("invalid cpu range {}", entry));
+                    }
+                    cpus.extend(first..=last);
+                },
+                None => cpus.push(parse(entry)?),
+            }
+        }
+        if cpus.is_empty(){
+            return Err(format!("no cpus in {}", s));
+        }
+        Ok(CpuList(cpus))
+    }
+}
+
+/// Restricts the calling thread to `cpu`.
+pub fn pin_current_thread(cpu: usize) -> anyhow::Result<(), CustomError>{
+    let mut set = unsafe { std::mem::zeroed::<libc::cpu_set_t>() };
+    if cpu >= libc::CPU_SETSIZE as usize{
+        return Err(CustomError::new(format!("cpu {} exceeds the cpu set", cpu), -libc::EINVAL));
+    }
+    unsafe { libc::CPU_SET(cpu, &mut set) };
+    let ret = unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) };
+    if ret != 0{
+        let error = std::io::Error::last_os_error();
+        return Err(CustomError::new(format!("sched_setaffinity to cpu {}: {}", cpu, error), -error.raw_os_error().unwrap_or(libc::EINVAL)));
+    }
+    Ok(())
+}
+
+/// CPU the calling thread runs on right now.
+pub fn current_cpu() -> Option<usize>{
+    let cpu = unsafe { libc::sched_getcpu() };
+    if cpu < 0{
+        return None;
+    }
+    Some(cpu as usize)
+}
+
+/// Where a benchmark thread was pinned and which CPUs it was seen on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ThreadPlacement{
+    pub name: String,
+    pub pinned: Option<usize>,
+    pub seen: BTreeSet<usize>,
+}
+
+impl ThreadPlacement{
+    /// Pins the calling thread to `cpu`, if set, and records the CPU it
+    /// runs on.
+    pub fn start(name: impl Into<String>, cpu: Option<usize>) -> anyhow::Result<ThreadPlacement, CustomError>{
+        if let Some(cpu) = cpu{
+            pin_current_thread(cpu)?;
+        }
+        let mut placement = ThreadPlacement{
+            name: name.into(),
+            pinned: cpu,
+            seen: BTreeSet::new(),
+        };
+        placement.sample();
+        Ok(placement)
+    }
+    /// Records the CPU the calling thread runs on now.
+    pub fn sample(&mut self){
+        if let Some(cpu) = current_cpu(){
+            self.seen.insert(cpu);
+        }
+    }
+}
+
+impl Display for ThreadPlacement{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+        match self.pinned{
+            Some(cpu) => write!(f, "{}: pinned to cpu {}, ran on cpus ", self.name, cpu)?,
+            None => write!(f, "{}: unpinned, ran on cpus ", self.name)?,
+        }
+        let seen = self.seen.iter().map(|cpu| cpu.to_string()).collect::<Vec<String>>();
+        write!(f, "{}", seen.join(","))
+    }
+}
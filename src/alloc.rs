@@ -0,0 +1,154 @@
+use std::ptr::null_mut;
+use libc::c_void;
+use crate::{CustomError, Id};
+
+const MPOL_BIND: libc::c_int = 2;
+
+/// How data buffers are allocated.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AllocStrategy{
+    /// Plain heap allocation.
+    #[default]
+    Heap,
+    /// Anonymous mapping aligned to the system page size.
+    PageAligned,
+    /// Anonymous `MAP_HUGETLB` mapping backed by 2 MiB pages.
+    Huge2M,
+    /// Anonymous `MAP_HUGETLB` mapping backed by 1 GiB pages.
+    Huge1G,
+}
+
+impl AllocStrategy{
+    pub fn page_size(&self) -> usize{
+        match self{
+            AllocStrategy::Heap | AllocStrategy::PageAligned => unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize },
+            AllocStrategy::Huge2M => 2 * 1024 * 1024,
+            AllocStrategy::Huge1G => 1024 * 1024 * 1024,
+        }
+    }
+    fn mmap_flags(&self) -> libc::c_int{
+        let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+        match self{
+            AllocStrategy::Heap | AllocStrategy::PageAligned => flags,
+            AllocStrategy::Huge2M => flags | libc::MAP_HUGETLB | libc::MAP_HUGE_2MB,
+            AllocStrategy::Huge1G => flags | libc::MAP_HUGETLB | libc::MAP_HUGE_1GB,
+        }
+    }
+}
+
+impl std::str::FromStr for AllocStrategy{
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err>{
+        match s{
+            "heap" => Ok(AllocStrategy::Heap),
+            "aligned" => Ok(AllocStrategy::PageAligned),
+            "huge-2m" => Ok(AllocStrategy::Huge2M),
+            "huge-1g" => Ok(AllocStrategy::Huge1G),
+            _ => Err(format!("unknown allocation strategy {}, expected heap, aligned, huge-2m or huge-1g", s)),
+        }
+    }
+}
+
+/// Allocation options for data buffers. With `numa_local` set the memory is
+/// bound to the NUMA node of the RDMA device the buffer is used on.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AllocOptions{
+    pub strategy: AllocStrategy,
+    pub numa_local: bool,
+}
+
+impl AllocOptions{
+    /// NUMA node the buffers for `id` should be bound to, if any.
+    pub fn numa_node(&self, id: &Id) -> Option<u32>{
+        if !self.numa_local{
+            return None;
+        }
+        id.device_name().and_then(|device_name| device_numa_node(&device_name))
+    }
+}
+
+/// Reads the NUMA node of an RDMA device from sysfs. Returns `None` if the
+/// device is not attached to a particular node.
+pub fn device_numa_node(device_name: &str) -> Option<u32>{
+    let path = format!("/sys/class/infiniband/{}/device/numa_node", device_name);
+    let node = std::fs::read_to_string(path).ok()?;
+    let node: i32 = node.trim().parse().ok()?;
+    if node < 0{
+        return None;
+    }
+    Some(node as u32)
+}
+
+/// Soft `RLIMIT_MEMLOCK` of the process in bytes, `None` if unlimited.
+/// Registered memory is pinned and counts against it.
+pub fn memlock_limit() -> Option<usize>{
+    let mut rlimit = unsafe { std::mem::zeroed::<libc::rlimit>() };
+    let ret = unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut rlimit) };
+    if ret != 0 || rlimit.rlim_cur == libc::RLIM_INFINITY{
+        return None;
+    }
+    Some(rlimit.rlim_cur as usize)
+}
+
+/// Backing memory of a `Data` buffer.
+pub(crate) enum Buffer{
+    Heap(Vec<u8>),
+    Mapped{
+        addr: *mut c_void,
+        size: usize,
+    },
+}
+
+impl Buffer{
+    /// Allocates at least `size` bytes filled with `1u8`. Mappings are bound
+    /// to `numa_node` before they are touched, so that the pages fault in on
+    /// that node.
+    pub(crate) fn new(size: usize, strategy: AllocStrategy, numa_node: Option<u32>) -> anyhow::Result<Buffer, CustomError>{
+        if strategy == AllocStrategy::Heap && numa_node.is_none(){
+            return Ok(Buffer::Heap(vec![1u8; size]));
+        }
+        let page_size = strategy.page_size();
+        let size = size.max(1).div_ceil(page_size) * page_size;
+        let addr = unsafe { libc::mmap(null_mut(), size, libc::PROT_READ | libc::PROT_WRITE, strategy.mmap_flags(), -1, 0) };
+        if addr == libc::MAP_FAILED{
+            return Err(CustomError::new("mmap".to_string(), std::io::Error::last_os_error().raw_os_error().unwrap_or(-1)));
+        }
+        let buffer = Buffer::Mapped{addr, size};
+        if let Some(node) = numa_node{
+            let mut nodemask = vec![0u64; node as usize / 64 + 1];
+            nodemask[node as usize / 64] |= 1 << (node % 64);
+            let ret = unsafe { libc::syscall(libc::SYS_mbind, addr, size, MPOL_BIND, nodemask.as_ptr(), nodemask.len() * 64 + 1, 0) };
+            if ret != 0{
+                return Err(CustomError::new("mbind".to_string(), std::io::Error::last_os_error().raw_os_error().unwrap_or(-1)));
+            }
+        }
+        unsafe { std::ptr::write_bytes(addr as *mut u8, 1u8, size) };
+        Ok(buffer)
+    }
+    pub(crate) fn capacity(&self) -> usize{
+        match self{
+            Buffer::Heap(vec) => vec.len(),
+            Buffer::Mapped{size, ..} => *size,
+        }
+    }
+    pub(crate) fn as_mut_ptr(&mut self) -> *mut c_void{
+        match self{
+            Buffer::Heap(vec) => vec.as_mut_ptr().cast(),
+            Buffer::Mapped{addr, ..} => *addr,
+        }
+    }
+    pub(crate) fn as_slice(&self) -> &[u8]{
+        match self{
+            Buffer::Heap(vec) => vec.as_slice(),
+            Buffer::Mapped{addr, size} => unsafe { std::slice::from_raw_parts(*addr as *const u8, *size) },
+        }
+    }
+}
+
+impl Drop for Buffer{
+    fn drop(&mut self){
+        if let Buffer::Mapped{addr, size} = self{
+            unsafe { libc::munmap(*addr, *size) };
+        }
+    }
+}
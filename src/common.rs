@@ -1,22 +1,103 @@
-use std::{fmt::Display, ptr::{self, null_mut}};
+use std::{ffi::{CStr, CString}, fmt::Display, net::IpAddr, ptr::null_mut};
 use libc::{c_int, c_void};
 use rdma_sys::*;
+use access::OffsetGenerator;
+use alloc::{AllocStrategy, Buffer};
+use credit::{CreditReceiver, CreditSender};
+use mr_pool::MrCache;
+use remote::RemoteRegion;
+use timeout::{get_recv_comp, get_send_comp, wait_cq, wait_event_channel, CancellationToken, PollMode, WaitControl};
+use verify::Verifier;
+
+pub mod access;
+pub mod affinity;
+pub mod alloc;
+pub mod counters;
+pub mod credit;
+pub mod doctor;
+pub mod grpc_transport;
+pub mod histogram;
+pub mod mr_pool;
+pub mod rate;
+pub mod remote;
+pub mod signal;
+pub mod srq;
+pub mod stream;
+pub mod timeout;
+pub mod ud;
+pub mod usage;
+pub mod user_mr;
+pub mod verify;
+pub mod warmup;
+pub mod workload;
 
 const BATCH_SIZE: usize = 10;
+/// Largest message posted inline, matches `max_inline_data` of the QPs.
+pub const MAX_INLINE_DATA: usize = 64;
+/// Bytes of the reason carried by an `ErrorResponse`.
+pub const ERROR_REASON_LEN: usize = 48;
 
 #[derive(Debug)]
 pub struct CustomError{
     message: String,
-    code: i32
+    code: i32,
+    server_error: Option<ServerError>,
 }
 
 impl CustomError{
     pub fn new(message: String, code: i32) -> CustomError{
-        CustomError{message, code}
+        CustomError{message, code, server_error: None}
+    }
+    /// Error the server reported with an `ErrorResponse`.
+    pub fn server(server_error: ServerError) -> CustomError{
+        CustomError{
+            message: format!("server error {:?}: {}", server_error.code, server_error.reason),
+            code: server_error.code as i32,
+            server_error: Some(server_error),
+        }
     }
     pub fn code(&self) -> i32{
         self.code
     }
+    pub fn message(&self) -> &str{
+        &self.message
+    }
+    pub fn server_error(&self) -> Option<&ServerError>{
+        self.server_error.as_ref()
+    }
+}
+
+/// Error codes carried by an `ErrorResponse`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode{
+    Unknown = 1,
+    UnsupportedRequest = 2,
+    AllocationFailed = 3,
+    MessageTooLarge = 4,
+    Internal = 5,
+    ResourceExhausted = 6,
+    /// Payloads received by the server did not match the verify pattern.
+    VerifyFailed = 7,
+}
+
+impl ErrorCode{
+    pub fn from_u32(code: u32) -> ErrorCode{
+        match code{
+            2 => ErrorCode::UnsupportedRequest,
+            3 => ErrorCode::AllocationFailed,
+            4 => ErrorCode::MessageTooLarge,
+            5 => ErrorCode::Internal,
+            6 => ErrorCode::ResourceExhausted,
+            7 => ErrorCode::VerifyFailed,
+            _ => ErrorCode::Unknown,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerError{
+    pub code: ErrorCode,
+    pub reason: String,
 }
 
 impl Display for CustomError{
@@ -26,49 +107,19 @@ impl Display for CustomError{
 }
 
 pub unsafe fn send_complete(id: Id, iterations: usize, opcode_type: ibv_wc_opcode::Type) -> anyhow::Result<i32, CustomError>{
-    let mut ret: c_int;
-    let mut cq = ptr::null::<ibv_cq>() as *mut _;
-    let mut context = ptr::null::<c_void>() as *mut _;
-
     let mut wc_vec: Vec<ibv_wc> = Vec::with_capacity(BATCH_SIZE);
     let wc_ptr = wc_vec.as_mut_ptr();
 
     let mut total_wc: i32 = 0;
 
-    let nevents = 1;
-    let solicited_only = 0;
-
-    loop {
-        ret = ibv_poll_cq((*id.0).send_cq, BATCH_SIZE as i32, wc_ptr.wrapping_add(total_wc as usize));
-        if ret < 0 {
-            return Err(CustomError::new("ibv_poll_cq".to_string(), ret).into());
-        }
-        total_wc += ret;
-        if total_wc >= iterations as i32{
-            break;
-        }
-        ret = ibv_req_notify_cq((*id.0).send_cq, solicited_only);
-        if ret != 0 {
-            return Err(CustomError::new("ibv_req_notify_cq".to_string(), ret).into());
-        }
-        ret = ibv_poll_cq((*id.0).send_cq, BATCH_SIZE as i32, wc_ptr.wrapping_add(total_wc as usize));
+    wait_cq(&id, (*id.0).send_cq, (*id.0).send_cq_channel, ||{
+        let ret = ibv_poll_cq((*id.0).send_cq, BATCH_SIZE as i32, wc_ptr.wrapping_add(total_wc as usize));
         if ret < 0 {
-            return Err(CustomError::new("ibv_poll_cq".to_string(), ret).into());
+            return Err(CustomError::new("ibv_poll_cq".to_string(), ret));
         }
         total_wc += ret;
-        if total_wc >= iterations as i32{
-            break;
-        }
-        ret = ibv_get_cq_event((*id.0).send_cq_channel, &mut cq, &mut context);
-        if ret != 0 {
-            return Err(CustomError::new("ibv_get_cq_event".to_string(), ret).into());
-        }
-        assert!(cq == (*id.0).send_cq && context as *mut rdma_cm_id == id.0);
-        ibv_ack_cq_events((*id.0).send_cq, nevents);
-    }
-    if ret < 0 {
-        return Err(CustomError::new("ibv_poll_cq".to_string(), ret).into());
-    }
+        Ok(total_wc >= iterations as i32)
+    })?;
     for i in 0..total_wc{
         let wc = wc_ptr.wrapping_add(i as usize);
         let status = (*wc).status;
@@ -81,49 +132,19 @@ pub unsafe fn send_complete(id: Id, iterations: usize, opcode_type: ibv_wc_opcod
 }
 
 pub unsafe fn recv_complete(id: Id, iterations: usize) -> anyhow::Result<i32, CustomError>{
-    let mut ret: c_int;
-    let mut cq = ptr::null::<ibv_cq>() as *mut _;
-    let mut context = ptr::null::<c_void>() as *mut _;
-
     let mut wc_vec: Vec<ibv_wc> = Vec::with_capacity(BATCH_SIZE);
     let wc_ptr = wc_vec.as_mut_ptr();
 
     let mut total_wc = 0;
 
-    let nevents = 1;
-    let solicited_only = 0;
-
-    loop {
-        ret = ibv_poll_cq((*id.0).recv_cq, BATCH_SIZE as i32, wc_ptr);
-        if ret < 0 {
-            return Err(CustomError::new("ibv_poll_cq".to_string(), ret).into());
-        }
-        total_wc += ret;
-        if total_wc == iterations as i32{
-            break;
-        }
-        ret = ibv_req_notify_cq((*id.0).recv_cq, solicited_only);
-        if ret != 0 {
-            return Err(CustomError::new("ibv_req_notify_cq".to_string(), ret).into());
-        }
-        ret = ibv_poll_cq((*id.0).recv_cq, BATCH_SIZE as i32, wc_ptr);
+    wait_cq(&id, (*id.0).recv_cq, (*id.0).recv_cq_channel, ||{
+        let ret = ibv_poll_cq((*id.0).recv_cq, BATCH_SIZE as i32, wc_ptr);
         if ret < 0 {
-            return Err(CustomError::new("ibv_poll_cq".to_string(), ret).into());
+            return Err(CustomError::new("ibv_poll_cq".to_string(), ret));
         }
         total_wc += ret;
-        if total_wc == iterations as i32{
-            break;
-        }
-        ret = ibv_get_cq_event((*id.0).recv_cq_channel, &mut cq, &mut context);
-        if ret != 0 {
-            return Err(CustomError::new("ibv_get_cq_event".to_string(), ret).into());
-        }
-        assert!(cq == (*id.0).recv_cq && context as *mut rdma_cm_id == id.0);
-        ibv_ack_cq_events((*id.0).recv_cq, nevents);
-    }
-    if ret < 0 {
-        return Err(CustomError::new("ibv_poll_cq".to_string(), ret).into());
-    }
+        Ok(total_wc == iterations as i32)
+    })?;
     for i in 0..total_wc{
         let wc = wc_ptr.wrapping_add(i as usize);
         let status = (*wc).status;
@@ -135,13 +156,62 @@ pub unsafe fn recv_complete(id: Id, iterations: usize) -> anyhow::Result<i32, Cu
 }
 
 #[derive(Clone)]
-pub struct Id(pub *mut rdma_sys::rdma_cm_id);
+pub struct Id(pub *mut rdma_sys::rdma_cm_id, WaitControl);
 unsafe impl Send for Id{}
 unsafe impl Sync for Id{}
 impl Id{
+    pub fn new(id: *mut rdma_sys::rdma_cm_id) -> Id{
+        Id(id, WaitControl::default())
+    }
     pub fn id(&self) -> *mut rdma_sys::rdma_cm_id{
         self.0
     }
+    pub fn wait_control(&self) -> &WaitControl{
+        &self.1
+    }
+    /// Bounds every single blocking wait on the id, `None` waits forever.
+    pub fn set_timeout(&mut self, timeout: Option<std::time::Duration>){
+        self.1.timeout = timeout;
+    }
+    pub fn set_cancellation_token(&mut self, token: CancellationToken){
+        self.1.token = token;
+    }
+    /// How the blocking waits on the id wait for completions.
+    pub fn set_poll_mode(&mut self, poll_mode: PollMode){
+        self.1.poll_mode = poll_mode;
+    }
+    pub fn cancellation_token(&self) -> CancellationToken{
+        self.1.token.clone()
+    }
+    /// Number of the QP of the id, 0 (never an RC or UD QP) before it has
+    /// one.
+    pub fn qp_num(&self) -> u32{
+        if self.0.is_null() || unsafe { (*self.0).qp }.is_null(){
+            return 0;
+        }
+        unsafe { (*(*self.0).qp).qp_num }
+    }
+    /// Name of the RDMA device the id is bound to.
+    pub fn device_name(&self) -> Option<String>{
+        if self.0.is_null() || unsafe { (*self.0).verbs }.is_null(){
+            return None;
+        }
+        let name = unsafe { ibv_get_device_name((*(*self.0).verbs).device) };
+        if name.is_null(){
+            return None;
+        }
+        Some(unsafe { CStr::from_ptr(name) }.to_string_lossy().into_owned())
+    }
+    /// Port of the device the id is bound to.
+    pub fn port_num(&self) -> Option<u8>{
+        if self.0.is_null(){
+            return None;
+        }
+        match unsafe { (*self.0).port_num }{
+            0 => None,
+            port => Some(port),
+        }
+    }
 }
 
 pub struct Address(pub *mut c_void);
@@ -162,7 +232,8 @@ impl Mr{
     }
 }
 
-pub fn process_rdma_cm_event(echannel: *mut rdma_event_channel, expected_event: rdma_cm_event_type::Type, rdma_event: *mut *mut rdma_cm_event) -> anyhow::Result<(), CustomError> {
+pub fn process_rdma_cm_event(echannel: *mut rdma_event_channel, expected_event: rdma_cm_event_type::Type, rdma_event: *mut *mut rdma_cm_event, wait_control: &WaitControl) -> anyhow::Result<(), CustomError> {
+    wait_event_channel(echannel, wait_control)?;
     let res = unsafe { rdma_get_cm_event(echannel, rdma_event) };
     if res != 0 {
         return Err(CustomError::new("rdma_get_cm_event".to_string(), res).into());
@@ -180,39 +251,121 @@ pub fn process_rdma_cm_event(echannel: *mut rdma_event_channel, expected_event:
     Ok(())
 }
 
+/// Resolves `node:port` and creates a synchronous endpoint on it with a QP
+/// of `init_attr`, or without a QP for `None`. A `passive`
+/// endpoint is listened on, an active one connects from `source` if set.
+pub fn create_ep(node: &str, port: u16, source: Option<IpAddr>, passive: bool, init_attr: Option<&mut ibv_qp_init_attr>) -> anyhow::Result<Id, CustomError>{
+    let c_node = CString::new(node).map_err(|_| CustomError::new(format!("invalid address {}", node), -libc::EINVAL))?;
+    let service = CString::new(port.to_string()).unwrap();
+    let mut hints = unsafe { std::mem::zeroed::<rdma_addrinfo>() };
+    let mut src_res: *mut rdma_addrinfo = null_mut();
+    if let Some(source) = source{
+        let source = CString::new(source.to_string()).unwrap();
+        let mut src_hints = unsafe { std::mem::zeroed::<rdma_addrinfo>() };
+        src_hints.ai_flags = (RAI_PASSIVE | RAI_NUMERICHOST) as i32;
+        src_hints.ai_port_space = rdma_port_space::RDMA_PS_TCP as i32;
+        let ret = unsafe { rdma_getaddrinfo(source.as_ptr(), null_mut(), &src_hints, &mut src_res) };
+        if ret != 0 {
+            return Err(CustomError::new("rdma_getaddrinfo source".to_string(), ret));
+        }
+        hints.ai_src_addr = unsafe { (*src_res).ai_src_addr };
+        hints.ai_src_len = unsafe { (*src_res).ai_src_len };
+    }
+    if passive{
+        hints.ai_flags = RAI_PASSIVE as i32;
+    }
+    // numeric addresses never wait for a name lookup
+    if node.parse::<IpAddr>().is_ok(){
+        hints.ai_flags |= RAI_NUMERICHOST as i32;
+    }
+    hints.ai_port_space = rdma_port_space::RDMA_PS_TCP as i32;
+    let mut res: *mut rdma_addrinfo = null_mut();
+    let ret = unsafe { rdma_getaddrinfo(c_node.as_ptr(), service.as_ptr(), &hints, &mut res) };
+    if !src_res.is_null(){
+        unsafe { rdma_freeaddrinfo(src_res) };
+    }
+    if ret != 0 {
+        return Err(CustomError::new(format!("rdma_getaddrinfo {}", node), ret));
+    }
+    let mut id = null_mut();
+    let init_attr = init_attr.map_or(null_mut(), |init_attr| init_attr as *mut ibv_qp_init_attr);
+    let ret = unsafe { rdma_create_ep(&mut id, res, null_mut(), init_attr) };
+    unsafe { rdma_freeaddrinfo(res) };
+    if ret != 0 {
+        return Err(CustomError::new(format!("rdma_create_ep {}", node), ret));
+    }
+    Ok(Id::new(id))
+}
+
 
 pub struct Data{
-    pub buffer: Vec<u8>,
+    buffer: Buffer,
+    len: usize,
     mr: *mut ibv_mr,
+    access: c_int,
 }
 
 impl Data{
     pub fn new(size: usize) -> Data{
         Data{
-            buffer: vec![1u8; size],
+            buffer: Buffer::Heap(vec![1u8; size]),
+            len: size,
             mr: null_mut(),
+            access: 0,
         }
     }
+    /// Allocates the buffer with `strategy`, bound to `numa_node` if given.
+    /// The allocation may be rounded up to the page size, `len` stays `size`.
+    pub fn with_strategy(size: usize, strategy: AllocStrategy, numa_node: Option<u32>) -> anyhow::Result<Data, CustomError>{
+        Ok(Data{
+            buffer: Buffer::new(size, strategy, numa_node)?,
+            len: size,
+            mr: null_mut(),
+            access: 0,
+        })
+    }
     pub fn buffer(&self) -> Vec<u8>{
-        self.buffer.clone()
+        self.as_slice().to_vec()
+    }
+    /// The whole allocation, `capacity` bytes whatever `len` is set to.
+    pub fn buffer_mut(&mut self) -> &mut [u8]{
+        let capacity = self.buffer.capacity();
+        unsafe { std::slice::from_raw_parts_mut(self.buffer.as_mut_ptr() as *mut u8, capacity) }
+    }
+    pub fn as_slice(&self) -> &[u8]{
+        &self.buffer.as_slice()[..self.len]
+    }
+    pub fn as_mut_slice(&mut self) -> &mut [u8]{
+        let len = self.len;
+        unsafe { std::slice::from_raw_parts_mut(self.buffer.as_mut_ptr() as *mut u8, len) }
+    }
+    pub fn capacity(&self) -> usize{
+        self.buffer.capacity()
+    }
+    /// Sets the length used for posting, at most `capacity`.
+    pub fn set_len(&mut self, len: usize){
+        self.len = len.min(self.buffer.capacity());
     }
 }
 
 impl MrObject for Data{
     fn len(&self) -> usize{
-        self.buffer.len()
+        self.len
     }
     fn addr(&mut self) -> *mut c_void{
-        self.buffer.as_mut_ptr().cast()
-    }
-    fn set_mr(&mut self, mr: *mut ibv_mr){
-        self.mr = mr;
+        self.buffer.as_mut_ptr()
     }
     fn mr(&self) -> *mut ibv_mr{
         self.mr
     }
 }
 
+impl MrRegister for Data{
+    fn set_mr(&mut self, mr: *mut ibv_mr){
+        self.mr = mr;
+    }
+}
+
 pub struct MrAddr{
     pub mr: *mut ibv_mr,
     pub addr: *mut c_void,
@@ -227,34 +380,17 @@ pub trait MrObject{
     }
     fn len(&self) -> usize;
     fn addr(&mut self) -> *mut c_void;
-    fn set_mr(&mut self, mr: *mut ibv_mr);
     fn mr(&self) -> *mut ibv_mr;
-    fn create_and_register_mr(&mut self, id: &Id, operation: Operation) -> anyhow::Result<MrAddr, CustomError> {
-        if id.id().is_null(){
-            return Err(CustomError::new("id is null".to_string(), -1).into());
-        }
-        let length = self.len();
-        let addr: *mut c_void = self.addr();
-        let mr = match operation{
-            Operation::SendRecv => {
-                unsafe { rdma_reg_msgs(id.id(), addr, length) }
-            },
-            Operation::Write => {
-                unsafe { rdma_reg_write(id.id(), addr, length) }
-            },
-            Operation::Read => {
-                unsafe { rdma_reg_read(id.id(), addr, length) }
-            }
-        };
-        if mr.is_null() {
-            unsafe { rdma_dereg_mr(mr); }
-            return Err(CustomError::new("rdma_reg_msgs".to_string(), -1).into());
-        }
-        self.set_mr(mr);
-        Ok(MrAddr{mr, addr})
+    /// Address pair of an object which was already registered, e.g. a buffer
+    /// checked out of a `BufferPool`.
+    fn registered_mr_addr(&mut self) -> MrAddr{
+        MrAddr{mr: self.mr(), addr: self.addr()}
     }
     fn rdma_send(&mut self, id: &Id, mr_addr: &MrAddr) -> anyhow::Result<(), CustomError>{
-        let flags = ibv_send_flags::IBV_SEND_INLINE.0 | ibv_send_flags::IBV_SEND_SIGNALED.0;
+        let mut flags = ibv_send_flags::IBV_SEND_SIGNALED.0;
+        if self.len() <= MAX_INLINE_DATA{
+            flags |= ibv_send_flags::IBV_SEND_INLINE.0;
+        }
         let mut ret = unsafe {
             rdma_post_send(
                 id.id(),
@@ -271,7 +407,7 @@ pub trait MrObject{
         }
         let mut wc = unsafe { std::mem::zeroed::<ibv_wc>() };
         while ret == 0 {
-            ret = unsafe { rdma_get_send_comp(id.id(), &mut wc) };
+            ret = get_send_comp(id, &mut wc)?;
         }
         if ret < 0 {
             println!("rdma_get_send_comp");
@@ -284,12 +420,11 @@ pub trait MrObject{
     fn rdma_recv(&mut self, id: &Id, mr_addr: &MrAddr) -> anyhow::Result<(), CustomError>{
         let mut ret = unsafe { rdma_post_recv(id.id(), null_mut(), mr_addr.addr, self.len(), mr_addr.mr) };
         if ret != 0 {
-            unsafe { rdma_dereg_mr(self.mr()) };
             return Err(CustomError::new("rdma_post_recv".to_string(), ret).into());
         }
         let mut wc = unsafe { std::mem::zeroed::<ibv_wc>() };
         while ret == 0 {
-            ret = unsafe { rdma_get_recv_comp(id.id(), &mut wc) };
+            ret = get_recv_comp(id, &mut wc)?;
         }
         if ret < 0 {
             unsafe { rdma_disconnect(id.id()); }
@@ -368,6 +503,124 @@ pub trait MrObject{
         }
         Ok(())
     }
+    /// `rdma_write` of the whole object to `offset` of `region`, if it fits.
+    fn rdma_write_region(&mut self, id: &Id, region: &RemoteRegion, offset: u64, iterations: usize) -> anyhow::Result<(), CustomError>{
+        region.allows(Operation::Write)?;
+        let remote_addr = region.range(offset, self.len() as u64)?;
+        self.rdma_write(id, region.rkey, remote_addr, iterations)
+    }
+    /// `rdma_read` of the whole object from `offset` of `region`, if it fits.
+    fn rdma_read_region(&mut self, id: &Id, region: &RemoteRegion, offset: u64, iterations: usize) -> anyhow::Result<(), CustomError>{
+        region.allows(Operation::Read)?;
+        let remote_addr = region.range(offset, self.len() as u64)?;
+        self.rdma_read(id, region.rkey, remote_addr, iterations)
+    }
+    /// Writes `iterations` messages of `message_size` bytes, each from and to
+    /// the next offset of `offsets` in this object and in `region`.
+    fn rdma_write_offsets(&mut self, id: &Id, region: &RemoteRegion, message_size: usize, offsets: &mut OffsetGenerator, iterations: usize) -> anyhow::Result<(), CustomError>{
+        self.rdma_post_offsets(id, Operation::Write, region, message_size, offsets, iterations)
+    }
+    /// Reads `iterations` messages of `message_size` bytes, each from and to
+    /// the next offset of `offsets` in `region` and in this object.
+    fn rdma_read_offsets(&mut self, id: &Id, region: &RemoteRegion, message_size: usize, offsets: &mut OffsetGenerator, iterations: usize) -> anyhow::Result<(), CustomError>{
+        self.rdma_post_offsets(id, Operation::Read, region, message_size, offsets, iterations)
+    }
+    fn rdma_post_offsets(&mut self, id: &Id, operation: Operation, region: &RemoteRegion, message_size: usize, offsets: &mut OffsetGenerator, iterations: usize) -> anyhow::Result<(), CustomError>{
+        let opcode = match operation{
+            Operation::Read => ibv_wc_opcode::IBV_WC_RDMA_READ,
+            _ => ibv_wc_opcode::IBV_WC_RDMA_WRITE,
+        };
+        region.allows(operation)?;
+        let mut flags = 0;
+        for i in 1..iterations+1{
+            let offset = offsets.next_offset();
+            if offset as usize + message_size > self.len(){
+                return Err(CustomError::new(format!("{} bytes at offset {} exceed local buffer of {} bytes", message_size, offset, self.len()), -libc::ERANGE));
+            }
+            let remote_addr = region.range(offset, message_size as u64)?;
+            if i == iterations || i % BATCH_SIZE == 0{
+                flags = ibv_send_flags::IBV_SEND_SIGNALED.0;
+            }
+            let local_addr = unsafe { self.addr().cast::<u8>().add(offset as usize).cast() };
+            let ret = unsafe {
+                match operation{
+                    Operation::Read => rdma_post_read(id.id(), null_mut(), local_addr, message_size, self.mr(), flags as i32, remote_addr, region.rkey),
+                    _ => rdma_post_write(id.id(), null_mut(), local_addr, message_size, self.mr(), flags as i32, remote_addr, region.rkey),
+                }
+            };
+            if ret != 0 {
+                unsafe { rdma_disconnect(id.id()) };
+                let call = if operation == Operation::Read { "rdma_post_read" } else { "rdma_post_write" };
+                return Err(CustomError::new(call.to_string(), ret));
+            }
+            if flags != 0{
+                unsafe { send_complete(id.clone(), 1, opcode)? };
+                flags = 0;
+            }
+        }
+        Ok(())
+    }
+    /// Reads or writes `len` bytes between `offset` of this object and
+    /// `remote_offset` of `region`, and waits for the completion.
+    fn rdma_one_sided_at(&mut self, id: &Id, operation: Operation, offset: usize, region: &RemoteRegion, remote_offset: u64, len: usize) -> anyhow::Result<(), CustomError>{
+        if offset + len > self.len(){
+            return Err(CustomError::new(format!("{} bytes at offset {} exceed local buffer of {} bytes", len, offset, self.len()), -libc::ERANGE));
+        }
+        region.allows(operation)?;
+        let remote_addr = region.range(remote_offset, len as u64)?;
+        let local_addr = unsafe { self.addr().cast::<u8>().add(offset).cast() };
+        let flags = ibv_send_flags::IBV_SEND_SIGNALED.0 as i32;
+        let (ret, call, opcode) = unsafe {
+            match operation{
+                Operation::Read => (rdma_post_read(id.id(), null_mut(), local_addr, len, self.mr(), flags, remote_addr, region.rkey), "rdma_post_read", ibv_wc_opcode::IBV_WC_RDMA_READ),
+                _ => (rdma_post_write(id.id(), null_mut(), local_addr, len, self.mr(), flags, remote_addr, region.rkey), "rdma_post_write", ibv_wc_opcode::IBV_WC_RDMA_WRITE),
+            }
+        };
+        if ret != 0 {
+            return Err(CustomError::new(call.to_string(), ret));
+        }
+        unsafe { send_complete(id.clone(), 1, opcode)? };
+        Ok(())
+    }
+    /// Atomically replaces the 8 bytes at `remote_offset` of `region` with
+    /// `swap` if they equal `compare`. The previous value lands at `offset`
+    /// of this object and is returned. The remote address must be 8 byte
+    /// aligned and `region` registered with `Operation::Atomic`.
+    fn rdma_compare_and_swap_at(&mut self, id: &Id, offset: usize, region: &RemoteRegion, remote_offset: u64, compare: u64, swap: u64) -> anyhow::Result<u64, CustomError>{
+        let len = std::mem::size_of::<u64>();
+        if offset + len > self.len(){
+            return Err(CustomError::new(format!("{} bytes at offset {} exceed local buffer of {} bytes", len, offset, self.len()), -libc::ERANGE));
+        }
+        region.allows(Operation::Atomic)?;
+        let remote_addr = region.range(remote_offset, len as u64)?;
+        if remote_addr % len as u64 != 0{
+            return Err(CustomError::new(format!("remote address {:#x} is not 8 byte aligned", remote_addr), -libc::EINVAL));
+        }
+        let local_addr = unsafe { self.addr().cast::<u8>().add(offset) };
+        let mut sge = ibv_sge{
+            addr: local_addr as u64,
+            length: len as u32,
+            lkey: unsafe { (*self.mr()).lkey },
+        };
+        let mut wr = unsafe { std::mem::zeroed::<ibv_send_wr>() };
+        wr.opcode = ibv_wr_opcode::IBV_WR_ATOMIC_CMP_AND_SWP;
+        wr.send_flags = ibv_send_flags::IBV_SEND_SIGNALED.0;
+        wr.sg_list = &mut sge;
+        wr.num_sge = 1;
+        wr.wr.atomic = atomic_t{
+            remote_addr,
+            compare_add: compare,
+            swap,
+            rkey: region.rkey,
+        };
+        let mut bad_wr = null_mut();
+        let ret = unsafe { ibv_post_send((*id.id()).qp, &mut wr, &mut bad_wr) };
+        if ret != 0 {
+            return Err(CustomError::new("ibv_post_send".to_string(), ret));
+        }
+        unsafe { send_complete(id.clone(), 1, ibv_wc_opcode::IBV_WC_COMP_SWAP)? };
+        Ok(unsafe { std::ptr::read_unaligned(local_addr.cast::<u64>()) })
+    }
     fn rdma_recv_data(&mut self, id: &Id, mr_addr: &MrAddr, iterations: usize) -> anyhow::Result<(), CustomError>{
         let mut ret;
         let mut comp = false;
@@ -396,6 +649,106 @@ pub trait MrObject{
         }
         Ok(())
     }
+    /// Posts the receive slots advertised to the sender before a credit
+    /// based transfer and returns the receiver side accounting for it.
+    fn rdma_post_credits(&mut self, id: &Id, mr_addr: &MrAddr, iterations: usize, credits: usize) -> anyhow::Result<CreditReceiver, CustomError>{
+        let credit_receiver = CreditReceiver::new(iterations, credits);
+        for _ in 0..credit_receiver.credits(){
+            let ret = unsafe { rdma_post_recv(id.id(), null_mut(), mr_addr.addr, self.len(), mr_addr.mr) };
+            if ret != 0 {
+                unsafe { rdma_disconnect(id.id()) };
+                return Err(CustomError::new("rdma_post_recv".to_string(), ret));
+            }
+        }
+        Ok(credit_receiver)
+    }
+    /// Receives `iterations` messages into the slots posted by
+    /// `rdma_post_credits`, reposting each consumed slot and returning it to
+    /// the sender as a credit. With a `verifier` every message is checked
+    /// before its slot is reposted, which requires a single credit.
+    fn rdma_recv_data_with_credits(&mut self, id: &Id, mr_addr: &MrAddr, mut credit_receiver: CreditReceiver, iterations: usize, mut verifier: Option<&mut Verifier>) -> anyhow::Result<(), CustomError>{
+        let mut wc = unsafe { std::mem::zeroed::<ibv_wc>() };
+        for i in 0..iterations{
+            let mut ret = 0;
+            while ret == 0 {
+                ret = get_recv_comp(id, &mut wc)?;
+            }
+            if ret < 0 {
+                unsafe { rdma_disconnect(id.id()); }
+                return Err(CustomError::new("rdma_get_recv_comp".to_string(), ret));
+            }
+            if wc.status != ibv_wc_status::IBV_WC_SUCCESS || wc.opcode != ibv_wc_opcode::IBV_WC_RECV{
+                return Err(CustomError::new(format!("wc status/opcode {}/{} wrong, expected {}/{}", wc.status, wc.opcode, ibv_wc_status::IBV_WC_SUCCESS, ibv_wc_opcode::IBV_WC_RECV), -1));
+            }
+            if let Some(verifier) = verifier.as_mut(){
+                let length = (wc.byte_len as usize).min(self.len());
+                let payload = unsafe { std::slice::from_raw_parts(mr_addr.addr as *const u8, length) };
+                verifier.check(payload, i as u64, i as u64);
+            }
+            if credit_receiver.needs_repost(){
+                let ret = unsafe { rdma_post_recv(id.id(), null_mut(), mr_addr.addr, self.len(), mr_addr.mr) };
+                if ret != 0 {
+                    unsafe { rdma_disconnect(id.id()) };
+                    return Err(CustomError::new("rdma_post_recv".to_string(), ret));
+                }
+                credit_receiver.reposted(id)?;
+            }
+        }
+        Ok(())
+    }
+    /// Like `rdma_send_data`, but never has more sends outstanding than the
+    /// receiver has receive slots for. Zero credits disable flow control.
+    /// With a `verifier` the payload is refilled for every iteration, each
+    /// send is signaled and completed before the buffer is touched again.
+    fn rdma_send_data_with_credits(&mut self, id: &Id, mr_addr: &MrAddr, iterations: usize, credits: usize, mut verifier: Option<&mut Verifier>) -> anyhow::Result<(), CustomError>{
+        if credits == 0{
+            match verifier{
+                Some(verifier) => {
+                    for i in 0..iterations{
+                        let payload = unsafe { std::slice::from_raw_parts_mut(mr_addr.addr as *mut u8, self.len()) };
+                        verifier.fill(payload, i as u64);
+                        self.rdma_send_data(id, mr_addr, 1)?;
+                    }
+                    return Ok(());
+                },
+                None => return self.rdma_send_data(id, mr_addr, iterations),
+            }
+        }
+        let mut credit_sender = CreditSender::new(id, iterations, credits)?;
+        let mut flags = 0;
+        let mut comp = false;
+        for i in 1..iterations+1{
+            credit_sender.acquire(id)?;
+            if let Some(verifier) = verifier.as_mut(){
+                let payload = unsafe { std::slice::from_raw_parts_mut(mr_addr.addr as *mut u8, self.len()) };
+                verifier.fill(payload, i as u64 - 1);
+            }
+            if i == iterations || i % BATCH_SIZE == 0 || verifier.is_some(){
+                flags = ibv_send_flags::IBV_SEND_SIGNALED.0;
+                comp = true;
+            }
+            let ret = unsafe {
+                rdma_post_send(
+                    id.0,
+                    null_mut(),
+                    mr_addr.addr,
+                    self.len(),
+                    mr_addr.mr,
+                    flags as i32
+                )
+            };
+            if ret != 0 {
+                unsafe { rdma_disconnect(id.0) };
+                return Err(CustomError::new("rdma_post_send".to_string(), ret));
+            }
+            if comp{
+                let _ret = unsafe { send_complete(id.clone(), 1, ibv_wc_opcode::IBV_WC_SEND)? };
+                comp = false;
+                flags = 0;
+            }
+        }
+        Ok(())
+    }
     fn rdma_send_data(&mut self, id: &Id, mr_addr: &MrAddr, iterations: usize) -> anyhow::Result<(), CustomError>{
         let mut flags = 0;
         let mut ret;
@@ -430,12 +783,84 @@ pub trait MrObject{
     }
 }
 
+/// Objects which take their registration from the caller. Buffers which
+/// register themselves, like `RegisteredBuffer`, only implement `MrObject`.
+pub trait MrRegister: MrObject{
+    fn set_mr(&mut self, mr: *mut ibv_mr);
+    fn create_and_register_mr(&mut self, id: &Id, operation: Operation) -> anyhow::Result<MrAddr, CustomError> {
+        if id.id().is_null(){
+            return Err(CustomError::new("id is null".to_string(), -1));
+        }
+        let length = self.len();
+        let addr: *mut c_void = self.addr();
+        let mr = match operation{
+            Operation::SendRecv => {
+                unsafe { rdma_reg_msgs(id.id(), addr, length) }
+            },
+            Operation::Write => {
+                unsafe { rdma_reg_write(id.id(), addr, length) }
+            },
+            Operation::Read => {
+                unsafe { rdma_reg_read(id.id(), addr, length) }
+            },
+            Operation::Atomic => {
+                unsafe { ibv_reg_mr((*id.id()).pd, addr, length, operation.access_flags()) }
+            }
+        };
+        if mr.is_null() {
+            unsafe { rdma_dereg_mr(mr); }
+            return Err(CustomError::new("rdma_reg_msgs".to_string(), -1));
+        }
+        self.set_mr(mr);
+        Ok(MrAddr{mr, addr})
+    }
+    /// Like `create_and_register_mr`, but reuses a registration from `cache`
+    /// when one covers this object. The object has to stay at its address
+    /// until the reference is dropped with `MrCache::release`.
+    fn create_and_register_mr_cached(&mut self, id: &Id, operation: Operation, cache: &mut MrCache) -> anyhow::Result<MrAddr, CustomError> {
+        let addr: *mut c_void = self.addr();
+        let mr = cache.register(id, addr, self.len(), operation)?;
+        self.set_mr(mr);
+        Ok(MrAddr{mr, addr})
+    }
+}
+
 
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Operation{
     SendRecv,
     Write,
     Read,
+    /// Remote reads, writes and atomics.
+    Atomic,
+}
+
+impl Operation{
+    /// Access flags `rdma_reg_msgs`/`rdma_reg_write`/`rdma_reg_read` use
+    /// for the operation, `Atomic` registers with `ibv_reg_mr`.
+    pub fn access_flags(&self) -> c_int{
+        let access = match self{
+            Operation::SendRecv => ibv_access_flags::IBV_ACCESS_LOCAL_WRITE,
+            Operation::Write => ibv_access_flags::IBV_ACCESS_LOCAL_WRITE | ibv_access_flags::IBV_ACCESS_REMOTE_WRITE,
+            Operation::Read => ibv_access_flags::IBV_ACCESS_LOCAL_WRITE | ibv_access_flags::IBV_ACCESS_REMOTE_READ,
+            Operation::Atomic => ibv_access_flags::IBV_ACCESS_LOCAL_WRITE | ibv_access_flags::IBV_ACCESS_REMOTE_READ | ibv_access_flags::IBV_ACCESS_REMOTE_WRITE | ibv_access_flags::IBV_ACCESS_REMOTE_ATOMIC,
+        };
+        access.0 as c_int
+    }
+}
+
+impl std::str::FromStr for Operation{
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err>{
+        match s{
+            "send" => Ok(Operation::SendRecv),
+            "write" => Ok(Operation::Write),
+            "read" => Ok(Operation::Read),
+            "atomic" => Ok(Operation::Atomic),
+            _ => Err(format!("unknown operation {}, expected send, write, read or atomic", s)),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -446,9 +871,17 @@ pub struct MetaData{
     pub rkey: u32,
     pub lkey: u32,
     pub iterations: u32,
+    pub credits: u32,
+    pub verify: u32,
+    pub seed: u64,
+    pub error_code: u32,
+    pub reason: [u8; ERROR_REASON_LEN],
+    pub buffer_size: u64,
     mr: *mut ibv_mr,
 }
 
+unsafe impl Send for MetaData{}
+
 impl Default for MetaData{
     fn default() -> MetaData{
         MetaData{
@@ -458,6 +891,12 @@ impl Default for MetaData{
             rkey: 0,
             lkey: 0,
             iterations: 0,
+            credits: 0,
+            verify: 0,
+            seed: 0,
+            error_code: 0,
+            reason: [0; ERROR_REASON_LEN],
+            buffer_size: 0,
             mr: null_mut(),
         }
     }
@@ -465,6 +904,13 @@ impl Default for MetaData{
 
 impl MetaData{
     pub const LEN: usize = std::mem::size_of::<MetaData>();
+    /// Clears the message for the next request, keeping the registration.
+    pub fn reset(&mut self){
+        *self = MetaData{
+            mr: self.mr,
+            ..MetaData::default()
+        };
+    }
     pub fn get_request_type(&self) -> MetaDataRequestTypes{
         match self.request_type{
             0 => MetaDataRequestTypes::Disconnect,
@@ -477,6 +923,10 @@ impl MetaData{
             7 => MetaDataRequestTypes::ReadRequest,
             8 => MetaDataRequestTypes::ReadResponse,
             9 => MetaDataRequestTypes::ReadFinished,
+            10 => MetaDataRequestTypes::ErrorResponse,
+            11 => MetaDataRequestTypes::WorkloadRequest,
+            12 => MetaDataRequestTypes::WorkloadResponse,
+            13 => MetaDataRequestTypes::WorkloadFinished,
             _ => MetaDataRequestTypes::UnDef,
         }
     }
@@ -532,6 +982,26 @@ impl MetaData{
                 unsafe { (*metadata_buffer).request_type = 9 };
                 self.request_type = 9
             },
+            MetaDataRequestTypes::ErrorResponse => {
+                let metadata_buffer: *mut MetaData = self.addr() as *const _ as *mut MetaData;
+                unsafe { (*metadata_buffer).request_type = 10 };
+                self.request_type = 10
+            },
+            MetaDataRequestTypes::WorkloadRequest => {
+                let metadata_buffer: *mut MetaData = self.addr() as *const _ as *mut MetaData;
+                unsafe { (*metadata_buffer).request_type = 11 };
+                self.request_type = 11
+            },
+            MetaDataRequestTypes::WorkloadResponse => {
+                let metadata_buffer: *mut MetaData = self.addr() as *const _ as *mut MetaData;
+                unsafe { (*metadata_buffer).request_type = 12 };
+                self.request_type = 12
+            },
+            MetaDataRequestTypes::WorkloadFinished => {
+                let metadata_buffer: *mut MetaData = self.addr() as *const _ as *mut MetaData;
+                unsafe { (*metadata_buffer).request_type = 13 };
+                self.request_type = 13
+            },
             MetaDataRequestTypes::UnDef => {
                 let metadata_buffer: *mut MetaData = self.addr() as *const _ as *mut MetaData;
                 unsafe { (*metadata_buffer).request_type = 128 };
@@ -554,11 +1024,75 @@ impl MetaData{
         unsafe { (*metadata_buffer).remote_address = remote_address };
         self.remote_address = remote_address;
     }
+    /// Hands out `region` in a response: its address, rkey and length.
+    pub fn set_remote_region(&mut self, region: &RemoteRegion){
+        self.set_remote_address(region.addr);
+        self.set_rkey(region.rkey);
+        self.set_buffer_size(region.len);
+    }
+    /// The region handed out in a response, with the access the server
+    /// registers it with for the request.
+    pub fn remote_region(&self) -> RemoteRegion{
+        let access = match self.get_request_type(){
+            MetaDataRequestTypes::WriteResponse => Operation::Write,
+            MetaDataRequestTypes::ReadResponse => Operation::Read,
+            MetaDataRequestTypes::WorkloadResponse => Operation::Atomic,
+            _ => Operation::SendRecv,
+        };
+        RemoteRegion::new(self.remote_address, self.buffer_size, self.rkey, access)
+    }
+    /// Size of the remote buffer, requested by the client and granted in the
+    /// response. A request of 0 asks for the message size.
+    pub fn set_buffer_size(&mut self, buffer_size: u64){
+        let metadata_buffer: *mut MetaData = self.addr() as *const _ as *mut MetaData;
+        unsafe { (*metadata_buffer).buffer_size = buffer_size };
+        self.buffer_size = buffer_size;
+    }
     pub fn set_iterations(&mut self, iterations: u32){
         let metadata_buffer: *mut MetaData = self.addr() as *const _ as *mut MetaData;
         unsafe { (*metadata_buffer).iterations = iterations };
         self.iterations = iterations;
     }
+    pub fn set_credits(&mut self, credits: u32){
+        let metadata_buffer: *mut MetaData = self.addr() as *const _ as *mut MetaData;
+        unsafe { (*metadata_buffer).credits = credits };
+        self.credits = credits;
+    }
+    /// Sets the `VerifyPattern` code and seed, a code of 0 disables verification.
+    pub fn set_verify(&mut self, verify: u32, seed: u64){
+        let metadata_buffer: *mut MetaData = self.addr() as *const _ as *mut MetaData;
+        unsafe {
+            (*metadata_buffer).verify = verify;
+            (*metadata_buffer).seed = seed;
+        }
+        self.verify = verify;
+        self.seed = seed;
+    }
+    /// Sets the code and reason of an `ErrorResponse`, the reason is cut
+    /// to fit `ERROR_REASON_LEN` bytes including a terminating 0.
+    pub fn set_error(&mut self, code: ErrorCode, reason: &str){
+        let mut reason_buffer = [0u8; ERROR_REASON_LEN];
+        let mut length = reason.len().min(ERROR_REASON_LEN - 1);
+        while !reason.is_char_boundary(length){
+            length -= 1;
+        }
+        reason_buffer[..length].copy_from_slice(&reason.as_bytes()[..length]);
+        let metadata_buffer: *mut MetaData = self.addr() as *const _ as *mut MetaData;
+        unsafe {
+            (*metadata_buffer).error_code = code as u32;
+            (*metadata_buffer).reason = reason_buffer;
+        }
+        self.error_code = code as u32;
+        self.reason = reason_buffer;
+    }
+    /// The error carried by an `ErrorResponse`.
+    pub fn server_error(&self) -> ServerError{
+        let length = self.reason.iter().position(|byte| *byte == 0).unwrap_or(ERROR_REASON_LEN);
+        ServerError{
+            code: ErrorCode::from_u32(self.error_code),
+            reason: String::from_utf8_lossy(&self.reason[..length]).into_owned(),
+        }
+    }
     pub fn rkey(&self) -> u32{
         self.rkey
     }
@@ -571,6 +1105,18 @@ impl MetaData{
     pub fn iterations(&self) -> u32{
         self.iterations
     }
+    pub fn credits(&self) -> u32{
+        self.credits
+    }
+    pub fn verify(&self) -> u32{
+        self.verify
+    }
+    pub fn seed(&self) -> u64{
+        self.seed
+    }
+    pub fn buffer_size(&self) -> u64{
+        self.buffer_size
+    }
 }
 
 impl MrObject for MetaData{
@@ -580,14 +1126,17 @@ impl MrObject for MetaData{
     fn addr(&mut self) -> *mut c_void {
         self as *const _ as *mut c_void
     }
-    fn set_mr(&mut self, mr: *mut ibv_mr){
-        self.mr = mr;
-    }
     fn mr(&self) -> *mut ibv_mr{
         self.mr
     }
 }
 
+impl MrRegister for MetaData{
+    fn set_mr(&mut self, mr: *mut ibv_mr){
+        self.mr = mr;
+    }
+}
+
 #[derive(Debug)]
 pub enum MetaDataRequestTypes{
     Disconnect = 0,
@@ -600,5 +1149,10 @@ pub enum MetaDataRequestTypes{
     ReadRequest = 7,
     ReadResponse = 8,
     ReadFinished = 9,
+    ErrorResponse = 10,
+    /// Asks for a region for reads, writes and atomics of a mixed workload.
+    WorkloadRequest = 11,
+    WorkloadResponse = 12,
+    WorkloadFinished = 13,
     UnDef = 128,
 }
\ No newline at end of file
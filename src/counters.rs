@@ -0,0 +1,273 @@
+use std::{collections::BTreeMap, fmt::Display, path::{Path, PathBuf}};
+use crate::{CustomError, Id};
+
+/// Where the kernel exposes RDMA devices.
+pub const DEFAULT_SYSFS_ROOT: &str = "/sys/class/infiniband";
+
+/// Counters in 4 byte words rather than bytes.
+const WORD_COUNTERS: [&str; 2] = ["port_xmit_data", "port_rcv_data"];
+
+/// Counters listed first when they changed: data and packets, retransmits
+/// and timeouts, out-of-sequence packets, RNR NAKs and congestion
+/// notifications. Drivers name their `hw_counters` differently, missing
+/// ones are skipped.
+const KEY_COUNTERS: [&str; 14] = [
+    "port_xmit_data",
+    "port_rcv_data",
+    "port_xmit_packets",
+    "port_rcv_packets",
+    "local_ack_timeout_err",
+    "packet_seq_err",
+    "out_of_sequence",
+    "duplicate_request",
+    "implied_nak_seq_err",
+    "rnr_nak_retry_err",
+    "np_cnp_sent",
+    "rp_cnp_handled",
+    "rp_cnp_ignored",
+    "np_ecn_marked_roce_packets",
+];
+
+/// The `counters` and `hw_counters` of one port of an RDMA device, read
+/// below a sysfs root which is configurable so other directory trees can
+/// stand in for `/sys/class/infiniband`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CounterSource{
+    root: PathBuf,
+    device: String,
+    port: u8,
+}
+
+impl CounterSource{
+    pub fn new(root: impl Into<PathBuf>, device: impl Into<String>, port: u8) -> CounterSource{
+        CounterSource{
+            root: root.into(),
+            device: device.into(),
+            port,
+        }
+    }
+    /// Counters of the device and port `id` is bound to.
+    pub fn for_id(root: impl Into<PathBuf>, id: &Id) -> Option<CounterSource>{
+        Some(CounterSource::new(root, id.device_name()?, id.port_num()?))
+    }
+    pub fn device(&self) -> &str{
+        &self.device
+    }
+    pub fn port(&self) -> u8{
+        self.port
+    }
+    pub fn port_dir(&self) -> PathBuf{
+        self.root.join(&self.device).join("ports").join(self.port.to_string())
+    }
+    /// Reads all counters. Files which cannot be read or do not hold a
+    /// number are skipped, it fails only if the port has no counters at all.
+    pub fn snapshot(&self) -> anyhow::Result<CounterSnapshot, CustomError>{
+        let port_dir = self.port_dir();
+        let mut snapshot = CounterSnapshot::default();
+        let mut found = false;
+        for dir in ["counters", "hw_counters"]{
+            found |= snapshot.read_dir(&port_dir.join(dir));
+        }
+        if !found{
+            return Err(CustomError::new(format!("no counters below {}", port_dir.display()), -libc::ENOENT));
+        }
+        Ok(snapshot)
+    }
+}
+
+/// Counter values by file name at one point in time.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CounterSnapshot(BTreeMap<String, u64>);
+
+impl CounterSnapshot{
+    /// Adds the counters in `dir`, returns whether it exists.
+    fn read_dir(&mut self, dir: &Path) -> bool{
+        let entries = match std::fs::read_dir(dir){
+            Ok(entries) => entries,
+            Err(_) => return false,
+        };
+        for entry in entries.flatten(){
+            let value = std::fs::read_to_string(entry.path()).ok().and_then(|value| value.trim().parse::<u64>().ok());
+            if let Some(value) = value{
+                self.0.insert(entry.file_name().to_string_lossy().into_owned(), value);
+            }
+        }
+        true
+    }
+    pub fn get(&self, name: &str) -> Option<u64>{
+        self.0.get(name).copied()
+    }
+    /// Change of every counter since `before`. A counter which went down, as
+    /// after a reset, counts as unchanged.
+    pub fn delta_since(&self, before: &CounterSnapshot) -> CounterDelta{
+        let deltas = self.0.iter()
+            .filter_map(|(name, value)| before.0.get(name).map(|before| (name.clone(), value.saturating_sub(*before))))
+            .collect();
+        CounterDelta(deltas)
+    }
+}
+
+/// Change of the counters of a port over a run. The counters cover the
+/// whole port, so traffic of other QPs on it is included.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CounterDelta(pub BTreeMap<String, u64>);
+
+impl CounterDelta{
+    pub fn get(&self, name: &str) -> Option<u64>{
+        self.0.get(name).copied()
+    }
+    /// Counters which changed, the key ones first.
+    pub fn changed(&self) -> Vec<(&str, u64)>{
+        let key = KEY_COUNTERS.iter().filter_map(|name| self.0.get_key_value(*name));
+        let other = self.0.iter().filter(|(name, _)| !KEY_COUNTERS.contains(&name.as_str()));
+        key.chain(other)
+            .filter(|(_, delta)| **delta > 0)
+            .map(|(name, delta)| (name.as_str(), *delta))
+            .collect()
+    }
+}
+
+impl Display for CounterDelta{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+        let changed = self.changed();
+        if changed.is_empty(){
+            return write!(f, "no counter changed");
+        }
+        for (i, (name, delta)) in changed.into_iter().enumerate(){
+            if i > 0{
+                write!(f, ", ")?;
+            }
+            if WORD_COUNTERS.contains(&name){
+                write!(f, "{} +{} ({} bytes)", name, delta, delta * 4)?;
+            } else {
+                write!(f, "{} +{}", name, delta)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Counters of a port before a run, see `stop`.
+pub struct CounterMeter{
+    source: CounterSource,
+    before: CounterSnapshot,
+}
+
+impl CounterMeter{
+    /// Snapshots the counters of `source`. Prints why and returns `None` if
+    /// they cannot be read, a run goes on without them.
+    pub fn start(source: &CounterSource) -> Option<CounterMeter>{
+        match source.snapshot(){
+            Ok(before) => Some(CounterMeter{
+                source: source.clone(),
+                before,
+            }),
+            Err(e) => {
+                println!("counters of {} port {} not available: {}", source.device(), source.port(), e);
+                None
+            }
+        }
+    }
+    /// Change of the counters since `start`.
+    pub fn stop(&self) -> Option<CounterDelta>{
+        match self.source.snapshot(){
+            Ok(after) => Some(after.delta_since(&self.before)),
+            Err(e) => {
+                println!("counters of {} port {} not available: {}", self.source.device(), self.source.port(), e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    /// A sysfs tree below the temp dir with one port of `mlx5_0`, removed
+    /// on drop.
+    struct Fixture{
+        root: PathBuf,
+    }
+
+    impl Fixture{
+        fn new(name: &str) -> Fixture{
+            let root = std::env::temp_dir().join(format!("rdma-counters-{}-{}", std::process::id(), name));
+            let _ = std::fs::remove_dir_all(&root);
+            Fixture{
+                root,
+            }
+        }
+        fn source(&self) -> CounterSource{
+            CounterSource::new(&self.root, "mlx5_0", 1)
+        }
+        fn write(&self, dir: &str, name: &str, value: &str){
+            let dir = self.source().port_dir().join(dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join(name), value).unwrap();
+        }
+    }
+
+    impl Drop for Fixture{
+        fn drop(&mut self){
+            let _ = std::fs::remove_dir_all(&self.root);
+        }
+    }
+
+    #[test]
+    fn reads_counters_and_hw_counters(){
+        let fixture = Fixture::new("read");
+        fixture.write("counters", "port_xmit_data", "1000\n");
+        fixture.write("counters", "port_rcv_packets", "7\n");
+        fixture.write("hw_counters", "out_of_sequence", "3\n");
+        let snapshot = fixture.source().snapshot().unwrap();
+        assert_eq!(snapshot.get("port_xmit_data"), Some(1000));
+        assert_eq!(snapshot.get("port_rcv_packets"), Some(7));
+        assert_eq!(snapshot.get("out_of_sequence"), Some(3));
+    }
+
+    #[test]
+    fn skips_files_which_are_not_numbers(){
+        let fixture = Fixture::new("skip");
+        fixture.write("counters", "port_xmit_data", "12");
+        fixture.write("hw_counters", "lifespan", "not a number");
+        fixture.write("hw_counters", "empty", "");
+        let snapshot = fixture.source().snapshot().unwrap();
+        assert_eq!(snapshot.get("port_xmit_data"), Some(12));
+        assert_eq!(snapshot.get("lifespan"), None);
+        assert_eq!(snapshot.get("empty"), None);
+    }
+
+    #[test]
+    fn only_hw_counters_are_enough(){
+        let fixture = Fixture::new("hw-only");
+        fixture.write("hw_counters", "np_cnp_sent", "5");
+        assert_eq!(fixture.source().snapshot().unwrap().get("np_cnp_sent"), Some(5));
+    }
+
+    #[test]
+    fn fails_without_counters(){
+        let fixture = Fixture::new("missing");
+        std::fs::create_dir_all(fixture.source().port_dir()).unwrap();
+        let error = fixture.source().snapshot().unwrap_err();
+        assert_eq!(error.code(), -libc::ENOENT);
+    }
+
+    #[test]
+    fn delta_lists_key_counters_first_and_ignores_resets(){
+        let fixture = Fixture::new("delta");
+        fixture.write("counters", "port_xmit_data", "100");
+        fixture.write("counters", "port_rcv_data", "50");
+        fixture.write("hw_counters", "a_vendor_counter", "10");
+        fixture.write("hw_counters", "out_of_sequence", "4");
+        let meter = CounterMeter::start(&fixture.source()).unwrap();
+        fixture.write("counters", "port_xmit_data", "350");
+        fixture.write("counters", "port_rcv_data", "50");
+        fixture.write("hw_counters", "a_vendor_counter", "12");
+        fixture.write("hw_counters", "out_of_sequence", "0");
+        let delta = meter.stop().unwrap();
+        assert_eq!(delta.get("out_of_sequence"), Some(0));
+        assert_eq!(delta.changed(), vec![("port_xmit_data", 250), ("a_vendor_counter", 2)]);
+        assert_eq!(delta.to_string(), "port_xmit_data +250 (1000 bytes), a_vendor_counter +2");
+    }
+}
@@ -0,0 +1,140 @@
+use std::ptr::null_mut;
+use rdma_sys::*;
+use crate::{send_complete, timeout::get_recv_comp, CustomError, Id};
+
+/// Maximum number of zero length receives the sender keeps posted for
+/// credit updates.
+const CREDIT_RECV_DEPTH: usize = 16;
+
+/// Number of receive slots the receiver returns per credit update.
+pub fn credit_batch(credits: usize) -> usize{
+    (credits / 2).max(1)
+}
+
+/// Number of credit updates the receiver sends for a transfer of
+/// `iterations` messages with `credits` initially advertised slots. Both
+/// sides derive it the same way so that the sender posts exactly as many
+/// receives for updates as will arrive.
+pub fn credit_updates(iterations: usize, credits: usize) -> usize{
+    let returned = iterations.saturating_sub(credits.min(iterations));
+    let batch = credit_batch(credits);
+    returned.div_ceil(batch)
+}
+
+/// Sends a zero length message carrying `credits` in its immediate data.
+pub fn post_credit_update(id: &Id, credits: u32) -> anyhow::Result<(), CustomError>{
+    let mut wr = unsafe { std::mem::zeroed::<ibv_send_wr>() };
+    wr.opcode = ibv_wr_opcode::IBV_WR_SEND_WITH_IMM;
+    wr.send_flags = ibv_send_flags::IBV_SEND_SIGNALED.0;
+    wr.imm_data_invalidated_rkey_union.imm_data = credits.to_be();
+    let mut bad_wr = null_mut();
+    let ret = unsafe { ibv_post_send((*id.id()).qp, &mut wr, &mut bad_wr) };
+    if ret != 0 {
+        return Err(CustomError::new("ibv_post_send".to_string(), ret));
+    }
+    unsafe { send_complete(id.clone(), 1, ibv_wc_opcode::IBV_WC_SEND)? };
+    Ok(())
+}
+
+/// Posts a zero length receive for one credit update.
+pub fn post_credit_recv(id: &Id) -> anyhow::Result<(), CustomError>{
+    let ret = unsafe { rdma_post_recvv(id.id(), null_mut(), null_mut(), 0) };
+    if ret != 0 {
+        return Err(CustomError::new("rdma_post_recvv".to_string(), ret));
+    }
+    Ok(())
+}
+
+/// Blocks until the next credit update arrives and returns the granted
+/// credits.
+pub fn wait_credit_update(id: &Id) -> anyhow::Result<u32, CustomError>{
+    let mut wc = unsafe { std::mem::zeroed::<ibv_wc>() };
+    let mut ret = 0;
+    while ret == 0 {
+        ret = get_recv_comp(id, &mut wc)?;
+    }
+    if ret < 0 {
+        return Err(CustomError::new("rdma_get_recv_comp".to_string(), ret));
+    }
+    if wc.status != ibv_wc_status::IBV_WC_SUCCESS || wc.opcode != ibv_wc_opcode::IBV_WC_RECV || wc.wc_flags & ibv_wc_flags::IBV_WC_WITH_IMM.0 == 0{
+        return Err(CustomError::new(format!("wc status/opcode {}/{} wrong, expected credit update", wc.status, wc.opcode), -1));
+    }
+    Ok(u32::from_be(unsafe { wc.imm_data_invalidated_rkey_union.imm_data }))
+}
+
+/// Sender side credit accounting. Keeps the receives for credit updates
+/// posted and never hands out more credits than the receiver advertised.
+pub struct CreditSender{
+    available: usize,
+    expected_updates: usize,
+    posted_updates: usize,
+}
+
+impl CreditSender{
+    pub fn new(id: &Id, iterations: usize, credits: usize) -> anyhow::Result<CreditSender, CustomError>{
+        let mut credit_sender = CreditSender{
+            available: credits.min(iterations),
+            expected_updates: credit_updates(iterations, credits),
+            posted_updates: 0,
+        };
+        while credit_sender.posted_updates < credit_sender.expected_updates.min(CREDIT_RECV_DEPTH){
+            post_credit_recv(id)?;
+            credit_sender.posted_updates += 1;
+        }
+        Ok(credit_sender)
+    }
+    /// Takes one credit, waiting for credit updates while none is left.
+    pub fn acquire(&mut self, id: &Id) -> anyhow::Result<(), CustomError>{
+        while self.available == 0{
+            self.available += wait_credit_update(id)? as usize;
+            if self.posted_updates < self.expected_updates{
+                post_credit_recv(id)?;
+                self.posted_updates += 1;
+            }
+        }
+        self.available -= 1;
+        Ok(())
+    }
+}
+
+/// Receiver side credit accounting. Counts reposted receive slots and
+/// returns them to the sender in batches of `credit_batch` slots.
+pub struct CreditReceiver{
+    iterations: usize,
+    credits: usize,
+    posted: usize,
+    unreturned: usize,
+    batch: usize,
+}
+
+impl CreditReceiver{
+    pub fn new(iterations: usize, credits: usize) -> CreditReceiver{
+        let credits = credits.min(iterations);
+        CreditReceiver{
+            iterations,
+            credits,
+            posted: credits,
+            unreturned: 0,
+            batch: credit_batch(credits),
+        }
+    }
+    /// Initially posted receive slots, to be advertised to the sender.
+    pub fn credits(&self) -> usize{
+        self.credits
+    }
+    /// Whether another receive has to be posted to cover the transfer.
+    pub fn needs_repost(&self) -> bool{
+        self.posted < self.iterations
+    }
+    /// Records a reposted receive slot and sends the credit updates which
+    /// became due.
+    pub fn reposted(&mut self, id: &Id) -> anyhow::Result<(), CustomError>{
+        self.posted += 1;
+        self.unreturned += 1;
+        if self.unreturned == self.batch || (self.posted == self.iterations && self.unreturned > 0){
+            post_credit_update(id, self.unreturned as u32)?;
+            self.unreturned = 0;
+        }
+        Ok(())
+    }
+}
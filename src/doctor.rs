@@ -0,0 +1,356 @@
+use std::{ffi::{CStr, CString}, fmt::Display, net::{IpAddr, Ipv6Addr}, path::{Path, PathBuf}, ptr::null_mut};
+use rdma_sys::*;
+use crate::{Id, alloc::memlock_limit};
+
+/// Kernel modules user space needs for verbs and the CM.
+const MODULES: [&str; 3] = ["ib_uverbs", "rdma_cm", "rdma_ucm"];
+/// Character devices of the verbs and CM modules.
+const DEV_DIR: &str = "/dev/infiniband";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CheckStatus{
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl Display for CheckStatus{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+        match self{
+            CheckStatus::Pass => write!(f, "PASS"),
+            CheckStatus::Warn => write!(f, "WARN"),
+            CheckStatus::Fail => write!(f, "FAIL"),
+        }
+    }
+}
+
+/// Outcome of one prerequisite, with a hint how to fix it unless it passed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Check{
+    pub name: String,
+    pub status: CheckStatus,
+    pub detail: String,
+    pub hint: Option<String>,
+}
+
+impl Check{
+    pub fn pass(name: impl Into<String>, detail: impl Into<String>) -> Check{
+        Check{
+            name: name.into(),
+            status: CheckStatus::Pass,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+    pub fn warn(name: impl Into<String>, detail: impl Into<String>, hint: impl Into<String>) -> Check{
+        Check{
+            name: name.into(),
+            status: CheckStatus::Warn,
+            detail: detail.into(),
+            hint: Some(hint.into()),
+        }
+    }
+    pub fn fail(name: impl Into<String>, detail: impl Into<String>, hint: impl Into<String>) -> Check{
+        Check{
+            name: name.into(),
+            status: CheckStatus::Fail,
+            detail: detail.into(),
+            hint: Some(hint.into()),
+        }
+    }
+}
+
+impl Display for Check{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+        write!(f, "[{}] {}: {}", self.status, self.name, self.detail)?;
+        if let Some(hint) = self.hint.as_ref(){
+            write!(f, "\n       hint: {}", hint)?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks in the order they ran.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DoctorReport{
+    pub checks: Vec<Check>,
+}
+
+impl DoctorReport{
+    pub fn push(&mut self, check: Check){
+        self.checks.push(check);
+    }
+    pub fn count(&self, status: CheckStatus) -> usize{
+        self.checks.iter().filter(|check| check.status == status).count()
+    }
+    pub fn passed(&self) -> bool{
+        self.count(CheckStatus::Fail) == 0
+    }
+}
+
+impl Display for DoctorReport{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+        for check in self.checks.iter(){
+            writeln!(f, "{}", check)?;
+        }
+        write!(f, "{} checks: {} passed, {} warnings, {} failed", self.checks.len(),
+            self.count(CheckStatus::Pass), self.count(CheckStatus::Warn), self.count(CheckStatus::Fail))
+    }
+}
+
+/// Checks the prerequisites of RDMA on this host, which otherwise only show
+/// up as a bare error code of `rdma_getaddrinfo` or `rdma_create_ep`. Ports
+/// and GIDs are read below a sysfs root which is configurable like the one
+/// of the counters.
+pub struct Doctor{
+    sysfs_root: PathBuf,
+}
+
+impl Doctor{
+    pub fn new(sysfs_root: impl Into<PathBuf>) -> Doctor{
+        Doctor{
+            sysfs_root: sysfs_root.into(),
+        }
+    }
+    /// Runs all checks which need nothing but the host: kernel modules,
+    /// device files, devices, their ports and GIDs, and whether the memlock
+    /// limit allows registering `memlock_required` bytes.
+    pub fn check_host(&self, memlock_required: usize) -> DoctorReport{
+        let mut report = DoctorReport::default();
+        for module in MODULES{
+            report.push(check_module(module));
+        }
+        report.push(check_dev_dir());
+        let (check, devices) = check_devices();
+        report.push(check);
+        for device in devices.iter(){
+            for check in self.check_ports(device){
+                report.push(check);
+            }
+        }
+        report.push(check_memlock(memlock_required));
+        report
+    }
+    /// State and GIDs of every port of `device`.
+    pub fn check_ports(&self, device: &str) -> Vec<Check>{
+        let ports_dir = self.sysfs_root.join(device).join("ports");
+        let mut ports = match std::fs::read_dir(&ports_dir){
+            Ok(entries) => entries.flatten().filter_map(|entry| entry.file_name().to_str()?.parse::<u8>().ok()).collect::<Vec<u8>>(),
+            Err(e) => return vec![Check::fail(format!("{} ports", device), format!("{}: {}", ports_dir.display(), e),
+                "check that sysfs is mounted and --sysfs-root points at the RDMA devices")],
+        };
+        ports.sort_unstable();
+        let mut checks = Vec::new();
+        for port in ports{
+            let port_dir = ports_dir.join(port.to_string());
+            checks.push(check_port_state(device, port, &port_dir));
+            checks.push(check_gids(device, port, &port_dir));
+        }
+        checks
+    }
+}
+
+fn read_trimmed(path: &Path) -> Option<String>{
+    std::fs::read_to_string(path).ok().map(|value| value.trim().to_string())
+}
+
+/// Modules show up below `/sys/module` whether they are loaded or built in.
+fn check_module(module: &str) -> Check{
+    let name = format!("kernel module {}", module);
+    if Path::new("/sys/module").join(module).exists(){
+        return Check::pass(name, "loaded");
+    }
+    Check::fail(name, "not loaded", format!("modprobe {}", module))
+}
+
+fn check_dev_dir() -> Check{
+    let name = DEV_DIR.to_string();
+    let entries = match std::fs::read_dir(DEV_DIR){
+        Ok(entries) => entries.flatten().map(|entry| entry.file_name().to_string_lossy().into_owned()).collect::<Vec<String>>(),
+        Err(e) => return Check::fail(name, e.to_string(), "modprobe ib_uverbs rdma_ucm, which create the device files"),
+    };
+    if !entries.iter().any(|entry| entry == "rdma_cm"){
+        return Check::fail(name, "no rdma_cm device file", "modprobe rdma_ucm");
+    }
+    if !entries.iter().any(|entry| entry.starts_with("uverbs")){
+        return Check::fail(name, "no uverbs device files", "modprobe ib_uverbs and the driver of the NIC");
+    }
+    let denied = entries.iter()
+        .filter(|entry| *entry == "rdma_cm" || entry.starts_with("uverbs"))
+        .filter(|entry| {
+            let path = CString::new(format!("{}/{}", DEV_DIR, entry)).unwrap();
+            let ret = unsafe { libc::access(path.as_ptr(), libc::R_OK | libc::W_OK) };
+            ret != 0
+        })
+        .cloned()
+        .collect::<Vec<String>>();
+    if !denied.is_empty(){
+        return Check::fail(name, format!("no read/write access to {}", denied.join(", ")),
+            "add the user to the group owning the device files (usually rdma) or install the udev rules of rdma-core");
+    }
+    Check::pass(name, format!("{} device files accessible", entries.len()))
+}
+
+/// Devices libibverbs sees, which takes the driver and its rdma-core provider.
+fn check_devices() -> (Check, Vec<String>){
+    let name = "rdma devices";
+    let mut num_devices = 0;
+    let list = unsafe { ibv_get_device_list(&mut num_devices) };
+    if list.is_null(){
+        let error = std::io::Error::last_os_error();
+        return (Check::fail(name, format!("ibv_get_device_list: {}", error), "modprobe ib_uverbs"), Vec::new());
+    }
+    let devices = (0..num_devices.max(0) as usize).filter_map(|i| {
+        let name = unsafe { ibv_get_device_name(*list.add(i)) };
+        if name.is_null(){
+            return None;
+        }
+        Some(unsafe { CStr::from_ptr(name) }.to_string_lossy().into_owned())
+    }).collect::<Vec<String>>();
+    unsafe { ibv_free_device_list(list) };
+    if devices.is_empty(){
+        return (Check::fail(name, "no devices found",
+            "load the driver of the NIC and install its rdma-core provider, or add a soft device with `rdma link add rxe0 type rxe netdev <netdev>`"), devices);
+    }
+    (Check::pass(name, devices.join(", ")), devices)
+}
+
+fn check_port_state(device: &str, port: u8, port_dir: &Path) -> Check{
+    let name = format!("{} port {} state", device, port);
+    let state = match read_trimmed(&port_dir.join("state")){
+        Some(state) => state,
+        None => return Check::fail(name, format!("cannot read {}", port_dir.join("state").display()), "check that sysfs is mounted"),
+    };
+    let link_layer = read_trimmed(&port_dir.join("link_layer")).unwrap_or_else(|| "unknown".to_string());
+    let phys_state = read_trimmed(&port_dir.join("phys_state")).unwrap_or_else(|| "unknown".to_string());
+    let detail = format!("{}, physical {}, link layer {}", state, phys_state, link_layer);
+    // the files hold the number and the name, like "4: ACTIVE"
+    if state.ends_with("ACTIVE"){
+        return Check::pass(name, detail);
+    }
+    let hint = if state.ends_with("INIT") && link_layer == "InfiniBand"{
+        "the link is up but no subnet manager configured it, run opensm on the fabric"
+    } else {
+        "check the cable and bring the netdev of the port up with `ip link set <netdev> up`"
+    };
+    Check::fail(name, detail, hint)
+}
+
+/// Parses a GID as sysfs prints it, eight colon separated groups of hex.
+fn parse_gid(gid: &str) -> Option<Ipv6Addr>{
+    let hex = gid.replace(':', "");
+    if hex.len() != 32{
+        return None;
+    }
+    u128::from_str_radix(&hex, 16).ok().map(Ipv6Addr::from)
+}
+
+fn gid_address(gid: Ipv6Addr) -> IpAddr{
+    match gid.to_ipv4_mapped(){
+        Some(ipv4) => IpAddr::V4(ipv4),
+        None => IpAddr::V6(gid),
+    }
+}
+
+fn check_gids(device: &str, port: u8, port_dir: &Path) -> Check{
+    let name = format!("{} port {} gids", device, port);
+    let gids_dir = port_dir.join("gids");
+    let entries = match std::fs::read_dir(&gids_dir){
+        Ok(entries) => entries,
+        Err(e) => return Check::fail(name, format!("{}: {}", gids_dir.display(), e), "check that sysfs is mounted"),
+    };
+    let mut gids = entries.flatten()
+        .filter_map(|entry| {
+            let index = entry.file_name().to_str()?.parse::<u32>().ok()?;
+            let gid = parse_gid(&read_trimmed(&entry.path())?)?;
+            Some((index, gid))
+        })
+        .filter(|(_, gid)| !gid.is_unspecified())
+        .collect::<Vec<(u32, Ipv6Addr)>>();
+    gids.sort_unstable();
+    if gids.is_empty(){
+        return Check::fail(name, "no valid gid", "assign an IP address to the netdev of the port, RoCE derives its GIDs from them");
+    }
+    let link_layer = read_trimmed(&port_dir.join("link_layer")).unwrap_or_default();
+    let listed = gids.iter().map(|(index, gid)| {
+        match read_trimmed(&port_dir.join("gid_attrs").join("types").join(index.to_string())){
+            Some(gid_type) => format!("{} {} ({})", index, gid_address(*gid), gid_type),
+            None => format!("{} {}", index, gid_address(*gid)),
+        }
+    }).collect::<Vec<String>>().join(", ");
+    // the link-local GID always exists, RoCE needs one of a routable address
+    let link_local = |gid: &Ipv6Addr| gid.segments()[0] == 0xfe80;
+    if link_layer == "Ethernet" && gids.iter().all(|(_, gid)| link_local(gid)){
+        return Check::warn(name, format!("only link-local gids: {}", listed),
+            "assign the IP address the benchmark uses to the netdev of the port");
+    }
+    Check::pass(name, listed)
+}
+
+fn check_memlock(required: usize) -> Check{
+    let name = "memlock limit";
+    let hint = "raise RLIMIT_MEMLOCK with `ulimit -l unlimited`, a memlock entry in /etc/security/limits.conf or LimitMEMLOCK=infinity for systemd services";
+    match memlock_limit(){
+        None => Check::pass(name, "unlimited"),
+        Some(limit) if limit < required => Check::fail(name, format!("{} bytes, {} needed", limit, required), hint),
+        Some(limit) => Check::pass(name, format!("{} bytes", limit)),
+    }
+}
+
+/// Resolves `node` like the CM does before it connects or listens and returns
+/// the device the address resolves to, `None` if none owns it.
+fn resolve_device(node: &str, passive: bool) -> Result<Option<String>, String>{
+    let c_node = CString::new(node).map_err(|e| e.to_string())?;
+    let mut hints = unsafe { std::mem::zeroed::<rdma_addrinfo>() };
+    if passive{
+        hints.ai_flags = RAI_PASSIVE as i32;
+    }
+    hints.ai_port_space = rdma_port_space::RDMA_PS_TCP as i32;
+    let mut res: *mut rdma_addrinfo = null_mut();
+    let ret = unsafe { rdma_getaddrinfo(c_node.as_ptr(), null_mut(), &hints, &mut res) };
+    if ret != 0{
+        return Err(format!("rdma_getaddrinfo {}: {} ({})", node, ret, std::io::Error::last_os_error()));
+    }
+    let mut id: *mut rdma_cm_id = null_mut();
+    let ret = unsafe { rdma_create_ep(&mut id, res, null_mut(), null_mut()) };
+    unsafe { rdma_freeaddrinfo(res) };
+    if ret != 0{
+        return Err(format!("rdma_create_ep {}: {} ({})", node, ret, std::io::Error::last_os_error()));
+    }
+    let device = Id::new(id).device_name();
+    unsafe { rdma_destroy_ep(id) };
+    Ok(device)
+}
+
+/// Whether an RDMA device owns the local address `address`, which a server
+/// listens on.
+pub fn check_local_address(address: &str) -> Check{
+    let name = format!("local address {}", address);
+    let hint = "listen on an address of the netdev of an RDMA device, `rdma link` lists them";
+    match resolve_device(address, true){
+        Ok(Some(device)) => Check::pass(name, format!("owned by {}", device)),
+        Ok(None) => Check::fail(name, "no RDMA device owns it", hint),
+        Err(e) => Check::fail(name, e, hint),
+    }
+}
+
+/// Whether the TCP port `port` on `address` is free to listen on.
+pub fn check_listen_port(address: &str, port: u16) -> Check{
+    let name = format!("tcp port {} on {}", port, address);
+    match std::net::TcpListener::bind((address, port)){
+        Ok(_) => Check::pass(name, "free"),
+        Err(e) => Check::fail(name, e.to_string(), "pick another port or stop the process listening on it, `ss -ltnp` shows it"),
+    }
+}
+
+/// Whether the CM resolves a route to `server` and which device it leaves
+/// through.
+pub fn check_route(server: IpAddr) -> Check{
+    let name = format!("route to {}", server);
+    let hint = "the server has to be reachable through the netdev of an RDMA device, check `ip route get` and `rdma link`";
+    match resolve_device(&server.to_string(), false){
+        Ok(Some(device)) => Check::pass(name, format!("through {}", device)),
+        Ok(None) => Check::fail(name, "not through an RDMA device", hint),
+        Err(e) => Check::fail(name, e, hint),
+    }
+}
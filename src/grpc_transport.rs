@@ -0,0 +1,112 @@
+use std::{future::Future, io, net::{IpAddr, SocketAddr}, pin::Pin, sync::Arc, task::{Context, Poll}, time::Duration};
+use futures::Stream;
+use tonic::transport::{server::Connected, Uri};
+use tower_service::Service;
+use crate::stream::{RdmaListener, RdmaStream, StreamConfig};
+
+/// Time a connecting client gets to complete the handshake, so a stalled
+/// one does not hold up the connections behind it.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Addresses of a gRPC connection over RDMA, available to services through
+/// the request extensions.
+#[derive(Debug, Clone)]
+pub struct RdmaConnectInfo{
+    pub local_addr: Option<SocketAddr>,
+    pub remote_addr: Option<SocketAddr>,
+}
+
+impl Connected for RdmaStream{
+    type ConnectInfo = RdmaConnectInfo;
+
+    fn connect_info(&self) -> Self::ConnectInfo{
+        RdmaConnectInfo{
+            local_addr: self.local_addr(),
+            remote_addr: self.peer_addr(),
+        }
+    }
+}
+
+/// Connects tonic channels over RDMA CM instead of TCP, for use with
+/// `Endpoint::connect_with_connector`. The host and port of the endpoint
+/// URI are the address of the `RdmaListener` of the server.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RdmaConnector{
+    config: StreamConfig,
+}
+
+impl RdmaConnector{
+    pub fn new(config: StreamConfig) -> RdmaConnector{
+        RdmaConnector{
+            config,
+        }
+    }
+}
+
+fn uri_addr(uri: &Uri) -> io::Result<SocketAddr>{
+    let host = uri.host().unwrap_or_default().trim_start_matches('[').trim_end_matches(']');
+    let ip = host.parse::<IpAddr>()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("{}: host must be an IP address", uri)))?;
+    match uri.port_u16(){
+        Some(port) => Ok(SocketAddr::new(ip, port)),
+        None => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{}: port missing", uri))),
+    }
+}
+
+impl Service<Uri> for RdmaConnector{
+    type Response = RdmaStream;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<RdmaStream>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>>{
+        Poll::Ready(Ok(()))
+    }
+    fn call(&mut self, uri: Uri) -> Self::Future{
+        let config = self.config;
+        Box::pin(async move{
+            let addr = uri_addr(&uri)?;
+            RdmaStream::connect_with_config(addr, config).await
+        })
+    }
+}
+
+/// What the accept loop of `incoming` waited for.
+enum Accepted{
+    Request(io::Result<crate::Id>),
+    Established(io::Result<Box<RdmaStream>>),
+}
+
+/// Streams the connections accepted by `listener`, for
+/// `Router::serve_with_incoming_shutdown`. Every connection is established
+/// on a task of its own, so one which stalls during the handshake does not
+/// hold up the others. Its errors are handed to `on_error` and it is
+/// skipped, as tonic would stop serving on the first error of the stream,
+/// which only ends with an error of the listener itself.
+pub fn incoming<E: Fn(io::Error) + Send + 'static>(listener: RdmaListener, on_error: E) -> impl Stream<Item = io::Result<RdmaStream>>{
+    let listener = Arc::new(listener);
+    async_stream::stream!{
+        let mut establishing = tokio::task::JoinSet::new();
+        loop {
+            let accepted = tokio::select!{
+                request = listener.get_request() => Accepted::Request(request),
+                Some(established) = establishing.join_next() => Accepted::Established(established
+                    .unwrap_or_else(|e| Err(io::Error::other(format!("handshake task failed: {}", e))))
+                    .map(Box::new)),
+            };
+            match accepted{
+                Accepted::Request(Ok(id)) => {
+                    let listener = listener.clone();
+                    establishing.spawn(async move{
+                        listener.establish(id, Some(HANDSHAKE_TIMEOUT)).await
+                    });
+                },
+                Accepted::Request(Err(e)) => {
+                    yield Err(e);
+                    break;
+                },
+                Accepted::Established(Ok(stream)) => yield Ok(*stream),
+                Accepted::Established(Err(e)) => on_error(e),
+            }
+        }
+    }
+}
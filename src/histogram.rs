@@ -0,0 +1,187 @@
+use std::{io::Write, time::Duration};
+
+/// Values below `2^SUB_BUCKET_BITS` are recorded exactly, larger ones with
+/// `2^(SUB_BUCKET_BITS - 1)` buckets per power of two, i.e. within 0.8%.
+const SUB_BUCKET_BITS: u32 = 8;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+const HALF_SUB_BUCKETS: u64 = SUB_BUCKETS / 2;
+const BUCKETS: usize = (SUB_BUCKETS + (64 - SUB_BUCKET_BITS as u64) * HALF_SUB_BUCKETS) as usize;
+
+fn index(value: u64) -> usize{
+    if value < SUB_BUCKETS{
+        return value as usize;
+    }
+    let exponent = 63 - value.leading_zeros();
+    let sub_bucket = value >> (exponent - (SUB_BUCKET_BITS - 1));
+    (SUB_BUCKETS + (exponent - SUB_BUCKET_BITS) as u64 * HALF_SUB_BUCKETS + (sub_bucket - HALF_SUB_BUCKETS)) as usize
+}
+
+/// Smallest value recorded in the bucket at `index`.
+fn lowest_value(index: usize) -> u64{
+    let index = index as u64;
+    if index < SUB_BUCKETS{
+        return index;
+    }
+    let octave = (index - SUB_BUCKETS) / HALF_SUB_BUCKETS;
+    let sub_bucket = (index - SUB_BUCKETS) % HALF_SUB_BUCKETS + HALF_SUB_BUCKETS;
+    sub_bucket << (octave + 1)
+}
+
+/// Largest value recorded in the bucket at `index`, which is what
+/// percentiles report.
+fn highest_value(index: usize) -> u64{
+    if index + 1 >= BUCKETS{
+        return u64::MAX;
+    }
+    lowest_value(index + 1) - 1
+}
+
+/// Log-linear histogram of latencies in nanoseconds, constant size however
+/// many values are recorded.
+#[derive(Clone, Debug)]
+pub struct Histogram{
+    counts: Vec<u64>,
+    count: u64,
+    sum: u128,
+    min: u64,
+    max: u64,
+}
+
+impl Default for Histogram{
+    fn default() -> Self{
+        Histogram::new()
+    }
+}
+
+impl Histogram{
+    pub fn new() -> Histogram{
+        Histogram{
+            counts: vec![0; BUCKETS],
+            count: 0,
+            sum: 0,
+            min: u64::MAX,
+            max: 0,
+        }
+    }
+    pub fn record(&mut self, latency: Duration){
+        let value = latency.as_nanos().min(u64::MAX as u128) as u64;
+        self.counts[index(value)] += 1;
+        self.count += 1;
+        self.sum += value as u128;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+    pub fn merge(&mut self, other: &Histogram){
+        for (count, other_count) in self.counts.iter_mut().zip(other.counts.iter()){
+            *count += other_count;
+        }
+        self.count += other.count;
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+    pub fn count(&self) -> u64{
+        self.count
+    }
+    pub fn min(&self) -> Duration{
+        if self.count == 0{
+            return Duration::ZERO;
+        }
+        Duration::from_nanos(self.min)
+    }
+    pub fn max(&self) -> Duration{
+        Duration::from_nanos(self.max)
+    }
+    pub fn mean(&self) -> Duration{
+        if self.count == 0{
+            return Duration::ZERO;
+        }
+        Duration::from_nanos((self.sum / self.count as u128) as u64)
+    }
+    /// Latency at `quantile` between 0 and 1, at most the recorded maximum.
+    pub fn percentile(&self, quantile: f64) -> Duration{
+        if self.count == 0{
+            return Duration::ZERO;
+        }
+        let target = ((quantile.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate(){
+            seen += count;
+            if seen >= target{
+                return Duration::from_nanos(highest_value(index).min(self.max));
+            }
+        }
+        Duration::from_nanos(self.max)
+    }
+    /// Writes the distribution as CSV rows `label,latency_ns,percentile,count`,
+    /// one per non-empty bucket, for plotting.
+    pub fn write_csv<W: Write>(&self, writer: &mut W, label: &str) -> std::io::Result<()>{
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate(){
+            if *count == 0{
+                continue;
+            }
+            seen += count;
+            let percentile = seen as f64 * 100.0 / self.count as f64;
+            writeln!(writer, "{},{},{:.6},{}", label, highest_value(index).min(self.max), percentile, count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn empty_histogram_reports_zero(){
+        let histogram = Histogram::new();
+        assert_eq!(histogram.percentile(0.5), Duration::ZERO);
+        assert_eq!(histogram.min(), Duration::ZERO);
+        assert_eq!(histogram.mean(), Duration::ZERO);
+    }
+
+    #[test]
+    fn small_values_are_exact(){
+        let mut histogram = Histogram::new();
+        for nanos in 1..=100{
+            histogram.record(Duration::from_nanos(nanos));
+        }
+        assert_eq!(histogram.percentile(0.0), Duration::from_nanos(1));
+        assert_eq!(histogram.percentile(0.5), Duration::from_nanos(50));
+        assert_eq!(histogram.percentile(0.99), Duration::from_nanos(99));
+        assert_eq!(histogram.percentile(1.0), Duration::from_nanos(100));
+        assert_eq!(histogram.mean(), Duration::from_nanos(50));
+    }
+
+    #[test]
+    fn large_values_stay_within_bucket_precision(){
+        let mut histogram = Histogram::new();
+        for micros in 1..=1000{
+            histogram.record(Duration::from_micros(micros));
+        }
+        for (quantile, expected) in [(0.5, 500_000.0), (0.9, 900_000.0), (0.999, 999_000.0)]{
+            let nanos = histogram.percentile(quantile).as_nanos() as f64;
+            assert!(nanos >= expected && nanos <= expected * 1.008, "p{} is {}ns", quantile, nanos);
+        }
+        assert_eq!(histogram.percentile(1.0), Duration::from_micros(1000));
+    }
+
+    #[test]
+    fn merge_combines_counts(){
+        let mut low = Histogram::new();
+        let mut high = Histogram::new();
+        for _ in 0..90{
+            low.record(Duration::from_nanos(10));
+        }
+        for _ in 0..10{
+            high.record(Duration::from_nanos(200));
+        }
+        low.merge(&high);
+        assert_eq!(low.count(), 100);
+        assert_eq!(low.percentile(0.9), Duration::from_nanos(10));
+        assert_eq!(low.percentile(0.91), Duration::from_nanos(200));
+        assert_eq!(low.min(), Duration::from_nanos(10));
+        assert_eq!(low.max(), Duration::from_nanos(200));
+    }
+}
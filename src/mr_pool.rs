@@ -0,0 +1,470 @@
+use std::{collections::HashMap, ops::{Deref, DerefMut}, sync::Mutex};
+use libc::{c_int, c_void};
+use rdma_sys::*;
+use crate::{alloc::AllocOptions, CustomError, Data, Id, MrObject, MrRegister, Operation};
+
+/// Smallest size class handed out by the buffer pool.
+const MIN_SIZE_CLASS: usize = 4096;
+/// Pages of the allocation strategy the pool caches at least, so that a
+/// cap meant for small pages does not turn away every 1 GiB page.
+const MIN_CACHED_PAGES: usize = 2;
+
+/// Rounds `size` up to the size class of the buffer pool. Size classes are
+/// never smaller than one page of `page_size`.
+pub fn size_class(size: usize, page_size: usize) -> usize{
+    size.max(MIN_SIZE_CLASS).max(page_size).next_power_of_two()
+}
+
+fn register(id: &Id, addr: *mut c_void, length: usize, access: c_int) -> anyhow::Result<*mut ibv_mr, CustomError>{
+    if id.id().is_null(){
+        return Err(CustomError::new("id is null".to_string(), -1));
+    }
+    let mr = unsafe { ibv_reg_mr((*id.id()).pd, addr, length, access) };
+    if mr.is_null(){
+        return Err(CustomError::new("ibv_reg_mr".to_string(), -1));
+    }
+    Ok(mr)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct PoolKey{
+    pd: usize,
+    size_class: usize,
+    access: c_int,
+}
+
+/// Pool of registered `Data` buffers keyed by protection domain, size class
+/// and access flags.
+///
+/// Buffers are allocated according to `alloc`, registered once with their
+/// full size class and handed out truncated to the requested size. Returned
+/// buffers are kept registered for the next checkout until
+/// `max_cached_bytes`, but at least two pages of the allocation strategy,
+/// are exceeded.
+pub struct BufferPool{
+    free: HashMap<PoolKey, Vec<Data>>,
+    cached_bytes: usize,
+    checked_out_bytes: usize,
+    max_cached_bytes: usize,
+    alloc: AllocOptions,
+}
+
+unsafe impl Send for BufferPool{}
+
+impl BufferPool{
+    pub fn new(max_cached_bytes: usize, alloc: AllocOptions) -> BufferPool{
+        BufferPool{
+            free: HashMap::new(),
+            cached_bytes: 0,
+            checked_out_bytes: 0,
+            max_cached_bytes: max_cached_bytes.max(MIN_CACHED_PAGES * alloc.strategy.page_size()),
+            alloc,
+        }
+    }
+    /// Hands out a registered buffer of `size` bytes usable for `operation`
+    /// on `id`, registering a new one only if none of the class is free.
+    pub fn checkout(&mut self, id: &Id, size: usize, operation: Operation) -> anyhow::Result<Data, CustomError>{
+        let key = self.key(id, size, operation);
+        let cached = self.free.get_mut(&key).and_then(|free| free.pop());
+        let from_cache = cached.is_some();
+        let mut data = match cached{
+            Some(data) => data,
+            None => {
+                let mut data = Data::with_strategy(key.size_class, self.alloc.strategy, self.alloc.numa_node(id))?;
+                let mr = register(id, data.addr(), key.size_class, key.access)?;
+                data.set_mr(mr);
+                data.access = key.access;
+                data
+            }
+        };
+        data.set_len(size);
+        self.account_checkout(key.size_class, from_cache);
+        Ok(data)
+    }
+    /// Accounts a checkout of a buffer of `size_class` bytes, taken from the
+    /// cached ones if `from_cache`.
+    fn account_checkout(&mut self, size_class: usize, from_cache: bool){
+        if from_cache{
+            self.cached_bytes -= size_class;
+        }
+        self.checked_out_bytes += size_class;
+    }
+    /// Accounts the checkin of a buffer of `size_class` bytes and tells
+    /// whether it stays cached, it is deregistered otherwise.
+    fn account_checkin(&mut self, size_class: usize) -> bool{
+        self.checked_out_bytes = self.checked_out_bytes.saturating_sub(size_class);
+        if self.cached_bytes + size_class > self.max_cached_bytes{
+            return false;
+        }
+        self.cached_bytes += size_class;
+        true
+    }
+    fn key(&self, id: &Id, size: usize, operation: Operation) -> PoolKey{
+        PoolKey{
+            pd: unsafe { (*id.id()).pd } as usize,
+            size_class: self.registered_size(size),
+            access: operation.access_flags(),
+        }
+    }
+    /// Bytes registered for a checkout of `size` bytes.
+    pub fn registered_size(&self, size: usize) -> usize{
+        size_class(size, self.alloc.strategy.page_size())
+    }
+    /// Bytes a checkout of `size` bytes registers on top of the ones the
+    /// pool has, 0 if a cached buffer is handed out.
+    pub fn new_registration(&self, id: &Id, size: usize, operation: Operation) -> usize{
+        let key = self.key(id, size, operation);
+        match self.free.get(&key).is_some_and(|free| !free.is_empty()){
+            true => 0,
+            false => key.size_class,
+        }
+    }
+    /// Takes back a buffer handed out by `checkout`. The registration is kept
+    /// unless the pool already caches `max_cached_bytes`.
+    pub fn checkin(&mut self, mut data: Data){
+        let mr = data.mr();
+        if mr.is_null(){
+            return;
+        }
+        let key = PoolKey{
+            pd: unsafe { (*mr).pd } as usize,
+            size_class: unsafe { (*mr).length },
+            access: data.access,
+        };
+        if !self.account_checkin(key.size_class){
+            unsafe { ibv_dereg_mr(mr) };
+            return;
+        }
+        data.set_len(key.size_class);
+        self.free.entry(key).or_default().push(data);
+    }
+    /// Checks a buffer out of a pool shared behind a mutex, which takes it
+    /// back when the returned guard is dropped, on error paths as well.
+    pub fn checkout_shared<'a>(pool: &'a Mutex<BufferPool>, id: &Id, size: usize, operation: Operation) -> anyhow::Result<PooledData<'a>, CustomError>{
+        let data = pool.lock().unwrap().checkout(id, size, operation)?;
+        Ok(PooledData{
+            pool,
+            data: Some(data),
+        })
+    }
+    pub fn cached_bytes(&self) -> usize{
+        self.cached_bytes
+    }
+    /// Bytes registered by the pool, cached and checked out.
+    pub fn registered_bytes(&self) -> usize{
+        self.cached_bytes + self.checked_out_bytes
+    }
+    /// Deregisters and frees all cached buffers.
+    pub fn clear(&mut self){
+        for data in self.free.values().flatten(){
+            unsafe { ibv_dereg_mr(data.mr()) };
+        }
+        self.free.clear();
+        self.cached_bytes = 0;
+    }
+}
+
+impl Drop for BufferPool{
+    fn drop(&mut self){
+        self.clear();
+    }
+}
+
+/// A buffer of a shared `BufferPool`, checked back in on drop.
+pub struct PooledData<'a>{
+    pool: &'a Mutex<BufferPool>,
+    data: Option<Data>,
+}
+
+impl Deref for PooledData<'_>{
+    type Target = Data;
+    fn deref(&self) -> &Data{
+        self.data.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledData<'_>{
+    fn deref_mut(&mut self) -> &mut Data{
+        self.data.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledData<'_>{
+    fn drop(&mut self){
+        if let Some(data) = self.data.take(){
+            self.pool.lock().unwrap().checkin(data);
+        }
+    }
+}
+
+struct CacheEntry{
+    mr: *mut ibv_mr,
+    pd: usize,
+    start: usize,
+    end: usize,
+    access: c_int,
+    refs: usize,
+    last_used: u64,
+}
+
+/// Registration cache for caller owned memory, keyed by address range.
+///
+/// A registration is reused when it covers the requested range with at
+/// least the requested access flags. Unreferenced registrations are evicted
+/// least recently used first once more than `max_registered_bytes` or
+/// `max_entries` are registered. Memory must stay mapped while it is cached,
+/// call `invalidate` before freeing it.
+pub struct MrCache{
+    entries: Vec<CacheEntry>,
+    registered_bytes: usize,
+    max_registered_bytes: usize,
+    max_entries: usize,
+    tick: u64,
+}
+
+unsafe impl Send for MrCache{}
+
+impl MrCache{
+    pub fn new(max_registered_bytes: usize, max_entries: usize) -> MrCache{
+        MrCache{
+            entries: Vec::new(),
+            registered_bytes: 0,
+            max_registered_bytes,
+            max_entries,
+            tick: 0,
+        }
+    }
+    /// Returns a registration covering `addr..addr+length` for `operation`,
+    /// taking a reference on it which is dropped by `release`.
+    pub fn register(&mut self, id: &Id, addr: *mut c_void, length: usize, operation: Operation) -> anyhow::Result<*mut ibv_mr, CustomError>{
+        let access = operation.access_flags();
+        let pd = unsafe { (*id.id()).pd } as usize;
+        let start = addr as usize;
+        let end = start + length;
+        self.tick += 1;
+        if let Some(i) = self.find(pd, start, end, access){
+            let entry = &mut self.entries[i];
+            entry.refs += 1;
+            entry.last_used = self.tick;
+            return Ok(entry.mr);
+        }
+        self.evict(length);
+        let mr = register(id, addr, length, access)?;
+        self.registered_bytes += length;
+        self.entries.push(CacheEntry{
+            mr,
+            pd,
+            start,
+            end,
+            access,
+            refs: 1,
+            last_used: self.tick,
+        });
+        Ok(mr)
+    }
+    /// Drops a reference taken by `register`. The registration stays cached.
+    pub fn release(&mut self, mr: *mut ibv_mr){
+        if let Some(entry) = self.entries.iter_mut().find(|entry| entry.mr == mr){
+            entry.refs = entry.refs.saturating_sub(1);
+        }
+    }
+    /// Deregisters every unreferenced registration overlapping the range.
+    pub fn invalidate(&mut self, addr: *mut c_void, length: usize){
+        let start = addr as usize;
+        let end = start + length;
+        let mut i = 0;
+        while i < self.entries.len(){
+            let entry = &self.entries[i];
+            if entry.refs == 0 && entry.start < end && start < entry.end{
+                self.remove(i);
+            } else {
+                i += 1;
+            }
+        }
+    }
+    /// Index of the registration of `pd` covering `start..end` with at
+    /// least the `access` flags.
+    fn find(&self, pd: usize, start: usize, end: usize, access: c_int) -> Option<usize>{
+        self.entries.iter().position(|entry| {
+            entry.pd == pd && entry.start <= start && end <= entry.end && entry.access & access == access
+        })
+    }
+    fn evict(&mut self, incoming: usize){
+        while let Some(i) = self.victim(incoming){
+            self.remove(i);
+        }
+    }
+    /// Index of the least recently used unreferenced registration while the
+    /// cache has no room for `incoming` more bytes.
+    fn victim(&self, incoming: usize) -> Option<usize>{
+        if self.entries.len() < self.max_entries && self.registered_bytes + incoming <= self.max_registered_bytes{
+            return None;
+        }
+        self.entries.iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.refs == 0)
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(i, _)| i)
+    }
+    fn remove(&mut self, i: usize){
+        let entry = self.take(i);
+        unsafe { ibv_dereg_mr(entry.mr) };
+    }
+    /// Takes entry `i` out of the cache without deregistering it.
+    fn take(&mut self, i: usize) -> CacheEntry{
+        let entry = self.entries.swap_remove(i);
+        self.registered_bytes -= entry.end - entry.start;
+        entry
+    }
+    pub fn registered_bytes(&self) -> usize{
+        self.registered_bytes
+    }
+    /// Deregisters all cached registrations, also referenced ones. Only call
+    /// it when no operation on them is outstanding anymore.
+    pub fn clear(&mut self){
+        for entry in self.entries.drain(..){
+            unsafe { ibv_dereg_mr(entry.mr) };
+        }
+        self.registered_bytes = 0;
+    }
+}
+
+impl Drop for MrCache{
+    fn drop(&mut self){
+        self.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::ptr::null_mut;
+    use super::*;
+    use crate::alloc::AllocStrategy;
+
+    const MIB: usize = 1024 * 1024;
+
+    fn huge_pool(max_cached_bytes: usize) -> BufferPool{
+        BufferPool::new(max_cached_bytes, AllocOptions{strategy: AllocStrategy::Huge2M, numa_local: false})
+    }
+
+    fn entry(pd: usize, start: usize, end: usize, access: c_int, refs: usize, last_used: u64) -> CacheEntry{
+        CacheEntry{
+            mr: null_mut(),
+            pd,
+            start,
+            end,
+            access,
+            refs,
+            last_used,
+        }
+    }
+
+    fn cache_with(max_registered_bytes: usize, max_entries: usize, entries: Vec<CacheEntry>) -> MrCache{
+        let mut cache = MrCache::new(max_registered_bytes, max_entries);
+        for entry in entries{
+            cache.registered_bytes += entry.end - entry.start;
+            cache.entries.push(entry);
+        }
+        cache
+    }
+
+    /// Drops the test entries, which were never registered, without
+    /// deregistering them.
+    fn forget(mut cache: MrCache){
+        cache.entries.clear();
+        cache.registered_bytes = 0;
+    }
+
+    #[test]
+    fn size_classes_round_up_to_powers_of_two(){
+        assert_eq!(size_class(1, 4096), MIN_SIZE_CLASS);
+        assert_eq!(size_class(4096, 4096), 4096);
+        assert_eq!(size_class(4097, 4096), 8192);
+        assert_eq!(size_class(3 * MIB, 4096), 4 * MIB);
+    }
+
+    #[test]
+    fn size_classes_are_at_least_one_page(){
+        assert_eq!(size_class(1, 2 * MIB), 2 * MIB);
+        assert_eq!(size_class(2 * MIB + 1, 2 * MIB), 4 * MIB);
+        assert_eq!(huge_pool(0).registered_size(100), 2 * MIB);
+    }
+
+    #[test]
+    fn cache_cap_is_at_least_two_pages(){
+        assert_eq!(huge_pool(0).max_cached_bytes, MIN_CACHED_PAGES * 2 * MIB);
+        assert_eq!(huge_pool(64 * MIB).max_cached_bytes, 64 * MIB);
+    }
+
+    #[test]
+    fn checkins_are_cached_up_to_the_cap(){
+        let mut pool = huge_pool(0);
+        pool.account_checkout(2 * MIB, false);
+        pool.account_checkout(4 * MIB, false);
+        assert_eq!(pool.registered_bytes(), 6 * MIB);
+        assert!(pool.account_checkin(2 * MIB));
+        assert_eq!(pool.cached_bytes(), 2 * MIB);
+        // 6 MiB would exceed the 4 MiB cap, the buffer is deregistered
+        assert!(!pool.account_checkin(4 * MIB));
+        assert_eq!(pool.cached_bytes(), 2 * MIB);
+        assert_eq!(pool.registered_bytes(), 2 * MIB);
+    }
+
+    #[test]
+    fn cached_checkouts_move_bytes_out_of_the_cache(){
+        let mut pool = huge_pool(0);
+        pool.account_checkout(2 * MIB, false);
+        assert!(pool.account_checkin(2 * MIB));
+        pool.account_checkout(2 * MIB, true);
+        assert_eq!(pool.cached_bytes(), 0);
+        assert_eq!(pool.registered_bytes(), 2 * MIB);
+        assert!(pool.account_checkin(2 * MIB));
+        assert_eq!(pool.registered_bytes(), 2 * MIB);
+    }
+
+    #[test]
+    fn lookup_needs_a_covering_range_with_the_access_flags(){
+        let cache = cache_with(usize::MAX, usize::MAX, vec![
+            entry(1, 100, 200, 0b01, 0, 1),
+            entry(1, 300, 400, 0b11, 0, 2),
+        ]);
+        assert_eq!(cache.find(1, 120, 180, 0b01), Some(0));
+        assert_eq!(cache.find(1, 100, 200, 0b01), Some(0));
+        assert_eq!(cache.find(1, 150, 250, 0b01), None);
+        assert_eq!(cache.find(1, 300, 350, 0b10), Some(1));
+        assert_eq!(cache.find(1, 120, 180, 0b10), None);
+        assert_eq!(cache.find(2, 120, 180, 0b01), None);
+        forget(cache);
+    }
+
+    #[test]
+    fn eviction_takes_the_least_recently_used_first(){
+        let mut cache = cache_with(300, usize::MAX, vec![
+            entry(1, 0, 100, 0b01, 0, 3),
+            entry(1, 100, 200, 0b01, 0, 1),
+            entry(1, 200, 300, 0b01, 1, 2),
+        ]);
+        assert_eq!(cache.victim(0), None);
+        let mut evicted = Vec::new();
+        while let Some(i) = cache.victim(200){
+            evicted.push(cache.take(i).start);
+        }
+        assert_eq!(evicted, vec![100, 0]);
+        assert_eq!(cache.registered_bytes(), 100);
+        // the referenced registration stays although there is no room
+        assert_eq!(cache.victim(300), None);
+        forget(cache);
+    }
+
+    #[test]
+    fn eviction_keeps_the_entry_count_below_the_cap(){
+        let mut cache = cache_with(usize::MAX, 2, vec![
+            entry(1, 0, 100, 0b01, 0, 2),
+            entry(1, 100, 200, 0b01, 0, 1),
+        ]);
+        let i = cache.victim(0).unwrap();
+        assert_eq!(cache.take(i).start, 100);
+        assert_eq!(cache.victim(0), None);
+        forget(cache);
+    }
+}
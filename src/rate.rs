@@ -0,0 +1,154 @@
+use std::{str::FromStr, time::{Duration, Instant}};
+use crate::access::Rng;
+
+/// Sleeps shorter than this are spun instead, the scheduler wakes up too
+/// late for them.
+const SPIN_THRESHOLD: Duration = Duration::from_micros(100);
+
+/// Offered load of an open-loop run.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rate{
+    OpsPerSec(f64),
+    BitsPerSec(f64),
+}
+
+impl Rate{
+    /// Operations per second for messages of `mean_message_size` bytes on
+    /// average.
+    pub fn ops_per_sec(&self, mean_message_size: f64) -> f64{
+        match self{
+            Rate::OpsPerSec(ops) => *ops,
+            Rate::BitsPerSec(bits) => bits / (8.0 * mean_message_size.max(1.0)),
+        }
+    }
+}
+
+impl FromStr for Rate{
+    type Err = String;
+    /// Operations per second like `250000` or `250k`, or bits per second
+    /// like `10gbps` or `400mbps`.
+    fn from_str(s: &str) -> Result<Self, Self::Err>{
+        let lower = s.trim().to_ascii_lowercase();
+        let (number, bits) = match lower.strip_suffix("bps"){
+            Some(number) => (number, true),
+            None => (lower.as_str(), false),
+        };
+        let (number, scale) = match number.chars().last(){
+            Some('k') => (&number[..number.len() - 1], 1e3),
+            Some('m') => (&number[..number.len() - 1], 1e6),
+            Some('g') => (&number[..number.len() - 1], 1e9),
+            _ => (number, 1.0),
+        };
+        let value = match number.parse::<f64>(){
+            Ok(value) if value > 0.0 && value.is_finite() => value * scale,
+            _ => return Err(format!("invalid rate {}, expected ops/s like 250k or bits/s like 10gbps", s)),
+        };
+        if bits{
+            Ok(Rate::BitsPerSec(value))
+        } else {
+            Ok(Rate::OpsPerSec(value))
+        }
+    }
+}
+
+/// Distribution of the gaps between scheduled operations.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Arrival{
+    Constant,
+    /// Exponential gaps, as independent clients would arrive.
+    Poisson,
+}
+
+impl FromStr for Arrival{
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err>{
+        match s{
+            "constant" => Ok(Arrival::Constant),
+            "poisson" => Ok(Arrival::Poisson),
+            _ => Err(format!("unknown arrival {}, expected constant or poisson", s)),
+        }
+    }
+}
+
+/// Intended start times of an open-loop run. An operation which is late
+/// because the previous ones took longer still counts its latency from the
+/// intended start, so queueing delay is not hidden (coordinated omission).
+pub struct Schedule{
+    start: Instant,
+    mean_interval: f64,
+    arrival: Arrival,
+    rng: Rng,
+    /// Nanoseconds from `start` to the next intended start.
+    next: f64,
+}
+
+impl Schedule{
+    pub fn new(ops_per_sec: f64, arrival: Arrival, seed: u64) -> Schedule{
+        Schedule{
+            start: Instant::now(),
+            mean_interval: 1e9 / ops_per_sec,
+            arrival,
+            rng: Rng::new(seed),
+            next: 0.0,
+        }
+    }
+    /// Intended start of the next operation.
+    pub fn next_start(&mut self) -> Instant{
+        let intended = self.start + Duration::from_nanos(self.next as u64);
+        let interval = match self.arrival{
+            Arrival::Constant => self.mean_interval,
+            Arrival::Poisson => -self.mean_interval * (1.0 - self.rng.next_f64()).ln(),
+        };
+        self.next += interval;
+        intended
+    }
+}
+
+/// Blocks until `instant`, sleeping for long waits and spinning for the
+/// rest.
+pub fn wait_until(instant: Instant){
+    loop {
+        let now = Instant::now();
+        if now >= instant{
+            return;
+        }
+        let remaining = instant - now;
+        if remaining > SPIN_THRESHOLD{
+            std::thread::sleep(remaining - SPIN_THRESHOLD);
+        } else {
+            std::hint::spin_loop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn parses_operations_per_second(){
+        assert_eq!("250000".parse::<Rate>(), Ok(Rate::OpsPerSec(250000.0)));
+        assert_eq!("250k".parse::<Rate>(), Ok(Rate::OpsPerSec(250000.0)));
+        assert_eq!("1.5M".parse::<Rate>(), Ok(Rate::OpsPerSec(1.5e6)));
+    }
+
+    #[test]
+    fn parses_bits_per_second(){
+        assert_eq!("10gbps".parse::<Rate>(), Ok(Rate::BitsPerSec(10e9)));
+        assert_eq!(" 400Mbps ".parse::<Rate>(), Ok(Rate::BitsPerSec(400e6)));
+        assert_eq!("8bps".parse::<Rate>(), Ok(Rate::BitsPerSec(8.0)));
+    }
+
+    #[test]
+    fn rejects_invalid_rates(){
+        for rate in ["", "k", "gbps", "0", "-5k", "10x", "inf", "nan"]{
+            assert!(rate.parse::<Rate>().is_err(), "{} parsed", rate);
+        }
+    }
+
+    #[test]
+    fn converts_bits_to_operations(){
+        assert_eq!(Rate::BitsPerSec(8e6).ops_per_sec(1000.0), 1000.0);
+        assert_eq!(Rate::OpsPerSec(42.0).ops_per_sec(1000.0), 42.0);
+    }
+}
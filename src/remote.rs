@@ -0,0 +1,147 @@
+use std::{marker::PhantomData, mem::size_of};
+use rdma_sys::*;
+use crate::{CustomError, Data, Id, MrAddr, MrObject, MrRegister, Operation};
+
+/// A registered buffer of the peer: its address, length, rkey and the
+/// operation it was registered for, as handed out in a response. One-sided
+/// operations through it are bounds- and access-checked locally before they
+/// are posted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RemoteRegion{
+    pub addr: u64,
+    pub len: u64,
+    pub rkey: u32,
+    pub access: Operation,
+}
+
+impl RemoteRegion{
+    pub fn new(addr: u64, len: u64, rkey: u32, access: Operation) -> RemoteRegion{
+        RemoteRegion{
+            addr,
+            len,
+            rkey,
+            access,
+        }
+    }
+    /// Fails unless the peer registered the region for `operation`, an
+    /// `Operation::Atomic` region allows reads, writes and atomics.
+    pub fn allows(&self, operation: Operation) -> anyhow::Result<(), CustomError>{
+        if self.access == operation || (self.access == Operation::Atomic && operation != Operation::SendRecv){
+            return Ok(());
+        }
+        Err(CustomError::new(format!("remote region registered for {:?} does not allow {:?}", self.access, operation), -libc::EACCES))
+    }
+    /// Remote address of the `len` bytes at `offset`. Fails if they are not
+    /// inside the region.
+    pub fn range(&self, offset: u64, len: u64) -> anyhow::Result<u64, CustomError>{
+        match offset.checked_add(len){
+            Some(end) if end <= self.len => Ok(self.addr + offset),
+            _ => Err(CustomError::new(format!("{} bytes at offset {} exceed remote region of {} bytes", len, offset, self.len), -libc::ERANGE)),
+        }
+    }
+    /// The `len` bytes at `offset` as a region of their own.
+    pub fn slice(&self, offset: u64, len: u64) -> anyhow::Result<RemoteRegion, CustomError>{
+        let addr = self.range(offset, len)?;
+        Ok(RemoteRegion::new(addr, len, self.rkey, self.access))
+    }
+}
+
+/// Types which are plain bytes on the wire: no padding, no pointers and
+/// valid for every bit pattern.
+///
+/// # Safety
+///
+/// Implementors must be `repr(C)` or primitive, without padding, and valid
+/// for any bit pattern.
+pub unsafe trait Pod: Copy + Send + 'static{}
+
+unsafe impl Pod for u8{}
+unsafe impl Pod for u16{}
+unsafe impl Pod for u32{}
+unsafe impl Pod for u64{}
+unsafe impl Pod for i8{}
+unsafe impl Pod for i16{}
+unsafe impl Pod for i32{}
+unsafe impl Pod for i64{}
+unsafe impl Pod for f32{}
+unsafe impl Pod for f64{}
+unsafe impl<T: Pod, const N: usize> Pod for [T; N]{}
+
+/// A remote region viewed as an array of `T`. Reads and writes go through a
+/// local registered staging buffer of one element, offsets count elements.
+pub struct RemoteSlice<T: Pod>{
+    id: Id,
+    region: RemoteRegion,
+    staging: Data,
+    staging_mr_addr: MrAddr,
+    _element: PhantomData<T>,
+}
+
+unsafe impl<T: Pod> Send for RemoteSlice<T>{}
+
+impl<T: Pod> RemoteSlice<T>{
+    /// Registers the staging buffer on `id`, the QP the operations are
+    /// posted on.
+    pub fn new(id: &Id, region: RemoteRegion) -> anyhow::Result<RemoteSlice<T>, CustomError>{
+        // atomics need 8 bytes even for smaller elements
+        let mut staging = Data::new(size_of::<T>().max(size_of::<u64>()));
+        let staging_mr_addr = staging.create_and_register_mr(id, Operation::SendRecv)?;
+        Ok(RemoteSlice{
+            id: id.clone(),
+            region,
+            staging,
+            staging_mr_addr,
+            _element: PhantomData,
+        })
+    }
+    pub fn region(&self) -> &RemoteRegion{
+        &self.region
+    }
+    /// Number of whole elements in the region.
+    pub fn len(&self) -> usize{
+        (self.region.len / size_of::<T>() as u64) as usize
+    }
+    pub fn is_empty(&self) -> bool{
+        self.len() == 0
+    }
+    /// Byte offset of the element at `offset`, if it is inside the region.
+    fn element_offset(&self, offset: usize) -> anyhow::Result<u64, CustomError>{
+        let size = size_of::<T>() as u64;
+        match (offset as u64).checked_mul(size){
+            Some(byte_offset) => {
+                self.region.range(byte_offset, size)?;
+                Ok(byte_offset)
+            },
+            None => Err(CustomError::new(format!("offset {} exceeds remote region of {} elements", offset, self.len()), -libc::ERANGE)),
+        }
+    }
+    /// Reads the element at `offset` with an RDMA read.
+    pub fn read_at(&mut self, offset: usize) -> anyhow::Result<T, CustomError>{
+        let remote_offset = self.element_offset(offset)?;
+        self.staging.rdma_one_sided_at(&self.id, Operation::Read, 0, &self.region, remote_offset, size_of::<T>())?;
+        Ok(unsafe { std::ptr::read_unaligned(self.staging.as_slice().as_ptr().cast::<T>()) })
+    }
+    /// Writes `value` to the element at `offset` with an RDMA write.
+    pub fn write_at(&mut self, offset: usize, value: &T) -> anyhow::Result<(), CustomError>{
+        let remote_offset = self.element_offset(offset)?;
+        unsafe { std::ptr::write_unaligned(self.staging.as_mut_slice().as_mut_ptr().cast::<T>(), *value) };
+        self.staging.rdma_one_sided_at(&self.id, Operation::Write, 0, &self.region, remote_offset, size_of::<T>())
+    }
+}
+
+impl RemoteSlice<u64>{
+    /// Atomically replaces the element at `offset` with `swap` if it equals
+    /// `compare` and returns the previous value. The element must be 8 byte
+    /// aligned and the peer must have registered the region with
+    /// `Operation::Atomic`, as for a workload.
+    pub fn compare_and_swap(&mut self, offset: usize, compare: u64, swap: u64) -> anyhow::Result<u64, CustomError>{
+        let remote_offset = self.element_offset(offset)?;
+        self.staging.rdma_compare_and_swap_at(&self.id, 0, &self.region, remote_offset, compare, swap)
+    }
+}
+
+impl<T: Pod> Drop for RemoteSlice<T>{
+    fn drop(&mut self){
+        unsafe { rdma_dereg_mr(self.staging_mr_addr.mr) };
+    }
+}
@@ -0,0 +1,12 @@
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Resolves on the first SIGINT or SIGTERM and returns the name of the
+/// signal.
+pub async fn shutdown_signal() -> &'static str{
+    let mut interrupt = signal(SignalKind::interrupt()).unwrap();
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+    tokio::select!{
+        _ = interrupt.recv() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
+    }
+}
@@ -0,0 +1,329 @@
+use std::{collections::{HashMap, HashSet, VecDeque}, ptr::null_mut, sync::{Arc, Condvar, Mutex}};
+use rdma_sys::*;
+use crate::{timeout::{flush_qp, get_cq_event, is_timeout, poll_until, CancellationToken, Deadline, WaitControl}, CustomError, Data, Id, MrObject, MrRegister};
+
+const POLL_BATCH_SIZE: usize = 16;
+
+/// A receive completion taken off the shared CQ which has not yet been
+/// claimed by the session owning `qp_num`.
+struct SrqCompletion{
+    buffer_index: usize,
+    byte_len: u32,
+    status: ibv_wc_status::Type,
+    opcode: ibv_wc_opcode::Type,
+}
+
+/// Shared receive queue used by all session QPs of a server.
+///
+/// All QPs attached to the SRQ complete their receives on one shared CQ,
+/// completions are stashed per `qp_num` until the owning session asks for
+/// them, failed ones included, so a QP going into error only fails its own
+/// session. Consumed buffers go back to the free list and are reposted in
+/// batches, or right away when the SRQ limit event fires.
+///
+/// One waiting session at a time sleeps on the completion channel of the
+/// shared CQ, the others on `wakeup`, which it notifies once it has taken
+/// the completions off the CQ.
+pub struct SharedReceiveQueue{
+    srq: *mut ibv_srq,
+    cq: *mut ibv_cq,
+    channel: *mut ibv_comp_channel,
+    channel_waiter: bool,
+    wakeup: Arc<Condvar>,
+    context: *mut ibv_context,
+    buffers: Vec<Data>,
+    free: Vec<usize>,
+    pending: HashMap<u32, VecDeque<SrqCompletion>>,
+    /// QPs attached to the SRQ, completions of others are dropped.
+    qps: HashSet<u32>,
+    srq_limit: u32,
+    refill_batch: usize,
+}
+
+unsafe impl Send for SharedReceiveQueue{}
+unsafe impl Sync for SharedReceiveQueue{}
+
+impl SharedReceiveQueue{
+    /// Creates the SRQ on the device and protection domain of `id`, so that
+    /// QPs and MRs of later ids on the same device can share it.
+    pub fn new(id: &Id, max_wr: u32, buffer_size: usize, srq_limit: u32) -> anyhow::Result<SharedReceiveQueue, CustomError>{
+        if id.id().is_null(){
+            return Err(CustomError::new("id is null".to_string(), -1));
+        }
+        let context = unsafe { (*id.id()).verbs };
+        let pd = unsafe { (*id.id()).pd };
+        if context.is_null() || pd.is_null(){
+            return Err(CustomError::new("id is not bound to a device".to_string(), -1));
+        }
+        let channel = unsafe { ibv_create_comp_channel(context) };
+        if channel.is_null(){
+            return Err(CustomError::new("ibv_create_comp_channel".to_string(), -1));
+        }
+        let cq = unsafe { ibv_create_cq(context, max_wr as i32, null_mut(), channel, 0) };
+        if cq.is_null(){
+            unsafe { ibv_destroy_comp_channel(channel); }
+            return Err(CustomError::new("ibv_create_cq".to_string(), -1));
+        }
+        let mut srq_init_attr = unsafe { std::mem::zeroed::<ibv_srq_init_attr>() };
+        srq_init_attr.attr.max_wr = max_wr;
+        srq_init_attr.attr.max_sge = 1;
+        let srq = unsafe { ibv_create_srq(pd, &mut srq_init_attr) };
+        if srq.is_null(){
+            unsafe {
+                ibv_destroy_cq(cq);
+                ibv_destroy_comp_channel(channel);
+            }
+            return Err(CustomError::new("ibv_create_srq".to_string(), -1));
+        }
+        let mut shared_receive_queue = SharedReceiveQueue{
+            srq,
+            cq,
+            channel,
+            channel_waiter: false,
+            wakeup: Arc::new(Condvar::new()),
+            context,
+            buffers: Vec::with_capacity(max_wr as usize),
+            free: Vec::with_capacity(max_wr as usize),
+            pending: HashMap::new(),
+            qps: HashSet::new(),
+            srq_limit,
+            refill_batch: (max_wr as usize / 4).max(1),
+        };
+        for index in 0..max_wr as usize{
+            let mut data = Data::new(buffer_size);
+            let mr = unsafe { ibv_reg_mr(pd, data.addr(), buffer_size, ibv_access_flags::IBV_ACCESS_LOCAL_WRITE.0 as i32) };
+            if mr.is_null(){
+                return Err(CustomError::new("ibv_reg_mr".to_string(), -1));
+            }
+            data.set_mr(mr);
+            shared_receive_queue.buffers.push(data);
+            shared_receive_queue.free.push(index);
+        }
+        shared_receive_queue.refill()?;
+        shared_receive_queue.arm_limit()?;
+        Ok(shared_receive_queue)
+    }
+    pub fn srq(&self) -> *mut ibv_srq{
+        self.srq
+    }
+    pub fn cq(&self) -> *mut ibv_cq{
+        self.cq
+    }
+    pub fn context(&self) -> *mut ibv_context{
+        self.context
+    }
+    pub fn buffer_size(&self) -> usize{
+        self.buffers.first().map(|data| data.len()).unwrap_or(0)
+    }
+    /// Creates the QP of `id` with its receive side attached to the SRQ and
+    /// the shared CQ. The send CQ stays private to the id.
+    pub fn create_qp(&mut self, id: &Id, init_attr: &mut ibv_qp_init_attr) -> anyhow::Result<(), CustomError>{
+        init_attr.srq = self.srq;
+        init_attr.recv_cq = self.cq;
+        let ret = unsafe { rdma_create_qp(id.id(), null_mut(), init_attr) };
+        if ret != 0 {
+            return Err(CustomError::new("rdma_create_qp".to_string(), ret));
+        }
+        self.qps.insert(id.qp_num());
+        Ok(())
+    }
+    /// Posts all free buffers back to the SRQ.
+    pub fn refill(&mut self) -> anyhow::Result<usize, CustomError>{
+        let mut posted = 0;
+        while let Some(index) = self.free.pop(){
+            let data = &mut self.buffers[index];
+            let mut sge = ibv_sge{
+                addr: data.addr() as u64,
+                length: data.len() as u32,
+                lkey: unsafe { (*data.mr()).lkey },
+            };
+            let mut wr = ibv_recv_wr{
+                wr_id: index as u64,
+                next: null_mut(),
+                sg_list: &mut sge,
+                num_sge: 1,
+            };
+            let mut bad_wr = null_mut();
+            let ret = unsafe { ibv_post_srq_recv(self.srq, &mut wr, &mut bad_wr) };
+            if ret != 0 {
+                self.free.push(index);
+                return Err(CustomError::new("ibv_post_srq_recv".to_string(), ret));
+            }
+            posted += 1;
+        }
+        Ok(posted)
+    }
+    /// Arms the SRQ limit so that an `IBV_EVENT_SRQ_LIMIT_REACHED` async
+    /// event is raised once fewer than `srq_limit` receives are posted.
+    /// The limit is disarmed by the event and has to be rearmed after it.
+    pub fn arm_limit(&mut self) -> anyhow::Result<(), CustomError>{
+        if self.srq_limit == 0{
+            return Ok(());
+        }
+        let mut srq_attr = unsafe { std::mem::zeroed::<ibv_srq_attr>() };
+        srq_attr.srq_limit = self.srq_limit;
+        let ret = unsafe { ibv_modify_srq(self.srq, &mut srq_attr, ibv_srq_attr_mask::IBV_SRQ_LIMIT.0 as i32) };
+        if ret != 0 {
+            return Err(CustomError::new("ibv_modify_srq".to_string(), ret));
+        }
+        Ok(())
+    }
+    /// Handles the SRQ limit event: reposts every free buffer and rearms.
+    pub fn on_limit_reached(&mut self) -> anyhow::Result<(), CustomError>{
+        self.refill()?;
+        self.arm_limit()
+    }
+    fn poll(&mut self) -> anyhow::Result<usize, CustomError>{
+        let mut wc_vec: Vec<ibv_wc> = Vec::with_capacity(POLL_BATCH_SIZE);
+        let ret = unsafe { ibv_poll_cq(self.cq, POLL_BATCH_SIZE as i32, wc_vec.as_mut_ptr()) };
+        if ret < 0 {
+            return Err(CustomError::new("ibv_poll_cq".to_string(), ret));
+        }
+        unsafe { wc_vec.set_len(ret as usize) };
+        for wc in wc_vec.iter(){
+            // late completions of a QP which went away
+            if !self.qps.contains(&wc.qp_num){
+                self.free.push(wc.wr_id as usize);
+                continue;
+            }
+            self.pending.entry(wc.qp_num).or_default().push_back(SrqCompletion{
+                buffer_index: wc.wr_id as usize,
+                byte_len: wc.byte_len,
+                status: wc.status,
+                opcode: wc.opcode,
+            });
+        }
+        Ok(ret as usize)
+    }
+    /// Takes the next receive completion for `qp_num` and copies its payload
+    /// into `target`. Returns `None` if nothing has arrived for the QP yet.
+    pub fn try_recv<T: MrObject + ?Sized>(&mut self, qp_num: u32, target: &mut T) -> anyhow::Result<Option<usize>, CustomError>{
+        if self.pending.get(&qp_num).map(|pending| pending.is_empty()).unwrap_or(true){
+            self.poll()?;
+        }
+        let completion = match self.pending.get_mut(&qp_num).and_then(|pending| pending.pop_front()){
+            Some(completion) => completion,
+            None => return Ok(None),
+        };
+        if completion.status != ibv_wc_status::IBV_WC_SUCCESS || completion.opcode != ibv_wc_opcode::IBV_WC_RECV{
+            self.free.push(completion.buffer_index);
+            return Err(CustomError::new(format!("wc status/opcode {}/{} wrong, expected {}/{}", completion.status, completion.opcode, ibv_wc_status::IBV_WC_SUCCESS, ibv_wc_opcode::IBV_WC_RECV), -1));
+        }
+        let length = (completion.byte_len as usize).min(target.len());
+        let source = self.buffers[completion.buffer_index].addr();
+        unsafe { std::ptr::copy_nonoverlapping(source as *const u8, target.addr() as *mut u8, length) };
+        self.free.push(completion.buffer_index);
+        if self.free.len() >= self.refill_batch{
+            self.refill()?;
+        }
+        Ok(Some(length))
+    }
+    /// Drops completions stashed for a QP which went away, and the ones
+    /// still to come.
+    pub fn remove_qp(&mut self, qp_num: u32){
+        self.qps.remove(&qp_num);
+        if let Some(pending) = self.pending.remove(&qp_num){
+            self.free.extend(pending.into_iter().map(|completion| completion.buffer_index));
+        }
+    }
+}
+
+impl Drop for SharedReceiveQueue{
+    fn drop(&mut self){
+        unsafe {
+            ibv_destroy_srq(self.srq);
+            for data in self.buffers.iter(){
+                ibv_dereg_mr(data.mr());
+            }
+            ibv_destroy_cq(self.cq);
+            ibv_destroy_comp_channel(self.channel);
+        }
+    }
+}
+
+/// Receives `iterations` messages for the QP of `id` from the SRQ, copying
+/// each one into `target`. Other sessions can poll the SRQ in between.
+pub fn srq_recv<T: MrObject + ?Sized>(srq: &Mutex<SharedReceiveQueue>, id: &Id, target: &mut T, iterations: usize) -> anyhow::Result<(), CustomError>{
+    let qp_num = id.qp_num();
+    let wait_control = id.wait_control();
+    for _ in 0..iterations{
+        let deadline = wait_control.deadline();
+        let received = poll_until(wait_control.poll_mode, &deadline, || Ok(srq.lock().unwrap().try_recv(qp_num, &mut *target)?.is_some()), |poll|{
+            srq_sleep(srq, &deadline, poll)
+        });
+        if let Err(e) = received{
+            if is_timeout(&e){
+                flush_qp(id);
+            }
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Sleeps until the shared CQ may hold a completion for the caller: on the
+/// completion channel if no other session does, on `wakeup` otherwise.
+/// Reports whether `poll` already found one.
+fn srq_sleep<F: FnMut() -> anyhow::Result<bool, CustomError>>(srq: &Mutex<SharedReceiveQueue>, deadline: &Deadline, poll: &mut F) -> anyhow::Result<bool, CustomError>{
+    let mut guard = srq.lock().unwrap();
+    if guard.channel_waiter{
+        let wakeup = guard.wakeup.clone();
+        drop(wakeup.wait_timeout(guard, deadline.slice()).unwrap());
+        deadline.check("srq receive")?;
+        return Ok(false);
+    }
+    let ret = unsafe { ibv_req_notify_cq(guard.cq, 0) };
+    if ret != 0 {
+        return Err(CustomError::new("ibv_req_notify_cq".to_string(), ret));
+    }
+    guard.channel_waiter = true;
+    let (cq, channel) = (guard.cq, guard.channel);
+    drop(guard);
+    let slept = match poll(){
+        Ok(true) => Ok(true),
+        Ok(false) => get_cq_event(deadline, channel, cq).map(|_| false),
+        Err(e) => Err(e),
+    };
+    let mut guard = srq.lock().unwrap();
+    guard.channel_waiter = false;
+    // stash what the event announced for the sessions on `wakeup`
+    let polled = guard.poll();
+    guard.wakeup.notify_all();
+    polled?;
+    slept
+}
+
+/// Waits on the async event queue of the SRQ device and refills the SRQ
+/// each time its limit is reached, until `token` is cancelled. A failed
+/// refill is reported and the next limit event tries again. Meant to run on
+/// a dedicated thread.
+pub fn srq_async_event_loop(srq: Arc<Mutex<SharedReceiveQueue>>, token: CancellationToken) -> anyhow::Result<(), CustomError>{
+    let context = srq.lock().unwrap().context();
+    let wait_control = WaitControl{
+        token,
+        ..Default::default()
+    };
+    loop {
+        match wait_control.deadline().wait_fd(unsafe { (*context).async_fd }, "srq async event"){
+            Ok(()) => {},
+            Err(e) if e.code() == -libc::ECANCELED => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        let mut event = unsafe { std::mem::zeroed::<ibv_async_event>() };
+        let ret = unsafe { ibv_get_async_event(context, &mut event) };
+        if ret != 0 {
+            return Err(CustomError::new("ibv_get_async_event".to_string(), ret));
+        }
+        let limit_reached = event.event_type == ibv_event_type::IBV_EVENT_SRQ_LIMIT_REACHED;
+        let event_srq = unsafe { event.element.srq };
+        unsafe { ibv_ack_async_event(&mut event) };
+        if limit_reached{
+            let mut srq = srq.lock().unwrap();
+            if event_srq == srq.srq(){
+                if let Err(e) = srq.on_limit_reached(){
+                    println!("srq refill failed: {}", e);
+                }
+            }
+        }
+    }
+}
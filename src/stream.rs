@@ -0,0 +1,674 @@
+use std::{collections::VecDeque, io, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr}, os::fd::RawFd, pin::Pin, ptr::null_mut, task::{ready, Context, Poll, Waker}, time::Duration};
+use libc::c_void;
+use rdma_sys::*;
+use tokio::io::{unix::AsyncFd, AsyncRead, AsyncWrite, ReadBuf};
+use crate::{create_ep, timeout::{accept_ep, connect_ep}, CustomError, Data, Id, MrAddr, MrRegister, Operation};
+
+/// Every frame starts with the payload length (u32), the receive slots the
+/// sender reposted since its last frame (u16) and flags (u16).
+const HEADER_LEN: usize = 8;
+/// First frame on a connection, its payload holds the ring depth and slot
+/// size of the sender.
+const FLAG_HELLO: u16 = 1;
+/// The sender shut down its write side.
+const FLAG_FIN: u16 = 2;
+/// Completions taken off a CQ at once.
+const POLL_BATCH: usize = 16;
+
+/// Ring sizing of an `RdmaStream`. Both sides may use different sizes, the
+/// frame size is the smaller slot size of the two.
+#[derive(Clone, Copy, Debug)]
+pub struct StreamConfig{
+    /// Receive slots posted at a time, which is also the number of frames
+    /// the peer may have in flight, and the number of send slots.
+    pub depth: u32,
+    /// Bytes of one slot, including the frame header.
+    pub slot_size: usize,
+}
+
+impl Default for StreamConfig{
+    fn default() -> Self{
+        StreamConfig{
+            depth: 64,
+            slot_size: 64 * 1024,
+        }
+    }
+}
+
+impl StreamConfig{
+    fn validate(&self) -> io::Result<()>{
+        if self.depth < 2 || self.depth > u16::MAX as u32{
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "stream depth must be between 2 and 65535"));
+        }
+        if self.slot_size <= HEADER_LEN || self.slot_size > u32::MAX as usize{
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("stream slot size must be larger than {}", HEADER_LEN)));
+        }
+        Ok(())
+    }
+    fn init_attr(&self) -> ibv_qp_init_attr{
+        let mut attr = unsafe { std::mem::zeroed::<ibv_qp_init_attr>() };
+        attr.cap.max_send_wr = self.depth;
+        attr.cap.max_recv_wr = self.depth;
+        attr.cap.max_send_sge = 1;
+        attr.cap.max_recv_sge = 1;
+        attr.sq_sig_all = 0;
+        attr
+    }
+}
+
+fn io_error(e: CustomError) -> io::Error{
+    io::Error::other(e.to_string())
+}
+
+/// The IPv4 or IPv6 address `addr` points to, `None` for other families.
+fn socket_addr(addr: &libc::sockaddr) -> Option<SocketAddr>{
+    match addr.sa_family as i32{
+        libc::AF_INET => {
+            let sin = unsafe { &*(addr as *const libc::sockaddr).cast::<libc::sockaddr_in>() };
+            let ip = Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr));
+            Some(SocketAddr::new(IpAddr::V4(ip), u16::from_be(sin.sin_port)))
+        },
+        libc::AF_INET6 => {
+            let sin6 = unsafe { &*(addr as *const libc::sockaddr).cast::<libc::sockaddr_in6>() };
+            let ip = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+            Some(SocketAddr::new(IpAddr::V6(ip), u16::from_be(sin6.sin6_port)))
+        },
+        _ => None,
+    }
+}
+
+fn set_nonblocking(fd: RawFd) -> io::Result<()>{
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0{
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// `depth` slots of `slot_size` bytes in one registration.
+struct Ring{
+    data: Data,
+    mr_addr: MrAddr,
+    slot_size: usize,
+}
+
+impl Ring{
+    fn new(id: &Id, config: &StreamConfig) -> io::Result<Ring>{
+        let mut data = Data::new(config.depth as usize * config.slot_size);
+        let mr_addr = data.create_and_register_mr(id, Operation::SendRecv).map_err(io_error)?;
+        Ok(Ring{
+            data,
+            mr_addr,
+            slot_size: config.slot_size,
+        })
+    }
+    fn slot_addr(&self, slot: usize) -> *mut c_void{
+        unsafe { self.mr_addr.addr.cast::<u8>().add(slot * self.slot_size).cast() }
+    }
+    fn slot(&self, slot: usize) -> &[u8]{
+        &self.data.as_slice()[slot * self.slot_size..(slot + 1) * self.slot_size]
+    }
+    fn slot_mut(&mut self, slot: usize) -> &mut [u8]{
+        let slot_size = self.slot_size;
+        &mut self.data.as_mut_slice()[slot * slot_size..(slot + 1) * slot_size]
+    }
+}
+
+impl Drop for Ring{
+    fn drop(&mut self){
+        unsafe { rdma_dereg_mr(self.mr_addr.mr) };
+    }
+}
+
+/// A CQ whose completion channel is driven by the tokio reactor.
+struct CompletionChannel{
+    cq: *mut ibv_cq,
+    channel: *mut ibv_comp_channel,
+    fd: AsyncFd<RawFd>,
+}
+
+impl CompletionChannel{
+    fn new(cq: *mut ibv_cq, channel: *mut ibv_comp_channel) -> io::Result<CompletionChannel>{
+        if cq.is_null() || channel.is_null(){
+            return Err(io::Error::other("id has no completion channel"));
+        }
+        let fd = unsafe { (*channel).fd };
+        set_nonblocking(fd)?;
+        Ok(CompletionChannel{
+            cq,
+            channel,
+            fd: AsyncFd::new(fd)?,
+        })
+    }
+    fn try_poll(&self, wcs: &mut [ibv_wc]) -> io::Result<usize>{
+        let ret = unsafe { ibv_poll_cq(self.cq, wcs.len() as i32, wcs.as_mut_ptr()) };
+        if ret < 0{
+            return Err(io::Error::other("ibv_poll_cq"));
+        }
+        Ok(ret as usize)
+    }
+    /// Takes completions off the CQ. If there are none, the CQ is armed and
+    /// the waker of `cx` is registered with the completion channel.
+    fn poll(&self, cx: &mut Context<'_>, wcs: &mut [ibv_wc]) -> Poll<io::Result<usize>>{
+        loop {
+            let polled = self.try_poll(wcs)?;
+            if polled > 0{
+                return Poll::Ready(Ok(polled));
+            }
+            if unsafe { ibv_req_notify_cq(self.cq, 0) } != 0{
+                return Poll::Ready(Err(io::Error::other("ibv_req_notify_cq")));
+            }
+            let polled = self.try_poll(wcs)?;
+            if polled > 0{
+                return Poll::Ready(Ok(polled));
+            }
+            let mut guard = ready!(self.fd.poll_read_ready(cx))?;
+            let mut ev_cq = null_mut();
+            let mut context = null_mut();
+            if unsafe { ibv_get_cq_event(self.channel, &mut ev_cq, &mut context) } == 0{
+                unsafe { ibv_ack_cq_events(ev_cq, 1) };
+            } else {
+                guard.clear_ready();
+            }
+        }
+    }
+}
+
+/// A received frame not yet read completely.
+struct Frame{
+    slot: usize,
+    offset: usize,
+    end: usize,
+}
+
+/// A byte stream over an RC connection, usable wherever tokio expects
+/// `AsyncRead` and `AsyncWrite`.
+///
+/// Writes are split into frames of at most one slot which are copied into a
+/// registered send ring and sent. Each side keeps all slots of its receive
+/// ring posted and hands out one credit per slot, a frame is only sent with a
+/// credit, so it never hits a receive queue without a posted buffer. A slot
+/// is reposted once its frame was read and the credit goes back to the
+/// sender with the next frame, or with a frame of its own once half of the
+/// ring is waiting. One credit is kept back for those, so a stream whose
+/// both sides wrote until they ran out of credits does not deadlock.
+pub struct RdmaStream{
+    id: Id,
+    config: StreamConfig,
+    recv_ring: Option<Ring>,
+    send_ring: Option<Ring>,
+    recv_cq: Option<CompletionChannel>,
+    send_cq: Option<CompletionChannel>,
+    cm: Option<AsyncFd<RawFd>>,
+    frames: VecDeque<Frame>,
+    free_send_slots: Vec<usize>,
+    credits: usize,
+    pending_credits: usize,
+    max_payload: usize,
+    peer_ready: bool,
+    fin_received: bool,
+    fin_sent: bool,
+    disconnected: bool,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+unsafe impl Send for RdmaStream{}
+
+impl RdmaStream{
+    pub async fn connect(addr: SocketAddr) -> io::Result<RdmaStream>{
+        RdmaStream::connect_with_config(addr, StreamConfig::default()).await
+    }
+    pub async fn connect_with_config(addr: SocketAddr, config: StreamConfig) -> io::Result<RdmaStream>{
+        config.validate()?;
+        // address and route resolution block on the sync CM API
+        let id = tokio::task::spawn_blocking(move || create_stream_ep(addr, &config, false)).await??;
+        let mut stream = RdmaStream::new(id, config);
+        stream.post_recv_ring()?;
+        let connect_id = stream.id.clone();
+        tokio::task::spawn_blocking(move || connect_ep(&connect_id)).await?
+            .map_err(|e| io::Error::new(io::ErrorKind::ConnectionRefused, e.to_string()))?;
+        stream.start().await?;
+        Ok(stream)
+    }
+    pub fn local_addr(&self) -> Option<SocketAddr>{
+        socket_addr(unsafe { rdma_get_local_addr(&*self.id.id()) })
+    }
+    pub fn peer_addr(&self) -> Option<SocketAddr>{
+        socket_addr(unsafe { rdma_get_peer_addr(&*self.id.id()) })
+    }
+    fn new(id: Id, config: StreamConfig) -> RdmaStream{
+        RdmaStream{
+            id,
+            config,
+            recv_ring: None,
+            send_ring: None,
+            recv_cq: None,
+            send_cq: None,
+            cm: None,
+            frames: VecDeque::new(),
+            free_send_slots: (0..config.depth as usize).rev().collect(),
+            credits: 0,
+            pending_credits: 0,
+            max_payload: 0,
+            peer_ready: false,
+            fin_received: false,
+            fin_sent: false,
+            disconnected: false,
+            read_waker: None,
+            write_waker: None,
+        }
+    }
+    /// Registers both rings and posts the receive ring. Happens before the
+    /// connection is established, so the first frame of the peer finds a
+    /// posted buffer.
+    fn post_recv_ring(&mut self) -> io::Result<()>{
+        self.recv_ring = Some(Ring::new(&self.id, &self.config)?);
+        self.send_ring = Some(Ring::new(&self.id, &self.config)?);
+        for slot in 0..self.config.depth as usize{
+            self.repost(slot)?;
+        }
+        Ok(())
+    }
+    /// Hooks the connected id up with the reactor and exchanges the hello
+    /// frames.
+    async fn start(&mut self) -> io::Result<()>{
+        let id = self.id.id();
+        self.recv_cq = Some(unsafe { CompletionChannel::new((*id).recv_cq, (*id).recv_cq_channel) }?);
+        self.send_cq = Some(unsafe { CompletionChannel::new((*id).send_cq, (*id).send_cq_channel) }?);
+        let cm_fd = unsafe { (*(*id).channel).fd };
+        set_nonblocking(cm_fd)?;
+        self.cm = Some(AsyncFd::new(cm_fd)?);
+        let mut hello = [0u8; 8];
+        hello[..4].copy_from_slice(&self.config.depth.to_le_bytes());
+        hello[4..].copy_from_slice(&(self.config.slot_size as u32).to_le_bytes());
+        self.post_send_slot(&hello, FLAG_HELLO)?;
+        std::future::poll_fn(|cx| {
+            loop {
+                if self.peer_ready{
+                    return Poll::Ready(Ok(()));
+                }
+                if self.disconnected{
+                    return Poll::Ready(Err(io::Error::from(io::ErrorKind::ConnectionReset)));
+                }
+                ready!(self.poll_progress(cx))?;
+            }
+        }).await
+    }
+    fn repost(&mut self, slot: usize) -> io::Result<()>{
+        let ring = self.recv_ring.as_ref().unwrap();
+        let ret = unsafe { rdma_post_recv(self.id.id(), slot as *mut c_void, ring.slot_addr(slot), ring.slot_size, ring.mr_addr.mr) };
+        if ret != 0{
+            return Err(io::Error::other("rdma_post_recv"));
+        }
+        Ok(())
+    }
+    /// Sends one frame from a free send slot, carrying all pending credits.
+    fn post_send_slot(&mut self, payload: &[u8], flags: u16) -> io::Result<()>{
+        let slot = match self.free_send_slots.pop(){
+            Some(slot) => slot,
+            None => return Err(io::Error::other("no free send slot")),
+        };
+        let ring = self.send_ring.as_mut().unwrap();
+        let buffer = ring.slot_mut(slot);
+        buffer[..4].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+        buffer[4..6].copy_from_slice(&(self.pending_credits as u16).to_le_bytes());
+        buffer[6..8].copy_from_slice(&flags.to_le_bytes());
+        buffer[HEADER_LEN..HEADER_LEN + payload.len()].copy_from_slice(payload);
+        let ret = unsafe {
+            rdma_post_send(
+                self.id.id(),
+                slot as *mut c_void,
+                ring.slot_addr(slot),
+                HEADER_LEN + payload.len(),
+                ring.mr_addr.mr,
+                ibv_send_flags::IBV_SEND_SIGNALED.0 as i32,
+            )
+        };
+        if ret != 0{
+            self.free_send_slots.push(slot);
+            return Err(io::Error::other("rdma_post_send"));
+        }
+        self.pending_credits = 0;
+        Ok(())
+    }
+    /// Whether a data or FIN frame can be sent, which leaves one credit for
+    /// credit returns.
+    fn can_send(&self) -> bool{
+        self.peer_ready && self.credits > 1 && !self.free_send_slots.is_empty()
+    }
+    fn post_frame(&mut self, payload: &[u8], flags: u16) -> io::Result<()>{
+        self.post_send_slot(payload, flags)?;
+        self.credits -= 1;
+        Ok(())
+    }
+    /// Sends the pending credits on their own once half of the ring waits
+    /// for them and no data frame took them along.
+    fn return_credits(&mut self) -> io::Result<()>{
+        let threshold = (self.config.depth as usize / 2).max(1);
+        if self.disconnected || !self.peer_ready || self.pending_credits < threshold || self.credits == 0 || self.free_send_slots.is_empty(){
+            return Ok(());
+        }
+        self.post_frame(&[], 0)
+    }
+    fn wake_reader(&self){
+        if let Some(waker) = self.read_waker.as_ref(){
+            waker.wake_by_ref();
+        }
+    }
+    fn wake_writer(&self){
+        if let Some(waker) = self.write_waker.as_ref(){
+            waker.wake_by_ref();
+        }
+    }
+    fn set_disconnected(&mut self){
+        self.disconnected = true;
+        self.wake_reader();
+        self.wake_writer();
+    }
+    fn process_recv(&mut self, wc: &ibv_wc) -> io::Result<()>{
+        if wc.status != ibv_wc_status::IBV_WC_SUCCESS{
+            if wc.status == ibv_wc_status::IBV_WC_WR_FLUSH_ERR{
+                self.set_disconnected();
+                return Ok(());
+            }
+            return Err(io::Error::new(io::ErrorKind::ConnectionReset, format!("receive completion status {}", wc.status)));
+        }
+        let slot = wc.wr_id as usize;
+        let buffer = self.recv_ring.as_ref().unwrap().slot(slot);
+        let len = u32::from_le_bytes(buffer[..4].try_into().unwrap()) as usize;
+        let credits = u16::from_le_bytes(buffer[4..6].try_into().unwrap()) as usize;
+        let flags = u16::from_le_bytes(buffer[6..8].try_into().unwrap());
+        if HEADER_LEN + len > wc.byte_len as usize{
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame of {} bytes truncated to {}", len, wc.byte_len)));
+        }
+        self.credits += credits;
+        if flags & FLAG_HELLO != 0{
+            if len < 8{
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("hello of {} bytes, expected 8", len)));
+            }
+            let depth = u32::from_le_bytes(buffer[HEADER_LEN..HEADER_LEN + 4].try_into().unwrap()) as usize;
+            let slot_size = u32::from_le_bytes(buffer[HEADER_LEN + 4..HEADER_LEN + 8].try_into().unwrap()) as usize;
+            if depth == 0 || slot_size <= HEADER_LEN{
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("hello with ring depth {} and slot size {}", depth, slot_size)));
+            }
+            // the hello took one slot of the peer, the hello of this side
+            // took one of ours
+            self.credits += depth - 1;
+            self.max_payload = slot_size.min(self.config.slot_size) - HEADER_LEN;
+            self.peer_ready = true;
+        }
+        if flags & FLAG_FIN != 0{
+            self.fin_received = true;
+        }
+        if len > 0 && flags & FLAG_HELLO == 0{
+            self.frames.push_back(Frame{
+                slot,
+                offset: HEADER_LEN,
+                end: HEADER_LEN + len,
+            });
+        } else {
+            self.repost(slot)?;
+            self.pending_credits += 1;
+        }
+        self.wake_reader();
+        if self.credits > 1{
+            self.wake_writer();
+        }
+        Ok(())
+    }
+    fn process_send(&mut self, wc: &ibv_wc) -> io::Result<()>{
+        if wc.status != ibv_wc_status::IBV_WC_SUCCESS{
+            if wc.status == ibv_wc_status::IBV_WC_WR_FLUSH_ERR{
+                self.set_disconnected();
+                return Ok(());
+            }
+            return Err(io::Error::new(io::ErrorKind::ConnectionReset, format!("send completion status {}", wc.status)));
+        }
+        self.free_send_slots.push(wc.wr_id as usize);
+        self.wake_writer();
+        Ok(())
+    }
+    fn process_cm_events(&mut self) -> io::Result<()>{
+        loop {
+            let mut event = null_mut();
+            let ret = unsafe { rdma_get_cm_event((*self.id.id()).channel, &mut event) };
+            if ret != 0{
+                let error = io::Error::last_os_error();
+                if error.kind() == io::ErrorKind::WouldBlock{
+                    return Ok(());
+                }
+                return Err(error);
+            }
+            let event_type = unsafe { (*event).event };
+            unsafe { rdma_ack_cm_event(event) };
+            if event_type == rdma_cm_event_type::RDMA_CM_EVENT_DISCONNECTED || event_type == rdma_cm_event_type::RDMA_CM_EVENT_DEVICE_REMOVAL{
+                self.set_disconnected();
+            }
+        }
+    }
+    /// Processes completions and CM events. Ready if anything was processed,
+    /// otherwise the waker of `cx` is registered with all channels.
+    fn poll_progress(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>>{
+        let mut progressed = false;
+        let mut wcs = unsafe { std::mem::zeroed::<[ibv_wc; POLL_BATCH]>() };
+        if let Poll::Ready(polled) = self.recv_cq.as_ref().unwrap().poll(cx, &mut wcs)?{
+            for wc in wcs[..polled].iter(){
+                self.process_recv(wc)?;
+            }
+            progressed = true;
+        }
+        if let Poll::Ready(polled) = self.send_cq.as_ref().unwrap().poll(cx, &mut wcs)?{
+            for wc in wcs[..polled].iter(){
+                self.process_send(wc)?;
+            }
+            progressed = true;
+        }
+        // polled until pending, so the waker stays registered with the CM
+        // channel. Readiness is cleared before draining, an event arriving
+        // meanwhile sets the fd ready again.
+        while let Poll::Ready(guard) = self.cm.as_ref().unwrap().poll_read_ready(cx){
+            guard?.clear_ready();
+            let disconnected = self.disconnected;
+            self.process_cm_events()?;
+            progressed |= self.disconnected != disconnected;
+        }
+        if !progressed{
+            return Poll::Pending;
+        }
+        self.return_credits()?;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for RdmaStream{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>>{
+        let this = self.get_mut();
+        this.read_waker = Some(cx.waker().clone());
+        loop {
+            if buf.remaining() == 0{
+                return Poll::Ready(Ok(()));
+            }
+            if !this.frames.is_empty(){
+                while buf.remaining() > 0{
+                    let frame = match this.frames.front_mut(){
+                        Some(frame) => frame,
+                        None => break,
+                    };
+                    let length = buf.remaining().min(frame.end - frame.offset);
+                    let slot = this.recv_ring.as_ref().unwrap().slot(frame.slot);
+                    buf.put_slice(&slot[frame.offset..frame.offset + length]);
+                    frame.offset += length;
+                    if frame.offset == frame.end{
+                        let slot = frame.slot;
+                        this.frames.pop_front();
+                        this.repost(slot)?;
+                        this.pending_credits += 1;
+                    }
+                }
+                this.return_credits()?;
+                return Poll::Ready(Ok(()));
+            }
+            if this.fin_received || this.disconnected{
+                return Poll::Ready(Ok(()));
+            }
+            ready!(this.poll_progress(cx))?;
+        }
+    }
+}
+
+impl AsyncWrite for RdmaStream{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>>{
+        let this = self.get_mut();
+        this.write_waker = Some(cx.waker().clone());
+        if this.fin_sent{
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe, "stream was shut down")));
+        }
+        if buf.is_empty(){
+            return Poll::Ready(Ok(0));
+        }
+        loop {
+            if this.disconnected{
+                return Poll::Ready(Err(io::Error::from(io::ErrorKind::BrokenPipe)));
+            }
+            if this.can_send(){
+                let length = buf.len().min(this.max_payload);
+                this.post_frame(&buf[..length], 0)?;
+                return Poll::Ready(Ok(length));
+            }
+            ready!(this.poll_progress(cx))?;
+        }
+    }
+    /// Ready once all sent frames completed, i.e. were acknowledged by the
+    /// peer.
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>>{
+        let this = self.get_mut();
+        this.write_waker = Some(cx.waker().clone());
+        loop {
+            if this.disconnected || this.free_send_slots.len() == this.config.depth as usize{
+                return Poll::Ready(Ok(()));
+            }
+            ready!(this.poll_progress(cx))?;
+        }
+    }
+    /// Sends a FIN frame, after which the peer reads end of file, and
+    /// flushes. Receiving keeps working.
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>>{
+        let this = self.as_mut().get_mut();
+        this.write_waker = Some(cx.waker().clone());
+        while !this.fin_sent && !this.disconnected{
+            if this.can_send(){
+                this.post_frame(&[], FLAG_FIN)?;
+                this.fin_sent = true;
+                break;
+            }
+            ready!(this.poll_progress(cx))?;
+        }
+        self.poll_flush(cx)
+    }
+}
+
+impl Drop for RdmaStream{
+    /// Disconnects, deregisters the rings and destroys the endpoint.
+    fn drop(&mut self){
+        // the reactor has to let go of the fds before rdma_destroy_ep
+        // closes them
+        self.recv_cq.take();
+        self.send_cq.take();
+        self.cm.take();
+        if self.id.id().is_null(){
+            return;
+        }
+        unsafe { rdma_disconnect(self.id.id()) };
+        self.recv_ring.take();
+        self.send_ring.take();
+        unsafe { rdma_destroy_ep(self.id.id()) };
+    }
+}
+
+/// Resolves `addr` and creates an endpoint with a QP sized for `config`,
+/// a passive one to listen on for `passive`.
+fn create_stream_ep(addr: SocketAddr, config: &StreamConfig, passive: bool) -> io::Result<Id>{
+    let mut init_attr = config.init_attr();
+    create_ep(&addr.ip().to_string(), addr.port(), None, passive, Some(&mut init_attr)).map_err(io_error)
+}
+
+/// Accepts `RdmaStream`s, the RDMA counterpart of a TCP listener.
+pub struct RdmaListener{
+    listen_id: Id,
+    config: StreamConfig,
+    fd: Option<AsyncFd<RawFd>>,
+}
+
+unsafe impl Send for RdmaListener{}
+unsafe impl Sync for RdmaListener{}
+
+impl RdmaListener{
+    pub async fn bind(addr: SocketAddr) -> io::Result<RdmaListener>{
+        RdmaListener::bind_with_config(addr, StreamConfig::default()).await
+    }
+    pub async fn bind_with_config(addr: SocketAddr, config: StreamConfig) -> io::Result<RdmaListener>{
+        config.validate()?;
+        let listen_id = create_stream_ep(addr, &config, true)?;
+        let mut listener = RdmaListener{
+            listen_id,
+            config,
+            fd: None,
+        };
+        let ret = unsafe { rdma_listen(listener.listen_id.id(), 0) };
+        if ret != 0{
+            return Err(io::Error::other(format!("rdma_listen {}: {}", addr, io::Error::last_os_error())));
+        }
+        let fd = unsafe { (*(*listener.listen_id.id()).channel).fd };
+        set_nonblocking(fd)?;
+        listener.fd = Some(AsyncFd::new(fd)?);
+        Ok(listener)
+    }
+    /// Waits for the next connection request and accepts it.
+    pub async fn accept(&self) -> io::Result<RdmaStream>{
+        let id = self.get_request().await?;
+        self.establish(id, None).await
+    }
+    /// Waits for the next connection request. Errors are errors of the
+    /// listener itself.
+    pub(crate) async fn get_request(&self) -> io::Result<Id>{
+        let fd = self.fd.as_ref().unwrap();
+        loop {
+            let mut guard = fd.readable().await?;
+            let mut id = null_mut();
+            if unsafe { rdma_get_request(self.listen_id.id(), &mut id) } == 0{
+                return Ok(Id::new(id));
+            }
+            let error = io::Error::last_os_error();
+            if error.kind() != io::ErrorKind::WouldBlock{
+                return Err(error);
+            }
+            guard.clear_ready();
+        }
+    }
+    /// Accepts the connection request `id` and exchanges the hello frames,
+    /// waiting at most `hello_timeout` for the establishment and for the
+    /// hello of the peer. Errors only concern this connection.
+    pub(crate) async fn establish(&self, id: Id, hello_timeout: Option<Duration>) -> io::Result<RdmaStream>{
+        let mut stream = RdmaStream::new(id, self.config);
+        stream.post_recv_ring()?;
+        // the accept is bounded on its blocking thread, dropping the stream
+        // while rdma_accept still runs would destroy the id under it
+        let mut accept_id = stream.id.clone();
+        accept_id.set_timeout(hello_timeout);
+        tokio::task::spawn_blocking(move || accept_ep(&accept_id)).await?.map_err(io_error)?;
+        match hello_timeout{
+            Some(hello_timeout) => tokio::time::timeout(hello_timeout, stream.start()).await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "peer did not complete the handshake"))??,
+            None => stream.start().await?,
+        }
+        Ok(stream)
+    }
+}
+
+impl Drop for RdmaListener{
+    fn drop(&mut self){
+        self.fd.take();
+        unsafe { rdma_destroy_ep(self.listen_id.id()) };
+    }
+}
@@ -0,0 +1,363 @@
+use std::{str::FromStr, sync::{atomic::{AtomicBool, Ordering}, Arc}, time::{Duration, Instant}};
+use libc::c_int;
+use rdma_sys::*;
+use crate::{CustomError, Id};
+
+/// Longest time a wait blocks before it rechecks the cancellation token.
+const WAIT_SLICE: Duration = Duration::from_millis(100);
+/// Spins between two checks of the deadline while busy polling.
+const SPINS_PER_CHECK: u32 = 1024;
+/// Time `PollMode::Adaptive` spins before it sleeps, unless given.
+pub const DEFAULT_SPIN_BUDGET: Duration = Duration::from_micros(50);
+/// Work request id of the markers `flush_qp` posts behind the flushed work
+/// requests.
+const FLUSH_WR_ID: u64 = u64::MAX;
+/// Longest time `flush_qp` waits for the flush completions.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How a blocking wait for completions uses the CPU.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PollMode{
+    /// Spins on `ibv_poll_cq`: lowest latency, a whole core per waiting
+    /// thread.
+    Busy,
+    /// Arms the completion channel and sleeps until it fires.
+    #[default]
+    Event,
+    /// Spins for the budget, then arms the channel and sleeps.
+    Adaptive(Duration),
+}
+
+impl FromStr for PollMode{
+    type Err = String;
+    /// `busy`, `event`, `adaptive` or `adaptive:<spin budget in µs>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err>{
+        match s.split_once(':'){
+            Some(("adaptive", budget)) => match budget.parse::<u64>(){
+                Ok(budget) => Ok(PollMode::Adaptive(Duration::from_micros(budget))),
+                Err(_) => Err(format!("invalid spin budget {} in µs", budget)),
+            },
+            None if s == "busy" => Ok(PollMode::Busy),
+            None if s == "event" => Ok(PollMode::Event),
+            None if s == "adaptive" => Ok(PollMode::Adaptive(DEFAULT_SPIN_BUDGET)),
+            _ => Err(format!("unknown poll mode {}, expected busy, event, adaptive or adaptive:<µs>", s)),
+        }
+    }
+}
+
+/// Cancels all blocking waits on the ids it is attached to. Clones share the
+/// same state, so the owner of a session can keep one and trigger it from
+/// another thread.
+#[derive(Clone, Default, Debug)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken{
+    pub fn new() -> CancellationToken{
+        CancellationToken::default()
+    }
+    pub fn cancel(&self){
+        self.0.store(true, Ordering::SeqCst);
+    }
+    pub fn is_cancelled(&self) -> bool{
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Timeout applied to every single blocking wait on an id, the token which
+/// cancels them and how they wait for completions.
+#[derive(Clone, Default, Debug)]
+pub struct WaitControl{
+    pub timeout: Option<Duration>,
+    pub token: CancellationToken,
+    pub poll_mode: PollMode,
+}
+
+impl WaitControl{
+    /// Starts the deadline of one operation.
+    pub fn deadline(&self) -> Deadline{
+        Deadline{
+            until: self.timeout.map(|timeout| Instant::now() + timeout),
+            token: self.token.clone(),
+        }
+    }
+}
+
+pub struct Deadline{
+    until: Option<Instant>,
+    token: CancellationToken,
+}
+
+impl Deadline{
+    /// Fails with `ECANCELED` or `ETIMEDOUT` once the operation has to stop.
+    pub fn check(&self, what: &str) -> anyhow::Result<(), CustomError>{
+        if self.token.is_cancelled(){
+            return Err(CustomError::new(format!("{} cancelled", what), -libc::ECANCELED));
+        }
+        if let Some(until) = self.until{
+            if Instant::now() >= until{
+                return Err(CustomError::new(format!("{} timed out", what), -libc::ETIMEDOUT));
+            }
+        }
+        Ok(())
+    }
+    /// Time left until the deadline, at most `WAIT_SLICE`, so that a wait
+    /// notices a cancellation.
+    pub(crate) fn slice(&self) -> Duration{
+        match self.until{
+            Some(until) => until.saturating_duration_since(Instant::now()).min(WAIT_SLICE),
+            None => WAIT_SLICE,
+        }
+    }
+    /// Blocks until `fd` becomes readable.
+    pub fn wait_fd(&self, fd: c_int, what: &str) -> anyhow::Result<(), CustomError>{
+        loop {
+            self.check(what)?;
+            let mut pollfd = libc::pollfd{
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let ret = unsafe { libc::poll(&mut pollfd, 1, self.slice().as_millis() as c_int) };
+            if ret > 0{
+                return Ok(());
+            }
+            if ret < 0{
+                let error = std::io::Error::last_os_error();
+                if error.kind() != std::io::ErrorKind::Interrupted{
+                    return Err(CustomError::new(format!("poll {}", what), -error.raw_os_error().unwrap_or(1)));
+                }
+            }
+        }
+    }
+}
+
+/// Whether `error` was raised by a timeout or a cancellation.
+pub fn is_timeout(error: &CustomError) -> bool{
+    error.code() == -libc::ETIMEDOUT || error.code() == -libc::ECANCELED
+}
+
+/// Posts a signaled marker behind the send queue of `qp`. Returns whether
+/// it was posted.
+fn post_flush_send(qp: *mut ibv_qp) -> bool{
+    let mut wr = unsafe { std::mem::zeroed::<ibv_send_wr>() };
+    wr.wr_id = FLUSH_WR_ID;
+    wr.opcode = ibv_wr_opcode::IBV_WR_SEND;
+    wr.send_flags = ibv_send_flags::IBV_SEND_SIGNALED.0;
+    let mut bad_wr = std::ptr::null_mut();
+    unsafe { ibv_post_send(qp, &mut wr, &mut bad_wr) == 0 }
+}
+
+/// Posts a marker behind the receive queue of `qp`. Returns whether it was
+/// posted.
+fn post_flush_recv(qp: *mut ibv_qp) -> bool{
+    let mut wr = unsafe { std::mem::zeroed::<ibv_recv_wr>() };
+    wr.wr_id = FLUSH_WR_ID;
+    let mut bad_wr = std::ptr::null_mut();
+    unsafe { ibv_post_recv(qp, &mut wr, &mut bad_wr) == 0 }
+}
+
+/// Takes all completions off `cq`. Returns whether there were any and
+/// whether the flush marker was among them.
+fn drain_cq(cq: *mut ibv_cq) -> (bool, bool){
+    let mut wc = unsafe { std::mem::zeroed::<ibv_wc>() };
+    let mut drained = false;
+    let mut marker = false;
+    while unsafe { ibv_poll_cq(cq, 1, &mut wc) } > 0 {
+        drained = true;
+        marker |= wc.wr_id == FLUSH_WR_ID;
+    }
+    (drained, marker)
+}
+
+/// Moves the QP of `id` to the error state, so that all posted work
+/// requests complete with a flush error, and drains its CQs until they are
+/// accounted for. Work requests complete in order, so a marker posted
+/// behind them completes last. Without a marker, e.g. on a full queue, the
+/// CQ is drained until a poll finds it empty. Receives of an SRQ are not
+/// flushed and the shared CQ is left to the SRQ.
+pub fn flush_qp(id: &Id){
+    let qp = unsafe { (*id.id()).qp };
+    if qp.is_null(){
+        return;
+    }
+    let mut qp_attr = unsafe { std::mem::zeroed::<ibv_qp_attr>() };
+    qp_attr.qp_state = ibv_qp_state::IBV_QPS_ERR;
+    unsafe { ibv_modify_qp(qp, &mut qp_attr, ibv_qp_attr_mask::IBV_QP_STATE.0 as i32) };
+    let mut pending = Vec::with_capacity(2);
+    let send_cq = unsafe { (*id.id()).send_cq };
+    if !send_cq.is_null(){
+        pending.push((send_cq, post_flush_send(qp)));
+    }
+    let recv_cq = unsafe { (*id.id()).recv_cq };
+    if !recv_cq.is_null() && unsafe { (*qp).srq }.is_null(){
+        pending.push((recv_cq, post_flush_recv(qp)));
+    }
+    let until = Instant::now() + FLUSH_TIMEOUT;
+    while !pending.is_empty() && Instant::now() < until{
+        pending.retain(|(cq, marked)|{
+            let (drained, marker) = drain_cq(*cq);
+            if *marked { !marker } else { drained }
+        });
+        std::hint::spin_loop();
+    }
+}
+
+/// Waits for the completion channel until `deadline` and takes the event
+/// off it.
+pub(crate) fn get_cq_event(deadline: &Deadline, channel: *mut ibv_comp_channel, cq: *mut ibv_cq) -> anyhow::Result<(), CustomError>{
+    deadline.wait_fd(unsafe { (*channel).fd }, "completion wait")?;
+    let mut ev_cq = std::ptr::null_mut();
+    let mut context = std::ptr::null_mut();
+    let ret = unsafe { ibv_get_cq_event(channel, &mut ev_cq, &mut context) };
+    if ret != 0 {
+        return Err(CustomError::new("ibv_get_cq_event".to_string(), ret));
+    }
+    unsafe { ibv_ack_cq_events(ev_cq, 1) };
+    if ev_cq != cq{
+        return Err(CustomError::new("completion event for unexpected cq".to_string(), -1));
+    }
+    Ok(())
+}
+
+/// Calls `poll` until it reports that the wait is over or `deadline` passes.
+/// In between, it spins or arms `channel` of `cq` and sleeps, as
+/// `poll_mode` says. The CQ is polled once more after arming, so no
+/// completion is missed.
+pub(crate) fn poll_cq_until<F: FnMut() -> anyhow::Result<bool, CustomError>>(poll_mode: PollMode, deadline: &Deadline, cq: *mut ibv_cq, channel: *mut ibv_comp_channel, poll: F) -> anyhow::Result<(), CustomError>{
+    poll_until(poll_mode, deadline, poll, |poll|{
+        let ret = unsafe { ibv_req_notify_cq(cq, 0) };
+        if ret != 0 {
+            return Err(CustomError::new("ibv_req_notify_cq".to_string(), ret));
+        }
+        if poll()?{
+            return Ok(true);
+        }
+        get_cq_event(deadline, channel, cq)?;
+        Ok(false)
+    })
+}
+
+/// Calls `poll` until it reports that the wait is over or `deadline` passes,
+/// spinning in between as `poll_mode` says. Once done spinning, it calls
+/// `sleep` instead, which blocks until there may be something to poll and
+/// reports whether the wait is over.
+pub(crate) fn poll_until<F, S>(poll_mode: PollMode, deadline: &Deadline, mut poll: F, mut sleep: S) -> anyhow::Result<(), CustomError>
+where
+    F: FnMut() -> anyhow::Result<bool, CustomError>,
+    S: FnMut(&mut F) -> anyhow::Result<bool, CustomError>,
+{
+    let spin_budget = match poll_mode{
+        PollMode::Busy => None,
+        PollMode::Event => Some(Duration::ZERO),
+        PollMode::Adaptive(budget) => Some(budget),
+    };
+    let start = Instant::now();
+    let mut spins = 0u32;
+    loop {
+        if poll()?{
+            return Ok(());
+        }
+        let spinning = match spin_budget{
+            Some(budget) => start.elapsed() < budget,
+            None => true,
+        };
+        if spinning{
+            spins += 1;
+            if spins == SPINS_PER_CHECK{
+                spins = 0;
+                deadline.check("completion wait")?;
+            }
+            std::hint::spin_loop();
+            continue;
+        }
+        if sleep(&mut poll)?{
+            return Ok(());
+        }
+    }
+}
+
+/// `poll_cq_until` bounded by the wait control of `id`. Flushes the QP of
+/// `id` when the wait times out or is cancelled.
+pub(crate) fn wait_cq<F: FnMut() -> anyhow::Result<bool, CustomError>>(id: &Id, cq: *mut ibv_cq, channel: *mut ibv_comp_channel, poll: F) -> anyhow::Result<(), CustomError>{
+    let deadline = id.wait_control().deadline();
+    poll_cq_until(id.wait_control().poll_mode, &deadline, cq, channel, poll).inspect_err(|e| {
+        if is_timeout(e){
+            flush_qp(id);
+        }
+    })
+}
+
+fn get_comp(id: &Id, cq: *mut ibv_cq, channel: *mut ibv_comp_channel, wc: &mut ibv_wc) -> anyhow::Result<c_int, CustomError>{
+    let mut ret = 0;
+    wait_cq(id, cq, channel, ||{
+        ret = unsafe { ibv_poll_cq(cq, 1, wc) };
+        Ok(ret != 0)
+    })?;
+    Ok(ret)
+}
+
+/// `rdma_get_send_comp` bounded by the wait control of `id`.
+pub fn get_send_comp(id: &Id, wc: &mut ibv_wc) -> anyhow::Result<c_int, CustomError>{
+    unsafe { get_comp(id, (*id.id()).send_cq, (*id.id()).send_cq_channel, wc) }
+}
+
+/// `rdma_get_recv_comp` bounded by the wait control of `id`.
+pub fn get_recv_comp(id: &Id, wc: &mut ibv_wc) -> anyhow::Result<c_int, CustomError>{
+    unsafe { get_comp(id, (*id.id()).recv_cq, (*id.id()).recv_cq_channel, wc) }
+}
+
+pub(crate) fn wait_event_channel(channel: *mut rdma_event_channel, wait_control: &WaitControl) -> anyhow::Result<(), CustomError>{
+    if channel.is_null(){
+        return Err(CustomError::new("event channel is null".to_string(), -1));
+    }
+    wait_control.deadline().wait_fd(unsafe { (*channel).fd }, "cm event wait")
+}
+
+/// `rdma_connect` of the synchronous `id` of `rdma_create_ep`, bounded by
+/// its wait control: the id is moved to an event channel of its own for the
+/// connect and back to synchronous operation once it is established.
+pub fn connect_ep(id: &Id) -> anyhow::Result<(), CustomError>{
+    establish_ep(id, "rdma_connect", |id| unsafe { rdma_connect(id, std::ptr::null_mut()) })
+}
+
+/// `rdma_accept` of a synchronous connection request `id`, bounded by its
+/// wait control like `connect_ep`.
+pub fn accept_ep(id: &Id) -> anyhow::Result<(), CustomError>{
+    establish_ep(id, "rdma_accept", |id| unsafe { rdma_accept(id, std::ptr::null_mut()) })
+}
+
+fn establish_ep<F: FnOnce(*mut rdma_cm_id) -> c_int>(id: &Id, what: &str, establish: F) -> anyhow::Result<(), CustomError>{
+    let channel = unsafe { rdma_create_event_channel() };
+    if channel.is_null(){
+        return Err(CustomError::new("rdma_create_event_channel".to_string(), -1));
+    }
+    let ret = unsafe { rdma_migrate_id(id.id(), channel) };
+    if ret != 0{
+        unsafe { rdma_destroy_event_channel(channel) };
+        return Err(CustomError::new("rdma_migrate_id".to_string(), ret));
+    }
+    let ret = establish(id.id());
+    let connected = if ret != 0{
+        Err(CustomError::new(what.to_string(), ret))
+    } else {
+        let mut event = std::ptr::null_mut();
+        crate::process_rdma_cm_event(channel, rdma_cm_event_type::RDMA_CM_EVENT_ESTABLISHED, &mut event, id.wait_control())
+            .map(|_| unsafe { rdma_ack_cm_event(event); })
+    };
+    let ret = unsafe { rdma_migrate_id(id.id(), std::ptr::null_mut()) };
+    unsafe { rdma_destroy_event_channel(channel) };
+    connected?;
+    if ret != 0{
+        return Err(CustomError::new("rdma_migrate_id".to_string(), ret));
+    }
+    Ok(())
+}
+
+/// Waits until a CM event is pending on the event channel of `id`, e.g.
+/// before `rdma_get_request` on a listening id.
+pub fn wait_cm_event(id: &Id, wait_control: &WaitControl) -> anyhow::Result<(), CustomError>{
+    if id.id().is_null(){
+        return Err(CustomError::new("id is null".to_string(), -1));
+    }
+    wait_event_channel(unsafe { (*id.id()).channel }, wait_control)
+}
@@ -0,0 +1,634 @@
+use std::{net::IpAddr, ptr::null_mut, time::Duration};
+use libc::c_int;
+use rdma_sys::*;
+use crate::{CustomError, Data, Id, MrObject, MrRegister, process_rdma_cm_event, timeout::{poll_cq_until, wait_cq, WaitControl}};
+
+/// Bytes in front of every UD receive, where the HCA puts the global
+/// routing header whether or not the packet carried one.
+pub const GRH_LEN: usize = 40;
+/// Every datagram starts with its kind (u32), padding (u32), a sequence
+/// number (u64) and an argument (u64) whose meaning depends on the kind.
+pub const UD_HEADER_LEN: usize = 24;
+/// Receives posted at a time, which is also the number of sends in flight.
+const UD_DEPTH: usize = 256;
+/// Time the CM gets to resolve the address and the route.
+const RESOLVE_TIMEOUT_MS: c_int = 2000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DatagramKind{
+    Data,
+    Ping,
+    Pong,
+    /// The sender is done, `seq` is the number of datagrams it sent.
+    Done,
+    /// Answer to `Done`, `seq` is the number of datagrams that arrived and
+    /// `arg` how many of them out of order.
+    Report,
+}
+
+impl DatagramKind{
+    fn to_u32(self) -> u32{
+        match self{
+            DatagramKind::Data => 1,
+            DatagramKind::Ping => 2,
+            DatagramKind::Pong => 3,
+            DatagramKind::Done => 4,
+            DatagramKind::Report => 5,
+        }
+    }
+    fn from_u32(kind: u32) -> Option<DatagramKind>{
+        match kind{
+            1 => Some(DatagramKind::Data),
+            2 => Some(DatagramKind::Ping),
+            3 => Some(DatagramKind::Pong),
+            4 => Some(DatagramKind::Done),
+            5 => Some(DatagramKind::Report),
+            _ => None,
+        }
+    }
+}
+
+/// Header of a received datagram.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Datagram{
+    pub kind: DatagramKind,
+    pub seq: u64,
+    pub arg: u64,
+    /// Bytes of the datagram without the GRH.
+    pub len: usize,
+    /// QP the datagram came from.
+    pub src_qp: u32,
+}
+
+fn write_header(buffer: &mut [u8], kind: DatagramKind, seq: u64, arg: u64){
+    buffer[0..4].copy_from_slice(&kind.to_u32().to_le_bytes());
+    buffer[4..8].copy_from_slice(&0u32.to_le_bytes());
+    buffer[8..16].copy_from_slice(&seq.to_le_bytes());
+    buffer[16..24].copy_from_slice(&arg.to_le_bytes());
+}
+
+fn read_header(buffer: &[u8], len: usize, src_qp: u32) -> Option<Datagram>{
+    if len < UD_HEADER_LEN || buffer.len() < UD_HEADER_LEN{
+        return None;
+    }
+    Some(Datagram{
+        kind: DatagramKind::from_u32(u32::from_le_bytes(buffer[0..4].try_into().ok()?))?,
+        seq: u64::from_le_bytes(buffer[8..16].try_into().ok()?),
+        arg: u64::from_le_bytes(buffer[16..24].try_into().ok()?),
+        len,
+        src_qp,
+    })
+}
+
+/// Socket address of `ip` and `port` the CM calls take.
+pub(crate) fn sockaddr(ip: IpAddr, port: u16) -> libc::sockaddr_storage{
+    let mut storage = unsafe { std::mem::zeroed::<libc::sockaddr_storage>() };
+    match ip{
+        IpAddr::V4(ip) => {
+            let sin = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage).cast::<libc::sockaddr_in>() };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = port.to_be();
+            sin.sin_addr.s_addr = u32::from(ip).to_be();
+        },
+        IpAddr::V6(ip) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage).cast::<libc::sockaddr_in6>() };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = port.to_be();
+            sin6.sin6_addr.s6_addr = ip.octets();
+        },
+    }
+    storage
+}
+
+/// Path MTU of `id`, the largest datagram UD carries: the MTU of the path
+/// record of its route, capped by the active MTU of its port. Ids without a
+/// resolved route, like the one of a listener, only have the port MTU.
+fn query_mtu(id: *mut rdma_cm_id) -> anyhow::Result<usize, CustomError>{
+    let mut port_attr = unsafe { std::mem::zeroed::<ibv_port_attr>() };
+    let ret = unsafe { ibv_query_port((*id).verbs, (*id).port_num, &mut port_attr) };
+    if ret != 0{
+        return Err(CustomError::new("ibv_query_port".to_string(), ret));
+    }
+    let mut mtu = port_attr.active_mtu as u32;
+    let route = unsafe { &(*id).route };
+    if route.num_paths > 0 && !route.path_rec.is_null(){
+        let path_mtu = unsafe { (*route.path_rec).mtu } as u32;
+        if path_mtu > 0{
+            mtu = mtu.min(path_mtu);
+        }
+    }
+    // IBV_MTU_256 is 1, every step doubles it
+    Ok(128usize << mtu)
+}
+
+fn register(pd: *mut ibv_pd, size: usize) -> anyhow::Result<Data, CustomError>{
+    let mut data = Data::new(size);
+    let mr = unsafe { ibv_reg_mr(pd, data.addr(), size, ibv_access_flags::IBV_ACCESS_LOCAL_WRITE.0 as i32) };
+    if mr.is_null(){
+        return Err(CustomError::new("ibv_reg_mr".to_string(), -1));
+    }
+    data.set_mr(mr);
+    Ok(data)
+}
+
+/// Where datagrams go: an address handle and the QP number and Q_Key of the
+/// remote UD QP.
+pub struct UdPeer{
+    ah: *mut ibv_ah,
+    qpn: u32,
+    qkey: u32,
+}
+
+unsafe impl Send for UdPeer{}
+
+impl UdPeer{
+    /// Address handle from the `ah_attr` the CM reported for `qpn`.
+    pub(crate) fn new(pd: *mut ibv_pd, ah_attr: &mut ibv_ah_attr, qpn: u32, qkey: u32) -> anyhow::Result<UdPeer, CustomError>{
+        let ah = unsafe { ibv_create_ah(pd, ah_attr) };
+        if ah.is_null(){
+            return Err(CustomError::new("ibv_create_ah".to_string(), -1));
+        }
+        Ok(UdPeer{ah, qpn, qkey})
+    }
+    pub fn qpn(&self) -> u32{
+        self.qpn
+    }
+}
+
+impl Drop for UdPeer{
+    fn drop(&mut self){
+        unsafe { ibv_destroy_ah(self.ah) };
+    }
+}
+
+/// A UD QP set up through the CM with `RDMA_PS_UDP`, exchanging datagrams
+/// of at most the path MTU with one peer or a multicast group. Receives are
+/// posted with room for the GRH in front of the payload.
+pub struct UdEndpoint{
+    id: Id,
+    /// Event channel of the endpoint, null for accepted ids, which report
+    /// on the channel of their listener.
+    channel: *mut rdma_event_channel,
+    mtu: usize,
+    recv_buffers: Vec<Data>,
+    send_buffers: Vec<Data>,
+    next_send: usize,
+    sends_in_flight: usize,
+    peer: Option<UdPeer>,
+    /// Whether a datagram from another QP makes its sender the peer, which
+    /// accepted endpoints do to answer their clients.
+    learns_peer: bool,
+    /// Multicast group the endpoint joined.
+    group: Option<libc::sockaddr_storage>,
+}
+
+unsafe impl Send for UdEndpoint{}
+
+impl UdEndpoint{
+    fn new(id: *mut rdma_cm_id, channel: *mut rdma_event_channel, wait_control: &WaitControl) -> UdEndpoint{
+        let mut id = Id::new(id);
+        id.set_timeout(wait_control.timeout);
+        id.set_cancellation_token(wait_control.token.clone());
+        id.set_poll_mode(wait_control.poll_mode);
+        UdEndpoint{
+            id,
+            channel,
+            mtu: 0,
+            recv_buffers: Vec::new(),
+            send_buffers: Vec::new(),
+            next_send: 0,
+            sends_in_flight: 0,
+            peer: None,
+            learns_peer: false,
+            group: None,
+        }
+    }
+    /// An endpoint with an id on an event channel of its own.
+    fn create(wait_control: &WaitControl) -> anyhow::Result<UdEndpoint, CustomError>{
+        let channel = unsafe { rdma_create_event_channel() };
+        if channel.is_null(){
+            return Err(CustomError::new("rdma_create_event_channel".to_string(), -1));
+        }
+        let mut id = null_mut();
+        let ret = unsafe { rdma_create_id(channel, &mut id, null_mut(), rdma_port_space::RDMA_PS_UDP) };
+        if ret != 0{
+            unsafe { rdma_destroy_event_channel(channel) };
+            return Err(CustomError::new("rdma_create_id".to_string(), ret));
+        }
+        Ok(UdEndpoint::new(id, channel, wait_control))
+    }
+    /// Resolves `server`, creates the QP and connects it to the UD QP
+    /// listening on `port`. The address handle of the server is built from
+    /// the established event.
+    pub fn connect(server: IpAddr, port: u16, wait_control: &WaitControl) -> anyhow::Result<UdEndpoint, CustomError>{
+        let mut endpoint = UdEndpoint::create(wait_control)?;
+        let id = endpoint.id.id();
+        let channel = endpoint.channel;
+        let mut dst_addr = sockaddr(server, port);
+        let ret = unsafe { rdma_resolve_addr(id, null_mut(), (&mut dst_addr as *mut libc::sockaddr_storage).cast(), RESOLVE_TIMEOUT_MS) };
+        if ret != 0{
+            return Err(CustomError::new("rdma_resolve_addr".to_string(), ret));
+        }
+        endpoint.expect_event(rdma_cm_event_type::RDMA_CM_EVENT_ADDR_RESOLVED)?;
+        let ret = unsafe { rdma_resolve_route(id, RESOLVE_TIMEOUT_MS) };
+        if ret != 0{
+            return Err(CustomError::new("rdma_resolve_route".to_string(), ret));
+        }
+        endpoint.expect_event(rdma_cm_event_type::RDMA_CM_EVENT_ROUTE_RESOLVED)?;
+        endpoint.create_qp()?;
+        let mut conn_param = unsafe { std::mem::zeroed::<rdma_conn_param>() };
+        let ret = unsafe { rdma_connect(id, &mut conn_param) };
+        if ret != 0{
+            return Err(CustomError::new("rdma_connect".to_string(), ret));
+        }
+        let mut event = null_mut();
+        process_rdma_cm_event(channel, rdma_cm_event_type::RDMA_CM_EVENT_ESTABLISHED, &mut event, endpoint.id.wait_control())?;
+        let mut ud_param = unsafe { (*event).param.ud };
+        unsafe { rdma_ack_cm_event(event) };
+        endpoint.peer = Some(UdPeer::new(unsafe { (*id).pd }, &mut ud_param.ah_attr, ud_param.qp_num, ud_param.qkey)?);
+        Ok(endpoint)
+    }
+    /// Resolves the multicast `group` from `bind_address`, or the address
+    /// routing to it without one, creates the QP and joins the group. The
+    /// address handle of the group is built from the join event, so
+    /// datagrams the endpoint sends go to all members.
+    pub fn join_multicast(bind_address: Option<IpAddr>, group: IpAddr, wait_control: &WaitControl) -> anyhow::Result<UdEndpoint, CustomError>{
+        let mut endpoint = UdEndpoint::create(wait_control)?;
+        let id = endpoint.id.id();
+        let mut src_addr = bind_address.map(|bind_address| sockaddr(bind_address, 0));
+        let src_addr_ptr = match src_addr.as_mut(){
+            Some(src_addr) => (src_addr as *mut libc::sockaddr_storage).cast(),
+            None => null_mut(),
+        };
+        let mut group_addr = sockaddr(group, 0);
+        let ret = unsafe { rdma_resolve_addr(id, src_addr_ptr, (&mut group_addr as *mut libc::sockaddr_storage).cast(), RESOLVE_TIMEOUT_MS) };
+        if ret != 0{
+            return Err(CustomError::new(format!("rdma_resolve_addr {}", group), ret));
+        }
+        endpoint.expect_event(rdma_cm_event_type::RDMA_CM_EVENT_ADDR_RESOLVED)?;
+        endpoint.create_qp()?;
+        let ret = unsafe { rdma_join_multicast(id, (&mut group_addr as *mut libc::sockaddr_storage).cast(), null_mut()) };
+        if ret != 0{
+            return Err(CustomError::new(format!("rdma_join_multicast {}", group), ret));
+        }
+        endpoint.group = Some(group_addr);
+        let mut event = null_mut();
+        process_rdma_cm_event(endpoint.channel, rdma_cm_event_type::RDMA_CM_EVENT_MULTICAST_JOIN, &mut event, endpoint.id.wait_control())?;
+        let mut ud_param = unsafe { (*event).param.ud };
+        unsafe { rdma_ack_cm_event(event) };
+        endpoint.peer = Some(UdPeer::new(unsafe { (*id).pd }, &mut ud_param.ah_attr, ud_param.qp_num, ud_param.qkey)?);
+        Ok(endpoint)
+    }
+    /// Leaves the multicast group, which dropping the endpoint does as well.
+    pub fn leave_multicast(&mut self) -> anyhow::Result<(), CustomError>{
+        let mut group_addr = match self.group.take(){
+            Some(group_addr) => group_addr,
+            None => return Ok(()),
+        };
+        self.peer = None;
+        let ret = unsafe { rdma_leave_multicast(self.id.id(), (&mut group_addr as *mut libc::sockaddr_storage).cast()) };
+        if ret != 0{
+            return Err(CustomError::new("rdma_leave_multicast".to_string(), ret));
+        }
+        Ok(())
+    }
+    fn expect_event(&self, expected_event: rdma_cm_event_type::Type) -> anyhow::Result<(), CustomError>{
+        let mut event = null_mut();
+        process_rdma_cm_event(self.channel, expected_event, &mut event, self.id.wait_control())?;
+        unsafe { rdma_ack_cm_event(event) };
+        Ok(())
+    }
+    /// Creates the UD QP with the CQs and PD of the CM, registers the
+    /// buffers and posts all receives.
+    fn create_qp(&mut self) -> anyhow::Result<(), CustomError>{
+        let mut init_attr = unsafe { std::mem::zeroed::<ibv_qp_init_attr>() };
+        init_attr.qp_type = ibv_qp_type::IBV_QPT_UD;
+        init_attr.cap.max_send_wr = UD_DEPTH as u32;
+        init_attr.cap.max_recv_wr = UD_DEPTH as u32;
+        init_attr.cap.max_send_sge = 1;
+        init_attr.cap.max_recv_sge = 1;
+        init_attr.sq_sig_all = 1;
+        let ret = unsafe { rdma_create_qp(self.id.id(), null_mut(), &mut init_attr) };
+        if ret != 0{
+            return Err(CustomError::new("rdma_create_qp".to_string(), ret));
+        }
+        self.mtu = query_mtu(self.id.id())?;
+        let pd = unsafe { (*self.id.id()).pd };
+        for _ in 0..UD_DEPTH{
+            self.recv_buffers.push(register(pd, GRH_LEN + self.mtu)?);
+            self.send_buffers.push(register(pd, self.mtu)?);
+        }
+        for slot in 0..UD_DEPTH{
+            self.post_recv(slot)?;
+        }
+        Ok(())
+    }
+    fn post_recv(&mut self, slot: usize) -> anyhow::Result<(), CustomError>{
+        let data = &mut self.recv_buffers[slot];
+        let mut sge = ibv_sge{
+            addr: data.addr() as u64,
+            length: data.len() as u32,
+            lkey: unsafe { (*data.mr()).lkey },
+        };
+        let mut wr = ibv_recv_wr{
+            wr_id: slot as u64,
+            next: null_mut(),
+            sg_list: &mut sge,
+            num_sge: 1,
+        };
+        let mut bad_wr = null_mut();
+        let ret = unsafe { ibv_post_recv((*self.id.id()).qp, &mut wr, &mut bad_wr) };
+        if ret != 0{
+            return Err(CustomError::new("ibv_post_recv".to_string(), ret));
+        }
+        Ok(())
+    }
+    pub fn id(&self) -> &Id{
+        &self.id
+    }
+    /// Largest datagram, header included.
+    pub fn mtu(&self) -> usize{
+        self.mtu
+    }
+    pub fn peer(&self) -> Option<&UdPeer>{
+        self.peer.as_ref()
+    }
+    /// Takes the send completions off the CQ, waiting for at least one if
+    /// `wait`.
+    fn reap_sends(&mut self, wait: bool) -> anyhow::Result<(), CustomError>{
+        let cq = unsafe { (*self.id.id()).send_cq };
+        let channel = unsafe { (*self.id.id()).send_cq_channel };
+        let mut reaped = 0;
+        let mut poll = ||{
+            let mut wc = unsafe { std::mem::zeroed::<ibv_wc>() };
+            loop {
+                let ret = unsafe { ibv_poll_cq(cq, 1, &mut wc) };
+                if ret < 0{
+                    return Err(CustomError::new("ibv_poll_cq".to_string(), ret));
+                }
+                if ret == 0{
+                    return Ok(reaped > 0);
+                }
+                if wc.status != ibv_wc_status::IBV_WC_SUCCESS{
+                    return Err(CustomError::new(format!("ud send completed with status {}", wc.status), -1));
+                }
+                reaped += 1;
+            }
+        };
+        if wait{
+            wait_cq(&self.id, cq, channel, poll)?;
+        } else {
+            poll()?;
+        }
+        self.sends_in_flight -= reaped;
+        Ok(())
+    }
+    /// Waits until all posted sends have completed.
+    pub fn wait_sends(&mut self) -> anyhow::Result<(), CustomError>{
+        while self.sends_in_flight > 0{
+            self.reap_sends(true)?;
+        }
+        Ok(())
+    }
+    /// Sends a datagram of `len` bytes, header included, to the peer.
+    pub fn send(&mut self, kind: DatagramKind, seq: u64, arg: u64, len: usize) -> anyhow::Result<(), CustomError>{
+        if len < UD_HEADER_LEN || len > self.mtu{
+            return Err(CustomError::new(format!("datagram of {} bytes, UD carries {} to {} bytes on this path", len, UD_HEADER_LEN, self.mtu), -libc::EMSGSIZE));
+        }
+        if self.peer.is_none(){
+            return Err(CustomError::new("no peer to send to".to_string(), -libc::ENOTCONN));
+        }
+        self.reap_sends(false)?;
+        while self.sends_in_flight == UD_DEPTH{
+            self.reap_sends(true)?;
+        }
+        let slot = self.next_send;
+        self.next_send = (slot + 1) % UD_DEPTH;
+        let data = &mut self.send_buffers[slot];
+        write_header(data.as_mut_slice(), kind, seq, arg);
+        let mut sge = ibv_sge{
+            addr: data.addr() as u64,
+            length: len as u32,
+            lkey: unsafe { (*data.mr()).lkey },
+        };
+        let peer = self.peer.as_ref().unwrap();
+        let mut wr = unsafe { std::mem::zeroed::<ibv_send_wr>() };
+        wr.wr_id = slot as u64;
+        wr.sg_list = &mut sge;
+        wr.num_sge = 1;
+        wr.opcode = ibv_wr_opcode::IBV_WR_SEND;
+        wr.send_flags = ibv_send_flags::IBV_SEND_SIGNALED.0;
+        wr.wr.ud = ud_t{
+            ah: peer.ah,
+            remote_qpn: peer.qpn,
+            remote_qkey: peer.qkey,
+        };
+        let mut bad_wr = null_mut();
+        let ret = unsafe { ibv_post_send((*self.id.id()).qp, &mut wr, &mut bad_wr) };
+        if ret != 0{
+            return Err(CustomError::new("ibv_post_send".to_string(), ret));
+        }
+        self.sends_in_flight += 1;
+        Ok(())
+    }
+    /// Takes the next datagram, waiting up to `timeout` as the poll mode of
+    /// the endpoint says. Unlike the waits of RC ids, running out of time
+    /// leaves the QP usable and returns `None`: a datagram which did not
+    /// arrive is lost, not an error. On accepted endpoints a datagram from
+    /// another QP than the peer makes its sender the peer, with an address
+    /// handle built from the completion and the GRH in front of it.
+    pub fn recv(&mut self, timeout: Duration) -> anyhow::Result<Option<Datagram>, CustomError>{
+        let cq = unsafe { (*self.id.id()).recv_cq };
+        let channel = unsafe { (*self.id.id()).recv_cq_channel };
+        let poll_mode = self.id.wait_control().poll_mode;
+        let deadline = WaitControl{
+            timeout: Some(timeout),
+            token: self.id.wait_control().token.clone(),
+            poll_mode,
+        }.deadline();
+        loop {
+            let mut wc = unsafe { std::mem::zeroed::<ibv_wc>() };
+            let ret = poll_cq_until(poll_mode, &deadline, cq, channel, ||{
+                let ret = unsafe { ibv_poll_cq(cq, 1, &mut wc) };
+                if ret < 0{
+                    return Err(CustomError::new("ibv_poll_cq".to_string(), ret));
+                }
+                Ok(ret > 0)
+            });
+            match ret{
+                Ok(()) => {},
+                Err(e) if e.code() == -libc::ETIMEDOUT => return Ok(None),
+                Err(e) => return Err(e),
+            }
+            if wc.status != ibv_wc_status::IBV_WC_SUCCESS{
+                return Err(CustomError::new(format!("ud receive completed with status {}", wc.status), -1));
+            }
+            let slot = wc.wr_id as usize;
+            // byte_len counts the GRH space, used or not
+            let len = (wc.byte_len as usize).saturating_sub(GRH_LEN);
+            let datagram = read_header(&self.recv_buffers[slot].as_slice()[GRH_LEN..], len, wc.src_qp);
+            if self.learns_peer && datagram.is_some() && self.peer.as_ref().map(|peer| peer.qpn) != Some(wc.src_qp){
+                self.peer = Some(self.peer_from(slot, &mut wc)?);
+            }
+            self.post_recv(slot)?;
+            if let Some(datagram) = datagram{
+                return Ok(Some(datagram));
+            }
+        }
+    }
+    /// Address handle back to the sender of the completion `wc` in `slot`.
+    /// Without `IBV_WC_GRH` the sender is in the same subnet and the GRH
+    /// space holds nothing.
+    fn peer_from(&mut self, slot: usize, wc: &mut ibv_wc) -> anyhow::Result<UdPeer, CustomError>{
+        let id = self.id.id();
+        let grh = self.recv_buffers[slot].addr().cast::<ibv_grh>();
+        let ah = unsafe { ibv_create_ah_from_wc((*id).pd, wc, grh, (*id).port_num) };
+        if ah.is_null(){
+            return Err(CustomError::new("ibv_create_ah_from_wc".to_string(), -1));
+        }
+        Ok(UdPeer{
+            ah,
+            qpn: wc.src_qp,
+            qkey: RDMA_UDP_QKEY,
+        })
+    }
+}
+
+impl Drop for UdEndpoint{
+    fn drop(&mut self){
+        // callers that care whether the leave worked call leave_multicast
+        let _ = self.leave_multicast();
+        let id = self.id.id();
+        if !id.is_null() && unsafe { !(*id).qp.is_null() }{
+            unsafe { rdma_destroy_qp(id) };
+        }
+        self.peer = None;
+        for data in self.recv_buffers.iter().chain(self.send_buffers.iter()){
+            unsafe { ibv_dereg_mr(data.mr()) };
+        }
+        if !id.is_null(){
+            unsafe { rdma_destroy_id(id) };
+        }
+        if !self.channel.is_null(){
+            unsafe { rdma_destroy_event_channel(self.channel) };
+        }
+    }
+}
+
+/// A UD id listening for endpoints to connect.
+pub struct UdListener{
+    id: Id,
+    channel: *mut rdma_event_channel,
+}
+
+unsafe impl Send for UdListener{}
+
+impl UdListener{
+    /// Listens on `address` and `port`. `wait_control` bounds the waits for
+    /// connecting endpoints.
+    pub fn bind(address: IpAddr, port: u16, wait_control: &WaitControl) -> anyhow::Result<UdListener, CustomError>{
+        let channel = unsafe { rdma_create_event_channel() };
+        if channel.is_null(){
+            return Err(CustomError::new("rdma_create_event_channel".to_string(), -1));
+        }
+        let mut id = null_mut();
+        let ret = unsafe { rdma_create_id(channel, &mut id, null_mut(), rdma_port_space::RDMA_PS_UDP) };
+        if ret != 0{
+            unsafe { rdma_destroy_event_channel(channel) };
+            return Err(CustomError::new("rdma_create_id".to_string(), ret));
+        }
+        let mut listener = UdListener{
+            id: Id::new(id),
+            channel,
+        };
+        listener.id.set_timeout(wait_control.timeout);
+        listener.id.set_cancellation_token(wait_control.token.clone());
+        let mut addr = sockaddr(address, port);
+        let ret = unsafe { rdma_bind_addr(id, (&mut addr as *mut libc::sockaddr_storage).cast()) };
+        if ret != 0{
+            return Err(CustomError::new(format!("rdma_bind_addr {}:{}", address, port), ret));
+        }
+        let ret = unsafe { rdma_listen(id, 128) };
+        if ret != 0{
+            return Err(CustomError::new("rdma_listen".to_string(), ret));
+        }
+        Ok(listener)
+    }
+    /// Waits for the next endpoint to connect, creates a UD QP for it and
+    /// accepts it. Its waits are bounded by `wait_control`.
+    pub fn accept(&self, wait_control: &WaitControl) -> anyhow::Result<UdEndpoint, CustomError>{
+        let mut event = null_mut();
+        process_rdma_cm_event(self.channel, rdma_cm_event_type::RDMA_CM_EVENT_CONNECT_REQUEST, &mut event, self.id.wait_control())?;
+        let id = unsafe { (*event).id };
+        unsafe { rdma_ack_cm_event(event) };
+        let mut endpoint = UdEndpoint::new(id, null_mut(), wait_control);
+        endpoint.learns_peer = true;
+        endpoint.create_qp()?;
+        let mut conn_param = unsafe { std::mem::zeroed::<rdma_conn_param>() };
+        let ret = unsafe { rdma_accept(id, &mut conn_param) };
+        if ret != 0{
+            return Err(CustomError::new("rdma_accept".to_string(), ret));
+        }
+        Ok(endpoint)
+    }
+}
+
+impl Drop for UdListener{
+    fn drop(&mut self){
+        unsafe { rdma_destroy_id(self.id.id()) };
+        unsafe { rdma_destroy_event_channel(self.channel) };
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    const KINDS: [DatagramKind; 5] = [DatagramKind::Data, DatagramKind::Ping, DatagramKind::Pong, DatagramKind::Done, DatagramKind::Report];
+
+    #[test]
+    fn headers_round_trip(){
+        let mut buffer = vec![0xaau8; 64];
+        for kind in KINDS{
+            write_header(&mut buffer, kind, u64::MAX - 1, 0x0102_0304_0506_0708);
+            let datagram = read_header(&buffer, 64, 17).unwrap();
+            assert_eq!(datagram, Datagram{kind, seq: u64::MAX - 1, arg: 0x0102_0304_0506_0708, len: 64, src_qp: 17});
+        }
+    }
+
+    #[test]
+    fn header_layout_is_little_endian(){
+        let mut buffer = [0u8; UD_HEADER_LEN];
+        write_header(&mut buffer, DatagramKind::Done, 0x11, 0x22);
+        assert_eq!(&buffer[0..8], &[4, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(buffer[8], 0x11);
+        assert_eq!(buffer[16], 0x22);
+    }
+
+    #[test]
+    fn kind_codes_are_stable(){
+        let codes: Vec<u32> = KINDS.iter().map(|kind| kind.to_u32()).collect();
+        assert_eq!(codes, vec![1, 2, 3, 4, 5]);
+        for kind in KINDS{
+            assert_eq!(DatagramKind::from_u32(kind.to_u32()), Some(kind));
+        }
+    }
+
+    #[test]
+    fn short_datagrams_are_rejected(){
+        let mut buffer = [0u8; UD_HEADER_LEN];
+        write_header(&mut buffer, DatagramKind::Data, 1, 0);
+        assert!(read_header(&buffer, UD_HEADER_LEN, 0).is_some());
+        assert!(read_header(&buffer, UD_HEADER_LEN - 1, 0).is_none());
+        assert!(read_header(&buffer[..UD_HEADER_LEN - 1], UD_HEADER_LEN, 0).is_none());
+    }
+
+    #[test]
+    fn unknown_kinds_are_rejected(){
+        let mut buffer = [0u8; UD_HEADER_LEN];
+        for code in [0u32, 6, u32::MAX]{
+            buffer[0..4].copy_from_slice(&code.to_le_bytes());
+            assert!(read_header(&buffer, UD_HEADER_LEN, 0).is_none(), "kind {} was accepted", code);
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use std::{fmt::Display, time::{Duration, Instant}};
+use crate::counters::CounterDelta;
+
+fn timeval_duration(tv: libc::timeval) -> Duration{
+    Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000)
+}
+
+/// `getrusage` of the calling thread.
+fn thread_rusage() -> libc::rusage{
+    let mut rusage = unsafe { std::mem::zeroed::<libc::rusage>() };
+    unsafe { libc::getrusage(libc::RUSAGE_THREAD, &mut rusage) };
+    rusage
+}
+
+/// CPU time and context switches of one or more threads over a run.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CpuUsage{
+    /// Wall time of the run, the longest of all threads.
+    pub wall: Duration,
+    pub user: Duration,
+    pub system: Duration,
+    pub voluntary_switches: u64,
+    pub involuntary_switches: u64,
+    pub threads: u32,
+}
+
+impl CpuUsage{
+    pub fn cpu_time(&self) -> Duration{
+        self.user + self.system
+    }
+    /// CPU time as a percentage of one core per thread over the wall time.
+    pub fn utilization(&self) -> f64{
+        let available = self.wall.as_secs_f64() * self.threads.max(1) as f64;
+        if available == 0.0{
+            return 0.0;
+        }
+        self.cpu_time().as_secs_f64() * 100.0 / available
+    }
+    /// CPU nanoseconds spent per byte moved.
+    pub fn ns_per_byte(&self, bytes: u64) -> f64{
+        if bytes == 0{
+            return 0.0;
+        }
+        self.cpu_time().as_nanos() as f64 / bytes as f64
+    }
+    /// Adds the usage of threads which ran concurrently.
+    pub fn merge(&mut self, other: &CpuUsage){
+        self.wall = self.wall.max(other.wall);
+        self.user += other.user;
+        self.system += other.system;
+        self.voluntary_switches += other.voluntary_switches;
+        self.involuntary_switches += other.involuntary_switches;
+        self.threads += other.threads;
+    }
+}
+
+impl Display for CpuUsage{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+        write!(f, "user {:?} sys {:?}, {:.1}% per core over {} threads, {} voluntary / {} involuntary context switches",
+            self.user, self.system, self.utilization(), self.threads, self.voluntary_switches, self.involuntary_switches)
+    }
+}
+
+/// What a server session used in one run: its CPU and the change of the
+/// port counters, if it reads them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RunUsage{
+    pub cpu: CpuUsage,
+    pub counters: Option<CounterDelta>,
+}
+
+/// Measures the `CpuUsage` of the calling thread from `start` to `stop`,
+/// which have to be called on the same thread.
+pub struct UsageMeter{
+    start: Instant,
+    rusage: libc::rusage,
+}
+
+impl UsageMeter{
+    pub fn start() -> UsageMeter{
+        UsageMeter{
+            start: Instant::now(),
+            rusage: thread_rusage(),
+        }
+    }
+    pub fn stop(&self) -> CpuUsage{
+        let rusage = thread_rusage();
+        CpuUsage{
+            wall: self.start.elapsed(),
+            user: timeval_duration(rusage.ru_utime).saturating_sub(timeval_duration(self.rusage.ru_utime)),
+            system: timeval_duration(rusage.ru_stime).saturating_sub(timeval_duration(self.rusage.ru_stime)),
+            voluntary_switches: (rusage.ru_nvcsw - self.rusage.ru_nvcsw).max(0) as u64,
+            involuntary_switches: (rusage.ru_nivcsw - self.rusage.ru_nivcsw).max(0) as u64,
+            threads: 1,
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use std::{fs::OpenOptions, os::fd::AsRawFd, path::Path, ptr::null_mut};
+use bytes::BytesMut;
+use libc::c_void;
+use rdma_sys::*;
+use crate::{CustomError, Id, MrAddr, MrObject, Operation};
+
+/// Memory which can be registered in place.
+///
+/// # Safety
+///
+/// `as_mut_ptr` must return the same address for as long as the value is
+/// not dropped, also when the value itself is moved, and `len` bytes from
+/// that address must stay valid for reads, and for writes unless
+/// `is_writable` returns false.
+pub unsafe trait StableBuffer{
+    fn as_ptr(&self) -> *const u8;
+    fn as_mut_ptr(&mut self) -> *mut u8;
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool{
+        self.len() == 0
+    }
+    fn is_writable(&self) -> bool{
+        true
+    }
+}
+
+unsafe impl StableBuffer for &mut [u8]{
+    fn as_ptr(&self) -> *const u8{
+        <[u8]>::as_ptr(self)
+    }
+    fn as_mut_ptr(&mut self) -> *mut u8{
+        <[u8]>::as_mut_ptr(self)
+    }
+    fn len(&self) -> usize{
+        <[u8]>::len(self)
+    }
+}
+
+unsafe impl StableBuffer for Vec<u8>{
+    fn as_ptr(&self) -> *const u8{
+        Vec::as_ptr(self)
+    }
+    fn as_mut_ptr(&mut self) -> *mut u8{
+        Vec::as_mut_ptr(self)
+    }
+    fn len(&self) -> usize{
+        Vec::len(self)
+    }
+}
+
+unsafe impl StableBuffer for Box<[u8]>{
+    fn as_ptr(&self) -> *const u8{
+        <[u8]>::as_ptr(self)
+    }
+    fn as_mut_ptr(&mut self) -> *mut u8{
+        <[u8]>::as_mut_ptr(self)
+    }
+    fn len(&self) -> usize{
+        <[u8]>::len(self)
+    }
+}
+
+unsafe impl StableBuffer for BytesMut{
+    fn as_ptr(&self) -> *const u8{
+        <[u8]>::as_ptr(self)
+    }
+    fn as_mut_ptr(&mut self) -> *mut u8{
+        <[u8]>::as_mut_ptr(self)
+    }
+    fn len(&self) -> usize{
+        BytesMut::len(self)
+    }
+}
+
+/// A file mapped shared into memory, so that RDMA operations read from and
+/// write to the page cache of the file directly.
+pub struct MappedFile{
+    addr: *mut c_void,
+    len: usize,
+    writable: bool,
+}
+
+unsafe impl Send for MappedFile{}
+
+impl MappedFile{
+    /// Maps the whole file at `path`. With `writable` set, data written by
+    /// remote writes or received messages ends up in the file.
+    pub fn open<P: AsRef<Path>>(path: P, writable: bool) -> anyhow::Result<MappedFile, CustomError>{
+        let file = OpenOptions::new().read(true).write(writable).open(path)
+            .map_err(|e| CustomError::new(format!("open: {}", e), e.raw_os_error().unwrap_or(-1)))?;
+        let len = file.metadata()
+            .map_err(|e| CustomError::new(format!("metadata: {}", e), e.raw_os_error().unwrap_or(-1)))?
+            .len() as usize;
+        if len == 0{
+            return Err(CustomError::new("cannot map an empty file".to_string(), -1));
+        }
+        let prot = if writable { libc::PROT_READ | libc::PROT_WRITE } else { libc::PROT_READ };
+        let addr = unsafe { libc::mmap(null_mut(), len, prot, libc::MAP_SHARED, file.as_raw_fd(), 0) };
+        if addr == libc::MAP_FAILED{
+            return Err(CustomError::new("mmap".to_string(), std::io::Error::last_os_error().raw_os_error().unwrap_or(-1)));
+        }
+        Ok(MappedFile{addr, len, writable})
+    }
+    pub fn as_slice(&self) -> &[u8]{
+        unsafe { std::slice::from_raw_parts(self.addr as *const u8, self.len) }
+    }
+}
+
+unsafe impl StableBuffer for MappedFile{
+    fn as_ptr(&self) -> *const u8{
+        self.addr as *const u8
+    }
+    fn as_mut_ptr(&mut self) -> *mut u8{
+        self.addr.cast()
+    }
+    fn len(&self) -> usize{
+        self.len
+    }
+    fn is_writable(&self) -> bool{
+        self.writable
+    }
+}
+
+impl Drop for MappedFile{
+    fn drop(&mut self){
+        unsafe { libc::munmap(self.addr, self.len) };
+    }
+}
+
+/// A user buffer registered in place.
+///
+/// The handle owns the buffer, or the mutable borrow of it, for as long as it
+/// is registered, so the memory can neither be freed nor moved while the NIC
+/// may access it. Dropping the handle deregisters the memory,
+/// `into_inner` deregisters it and hands the buffer back. Read-only buffers
+/// are registered without local write access, so they can only be the
+/// source of sends and writes, or the target of remote reads.
+pub struct RegisteredBuffer<B: StableBuffer>{
+    buffer: B,
+    mr: *mut ibv_mr,
+}
+
+unsafe impl<B: StableBuffer + Send> Send for RegisteredBuffer<B>{}
+
+impl<B: StableBuffer> RegisteredBuffer<B>{
+    pub fn register(id: &Id, buffer: B, operation: Operation) -> anyhow::Result<RegisteredBuffer<B>, CustomError>{
+        if buffer.is_empty(){
+            return Err(CustomError::new("cannot register an empty buffer".to_string(), -1));
+        }
+        if id.id().is_null(){
+            return Err(CustomError::new("id is null".to_string(), -1));
+        }
+        let access = match (buffer.is_writable(), operation){
+            (true, operation) => operation.access_flags(),
+            (false, Operation::SendRecv) => 0,
+            (false, Operation::Read) => ibv_access_flags::IBV_ACCESS_REMOTE_READ.0 as i32,
+            (false, operation) => return Err(CustomError::new(format!("a read-only buffer cannot be registered for {:?}", operation), -libc::EACCES)),
+        };
+        let mut registered_buffer = RegisteredBuffer{
+            buffer,
+            mr: null_mut(),
+        };
+        let mr = unsafe { ibv_reg_mr((*id.id()).pd, registered_buffer.addr(), registered_buffer.len(), access) };
+        if mr.is_null(){
+            return Err(CustomError::new("ibv_reg_mr".to_string(), std::io::Error::last_os_error().raw_os_error().unwrap_or(-1)));
+        }
+        registered_buffer.mr = mr;
+        Ok(registered_buffer)
+    }
+    /// Address pair to post operations on the buffer with.
+    pub fn mr_addr(&mut self) -> MrAddr{
+        self.registered_mr_addr()
+    }
+    pub fn as_slice(&self) -> &[u8]{
+        unsafe { std::slice::from_raw_parts(self.buffer.as_ptr(), self.buffer.len()) }
+    }
+    /// Fails for read-only buffers, like files mapped without `writable`.
+    pub fn as_mut_slice(&mut self) -> anyhow::Result<&mut [u8], CustomError>{
+        if !self.buffer.is_writable(){
+            return Err(CustomError::new("buffer is read-only".to_string(), -libc::EACCES));
+        }
+        Ok(unsafe { std::slice::from_raw_parts_mut(self.buffer.as_mut_ptr(), self.buffer.len()) })
+    }
+    pub fn into_inner(mut self) -> B{
+        self.deregister();
+        let buffer = unsafe { std::ptr::read(&self.buffer) };
+        std::mem::forget(self);
+        buffer
+    }
+    fn deregister(&mut self){
+        if !self.mr.is_null(){
+            unsafe { ibv_dereg_mr(self.mr) };
+            self.mr = null_mut();
+        }
+    }
+}
+
+impl<B: StableBuffer> MrObject for RegisteredBuffer<B>{
+    fn len(&self) -> usize{
+        self.buffer.len()
+    }
+    fn addr(&mut self) -> *mut c_void{
+        self.buffer.as_mut_ptr().cast()
+    }
+    fn mr(&self) -> *mut ibv_mr{
+        self.mr
+    }
+}
+
+impl<B: StableBuffer> Drop for RegisteredBuffer<B>{
+    fn drop(&mut self){
+        self.deregister();
+    }
+}
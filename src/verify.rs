@@ -0,0 +1,268 @@
+use std::fmt::Display;
+use crate::access::{Rng, GOLDEN_GAMMA};
+
+/// Mismatches kept for the report, later ones are only counted.
+const MAX_REPORTED_MISMATCHES: usize = 16;
+
+/// Payload pattern written by the sender and checked by the receiver.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VerifyPattern{
+    /// Byte `i` of the payload is `i as u8`.
+    Incrementing,
+    /// xorshift64* stream seeded from the seed and the iteration.
+    Prng,
+    /// Every 8 byte word holds the iteration in its upper and the word index
+    /// in its lower 32 bits, so stale or reordered payloads show up.
+    Sequence,
+}
+
+impl VerifyPattern{
+    /// Wire representation carried in `MetaData`, 0 means no verification.
+    pub fn code(&self) -> u32{
+        match self{
+            VerifyPattern::Incrementing => 1,
+            VerifyPattern::Prng => 2,
+            VerifyPattern::Sequence => 3,
+        }
+    }
+    pub fn from_code(code: u32) -> Option<VerifyPattern>{
+        match code{
+            1 => Some(VerifyPattern::Incrementing),
+            2 => Some(VerifyPattern::Prng),
+            3 => Some(VerifyPattern::Sequence),
+            _ => None,
+        }
+    }
+}
+
+impl std::str::FromStr for VerifyPattern{
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err>{
+        match s{
+            "incrementing" => Ok(VerifyPattern::Incrementing),
+            "prng" => Ok(VerifyPattern::Prng),
+            "sequence" => Ok(VerifyPattern::Sequence),
+            _ => Err(format!("unknown verify pattern {}, expected incrementing, prng or sequence", s)),
+        }
+    }
+}
+
+/// Writes the pattern for `iteration` into `buffer`.
+pub fn fill(buffer: &mut [u8], pattern: VerifyPattern, seed: u64, iteration: u64){
+    match pattern{
+        VerifyPattern::Incrementing => {
+            for (i, byte) in buffer.iter_mut().enumerate(){
+                *byte = i as u8;
+            }
+        },
+        VerifyPattern::Prng => {
+            let mut rng = Rng::from_state(seed ^ iteration.wrapping_mul(GOLDEN_GAMMA));
+            for chunk in buffer.chunks_mut(8){
+                let word = rng.next_u64().to_le_bytes();
+                chunk.copy_from_slice(&word[..chunk.len()]);
+            }
+        },
+        VerifyPattern::Sequence => {
+            for (i, chunk) in buffer.chunks_mut(8).enumerate(){
+                let word = ((iteration << 32) | (i as u64 & 0xffff_ffff)).to_le_bytes();
+                chunk.copy_from_slice(&word[..chunk.len()]);
+            }
+        },
+    }
+}
+
+const CRC32C_POLY: u32 = 0x82F6_3B78;
+
+const fn crc32c_table() -> [u32; 256]{
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256{
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8{
+            crc = if crc & 1 == 1 { (crc >> 1) ^ CRC32C_POLY } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+/// Continues the CRC32C `crc` over `buffer`. Start with 0.
+pub fn crc32c(crc: u32, buffer: &[u8]) -> u32{
+    let mut crc = !crc;
+    for byte in buffer{
+        crc = CRC32C_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+pub struct Mismatch{
+    pub iteration: u64,
+    pub offset: usize,
+    pub expected: u8,
+    pub actual: u8,
+}
+
+/// Fills payloads on the sending side and checks them on the receiving side,
+/// keeping a CRC32C over the payloads this side filled or checked. Both sides
+/// of a send test hash the same messages, one-sided tests only check the
+/// final buffer on the server, so their digests differ.
+pub struct Verifier{
+    pattern: VerifyPattern,
+    seed: u64,
+    expected: Vec<u8>,
+    messages: u64,
+    bytes: u64,
+    crc: u32,
+    mismatch_count: u64,
+    mismatches: Vec<Mismatch>,
+}
+
+impl Verifier{
+    pub fn new(pattern: VerifyPattern, seed: u64) -> Verifier{
+        Verifier{
+            pattern,
+            seed,
+            expected: Vec::new(),
+            messages: 0,
+            bytes: 0,
+            crc: 0,
+            mismatch_count: 0,
+            mismatches: Vec::new(),
+        }
+    }
+    pub fn pattern(&self) -> VerifyPattern{
+        self.pattern
+    }
+    pub fn seed(&self) -> u64{
+        self.seed
+    }
+    /// Fills `buffer` with the payload of `iteration` and accounts for it.
+    pub fn fill(&mut self, buffer: &mut [u8], iteration: u64){
+        fill(buffer, self.pattern, self.seed, iteration);
+        self.messages += 1;
+        self.bytes += buffer.len() as u64;
+        self.crc = crc32c(self.crc, buffer);
+    }
+    /// Checks `buffer` against the payload of `stamp`, reporting mismatches
+    /// under `iteration`. Returns whether the payload matched.
+    pub fn check(&mut self, buffer: &[u8], stamp: u64, iteration: u64) -> bool{
+        self.expected.resize(buffer.len(), 0);
+        fill(&mut self.expected, self.pattern, self.seed, stamp);
+        self.messages += 1;
+        self.bytes += buffer.len() as u64;
+        self.crc = crc32c(self.crc, buffer);
+        let mut matched = true;
+        for (offset, (actual, expected)) in buffer.iter().zip(self.expected.iter()).enumerate(){
+            if actual == expected{
+                continue;
+            }
+            matched = false;
+            self.mismatch_count += 1;
+            if self.mismatches.len() < MAX_REPORTED_MISMATCHES{
+                self.mismatches.push(Mismatch{
+                    iteration,
+                    offset,
+                    expected: *expected,
+                    actual: *actual,
+                });
+            }
+        }
+        matched
+    }
+    pub fn mismatch_count(&self) -> u64{
+        self.mismatch_count
+    }
+    pub fn mismatches(&self) -> &[Mismatch]{
+        &self.mismatches
+    }
+    pub fn crc(&self) -> u32{
+        self.crc
+    }
+}
+
+impl Display for Verifier{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+        write!(f, "verify {:?}: {} messages, {} bytes, {} mismatched bytes, crc32c {:#010x}", self.pattern, self.messages, self.bytes, self.mismatch_count, self.crc)?;
+        for mismatch in self.mismatches.iter(){
+            write!(f, "\n  iteration {} offset {}: expected {:#04x} got {:#04x}", mismatch.iteration, mismatch.offset, mismatch.expected, mismatch.actual)?;
+        }
+        if self.mismatch_count > self.mismatches.len() as u64{
+            write!(f, "\n  ...")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    const PATTERNS: [VerifyPattern; 3] = [VerifyPattern::Incrementing, VerifyPattern::Prng, VerifyPattern::Sequence];
+
+    #[test]
+    fn crc32c_known_answer(){
+        assert_eq!(crc32c(0, b"123456789"), 0xE306_9283);
+        assert_eq!(crc32c(0, b""), 0);
+    }
+
+    #[test]
+    fn crc32c_continues_over_chunks(){
+        assert_eq!(crc32c(crc32c(0, b"1234"), b"56789"), crc32c(0, b"123456789"));
+    }
+
+    #[test]
+    fn filled_payloads_check_clean(){
+        for pattern in PATTERNS{
+            let mut sender = Verifier::new(pattern, 42);
+            let mut receiver = Verifier::new(pattern, 42);
+            // an odd length leaves a partial word at the end
+            let mut buffer = vec![0u8; 1021];
+            for iteration in 0..4{
+                sender.fill(&mut buffer, iteration);
+                assert!(receiver.check(&buffer, iteration, iteration), "{:?} iteration {}", pattern, iteration);
+            }
+            assert_eq!(receiver.mismatch_count(), 0);
+            assert_eq!(sender.crc(), receiver.crc());
+        }
+    }
+
+    #[test]
+    fn prng_and_sequence_depend_on_the_iteration(){
+        for pattern in [VerifyPattern::Prng, VerifyPattern::Sequence]{
+            let mut buffer = vec![0u8; 64];
+            fill(&mut buffer, pattern, 7, 1);
+            let mut verifier = Verifier::new(pattern, 7);
+            assert!(!verifier.check(&buffer, 2, 2), "{:?}", pattern);
+        }
+    }
+
+    #[test]
+    fn mismatches_report_offset_and_iteration(){
+        let mut verifier = Verifier::new(VerifyPattern::Incrementing, 0);
+        let mut buffer = vec![0u8; 32];
+        fill(&mut buffer, VerifyPattern::Incrementing, 0, 0);
+        buffer[5] ^= 0xff;
+        buffer[20] = 0;
+        assert!(!verifier.check(&buffer, 0, 9));
+        assert_eq!(verifier.mismatch_count(), 2);
+        let reported: Vec<(u64, usize, u8, u8)> = verifier.mismatches().iter()
+            .map(|mismatch| (mismatch.iteration, mismatch.offset, mismatch.expected, mismatch.actual))
+            .collect();
+        assert_eq!(reported, vec![(9, 5, 5, 0xfa), (9, 20, 20, 0)]);
+    }
+
+    #[test]
+    fn reported_mismatches_are_capped(){
+        let mut verifier = Verifier::new(VerifyPattern::Incrementing, 0);
+        let buffer = vec![0xffu8; 100];
+        assert!(!verifier.check(&buffer, 0, 0));
+        assert_eq!(verifier.mismatch_count(), 100);
+        assert_eq!(verifier.mismatches().len(), MAX_REPORTED_MISMATCHES);
+        assert!(verifier.to_string().ends_with("..."));
+    }
+}
@@ -0,0 +1,21 @@
+use std::time::{Duration, Instant};
+
+/// Operations run before a measurement and left out of its results, so it
+/// does not include cold caches, first-touch page faults and a scheduler
+/// which has not settled yet.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Warmup{
+    pub iterations: usize,
+    pub duration: Duration,
+}
+
+impl Warmup{
+    pub fn is_enabled(&self) -> bool{
+        self.iterations > 0 || !self.duration.is_zero()
+    }
+    /// Whether `done` operations since `start` finish the warmup, which takes
+    /// both the iterations and the duration.
+    pub fn is_done(&self, done: usize, start: Instant) -> bool{
+        done >= self.iterations && start.elapsed() >= self.duration
+    }
+}
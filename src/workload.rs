@@ -0,0 +1,398 @@
+use std::{fmt::Display, str::FromStr, time::Duration};
+use crate::{access::Rng, affinity::ThreadPlacement, counters::CounterDelta, histogram::Histogram, rate::{Arrival, Rate}, usage::CpuUsage};
+
+/// One-sided operations a workload mixes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum OpKind{
+    Read,
+    Write,
+    /// 8 byte compare-and-swap.
+    CompareAndSwap,
+}
+
+impl OpKind{
+    pub const ALL: [OpKind; 3] = [OpKind::Read, OpKind::Write, OpKind::CompareAndSwap];
+
+    fn index(&self) -> usize{
+        match self{
+            OpKind::Read => 0,
+            OpKind::Write => 1,
+            OpKind::CompareAndSwap => 2,
+        }
+    }
+}
+
+impl FromStr for OpKind{
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err>{
+        match s{
+            "read" => Ok(OpKind::Read),
+            "write" => Ok(OpKind::Write),
+            "cas" => Ok(OpKind::CompareAndSwap),
+            _ => Err(format!("unknown operation {}, expected read, write or cas", s)),
+        }
+    }
+}
+
+impl Display for OpKind{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+        match self{
+            OpKind::Read => write!(f, "read"),
+            OpKind::Write => write!(f, "write"),
+            OpKind::CompareAndSwap => write!(f, "cas"),
+        }
+    }
+}
+
+/// Message sizes of one operation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SizeDistribution{
+    Fixed(usize),
+    /// Uniform between both bounds, inclusive.
+    Uniform(usize, usize),
+    /// One of the sizes, each equally likely.
+    Choice(Vec<usize>),
+}
+
+impl SizeDistribution{
+    pub fn sample(&self, rng: &mut Rng) -> usize{
+        match self{
+            SizeDistribution::Fixed(size) => *size,
+            SizeDistribution::Uniform(min, max) => min + rng.below((max - min + 1) as u64) as usize,
+            SizeDistribution::Choice(sizes) => sizes[rng.below(sizes.len() as u64) as usize],
+        }
+    }
+    pub fn mean(&self) -> f64{
+        match self{
+            SizeDistribution::Fixed(size) => *size as f64,
+            SizeDistribution::Uniform(min, max) => (min + max) as f64 / 2.0,
+            SizeDistribution::Choice(sizes) => sizes.iter().sum::<usize>() as f64 / sizes.len() as f64,
+        }
+    }
+    pub fn max(&self) -> usize{
+        match self{
+            SizeDistribution::Fixed(size) => *size,
+            SizeDistribution::Uniform(_, max) => *max,
+            SizeDistribution::Choice(sizes) => sizes.iter().copied().max().unwrap_or(0),
+        }
+    }
+}
+
+impl FromStr for SizeDistribution{
+    type Err = String;
+    /// `N`, `MIN-MAX` or `A|B|C`, in bytes.
+    fn from_str(s: &str) -> Result<Self, Self::Err>{
+        let parse = |size: &str| match size.trim().parse::<usize>(){
+            Ok(size) if size > 0 => Ok(size),
+            _ => Err(format!("invalid message size {}", size)),
+        };
+        if let Some((min, max)) = s.split_once('-'){
+            let (min, max) = (parse(min)?, parse(max)?);
+            if min > max{
+                return Err(format!("invalid size range {}", s));
+            }
+            return Ok(SizeDistribution::Uniform(min, max));
+        }
+        if s.contains('|'){
+            let sizes = s.split('|').map(parse).collect::<Result<Vec<usize>, String>>()?;
+            return Ok(SizeDistribution::Choice(sizes));
+        }
+        Ok(SizeDistribution::Fixed(parse(s)?))
+    }
+}
+
+/// An operation of the mix with its weight and sizes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OpSpec{
+    pub kind: OpKind,
+    pub weight: u32,
+    pub size: SizeDistribution,
+}
+
+/// When a workload ends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WorkloadTarget{
+    /// Total operations over all QPs.
+    Ops(u64),
+    Duration(Duration),
+}
+
+/// Operations drawn by weight, each with its own size distribution, until
+/// the target is reached.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WorkloadSpec{
+    pub ops: Vec<OpSpec>,
+    pub target: WorkloadTarget,
+    /// Post on a schedule instead of as fast as completions allow.
+    pub open_loop: Option<OpenLoop>,
+}
+
+/// Schedule of an open-loop run.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OpenLoop{
+    pub rate: Rate,
+    pub arrival: Arrival,
+}
+
+impl WorkloadSpec{
+    /// Parses the mix `op:weight[:size],...`, e.g.
+    /// `read:70:4096,write:25:64-65536,cas:5`. Sizes are described at
+    /// `SizeDistribution`, CAS is always 8 bytes.
+    pub fn parse(mix: &str, target: WorkloadTarget) -> Result<WorkloadSpec, String>{
+        let mut ops = Vec::new();
+        for entry in mix.split(',').filter(|entry| !entry.trim().is_empty()){
+            let mut fields = entry.trim().splitn(3, ':');
+            let kind = fields.next().unwrap_or_default().parse::<OpKind>()?;
+            let weight = match fields.next(){
+                Some(weight) => weight.parse::<u32>().map_err(|_| format!("invalid weight in {}", entry))?,
+                None => return Err(format!("weight missing in {}, expected op:weight[:size]", entry)),
+            };
+            let size = match (kind, fields.next()){
+                (OpKind::CompareAndSwap, None) => SizeDistribution::Fixed(8),
+                (OpKind::CompareAndSwap, Some(size)) if size.trim() == "8" => SizeDistribution::Fixed(8),
+                (OpKind::CompareAndSwap, Some(_)) => return Err("cas is always 8 bytes".to_string()),
+                (_, Some(size)) => size.parse::<SizeDistribution>()?,
+                (_, None) => return Err(format!("size missing in {}", entry)),
+            };
+            ops.push(OpSpec{kind, weight, size});
+        }
+        let spec = WorkloadSpec{ops, target, open_loop: None};
+        spec.validate()?;
+        Ok(spec)
+    }
+    pub fn validate(&self) -> Result<(), String>{
+        if self.total_weight() == 0{
+            return Err("workload needs at least one operation with a weight above 0".to_string());
+        }
+        Ok(())
+    }
+    fn total_weight(&self) -> u64{
+        self.ops.iter().map(|op| op.weight as u64).sum()
+    }
+    /// Largest message of the workload, which sizes the buffers.
+    pub fn max_message_size(&self) -> usize{
+        self.ops.iter().map(|op| op.size.max()).max().unwrap_or(0)
+    }
+    /// Average message size of the mix, which turns a rate in bits per
+    /// second into operations.
+    pub fn mean_message_size(&self) -> f64{
+        let total_weight = self.total_weight() as f64;
+        self.ops.iter().map(|op| op.size.mean() * op.weight as f64 / total_weight).sum()
+    }
+    /// Operations per second of an open-loop run.
+    pub fn offered_ops_per_sec(&self) -> Option<f64>{
+        self.open_loop.map(|open_loop| open_loop.rate.ops_per_sec(self.mean_message_size()))
+    }
+    /// Draws the next operation and its message size.
+    pub fn sample(&self, rng: &mut Rng) -> (OpKind, usize){
+        let mut pick = rng.below(self.total_weight());
+        for op in self.ops.iter(){
+            if pick < op.weight as u64{
+                return (op.kind, op.size.sample(rng));
+            }
+            pick -= op.weight as u64;
+        }
+        unreachable!("weights were summed over the same operations")
+    }
+}
+
+/// Latencies and volume of one operation type.
+#[derive(Clone, Debug, Default)]
+pub struct OpStats{
+    pub count: u64,
+    pub bytes: u64,
+    pub latencies: Histogram,
+}
+
+impl OpStats{
+    pub fn record(&mut self, bytes: usize, latency: Duration){
+        self.count += 1;
+        self.bytes += bytes as u64;
+        self.latencies.record(latency);
+    }
+    pub fn merge(&mut self, other: &OpStats){
+        self.count += other.count;
+        self.bytes += other.bytes;
+        self.latencies.merge(&other.latencies);
+    }
+}
+
+/// Per operation type results of a workload run.
+#[derive(Clone, Debug, Default)]
+pub struct WorkloadReport{
+    stats: [OpStats; 3],
+    /// Wall time of the run, the longest of all QPs.
+    pub elapsed: Duration,
+    pub qps: usize,
+    /// Rate of an open-loop run, `None` for a closed loop.
+    pub offered_ops_per_sec: Option<f64>,
+    /// CPUs of the threads which posted and polled.
+    pub threads: Vec<ThreadPlacement>,
+    /// CPU usage of those threads during the run.
+    pub client_usage: CpuUsage,
+    /// CPU usage of the server sessions, as reported over the control plane.
+    pub server_usage: Option<CpuUsage>,
+    /// Change of the port counters of the client. They cover the whole
+    /// port, so of QPs which ran concurrently only the first one is kept.
+    pub counters: Option<CounterDelta>,
+    /// Change of the port counters of the server, kept the same way.
+    pub server_counters: Option<CounterDelta>,
+}
+
+impl WorkloadReport{
+    pub fn new(qps: usize) -> WorkloadReport{
+        WorkloadReport{
+            qps,
+            ..Default::default()
+        }
+    }
+    pub fn stats(&self, kind: OpKind) -> &OpStats{
+        &self.stats[kind.index()]
+    }
+    pub fn stats_mut(&mut self, kind: OpKind) -> &mut OpStats{
+        &mut self.stats[kind.index()]
+    }
+    /// Adds the results of a QP which ran concurrently with the others.
+    pub fn merge(&mut self, other: &WorkloadReport){
+        for kind in OpKind::ALL{
+            self.stats_mut(kind).merge(other.stats(kind));
+        }
+        self.elapsed = self.elapsed.max(other.elapsed);
+        if let Some(offered) = other.offered_ops_per_sec{
+            self.offered_ops_per_sec = Some(self.offered_ops_per_sec.unwrap_or(0.0) + offered);
+        }
+        self.threads.extend(other.threads.iter().cloned());
+        self.client_usage.merge(&other.client_usage);
+        if let Some(server_usage) = other.server_usage.as_ref(){
+            self.server_usage.get_or_insert_with(CpuUsage::default).merge(server_usage);
+        }
+        if self.counters.is_none(){
+            self.counters = other.counters.clone();
+        }
+        if self.server_counters.is_none(){
+            self.server_counters = other.server_counters.clone();
+        }
+    }
+    /// Writes the latency distribution of every operation type as CSV, see
+    /// `Histogram::write_csv`.
+    pub fn write_csv<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()>{
+        writeln!(writer, "op,latency_ns,percentile,count")?;
+        for kind in OpKind::ALL{
+            self.stats(kind).latencies.write_csv(writer, &kind.to_string())?;
+        }
+        Ok(())
+    }
+    pub fn total_ops(&self) -> u64{
+        self.stats.iter().map(|stats| stats.count).sum()
+    }
+    pub fn total_bytes(&self) -> u64{
+        self.stats.iter().map(|stats| stats.bytes).sum()
+    }
+}
+
+impl Display for WorkloadReport{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+        let secs = self.elapsed.as_secs_f64().max(f64::MIN_POSITIVE);
+        write!(f, "workload: {} ops over {} qps in {:?}, {:.0} ops/s", self.total_ops(), self.qps, self.elapsed, self.total_ops() as f64 / secs)?;
+        match self.offered_ops_per_sec{
+            Some(offered) => writeln!(f, " of {:.0} ops/s offered", offered)?,
+            None => writeln!(f, " closed loop")?,
+        }
+        for kind in OpKind::ALL{
+            let stats = self.stats(kind);
+            if stats.count == 0{
+                continue;
+            }
+            let latencies = &stats.latencies;
+            writeln!(f, "  {:<5} {:>10} ops {:>12.0} ops/s {:>9.3} Gbit/s  lat mean {:?} p50 {:?} p90 {:?} p99 {:?} p99.9 {:?} max {:?}",
+                kind, stats.count, stats.count as f64 / secs, stats.bytes as f64 * 8.0 / secs / 1e9,
+                latencies.mean(), latencies.percentile(0.5), latencies.percentile(0.9), latencies.percentile(0.99), latencies.percentile(0.999), latencies.max())?;
+        }
+        writeln!(f, "  client cpu: {}, {:.3} ns/byte", self.client_usage, self.client_usage.ns_per_byte(self.total_bytes()))?;
+        if let Some(server_usage) = self.server_usage.as_ref(){
+            writeln!(f, "  server cpu: {}, {:.3} ns/byte", server_usage, server_usage.ns_per_byte(self.total_bytes()))?;
+        }
+        if let Some(counters) = self.counters.as_ref(){
+            writeln!(f, "  client counters: {}", counters)?;
+        }
+        if let Some(server_counters) = self.server_counters.as_ref(){
+            writeln!(f, "  server counters: {}", server_counters)?;
+        }
+        for placement in self.threads.iter(){
+            writeln!(f, "  {}", placement)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    const TARGET: WorkloadTarget = WorkloadTarget::Ops(1000);
+
+    #[test]
+    fn parses_the_documented_mix(){
+        let spec = WorkloadSpec::parse("read:70:4096,write:25:64-65536,cas:5", TARGET).unwrap();
+        assert_eq!(spec.ops, vec![
+            OpSpec{kind: OpKind::Read, weight: 70, size: SizeDistribution::Fixed(4096)},
+            OpSpec{kind: OpKind::Write, weight: 25, size: SizeDistribution::Uniform(64, 65536)},
+            OpSpec{kind: OpKind::CompareAndSwap, weight: 5, size: SizeDistribution::Fixed(8)},
+        ]);
+        assert_eq!(spec.target, TARGET);
+        assert_eq!(spec.max_message_size(), 65536);
+    }
+
+    #[test]
+    fn parses_size_distributions(){
+        assert_eq!("512".parse(), Ok(SizeDistribution::Fixed(512)));
+        assert_eq!("64-128".parse(), Ok(SizeDistribution::Uniform(64, 128)));
+        assert_eq!("64|1024|4096".parse(), Ok(SizeDistribution::Choice(vec![64, 1024, 4096])));
+        assert!("0".parse::<SizeDistribution>().is_err());
+        assert!("big".parse::<SizeDistribution>().is_err());
+        assert!("128-64".parse::<SizeDistribution>().is_err());
+        assert!("64|".parse::<SizeDistribution>().is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_mixes(){
+        for mix in ["read", "read:x:64", "read:70", "write:10:128-64", "cas:5:16", "scan:5:64", "read:0:64,cas:0", ""]{
+            assert!(WorkloadSpec::parse(mix, TARGET).is_err(), "{} was accepted", mix);
+        }
+        assert!(WorkloadSpec::parse("cas:5:8", TARGET).is_ok());
+    }
+
+    #[test]
+    fn samples_follow_the_weights(){
+        let spec = WorkloadSpec::parse("read:70:4096,write:25:64-65536,cas:5", TARGET).unwrap();
+        let mut rng = Rng::new(7);
+        let mut counts = [0u64; 3];
+        let samples = 100_000;
+        for _ in 0..samples{
+            let (kind, size) = spec.sample(&mut rng);
+            match kind{
+                OpKind::Read => assert_eq!(size, 4096),
+                OpKind::Write => assert!((64..=65536).contains(&size)),
+                OpKind::CompareAndSwap => assert_eq!(size, 8),
+            }
+            counts[kind.index()] += 1;
+        }
+        for (kind, weight) in [(OpKind::Read, 70.0), (OpKind::Write, 25.0), (OpKind::CompareAndSwap, 5.0)]{
+            let share = counts[kind.index()] as f64 * 100.0 / samples as f64;
+            assert!((share - weight).abs() < 1.0, "{} drawn {:.2}% of the time, weight {}", kind, share, weight);
+        }
+    }
+
+    #[test]
+    fn zero_weights_are_never_drawn(){
+        let spec = WorkloadSpec::parse("read:0:64,write:1:64", TARGET).unwrap();
+        let mut rng = Rng::new(1);
+        assert!((0..1000).all(|_| spec.sample(&mut rng).0 == OpKind::Write));
+    }
+
+    #[test]
+    fn mean_size_is_weighted(){
+        let spec = WorkloadSpec::parse("read:3:100,write:1:200-400", TARGET).unwrap();
+        assert_eq!(spec.mean_message_size(), 150.0);
+    }
+}